@@ -0,0 +1,48 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares building the command line of a child by filtering that of the
+//! test process every time with doing so from the filtered command line
+//! cached by `FilteredArgs::cached_from_env()`, as `fork()` does.
+//!
+//! Run with `cargo bench --bench child_args`. Any arguments after the
+//! benchmark's own are filtered along with them, e.g. `cargo bench --bench
+//! child_args -- --color always --skip slow -Z unstable-options`.
+
+use std::time::{Duration, Instant};
+
+use rusty_fork::cmdline::FilteredArgs;
+use rusty_fork::UnknownFlagPolicy;
+
+const ITERATIONS: u32 = 10_000;
+
+fn time<F : FnMut () -> FilteredArgs>(what: &str, mut filtered_args: F) {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        let args = filtered_args()
+            .child_args(&format!("some::test_{}", i)).unwrap();
+        assert!(!args.is_empty());
+    }
+    let elapsed = start.elapsed();
+    println!("{:<28} {:>8.1} ms total, {:>6.2} µs per fork",
+             what, millis(elapsed),
+             millis(elapsed) * 1000.0 / f64::from(ITERATIONS));
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 +
+        f64::from(duration.subsec_nanos()) / 1e6
+}
+
+fn main() {
+    time("filtered on every fork", || FilteredArgs::from_env(
+        UnknownFlagPolicy::Strict).unwrap());
+    time("filtered once", || FilteredArgs::cached_from_env(
+        UnknownFlagPolicy::Strict).unwrap());
+}
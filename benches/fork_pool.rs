@@ -0,0 +1,77 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares running many small cases in a fresh process each with running
+//! them in a `ForkPool`.
+//!
+//! Run with `cargo bench --bench fork_pool`. This target has its own minimal
+//! harness: without a filter it times both approaches, and given a filter
+//! after `--` (as rusty-fork passes to children) it runs just that test.
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use rusty_fork::{fork, rusty_fork_id, ForkPool};
+
+const CASES: u32 = 100;
+const WORKERS: usize = 4;
+const PER_CASE_PREFIX: &str = "per_case_fork::";
+
+fn check(case: u32) {
+    assert_eq!(case * 2, case + case);
+}
+
+fn per_case_fork(case: u32) {
+    let status = fork(
+        &format!("{}{}", PER_CASE_PREFIX, case), rusty_fork_id!(),
+        |_| (),
+        |child, _| child.wait().unwrap(),
+        || check(case)).unwrap();
+    assert!(status.success());
+}
+
+fn pooled() {
+    let mut pool = ForkPool::new("pooled", rusty_fork_id!(), WORKERS).unwrap();
+    for case in 0..CASES {
+        pool.run_case(&case.to_string(), || check(case)).unwrap();
+    }
+    pool.finish().unwrap();
+}
+
+fn time<F : FnOnce ()>(what: &str, f: F) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!("{:<28} {:>8.1} ms total, {:>6.2} ms per case",
+             what, millis(elapsed), millis(elapsed) / f64::from(CASES));
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 +
+        f64::from(duration.subsec_nanos()) / 1e6
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let filter = args.iter().position(|arg| "--" == arg)
+        .and_then(|ix| args.get(ix + 1));
+
+    match filter {
+        // In a child; run only the test it was started for
+        Some(filter) if "pooled" == filter => pooled(),
+        Some(filter) if filter.starts_with(PER_CASE_PREFIX) =>
+            per_case_fork(filter[PER_CASE_PREFIX.len()..].parse().unwrap()),
+        Some(filter) => panic!("Unknown test {:?}", filter),
+        None => {
+            time("fresh process per case",
+                 || (0..CASES).for_each(per_case_fork));
+            time(&format!("pool of {} workers", WORKERS), pooled);
+        },
+    }
+}
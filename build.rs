@@ -0,0 +1,17 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::env;
+
+fn main() {
+    // Needed to find the runner Cargo is configured to use for this target
+    println!("cargo:rustc-env=RUSTY_FORK_TARGET={}",
+             env::var("TARGET").unwrap());
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,507 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fork_opts::{OutputHook, OutputStream};
+
+/// The line a child whose output is captured in memory writes to one of its
+/// streams once it has reached the fork point.
+///
+/// It is removed from the captured output.
+pub(crate) const RAN_LINE: &[u8] = b"\x1erusty-fork-ran\n";
+
+/// The most a pipe reader buffers while looking for the end of a line.
+const MAX_CHUNK: u64 = 8192;
+
+/// How long to wait for the pipes of a child which has exited to be drained.
+///
+/// Processes started by the child may keep the pipes open indefinitely, so
+/// whatever has arrived by then is taken to be all of the output.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A handle to output captured from a child process, as passed to the
+/// `in_parent` callback of
+/// [`ForkBuilder::run_captured`](struct.ForkBuilder.html#method.run_captured).
+///
+/// The output is held in a temporary file or, with
+/// [`ForkOpts::capture_in_memory`][capture_in_memory], in memory. Either way,
+/// reading from the handle returns whatever the child has written so far that
+/// has not yet been read through the handle, so `read_to_string()` returns
+/// the output up to now rather than waiting for the child to exit. Seeking
+/// back to the start makes everything available to read again.
+///
+/// [capture_in_memory]: struct.ForkOpts.html#method.capture_in_memory
+#[derive(Debug)]
+pub struct CapturedOutput {
+    inner: Inner,
+    pos: u64,
+}
+
+#[derive(Debug)]
+enum Inner {
+    /// The file, and the buffer feeding it if the child writes to a pipe.
+    File(fs::File, Option<Arc<PipeBuffer>>),
+    Memory(Arc<PipeBuffer>),
+}
+
+impl CapturedOutput {
+    pub(crate) fn file(file: fs::File, feed: Option<Arc<PipeBuffer>>)
+                       -> Self {
+        CapturedOutput { inner: Inner::File(file, feed), pos: 0 }
+    }
+
+    pub(crate) fn memory(buffer: Arc<PipeBuffer>) -> Self {
+        CapturedOutput { inner: Inner::Memory(buffer), pos: 0 }
+    }
+
+    /// Return the number of bytes captured so far.
+    pub fn len(&self) -> u64 {
+        match self.inner {
+            Inner::File(ref file, _) =>
+                file.metadata().map(|m| m.len()).unwrap_or(0),
+            Inner::Memory(ref buffer) => buffer.lock().len,
+        }
+    }
+
+    /// Return whether nothing has been captured so far.
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+
+    /// Return whether the output is held in memory, as opposed to in a
+    /// temporary file.
+    ///
+    /// This becomes false once output captured in memory grows beyond the
+    /// limit given to `ForkOpts::capture_in_memory`.
+    pub fn is_in_memory(&self) -> bool {
+        match self.inner {
+            Inner::File(..) => false,
+            Inner::Memory(ref buffer) => buffer.lock().spill.is_none(),
+        }
+    }
+
+    pub(crate) fn as_file_mut(&mut self) -> Option<&mut fs::File> {
+        match self.inner {
+            Inner::File(ref mut file, _) => Some(file),
+            Inner::Memory(_) => None,
+        }
+    }
+
+    /// Wait until everything the child wrote has been captured.
+    ///
+    /// Output captured in memory, or passed to
+    /// [`ForkOpts::on_output_line`][on_output_line], is read from pipes by
+    /// background threads. This waits for the child's end of the pipes to be
+    /// closed, but no longer than a second, since processes the child left
+    /// running may keep them open indefinitely. The wait methods of
+    /// `ChildWrapper` already do this once the child has exited, so this is
+    /// only needed if something else reaps the child.
+    ///
+    /// [on_output_line]: struct.ForkOpts.html#method.on_output_line
+    pub fn wait_drained(&self) {
+        match self.inner {
+            Inner::File(_, Some(ref buffer)) | Inner::Memory(ref buffer) =>
+                buffer.wait_drained(),
+            Inner::File(_, None) => (),
+        }
+    }
+
+    /// Cut the output down to the maximum size it may have, if any, and
+    /// return how many bytes of it have been thrown away.
+    pub(crate) fn truncate(&self) -> u64 {
+        match self.inner {
+            Inner::File(_, Some(ref buffer)) | Inner::Memory(ref buffer) =>
+                buffer.truncate(),
+            Inner::File(_, None) => 0,
+        }
+    }
+
+    /// Return whether the child wrote `RAN_LINE` to the output.
+    pub(crate) fn saw_ran_line(&self) -> bool {
+        match self.inner {
+            Inner::File(..) => false,
+            Inner::Memory(ref buffer) => buffer.lock().ran,
+        }
+    }
+}
+
+impl Read for CapturedOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The child shares the offset of the file, so it can't be relied on
+        let n = match self.inner {
+            Inner::File(ref mut file, _) => {
+                file.seek(SeekFrom::Start(self.pos))?;
+                file.read(buf)?
+            },
+            Inner::Memory(ref buffer) => buffer.read_at(self.pos, buf)?,
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for CapturedOutput {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            },
+            SeekFrom::Current(offset) => (self.pos, offset),
+            SeekFrom::End(offset) => (self.len(), offset),
+        };
+        let pos = if offset < 0 {
+            base.checked_sub(offset.unsigned_abs())
+        } else {
+            base.checked_add(offset as u64)
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "invalid seek to a negative position")),
+        }
+    }
+}
+
+/// Output of a child captured in memory, fed from one or more pipes by
+/// threads of its own.
+#[derive(Debug)]
+pub(crate) struct PipeBuffer {
+    state: Mutex<PipeState>,
+    drained: Condvar,
+    limit: usize,
+    /// The most output to keep, from the end.
+    max: Option<usize>,
+}
+
+#[derive(Debug)]
+struct PipeState {
+    data: Vec<u8>,
+    /// Where the output goes instead of `data` once there is too much of it.
+    spill: Option<fs::File>,
+    len: u64,
+    /// How much output was thrown away to stay within `max`.
+    discarded: u64,
+    ran: bool,
+    open_pipes: usize,
+    gave_up_draining: bool,
+}
+
+impl PipeState {
+    fn keep_last(&mut self, keep: usize) -> io::Result<()> {
+        let excess = self.len - keep as u64;
+        match self.spill {
+            Some(ref mut file) => {
+                let mut tail = vec![0; keep];
+                file.seek(SeekFrom::Start(excess))?;
+                file.read_exact(&mut tail)?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&tail)?;
+                file.set_len(keep as u64)?;
+            },
+            None => {
+                self.data.drain(..excess as usize);
+            },
+        }
+        self.len = keep as u64;
+        self.discarded += excess;
+        Ok(())
+    }
+}
+
+impl PipeBuffer {
+    /// Create an empty buffer which moves to a temporary file once more than
+    /// `limit` bytes are written to it.
+    ///
+    /// If `max` is given, only roughly the last `max` bytes are kept, and
+    /// exactly that many after `truncate()`.
+    pub(crate) fn new(limit: usize, max: Option<usize>) -> Arc<Self> {
+        Self::with_spill(limit, None, max)
+    }
+
+    /// Create a buffer which writes everything to `file`, keeping the last
+    /// `max` bytes like `new()` does.
+    pub(crate) fn feeding_file(file: fs::File, max: Option<usize>)
+                               -> Arc<Self> {
+        Self::with_spill(0, Some(file), max)
+    }
+
+    fn with_spill(limit: usize, spill: Option<fs::File>, max: Option<usize>)
+                  -> Arc<Self> {
+        Arc::new(PipeBuffer {
+            state: Mutex::new(PipeState {
+                data: Vec::new(),
+                spill,
+                len: 0,
+                discarded: 0,
+                ran: false,
+                open_pipes: 0,
+                gave_up_draining: false,
+            }),
+            drained: Condvar::new(),
+            limit,
+            max,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Copy everything read from `pipe` into `buffer` on a background thread
+    /// until `pipe` reaches EOF.
+    ///
+    /// The thread keeps reading whatever the parent is doing in the meantime,
+    /// so the child can never block on a full pipe.
+    ///
+    /// If `hook` is given, it is also passed each line read from `pipe`, as
+    /// coming from `stream`.
+    pub(crate) fn drain<R : Read + Send + 'static>(
+        buffer: &Arc<Self>, pipe: R,
+        stream: OutputStream, hook: Option<OutputHook>)
+    {
+        /// Mark the pipe closed even if `hook` panics.
+        struct Closed(Arc<PipeBuffer>);
+        impl Drop for Closed {
+            fn drop(&mut self) {
+                self.0.lock().open_pipes -= 1;
+                self.0.drained.notify_all();
+            }
+        }
+
+        buffer.lock().open_pipes += 1;
+        let closed = Closed(Arc::clone(buffer));
+        thread::spawn(move || {
+            let buffer = &closed.0;
+            let mut pipe = io::BufReader::new(pipe);
+            let mut chunk = Vec::new();
+            // What has been read of the line to pass to `hook` next
+            let mut line = Vec::new();
+            // Read up to the end of each line to find `RAN_LINE`, but without
+            // letting a child which never writes a line feed make us buffer
+            // everything.
+            while let Ok(n) = (&mut pipe).take(MAX_CHUNK)
+                .read_until(b'\n', &mut chunk)
+            {
+                if 0 == n {
+                    break;
+                }
+
+                let (end, ran) = if chunk.ends_with(RAN_LINE) {
+                    (chunk.len() - RAN_LINE.len(), true)
+                } else if chunk.ends_with(b"\n") {
+                    (chunk.len(), false)
+                } else {
+                    // Hold on to anything which could be the start of
+                    // `RAN_LINE`
+                    (chunk.len().saturating_sub(RAN_LINE.len() - 1), false)
+                };
+                buffer.append(&chunk[..end], ran);
+                if let Some(ref hook) = hook {
+                    line.extend_from_slice(&chunk[..end]);
+                    if line.ends_with(b"\n") {
+                        hook.call(stream, &line);
+                        line.clear();
+                    }
+                }
+                if ran {
+                    chunk.clear();
+                } else {
+                    chunk.drain(..end);
+                }
+            }
+
+            buffer.append(&chunk, false);
+            if let Some(ref hook) = hook {
+                line.extend_from_slice(&chunk);
+                if !line.is_empty() {
+                    hook.call(stream, &line);
+                }
+            }
+        });
+    }
+
+    fn append(&self, bytes: &[u8], ran: bool) {
+        let mut state = self.lock();
+        state.ran |= ran;
+        if bytes.is_empty() {
+            return;
+        }
+
+        if state.spill.is_none() &&
+            state.data.len() + bytes.len() > self.limit
+        {
+            // If the file can't be created, just keep going in memory
+            if let Ok(mut file) = tempfile::tempfile() {
+                if file.write_all(&state.data).is_ok() {
+                    state.data = Vec::new();
+                    state.spill = Some(file);
+                }
+            }
+        }
+
+        let written = match state.spill {
+            Some(ref mut file) => file.seek(SeekFrom::End(0))
+                .and_then(|_| file.write_all(bytes)).is_ok(),
+            None => {
+                state.data.extend_from_slice(bytes);
+                true
+            },
+        };
+        if written {
+            state.len += bytes.len() as u64;
+        }
+
+        // Only throw output away in large steps so that we aren't forever
+        // moving the rest around
+        if let Some(max) = self.max {
+            if state.len > max as u64 &&
+                state.len - max as u64 >= cmp::max(max as u64, MAX_CHUNK)
+            {
+                let _ = state.keep_last(max);
+            }
+        }
+    }
+
+    /// Throw away all but the last `max` bytes, if there is a maximum, and
+    /// return how many bytes have been thrown away in total.
+    pub(crate) fn truncate(&self) -> u64 {
+        let mut state = self.lock();
+        if let Some(max) = self.max {
+            if state.len > max as u64 {
+                let _ = state.keep_last(max);
+            }
+        }
+        state.discarded
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        match state.spill {
+            Some(ref mut file) => {
+                file.seek(SeekFrom::Start(pos))?;
+                file.read(buf)
+            },
+            None => {
+                let start = cmp::min(pos, state.data.len() as u64) as usize;
+                let available = &state.data[start..];
+                let n = cmp::min(available.len(), buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                Ok(n)
+            },
+        }
+    }
+
+    pub(crate) fn wait_drained(&self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        let mut state = self.lock();
+        while state.open_pipes > 0 && !state.gave_up_draining {
+            let now = Instant::now();
+            if now >= deadline {
+                state.gave_up_draining = true;
+                break;
+            }
+            state = self.drained.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn drained(limit: usize, input: &[u8]) -> (CapturedOutput, String) {
+        let buffer = PipeBuffer::new(limit, None);
+        PipeBuffer::drain(&buffer, io::Cursor::new(input.to_vec()),
+                          OutputStream::Stdout, None);
+        let mut output = CapturedOutput::memory(buffer);
+        output.wait_drained();
+        let mut text = String::new();
+        output.read_to_string(&mut text).unwrap();
+        (output, text)
+    }
+
+    #[test]
+    fn ran_line_removed() {
+        let mut input = b"running 1 test\npartial ".to_vec();
+        input.extend_from_slice(RAN_LINE);
+        input.extend_from_slice(b"rest");
+        let (output, text) = drained(1024, &input);
+        assert_eq!("running 1 test\npartial rest", text);
+        assert!(output.saw_ran_line());
+        assert!(output.is_in_memory());
+    }
+
+    #[test]
+    fn ran_line_found_after_long_partial_line() {
+        let mut input = vec![b'x'; MAX_CHUNK as usize - 3];
+        input.extend_from_slice(RAN_LINE);
+        let (output, text) = drained(1 << 20, &input);
+        assert_eq!(MAX_CHUNK as usize - 3, text.len());
+        assert!(output.saw_ran_line());
+    }
+
+    #[test]
+    fn spills_past_limit() {
+        let input = b"0123456789\n".repeat(10);
+        let (mut output, text) = drained(32, &input);
+        assert_eq!(String::from_utf8_lossy(&input), text);
+        assert!(!output.is_in_memory());
+        assert!(!output.saw_ran_line());
+
+        output.seek(SeekFrom::End(-11)).unwrap();
+        let mut text = String::new();
+        output.read_to_string(&mut text).unwrap();
+        assert_eq!("0123456789\n", text);
+    }
+
+    #[test]
+    fn truncation_keeps_last_bytes() {
+        for &limit in &[16, 1 << 20] {
+            let buffer = PipeBuffer::new(limit, Some(32));
+            let input = (0..10000).map(|i| format!("{}\n", i))
+                .collect::<String>();
+            PipeBuffer::drain(&buffer, io::Cursor::new(input.clone()),
+                              OutputStream::Stdout, None);
+            let mut output = CapturedOutput::memory(Arc::clone(&buffer));
+            output.wait_drained();
+            assert_eq!(input.len() as u64 - 32, output.truncate());
+            assert_eq!(32, output.len());
+
+            let mut text = String::new();
+            output.read_to_string(&mut text).unwrap();
+            assert_eq!(&input[input.len() - 32..], text);
+        }
+    }
+
+    #[test]
+    fn reads_continue_where_they_left_off() {
+        let buffer = PipeBuffer::new(1024, None);
+        let mut output = CapturedOutput::memory(Arc::clone(&buffer));
+        let mut text = String::new();
+        buffer.append(b"first\n", false);
+        output.read_to_string(&mut text).unwrap();
+        assert_eq!("first\n", text);
+
+        text.clear();
+        buffer.append(b"second\n", false);
+        output.read_to_string(&mut text).unwrap();
+        assert_eq!("second\n", text);
+    }
+}
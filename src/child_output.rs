@@ -0,0 +1,52 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::child_wrapper::ExitStatusWrapper;
+
+/// The exit status and captured output of a child process, as returned by
+/// [`fork_output`](fn.fork_output.html).
+///
+/// The output is lossily converted to UTF-8.
+#[derive(Clone, Debug)]
+pub struct ChildOutput {
+    status: ExitStatusWrapper,
+    stdout: String,
+    stderr: String,
+}
+
+impl ChildOutput {
+    pub(crate) fn new(status: ExitStatusWrapper, stdout: &[u8],
+                      stderr: &[u8]) -> Self {
+        ChildOutput {
+            status,
+            stdout: String::from_utf8_lossy(stdout).into_owned(),
+            stderr: String::from_utf8_lossy(stderr).into_owned(),
+        }
+    }
+
+    /// Return the exit status of the child process.
+    pub fn status(&self) -> ExitStatusWrapper {
+        self.status
+    }
+
+    /// Return what the child wrote to standard output.
+    ///
+    /// With `CaptureMode::Merged`, this also includes whatever the child
+    /// wrote to standard error.
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    /// Return what the child wrote to standard error.
+    ///
+    /// This is always empty with `CaptureMode::Merged`.
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+}
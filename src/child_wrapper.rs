@@ -9,13 +9,20 @@
 
 use std::fmt;
 use std::io;
-use std::process::{Child, Output};
-#[cfg(feature = "timeout")]
+use std::process::{Child, ChildStdin, Output};
+use std::sync::Arc;
 use std::time::Duration;
+#[cfg(feature = "timeout")]
+use std::time::Instant;
 
 #[cfg(feature = "timeout")]
 use wait_timeout::ChildExt;
 
+#[cfg(feature = "timeout")]
+use crate::fork_opts::TimeoutHook;
+use crate::captured_output::PipeBuffer;
+use crate::sys;
+
 /// Wraps `std::process::ExitStatus`. Historically, this was due to the
 /// `wait_timeout` crate having its own `ExitStatus` type.
 ///
@@ -32,7 +39,7 @@ enum ExitStatusEnum {
 }
 
 impl ExitStatusWrapper {
-    fn std(es: ::std::process::ExitStatus) -> Self {
+    pub(crate) fn std(es: ::std::process::ExitStatus) -> Self {
         ExitStatusWrapper(ExitStatusEnum::Std(es))
     }
 
@@ -82,6 +89,15 @@ impl ExitStatusWrapper {
     pub fn unix_signal(&self) -> Option<i32> {
         None
     }
+
+    /// Returns the conventional name (e.g. `SIGSEGV`) of the Unix signal
+    /// which terminated this process, if it is a standard signal.
+    ///
+    /// Like `unix_signal()`, this returns `None` on Windows and if the
+    /// process exited otherwise.
+    pub fn unix_signal_name(&self) -> Option<&'static str> {
+        self.unix_signal().and_then(sys::signal_name)
+    }
 }
 
 impl fmt::Debug for ExitStatusWrapper {
@@ -114,11 +130,132 @@ impl fmt::Display for ExitStatusWrapper {
 pub struct ChildWrapper {
     child: Child,
     exit_status: Option<ExitStatusWrapper>,
+    #[cfg(feature = "timeout")]
+    deadline: Option<Instant>,
+    #[cfg(feature = "timeout")]
+    grace_period: Duration,
+    #[cfg(feature = "timeout")]
+    spawned: Instant,
+    #[cfg(feature = "timeout")]
+    on_timeout: Option<TimeoutHook>,
+    /// The tree of processes rooted at the child, if it is tracked.
+    tree: Option<sys::ProcessTree>,
+    /// How long the child had been running when it was found to have
+    /// exceeded its deadline.
+    timed_out_after: Option<Duration>,
+    /// The buffers fed by pipes from the child, which must be drained once it
+    /// exits.
+    output_feeds: Vec<Arc<PipeBuffer>>,
 }
 
 impl ChildWrapper {
     pub(crate) fn new(child: Child) -> Self {
-        ChildWrapper { child, exit_status: None }
+        ChildWrapper {
+            child, exit_status: None,
+            #[cfg(feature = "timeout")]
+            deadline: None,
+            #[cfg(feature = "timeout")]
+            grace_period: Duration::from_secs(0),
+            #[cfg(feature = "timeout")]
+            spawned: Instant::now(),
+            #[cfg(feature = "timeout")]
+            on_timeout: None,
+            tree: None,
+            timed_out_after: None,
+            output_feeds: Vec::new(),
+        }
+    }
+
+    /// Make the wait methods wait for `feeds` to be drained once the child
+    /// has exited.
+    pub(crate) fn set_output_feeds(&mut self, feeds: Vec<Arc<PipeBuffer>>) {
+        self.output_feeds = feeds;
+    }
+
+    /// Record that the child exited with `status` and wait for the rest of
+    /// its output to arrive.
+    fn reaped(&mut self, status: ExitStatusWrapper) -> ExitStatusWrapper {
+        self.exit_status = Some(status);
+        for feed in &self.output_feeds {
+            feed.wait_drained();
+        }
+        status
+    }
+
+    /// Set the point in time after which the wait methods kill the child
+    /// rather than waiting any longer.
+    ///
+    /// Once the deadline passes, the child is first asked to terminate, and
+    /// only killed forcibly if it is still running after `grace_period`.
+    /// Before that, `on_timeout` is called with the process id of the child.
+    #[cfg(feature = "timeout")]
+    pub(crate) fn set_deadline(&mut self, deadline: Instant,
+                               grace_period: Duration,
+                               on_timeout: Option<TimeoutHook>) {
+        self.deadline = Some(deadline);
+        self.grace_period = grace_period;
+        self.on_timeout = on_timeout;
+    }
+
+    /// Return the point in time after which the wait methods kill the child,
+    /// if the fork has a timeout.
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Make killing the child kill all of `tree` instead.
+    pub(crate) fn set_process_tree(&mut self, tree: sys::ProcessTree) {
+        self.tree = Some(tree);
+    }
+
+    /// Kill whatever remains of the tree of processes rooted at the child,
+    /// if it is tracked.
+    pub(crate) fn kill_tree(&self) {
+        if let Some(ref tree) = self.tree {
+            let _ = tree.kill();
+        }
+    }
+
+    /// Return the exit status of the child if it has been reaped.
+    pub(crate) fn exit_status(&self) -> Option<ExitStatusWrapper> {
+        self.exit_status
+    }
+
+    /// Returns whether the child was killed because it exceeded the timeout
+    /// configured for the fork.
+    ///
+    /// This only becomes `true` as a result of one of the wait methods on
+    /// this handle noticing the timeout has elapsed.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out_after.is_some()
+    }
+
+    /// If the child was killed because it exceeded the timeout, return how
+    /// long it had been running at that point.
+    pub(crate) fn timed_out_after(&self) -> Option<Duration> {
+        self.timed_out_after
+    }
+
+    /// Terminate the child because it ran past its deadline and reap it.
+    #[cfg(feature = "timeout")]
+    fn expire(&mut self) -> io::Result<ExitStatusWrapper> {
+        // The waits may return marginally before the deadline; don't report
+        // the child as having run for less time than it was allowed.
+        let now = Instant::now();
+        let expired_at = match self.deadline {
+            Some(deadline) if deadline > now => deadline,
+            _ => now,
+        };
+        self.timed_out_after = Some(expired_at - self.spawned);
+
+        if let Some(ref on_timeout) = self.on_timeout {
+            on_timeout.call(self.child.id());
+        }
+
+        self.terminate_gracefully(self.grace_period)
     }
 
     /// Return a reference to the inner `std::process::Child`.
@@ -139,19 +276,74 @@ impl ChildWrapper {
         &mut self.child
     }
 
+    /// Take the writing end of the pipe to the child's standard input.
+    ///
+    /// This returns `None` unless the fork was configured with
+    /// `StdinSource::Piped`, or if the pipe has already been taken.
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
     /// Forces the child to exit. This is equivalent to sending a SIGKILL on
     /// unix platforms.
     ///
+    /// If the fork was configured with `ForkOpts::kill_process_tree()`, all
+    /// processes started by the child are killed as well.
+    ///
     /// If the process has already been reaped by this handle, returns a
     /// `NotFound` error.
     pub fn kill(&mut self) -> io::Result<()> {
         if self.exit_status.is_none() {
+            self.kill_tree();
             self.child.kill()
         } else {
             Err(io::Error::new(io::ErrorKind::NotFound, "Process already reaped"))
         }
     }
 
+    /// Asks the child to exit, forces it to exit if it is still running after
+    /// `grace`, and reaps it, returning the status it exited with.
+    ///
+    /// On Unix, the child is first sent `SIGTERM`, so that it has a chance
+    /// to flush output, remove temporary files, and so forth, and only sent
+    /// `SIGKILL` once `grace` has elapsed. Windows has no way to politely ask
+    /// a single process to exit, so the child is always forced to exit
+    /// immediately there. A `grace` of zero forces the child to exit
+    /// immediately on every platform. This is how a child which exceeded the
+    /// timeout is terminated, with `ForkOpts::grace_period()` as `grace`.
+    ///
+    /// If the fork was configured with `ForkOpts::kill_process_tree()`, all
+    /// processes started by the child are asked to exit and killed as well.
+    ///
+    /// If the process has already been reaped by this handle, its exit status
+    /// is returned.
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn terminate_gracefully(&mut self, grace: Duration)
+                                -> io::Result<ExitStatusWrapper> {
+        if let Some(status) = self.exit_status {
+            return Ok(status);
+        }
+
+        // The child could exit before the signals below are sent, in which
+        // case sending them fails but the waits still succeed.
+        if grace > Duration::from_secs(0) {
+            let _ = match self.tree {
+                Some(ref tree) => tree.terminate(),
+                None => sys::terminate(&mut self.child),
+            };
+            if let Some(status) = self.child.wait_timeout(grace)? {
+                return Ok(self.reaped(ExitStatusWrapper::std(status)));
+            }
+        }
+
+        self.kill_tree();
+        let _ = self.child.kill();
+        let status = ExitStatusWrapper::std(self.child.wait()?);
+        Ok(self.reaped(status))
+    }
+
     /// Returns the OS-assigned processor identifier associated with this child.
     ///
     /// This succeeds even if the child has already been reaped. In this case,
@@ -172,14 +364,28 @@ impl ChildWrapper {
     ///
     /// If the child process has already been reaped, returns its exit status
     /// without blocking.
+    ///
+    /// If the fork was configured with a timeout, this waits no further than
+    /// the end of the timeout, at which point the child is killed and its
+    /// resulting exit status returned.
     pub fn wait(&mut self) -> io::Result<ExitStatusWrapper> {
         if let Some(status) = self.exit_status {
-            Ok(status)
-        } else {
-            let status = ExitStatusWrapper::std(self.child.wait()?);
-            self.exit_status = Some(status);
-            Ok(status)
+            return Ok(status);
+        }
+
+        #[cfg(feature = "timeout")]
+        {
+            if let Some(deadline) = self.deadline {
+                return match self.child.wait_timeout(remaining(deadline))? {
+                    Some(status) =>
+                        Ok(self.reaped(ExitStatusWrapper::std(status))),
+                    None => self.expire(),
+                };
+            }
         }
+
+        let status = ExitStatusWrapper::std(self.child.wait()?);
+        Ok(self.reaped(status))
     }
 
     /// Attempts to collect the exit status of the child if it has already exited.
@@ -193,14 +399,28 @@ impl ChildWrapper {
     /// If the child has exited, then `Ok(Some(status))` is returned. If the
     /// exit status is not available at this time then `Ok(None)` is returned.
     /// If an error occurs, then that error is returned.
+    ///
+    /// If the fork was configured with a timeout which has elapsed and the
+    /// child is still running, the child is killed and its resulting exit
+    /// status returned.
     pub fn try_wait(&mut self) -> io::Result<Option<ExitStatusWrapper>> {
         if let Some(status) = self.exit_status {
-            Ok(Some(status))
-        } else {
-            let status = self.child.try_wait()?.map(ExitStatusWrapper::std);
-            self.exit_status = status;
-            Ok(status)
+            return Ok(Some(status));
+        }
+
+        let status = self.child.try_wait()?.map(ExitStatusWrapper::std)
+            .map(|status| self.reaped(status));
+
+        #[cfg(feature = "timeout")]
+        {
+            if let Some(deadline) = self.deadline {
+                if status.is_none() && Instant::now() >= deadline {
+                    return self.expire().map(Some);
+                }
+            }
         }
+
+        Ok(status)
     }
 
     /// Simultaneously waits for the child to exit and collect all remaining
@@ -218,6 +438,8 @@ impl ChildWrapper {
     /// `stdout(Stdio::piped())` or `stderr(Stdio::piped())`, respectively.
     ///
     /// If the process has already been reaped, returns a `NotFound` error.
+    ///
+    /// This does not honour any timeout configured for the fork.
     pub fn wait_with_output(self) -> io::Result<Output> {
         if self.exit_status.is_some() {
             return Err(io::Error::new(
@@ -231,18 +453,50 @@ impl ChildWrapper {
     ///
     /// If the process has already been reaped, returns its exit status
     /// immediately. Otherwise, if the process terminates within the duration,
-    /// returns `Ok(Sone(..))`, or `Ok(None)` otherwise.
+    /// returns `Ok(Some(..))`, or `Ok(None)` otherwise.
+    ///
+    /// If the fork was configured with a timeout which elapses before `dur`
+    /// does, the child is killed at the end of the timeout and its resulting
+    /// exit status returned.
     ///
     /// This is only present if the "timeout" feature is enabled.
     #[cfg(feature = "timeout")]
     pub fn wait_timeout(&mut self, dur: Duration)
                         -> io::Result<Option<ExitStatusWrapper>> {
         if let Some(status) = self.exit_status {
-            Ok(Some(status))
+            return Ok(Some(status));
+        }
+
+        let (dur, hits_deadline) = match self.deadline {
+            Some(deadline) => {
+                let remaining = remaining(deadline);
+                if remaining <= dur {
+                    (remaining, true)
+                } else {
+                    (dur, false)
+                }
+            },
+            None => (dur, false),
+        };
+
+        let status = self.child.wait_timeout(dur)?
+            .map(ExitStatusWrapper::std)
+            .map(|status| self.reaped(status));
+        if status.is_none() && hits_deadline {
+            self.expire().map(Some)
         } else {
-            let status = self.child.wait_timeout(dur)?.map(ExitStatusWrapper::std);
-            self.exit_status = status;
             Ok(status)
         }
     }
 }
+
+/// Return the time left until `deadline`, or zero if it has already passed.
+#[cfg(feature = "timeout")]
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
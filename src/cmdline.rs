@@ -7,11 +7,60 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! Internal module which parses and modifies the rust test command-line.
+//! Parsing and rewriting of the Rust test harness command line.
+//!
+//! This is the machinery `fork()` uses to build the command line of a child
+//! process, exposed for runners which spawn copies of the current test
+//! executable themselves. A child process is normally built by
+//!
+//! - running the current executable with the arguments from
+//!   `FilteredArgs::child_args()`, and
+//!
+//! - setting the environment variable `OCCURS_ENV` to the value returned by
+//!   `occurs_for_child()`, so that the child can recognise itself with
+//!   `is_child_of()`.
+//!
+//! ```
+//! use std::env;
+//! use std::process::Command;
+//! use rusty_fork::cmdline::{self, FilteredArgs};
+//! use rusty_fork::UnknownFlagPolicy;
+//!
+//! # /*
+//! #[test]
+//! # */
+//! fn my_test() {
+//!     if cmdline::is_child_of("my_test") {
+//!         println!("hello from the child");
+//!         return;
+//!     }
+//!
+//!     let args = FilteredArgs::from_env(UnknownFlagPolicy::Strict).unwrap();
+//!     let status = Command::new(env::current_exe().unwrap())
+//!         .args(args.child_args("my_test").unwrap())
+//!         .env(cmdline::OCCURS_ENV, cmdline::occurs_for_child("my_test"))
+//!         .status().unwrap();
+//!     assert!(status.success());
+//! }
+//! # fn main() { } // Can't actually run this without a test harness
+//! ```
 
 use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use fnv::FnvHashMap;
+use once_cell::sync::Lazy;
 
 use crate::error::*;
+use crate::fork_opts::UnknownFlagPolicy;
+
+/// Environment variable which, when set to `1`, forces
+/// `UnknownFlagPolicy::StripAndWarn`.
+const ALLOW_UNKNOWN_FLAGS_ENV: &str = "RUSTY_FORK_ALLOW_UNKNOWN_FLAGS";
 
 /// How a hyphen-prefixed argument passed to the parent process should be
 /// handled when constructing the command-line for the child process.
@@ -32,22 +81,69 @@ enum FlagType {
 ///
 /// A number of these that affect output are dropped because we append our own
 /// options.
+///
+/// `--report-time` takes an optional value (e.g., `--report-time=colored`).
+/// Since getopts only accepts such a value in the joined form, it is listed as
+/// not taking an argument; the `=` form is handled like any other.
 static KNOWN_FLAGS: &[(&str, FlagType)] = &[
+    // A benchmark which forks must be run as one in the child too, where the
+    // harness would otherwise skip it
     ("--bench", FlagType::Pass(false)),
     ("--color", FlagType::Pass(true)),
-    ("--ensure-time", FlagType::Drop(false)),
+    // Timing the one test in the child is as useful as timing any other
+    ("--ensure-time", FlagType::Pass(false)),
+    // The child always gets `--exact` from `FILTER_ARGS`, which would
+    // conflict with a second one
     ("--exact", FlagType::Drop(false)),
-    ("--exclude-should-panic", FlagType::Pass(false)),
+    ("--exclude-should-panic", FlagType::Drop(false)),
+    // Harmless in the child, which runs its one test in-process either way
     ("--force-run-in-process", FlagType::Pass(false)),
+    // The child's output is replayed into ours, where e.g. JSON events of its
+    // own would confuse whatever is consuming our output. `RUN_TEST_ARGS`
+    // makes it use terse output instead.
+    ("--format", FlagType::Drop(true)),
+    ("--help", FlagType::Error("Tests run but --help passed to process?")),
+    // These must reach the child, or else it would skip an ignored test
+    // instead of running it
+    ("--ignored", FlagType::Pass(false)),
+    ("--include-ignored", FlagType::Pass(false)),
+    // `fork()` fails with `Error::Listing` before getting here, but other
+    // users of `FilteredArgs` may not
+    ("--list", FlagType::Error("Tests run but --list passed to process?")),
+    ("--logfile", FlagType::Drop(true)),
+    ("--nocapture", FlagType::Drop(false)),
+    ("--quiet", FlagType::Drop(false)),
+    ("--report-time", FlagType::Pass(false)),
+    ("--show-output", FlagType::Pass(false)),
+    ("--shuffle", FlagType::Drop(false)),
+    ("--shuffle-seed", FlagType::Drop(true)),
+    // The child is only given the one test to run, which the parent already
+    // did not skip
+    ("--skip", FlagType::Drop(true)),
+    ("--test", FlagType::Pass(false)),
+    ("--test-threads", FlagType::Drop(true)),
+    ("-Z", FlagType::Pass(true)),
+    ("-h", FlagType::Error("Tests run but -h passed to process?")),
+    ("-q", FlagType::Drop(false)),
+];
+
+/// Table of all flags accepted by harnesses built with the `libtest-mimic`
+/// crate, as of version 0.6.
+///
+/// These are a subset of those of the standard harness, and are handled the
+/// same way.
+static MIMIC_KNOWN_FLAGS: &[(&str, FlagType)] = &[
+    ("--bench", FlagType::Pass(false)),
+    ("--color", FlagType::Pass(true)),
+    ("--exact", FlagType::Drop(false)),
     ("--format", FlagType::Drop(true)),
     ("--help", FlagType::Error("Tests run but --help passed to process?")),
     ("--ignored", FlagType::Pass(false)),
     ("--include-ignored", FlagType::Pass(false)),
     ("--list", FlagType::Error("Tests run but --list passed to process?")),
     ("--logfile", FlagType::Drop(true)),
-    ("--nocapture", FlagType::Drop(true)),
+    ("--nocapture", FlagType::Drop(false)),
     ("--quiet", FlagType::Drop(false)),
-    ("--report-time", FlagType::Drop(true)),
     ("--show-output", FlagType::Pass(false)),
     ("--skip", FlagType::Drop(true)),
     ("--test", FlagType::Pass(false)),
@@ -57,8 +153,104 @@ static KNOWN_FLAGS: &[(&str, FlagType)] = &[
     ("-q", FlagType::Drop(false)),
 ];
 
+/// The kind of test harness the test executable was built with, which
+/// determines the flags it accepts.
+///
+/// The flags of the test process are only validated against those of one
+/// kind of harness; a flag the harness does not know about is an unknown
+/// flag even if some other harness has one of the same name. Either way,
+/// the child process is given its test name as the only filter, following
+/// `--exact` and `--`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HarnessKind {
+    /// The standard Rust test harness, `libtest`.
+    #[default]
+    Libtest,
+    /// A custom harness (`harness = false`) built with the `libtest-mimic`
+    /// crate.
+    LibtestMimic,
+}
+
+impl HarnessKind {
+    fn known_flags(self) -> &'static [(&'static str, FlagType)] {
+        match self {
+            HarnessKind::Libtest => KNOWN_FLAGS,
+            HarnessKind::LibtestMimic => MIMIC_KNOWN_FLAGS,
+        }
+    }
+}
+
+static HARNESS_KIND: Lazy<RwLock<HarnessKind>> =
+    Lazy::new(|| RwLock::new(HarnessKind::default()));
+
+/// Set the kind of harness the test executable was built with for the rest
+/// of the process.
+///
+/// This should be called at the start of `main()` of a custom harness,
+/// before any test forks; tests built with the standard harness do not need
+/// it.
+pub fn set_harness_kind(kind: HarnessKind) {
+    *HARNESS_KIND.write().unwrap_or_else(|e| e.into_inner()) = kind;
+    clear_filtered_args_cache();
+}
+
+/// Return the kind of harness set by `set_harness_kind()`.
+pub fn harness_kind() -> HarnessKind {
+    *HARNESS_KIND.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Flags which the test harness only accepts together with
+/// `-Zunstable-options`.
+///
+/// Only flags which are still unstable are listed: stable harnesses reject
+/// `-Z` altogether, so e.g. `--include-ignored` and `--show-output`, which
+/// have since been stabilised, must not bring it along.
+static UNSTABLE_FLAGS: &[&str] = &[
+    "--ensure-time",
+    "--exclude-should-panic",
+    "--force-run-in-process",
+    "--report-time",
+    "--shuffle",
+    "--shuffle-seed",
+];
+
+/// The flag enabling the flags in `UNSTABLE_FLAGS`.
+const UNSTABLE_GATE: &str = "-Zunstable-options";
+
+/// Return whether `args`, which do not include the command name, enable
+/// unstable options, in either of the ways `-Z` can be written.
+fn has_unstable_gate(args: &[OsString]) -> bool {
+    args.iter().enumerate().any(|(ix, arg)| {
+        UNSTABLE_GATE == arg.as_os_str() || (
+            "-Z" == arg.as_os_str() &&
+                args.get(ix + 1).map(OsString::as_os_str) ==
+                Some(OsStr::new("unstable-options")))
+    })
+}
+
+/// Table of the values accepted by flags which take one of a fixed set of
+/// arguments.
+static FLAG_VALUES: &[(&str, &[&str])] = &[
+    ("--color", &["auto", "always", "never"]),
+    ("--format", &["pretty", "terse", "json", "junit"]),
+];
+
+pub(crate) fn allowed_values(flag: &str) -> Option<&'static [&'static str]> {
+    FLAG_VALUES.iter().cloned().filter(|&(name, _)| name == flag)
+        .map(|(_, values)| values).next()
+}
+
+fn check_flag_value(flag: &str, value: &str) -> Result<()> {
+    match allowed_values(flag) {
+        Some(values) if !values.contains(&value) =>
+            Err(Error::InvalidFlagValue(flag.to_owned(), value.to_owned())),
+        _ => Ok(()),
+    }
+}
+
 fn look_up_flag_from_table(flag: &str) -> Option<FlagType> {
-    KNOWN_FLAGS.iter().cloned().filter(|&(name, _)| name == flag)
+    harness_kind().known_flags().iter().cloned()
+        .filter(|&(name, _)| name == flag)
         .map(|(_, typ)| typ).next()
 }
 
@@ -69,8 +261,104 @@ pub(crate) fn env_var_for_flag(flag: &str) -> String {
     var
 }
 
+/// How a flag passed to the test process is handled when building the
+/// command line of a child process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagKind {
+    /// The flag stands alone and is not passed to the child process.
+    Switch,
+    /// The flag is followed by an argument, and neither is passed to the
+    /// child process.
+    TakesArg,
+    /// The flag stands alone and is passed to the child process verbatim.
+    PassThrough,
+    /// The flag is followed by an argument, and both are passed to the child
+    /// process.
+    PassThroughArg,
+    /// The flag cannot be handled in any sensible way, for the given reason,
+    /// and results in `Error::DisallowedFlag`.
+    Disallowed(&'static str),
+}
+
+impl FlagKind {
+    fn flag_type(self) -> FlagType {
+        match self {
+            FlagKind::Switch => FlagType::Drop(false),
+            FlagKind::TakesArg => FlagType::Drop(true),
+            FlagKind::PassThrough => FlagType::Pass(false),
+            FlagKind::PassThroughArg => FlagType::Pass(true),
+            FlagKind::Disallowed(message) => FlagType::Error(message),
+        }
+    }
+
+    fn of(typ: FlagType) -> Self {
+        match typ {
+            FlagType::Drop(false) => FlagKind::Switch,
+            FlagType::Drop(true) => FlagKind::TakesArg,
+            FlagType::Pass(false) => FlagKind::PassThrough,
+            FlagType::Pass(true) => FlagKind::PassThroughArg,
+            FlagType::Error(message) => FlagKind::Disallowed(message),
+        }
+    }
+}
+
+/// Return the table of flags of the current kind of harness (see
+/// `set_harness_kind()`) which are built into rusty-fork.
+///
+/// This does not include flags added with `register_flag()` or through
+/// `RUSTY_FORK_FLAG_*` environment variables; use `flag_kind()` to take those
+/// into account.
+pub fn known_flags() -> Vec<(&'static str, FlagKind)> {
+    harness_kind().known_flags().iter()
+        .map(|&(name, typ)| (name, FlagKind::of(typ))).collect()
+}
+
+/// Return how the given flag (including its leading hyphens) is handled, or
+/// `None` if it is unknown.
+pub fn flag_kind(flag: &str) -> Option<FlagKind> {
+    look_up_flag(flag).map(FlagKind::of)
+}
+
+/// Flags registered at runtime via `register_flag()`.
+static REGISTERED_FLAGS: Lazy<RwLock<FnvHashMap<String, FlagType>>> =
+    Lazy::new(Default::default);
+
+/// Teach rusty-fork how to handle an additional flag passed to the test
+/// process.
+///
+/// This is intended for custom harnesses or wrappers which add their own
+/// flags to the test binary's command line. `name` is the flag including its
+/// leading hyphens, e.g., `--our-tool-seed` or `-x`. A flag which takes an
+/// argument may be given either as a separate argument or in `--flag=value`
+/// form.
+///
+/// Registration is process-wide and affects all forks started afterwards, so
+/// it should happen before the first test forks. A registered flag takes
+/// precedence over both the built-in table and `RUSTY_FORK_FLAG_*`
+/// environment variables. Registering the same flag again replaces the
+/// earlier registration.
+///
+/// ## Panics
+///
+/// Panics if `name` does not start with `-`, or if it starts with a single
+/// `-` but is not exactly one character long after that.
+pub fn register_flag(name: &str, kind: FlagKind) {
+    assert!(name.starts_with("--") ||
+            (name.starts_with('-') && 1 == name[1..].chars().count()),
+            "rusty-fork: invalid flag name {:?}", name);
+
+    REGISTERED_FLAGS.write().unwrap_or_else(|e| e.into_inner())
+        .insert(name.to_owned(), kind.flag_type());
+    clear_filtered_args_cache();
+}
+
+fn look_up_flag_from_registry(flag: &str) -> Option<FlagType> {
+    REGISTERED_FLAGS.read().unwrap_or_else(|e| e.into_inner())
+        .get(flag).cloned()
+}
+
 fn look_up_flag_from_env(flag: &str) -> Option<FlagType> {
-    env::var(&env_var_for_flag(flag)).ok().map(
+    env::var(env_var_for_flag(flag)).ok().map(
         |value| match &*value {
             "pass" => FlagType::Pass(false),
             "pass-arg" => FlagType::Pass(true),
@@ -83,13 +371,84 @@ fn look_up_flag_from_env(flag: &str) -> Option<FlagType> {
 }
 
 fn look_up_flag(flag: &str) -> Option<FlagType> {
-    look_up_flag_from_table(flag).or_else(|| look_up_flag_from_env(flag))
+    look_up_flag_from_registry(flag)
+        .or_else(|| look_up_flag_from_table(flag))
+        .or_else(|| look_up_flag_from_env(flag))
+}
+
+fn effective_policy(policy: UnknownFlagPolicy) -> UnknownFlagPolicy {
+    match env::var(ALLOW_UNKNOWN_FLAGS_ENV) {
+        Ok(ref value) if value == "1" => UnknownFlagPolicy::StripAndWarn,
+        _ => policy,
+    }
+}
+
+/// Return the known flag with the name closest to that of `flag`, if any is
+/// close enough for `flag` to plausibly be a typo of it.
+fn suggest_flag(flag: &str) -> Option<String> {
+    // Single-letter flags are too short for anything to be close
+    if !flag.starts_with("--") {
+        return None;
+    }
+
+    // Allow roughly one typo per four characters
+    let max_distance = match flag.len() / 4 {
+        0 => 1,
+        n if n > 3 => 3,
+        n => n,
+    };
+    let registered = REGISTERED_FLAGS.read().unwrap_or_else(|e| e.into_inner())
+        .keys().cloned().collect::<Vec<_>>();
+    harness_kind().known_flags().iter().map(|&(name, _)| name.to_owned())
+        .chain(registered)
+        .filter(|name| name.starts_with("--"))
+        .map(|name| (edit_distance(flag, &name), name))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, name)| name)
 }
 
-fn look_up_flag_or_err(flag: &str) -> Result<(bool, bool)> {
+/// Return the Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a_ch) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &b_ch) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_ch == b_ch { 0 } else { 1 };
+            current[j + 1] = substitution
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous.copy_from_slice(&current);
+    }
+    previous[b.len()]
+}
+
+/// Look up how `flag` should be handled, returning whether it is passed to the
+/// child and whether it takes a separate argument.
+///
+/// `full_args` is the command line `flag` was encountered in, for error
+/// reporting.
+fn look_up_flag_or_err(flag: &str, policy: UnknownFlagPolicy,
+                       full_args: &[OsString])
+                       -> Result<(bool, bool)> {
     match look_up_flag(flag) {
-        None =>
-            Err(Error::UnknownFlag(flag.to_owned())),
+        None => match policy {
+            UnknownFlagPolicy::Strict => Err(Error::UnknownFlag {
+                flag: flag.to_owned(),
+                full_args: full_args.iter()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+                suggestion: suggest_flag(flag),
+            }),
+            UnknownFlagPolicy::StripAndWarn => {
+                eprintln!("rusty-fork: warning: not passing unknown flag \
+                           '{}' to the child process", flag);
+                Ok((false, false))
+            },
+        },
         Some(FlagType::Error(message)) =>
             Err(Error::DisallowedFlag(flag.to_owned(), message.to_owned())),
         Some(FlagType::Pass(has_arg)) => Ok((true, has_arg)),
@@ -97,15 +456,162 @@ fn look_up_flag_or_err(flag: &str) -> Result<(bool, bool)> {
     }
 }
 
+/// What an argument of a test harness command line is, as determined by
+/// `ArgWalker`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArgRole {
+    /// A flag, possibly with its value joined onto it.
+    Flag,
+    /// The separate value of the flag before it.
+    Value,
+    /// The `--` after which everything is a filter.
+    Separator,
+    /// A filter.
+    Filter,
+}
+
+/// Classifies the arguments of a command line (after the zeroth) one at a
+/// time, using the same flag tables as `strip_cmdline()` to tell which flags
+/// take a separate value.
+///
+/// Unknown flags are taken not to have a value.
+#[derive(Debug, Default)]
+struct ArgWalker {
+    /// Whether the next argument is the value of the flag before it.
+    value_next: bool,
+    /// Whether the `--` has been seen.
+    filters_only: bool,
+}
+
+impl ArgWalker {
+    fn next(&mut self, arg: &OsStr) -> ArgRole {
+        let text = arg.to_string_lossy();
+        if self.filters_only {
+            ArgRole::Filter
+        } else if self.value_next {
+            self.value_next = false;
+            ArgRole::Value
+        } else if "--" == text {
+            self.filters_only = true;
+            ArgRole::Separator
+        } else if text.starts_with("--") {
+            self.value_next = !text.contains('=') && flag_has_arg(&text);
+            ArgRole::Flag
+        } else if text.starts_with('-') && "-" != text {
+            let mut chars = text.chars().skip(1);
+            while let Some(flag_ch) = chars.next() {
+                if flag_has_arg(&format!("-{}", flag_ch)) {
+                    // The rest of the argument, if any, is the value
+                    self.value_next = chars.next().is_none();
+                    break;
+                }
+            }
+            ArgRole::Flag
+        } else {
+            ArgRole::Filter
+        }
+    }
+}
+
+/// Return whether `flag` is known to take an argument.
+fn flag_has_arg(flag: &str) -> bool {
+    match look_up_flag(flag) {
+        Some(FlagType::Pass(has_arg)) | Some(FlagType::Drop(has_arg)) =>
+            has_arg,
+        _ => false,
+    }
+}
+
+/// Replace every `@path` argument after the zeroth with the contents of the
+/// file at `path`, one argument per line.
+///
+/// Only arguments in the place of a filter are expanded: those after `--`,
+/// or which are the value of a flag, are left alone, so that e.g.
+/// `--skip @foo` still skips tests with `@foo` in their name. Argument files
+/// are not expanded recursively.
+fn expand_argfiles<A : Iterator<Item = OsString>>(args: A)
+                                                 -> Result<Vec<OsString>> {
+    let mut ret = Vec::new();
+    let mut walker = ArgWalker::default();
+    for (ix, arg) in args.enumerate() {
+        if 0 == ix {
+            ret.push(arg);
+            continue;
+        }
+
+        // A filter which is an argument file changes nothing in the walker,
+        // so the walker can see it before it is expanded
+        let after_separator = walker.filters_only;
+        let role = walker.next(&arg);
+        match argfile_path(&arg) {
+            Some(path) if ArgRole::Filter == role && !after_separator => {
+                let contents = fs::read_to_string(&path).map_err(
+                    |err| Error::ArgFile(
+                        path.to_string_lossy().into_owned(), err))?;
+                for line in contents.lines() {
+                    walker.next(OsStr::new(line));
+                    ret.push(line.into());
+                }
+            },
+            _ => ret.push(arg),
+        }
+    }
+    Ok(ret)
+}
+
+/// If `arg` is of the form `@path`, return `path`.
+#[cfg(unix)]
+fn argfile_path(arg: &OsStr) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = arg.as_bytes();
+    if bytes.starts_with(b"@") {
+        Some(OsStr::from_bytes(&bytes[1..]).into())
+    } else {
+        None
+    }
+}
+
+/// If `arg` is of the form `@path`, return `path`.
+#[cfg(not(unix))]
+fn argfile_path(arg: &OsStr) -> Option<PathBuf> {
+    // There's no portable way to slice an `OsStr`, but an argument starting
+    // with `@` is at least not a flag, so a non-Unicode one can be treated
+    // as a filter
+    arg.to_str().and_then(|arg| if arg.starts_with('@') {
+        Some(arg[1..].into())
+    } else {
+        None
+    })
+}
+
 /// Parse the full command line as would be given to the Rust test harness, and
 /// strip out any flags that should be dropped as well as all filters. The
 /// resulting argument list is also guaranteed to not have "--", so that new
 /// flags can be appended.
 ///
+/// Long flags which take an argument are accepted both as `--flag value` and
+/// as `--flag=value`; those which are passed on are always passed in the
+/// latter form.
+///
 /// The zeroth argument (the command name) is also dropped.
-pub(crate) fn strip_cmdline<A : Iterator<Item = String>>
-    (args: A) -> Result<Vec<String>>
+///
+/// Flags which are not known are handled according to `policy`, unless the
+/// environment overrides it. The exception is an unknown long flag written as
+/// a single `--flag=value` argument, which is always dropped with a warning.
+/// What makes unknown flags a problem is that there is no telling whether the
+/// following argument belongs to the flag or is a filter; in this form, the
+/// value is already part of the same argument, so the following argument
+/// cannot belong to the flag and the flag can be dropped on its own without
+/// guessing. (Were it a flag that takes an optional value, the harness would
+/// only accept the value in this form anyway.)
+fn strip_cmdline<A : Iterator<Item = OsString>>
+    (args: A, policy: UnknownFlagPolicy) -> Result<Vec<OsString>>
 {
+    let policy = effective_policy(policy);
+    let original = args.collect::<Vec<_>>();
+    let args = expand_argfiles(original.iter().cloned())?;
+
     #[derive(Clone, Copy)]
     enum State {
         Ground, PassingArg, DroppingArg,
@@ -113,33 +619,61 @@ pub(crate) fn strip_cmdline<A : Iterator<Item = String>>
 
     // Start in DroppingArg since we need to drop the exec name.
     let mut state = State::DroppingArg;
+    // The long flag whose separate argument is expected next, if any
+    let mut arg_of = String::new();
     let mut ret = Vec::new();
 
     for arg in args {
+        // Flags are recognised by the Unicode parts of the argument. Any
+        // argument passed on is passed in its original form, however.
+        let text = arg.to_string_lossy().into_owned();
         match state {
             State::DroppingArg => {
+                check_flag_value(&arg_of, &text)?;
                 state = State::Ground;
             },
 
             State::PassingArg => {
-                ret.push(arg);
+                check_flag_value(&arg_of, &text)?;
+                if arg_of.is_empty() {
+                    // Argument to a short flag
+                    ret.push(arg);
+                } else {
+                    // Join the argument onto the long flag, so the child gets
+                    // the same command line whichever spelling was used
+                    let flag = ret.last_mut().expect("flag not passed");
+                    flag.push("=");
+                    flag.push(&arg);
+                }
                 state = State::Ground;
             },
 
             State::Ground => {
-                if &arg == "--" {
+                if text == "--" {
                     // Everything after this point is a filter
                     break;
-                } else if &arg == "-" {
+                } else if text == "-" {
                     // "-" by itself is interpreted as a filter
                     continue;
-                } else if arg.starts_with("--") {
+                } else if text.starts_with("--") {
+                    let mut parts = text.splitn(2, '=');
+                    let name = parts.next().expect("split returned empty");
+                    let policy = if text.contains('=') {
+                        // Unambiguous; see above
+                        UnknownFlagPolicy::StripAndWarn
+                    } else {
+                        policy
+                    };
                     let (pass, has_arg) = look_up_flag_or_err(
-                        arg.split('=').next().expect("split returned empty"))?;
+                        name, policy, &original)?;
                     // If there's an = sign, the physical argument also
                     // contains the associated value, so don't pay attention to
                     // has_arg.
-                    let has_arg = has_arg && !arg.contains('=');
+                    if let Some(value) = parts.next() {
+                        check_flag_value(name, value)?;
+                    }
+                    let has_arg = has_arg && !text.contains('=');
+                    arg_of = name.to_owned();
                     if pass {
                         ret.push(arg);
                         if has_arg {
@@ -148,14 +682,16 @@ pub(crate) fn strip_cmdline<A : Iterator<Item = String>>
                     } else if has_arg {
                         state = State::DroppingArg;
                     }
-                } else if arg.starts_with("-") {
-                    let mut chars = arg.chars();
+                } else if text.starts_with('-') {
+                    let mut chars = text.chars();
                     let mut to_pass = "-".to_owned();
 
                     chars.next(); // skip initial '-'
+                    arg_of.clear();
                     while let Some(flag_ch) = chars.next() {
                         let flag = format!("-{}", flag_ch);
-                        let (pass, has_arg) = look_up_flag_or_err(&flag)?;
+                        let (pass, has_arg) = look_up_flag_or_err(
+                            &flag, policy, &original)?;
                         if pass {
                             to_pass.push(flag_ch);
                             if has_arg {
@@ -177,8 +713,10 @@ pub(crate) fn strip_cmdline<A : Iterator<Item = String>>
                         }
                     }
 
-                    if "-" != &to_pass {
-                        ret.push(to_pass);
+                    if to_pass == text {
+                        ret.push(arg);
+                    } else if "-" != to_pass {
+                        ret.push(to_pass.into());
                     }
                 } else {
                     // It's a filter, drop
@@ -190,9 +728,404 @@ pub(crate) fn strip_cmdline<A : Iterator<Item = String>>
     Ok(ret)
 }
 
+/// Environment variable holding additional arguments for every child
+/// process.
+const EXTRA_ARGS_ENV: &str = "RUSTY_FORK_EXTRA_ARGS";
+/// Environment variable holding a command through which to run every child
+/// process.
+const WRAPPER_ENV: &str = "RUSTY_FORK_WRAPPER";
+/// Environment variable holding the path to the executable to run as every
+/// child process instead of the current one.
+const TEST_EXE_ENV: &str = "RUSTY_FORK_TEST_EXE";
+/// Environment variable holding the runner of the test executable, for when
+/// Cargo's `CARGO_TARGET_<triple>_RUNNER` is not visible to the tests.
+const RUNNER_ENV: &str = "RUSTY_FORK_RUNNER";
+
+/// Split `value` into words like a POSIX shell would, honouring single
+/// quotes, double quotes, and backslash escapes, but without performing any
+/// expansion.
+///
+/// Returns `None` if a quote is unterminated or there is a trailing
+/// backslash.
+fn split_shell_words(value: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = value.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        ch => word.push(ch),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            ch @ '"' | ch @ '\\' | ch @ '$' | ch @ '`' =>
+                                word.push(ch),
+                            ch => {
+                                word.push('\\');
+                                word.push(ch);
+                            },
+                        },
+                        ch => word.push(ch),
+                    }
+                }
+            },
+            '\\' => {
+                in_word = true;
+                word.push(chars.next()?);
+            },
+            ch if ch.is_whitespace() => if in_word {
+                words.push(word.clone());
+                word.clear();
+                in_word = false;
+            },
+            ch => {
+                in_word = true;
+                word.push(ch);
+            },
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+/// Split the environment variable `var` into words like a shell would.
+///
+/// Returns `None` if the variable is not set.
+fn args_from_env(var: &str) -> Result<Option<Vec<String>>> {
+    match env::var(var) {
+        Ok(value) => split_shell_words(&value).map(Some).ok_or_else(
+            || Error::InvalidArgsEnv(var.to_owned(), value)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Return the extra arguments for the child process given by
+/// `RUSTY_FORK_EXTRA_ARGS`, if any.
+fn extra_args_from_env() -> Result<Vec<String>> {
+    args_from_env(EXTRA_ARGS_ENV).map(Option::unwrap_or_default)
+}
+
+/// Return the wrapper command given by `RUSTY_FORK_WRAPPER`, or if that is
+/// not set, the runner given by `RUSTY_FORK_RUNNER` or the one Cargo was told
+/// to use for the current target, if any is set and not empty.
+pub(crate) fn wrapper_from_env() -> Result<Option<Vec<String>>> {
+    let wrapper = match args_from_env(WRAPPER_ENV)? {
+        Some(wrapper) => wrapper,
+        None => match args_from_env(RUNNER_ENV)? {
+            Some(runner) => runner,
+            None => runner_from_env(env!("RUSTY_FORK_TARGET")),
+        },
+    };
+    Ok(Some(wrapper).filter(|wrapper| !wrapper.is_empty()))
+}
+
+/// Return the name of the environment variable by which Cargo can be told
+/// the runner for the target `target`.
+pub(crate) fn runner_env(target: &str) -> String {
+    format!("CARGO_TARGET_{}_RUNNER",
+            target.to_uppercase().replace(['-', '.'], "_"))
+}
+
+/// Return the runner given by `runner_env(target)`, split into words like
+/// Cargo does, or nothing if it is not set.
+fn runner_from_env(target: &str) -> Vec<String> {
+    env::var(runner_env(target)).map(|runner| runner.split_whitespace()
+                                     .map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Return the test executable given by `RUSTY_FORK_TEST_EXE`, if it is set
+/// and not empty.
+pub(crate) fn test_exe_from_env() -> Option<PathBuf> {
+    env::var_os(TEST_EXE_ENV).filter(|exe| !exe.is_empty()).map(Into::into)
+}
+
+/// Return whether the test process was asked not to capture test output,
+/// either by `--nocapture` or `--show-output` among its flags, or by
+/// `RUST_TEST_NOCAPTURE` being set to something other than `0`.
+pub(crate) fn nocapture_requested() -> bool {
+    match env::var_os("RUST_TEST_NOCAPTURE") {
+        Some(ref value) if value != "0" => return true,
+        _ => (),
+    }
+
+    process_flags().nocapture
+}
+
+/// Return whether the test process was asked to list tests, with `--list`,
+/// rather than to run them.
+pub(crate) fn listing_requested() -> bool {
+    process_flags().listing
+}
+
+/// Return the name of the test the current process was started to run, if it
+/// is a child process.
+///
+/// This is the argument following the `--` which `child_args()` puts before
+/// the test name.
+pub(crate) fn child_test_name() -> Option<String> {
+    env::var_os(OCCURS_ENV)?;
+    let args = process_args();
+    let mut args = args.iter().skip(1)
+        .skip_while(|arg| arg.as_os_str() != "--");
+    args.next()?;
+    args.next().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Return the command line of the current process, starting with the path
+/// to the test executable.
+///
+/// Some runners launch the test executable such that their own arguments
+/// come first, e.g. `wrapper --opt /path/to/test-exe --ignored`. If the
+/// first argument does not name the current executable but a later one
+/// does, everything before the later one is skipped, so that the runner's
+/// arguments are not mistaken for filters.
+fn process_args() -> Vec<OsString> {
+    let args = env::args_os().collect::<Vec<_>>();
+    match current_exe() {
+        Some(exe) => match exe.file_stem() {
+            Some(stem) => skip_to_exe(args, stem),
+            None => args,
+        },
+        None => args,
+    }
+}
+
+static CURRENT_EXE: Lazy<Option<PathBuf>> =
+    Lazy::new(|| env::current_exe().ok());
+
+/// Return the path to the current executable, or `None` if it cannot be
+/// determined.
+///
+/// The executable does not change while the process runs, so this is only
+/// looked up once.
+pub(crate) fn current_exe() -> Option<&'static Path> {
+    CURRENT_EXE.as_ref().map(PathBuf::as_path)
+}
+
+/// The command line of the current process as filtered by
+/// `FilteredArgs::cached_from_env()`, for each effective policy it has been
+/// filtered with so far.
+static FILTERED_ARGS_CACHE:
+    Lazy<RwLock<Vec<(UnknownFlagPolicy, FilteredArgs)>>> =
+    Lazy::new(|| RwLock::new(Vec::new()));
+
+/// The flags of interest on the command line of the current process, as
+/// returned by `process_flags()`.
+#[derive(Clone, Copy, Debug, Default)]
+struct ProcessFlags {
+    /// Whether `--list` was given.
+    listing: bool,
+    /// Whether `--nocapture` or `--show-output` was given.
+    nocapture: bool,
+}
+
+/// The result of `process_flags()`, once the command line of the current
+/// process has been read successfully.
+static PROCESS_FLAGS_CACHE: Lazy<RwLock<Option<ProcessFlags>>> =
+    Lazy::new(|| RwLock::new(None));
+
+/// Forget everything in `FILTERED_ARGS_CACHE` and `PROCESS_FLAGS_CACHE`,
+/// since a change to the flag tables may change how the command line is
+/// filtered and which of its arguments are flags.
+fn clear_filtered_args_cache() {
+    FILTERED_ARGS_CACHE.write().unwrap_or_else(|e| e.into_inner()).clear();
+    *PROCESS_FLAGS_CACHE.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Return which flags of interest the command line of the current process
+/// contains, from `PROCESS_FLAGS_CACHE` if it has been read before.
+///
+/// A command line which cannot be read (e.g. for a missing argument file)
+/// counts as containing none of them; the error is reported when forking
+/// instead. As with `cached_filtered_args()`, it is not cached.
+fn process_flags() -> ProcessFlags {
+    let cached = *PROCESS_FLAGS_CACHE.read().unwrap_or_else(|e| e.into_inner());
+    if let Some(flags) = cached {
+        return flags;
+    }
+
+    match expand_argfiles(process_args().into_iter()) {
+        Ok(args) => {
+            let flags = ProcessFlags {
+                listing: args_have_flag(&args, &["--list"]),
+                nocapture: args_have_flag(
+                    &args, &["--nocapture", "--show-output"]),
+            };
+            *PROCESS_FLAGS_CACHE.write().unwrap_or_else(|e| e.into_inner()) =
+                Some(flags);
+            flags
+        },
+        Err(_) => ProcessFlags::default(),
+    }
+}
+
+/// Return the filtered form of the command line returned by `argv` for
+/// `policy` from `FILTERED_ARGS_CACHE`, filtering it and adding it to the
+/// cache if it is not there yet.
+///
+/// Errors are not cached, so a command line which cannot be filtered is
+/// tried again every time.
+fn cached_filtered_args<F>(policy: UnknownFlagPolicy, argv: F)
+                           -> Result<FilteredArgs>
+where F : FnOnce () -> Vec<OsString> {
+    let policy = effective_policy(policy);
+    let cached = FILTERED_ARGS_CACHE.read().unwrap_or_else(|e| e.into_inner())
+        .iter().filter(|&&(p, _)| p == policy)
+        .map(|(_, args)| args.clone()).next();
+    if let Some(args) = cached {
+        return Ok(args);
+    }
+
+    let args = FilteredArgs::new(argv(), policy)?;
+    FILTERED_ARGS_CACHE.write().unwrap_or_else(|e| e.into_inner())
+        .push((policy, args.clone()));
+    Ok(args)
+}
+
+/// Drop the arguments of `args` preceding the first one naming an
+/// executable whose file stem is `exe_stem`, unless that is already the
+/// first.
+///
+/// The stem is compared so that `argv[0]` matches with or without an `.exe`
+/// suffix on Windows.
+fn skip_to_exe(mut args: Vec<OsString>, exe_stem: &OsStr) -> Vec<OsString> {
+    let names_exe =
+        |arg: &OsString| Path::new(arg).file_stem() == Some(exe_stem);
+
+    match args.first() {
+        Some(first) if !names_exe(first) => (),
+        _ => return args,
+    }
+    if let Some(ix) = args.iter().position(names_exe) {
+        args.drain(..ix);
+    }
+    args
+}
+
+/// Return whether `args`, a full command line including the command name,
+/// contains any of `flags` as a flag.
+///
+/// Values of flags and filters are not flags, even where they look like
+/// them, as in `--skip --list`.
+fn args_have_flag(args: &[OsString], flags: &[&str]) -> bool {
+    let mut walker = ArgWalker::default();
+    args.iter().skip(1)
+        .filter(|arg| ArgRole::Flag == walker.next(arg))
+        .any(|arg| flags.iter().any(|&flag| arg.as_os_str() == flag))
+}
+
+/// Build the full argument list for a child process which runs only
+/// `test_name`.
+///
+/// `stripped` is the result of `strip_cmdline()`, and `extra` any additional
+/// arguments. The extra arguments are placed before the `--` which precedes
+/// the test name so that the child interprets them as flags rather than
+/// filters. Extra arguments which are flags already present in
+/// `RUN_TEST_ARGS` or `FILTER_ARGS` are omitted (along with their values),
+/// since the harness rejects duplicates, as is `--list`, which would stop the
+/// child from running the test.
+///
+/// If any of the flags given to the child of a `libtest` harness is in
+/// `UNSTABLE_FLAGS`, `-Zunstable-options` is added unless it is already
+/// there.
+fn child_args(stripped: Vec<OsString>, extra: Vec<OsString>,
+              test_name: &str) -> Vec<OsString> {
+    let mut args = stripped;
+    let mut extra = extra.into_iter();
+    while let Some(arg) = extra.next() {
+        let text = arg.to_string_lossy().into_owned();
+        let name = text.split('=').next().expect("split returned empty");
+        if !text.starts_with('-') {
+            args.push(arg);
+        } else if UNSTABLE_GATE == text && has_unstable_gate(&args) {
+            continue;
+        } else if RUN_TEST_ARGS.contains(&name) ||
+            FILTER_ARGS.contains(&name)
+        {
+            if name == text {
+                if let Some(FlagType::Drop(true)) =
+                    look_up_flag_from_table(name)
+                {
+                    // Skip the separate value, e.g. of `--test-threads`
+                    extra.next();
+                }
+            }
+        } else if "--list" != name {
+            args.push(arg);
+        }
+    }
+    let gated = args.iter().any(
+        |arg| UNSTABLE_FLAGS.iter().any(
+            |flag| arg.to_string_lossy().split('=').next() == Some(flag)));
+    if gated && HarnessKind::Libtest == harness_kind() &&
+        !has_unstable_gate(&args)
+    {
+        args.push(UNSTABLE_GATE.into());
+    }
+    args.extend(RUN_TEST_ARGS.iter().map(OsString::from));
+    // The name stays a single argument of its own; it is never joined with
+    // anything or quoted.
+    args.extend(FILTER_ARGS.iter().map(OsString::from));
+    args.push(test_name.into());
+    args
+}
+
+/// Split `args`, which select tests for the child beyond its test name, into
+/// flags and filters.
+///
+/// Every flag must be known to the harness, and is returned along with its
+/// value if it takes one, whether or not rusty-fork would pass it on from
+/// the command line of the test process. Everything else, including anything
+/// after a `--`, is a filter.
+fn split_selection_args(args: &[OsString])
+                        -> Result<(Vec<OsString>, Vec<OsString>)> {
+    let mut flags = Vec::new();
+    let mut filters = Vec::new();
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let text = arg.to_string_lossy().into_owned();
+        if "--" == text {
+            filters.extend(args_iter.by_ref().cloned());
+        } else if text.starts_with('-') && "-" != text {
+            let mut parts = text.splitn(2, '=');
+            let name = parts.next().expect("split returned empty");
+            let (_, has_arg) = look_up_flag_or_err(
+                name, UnknownFlagPolicy::Strict, args)?;
+            flags.push(arg.clone());
+            match parts.next() {
+                Some(value) => check_flag_value(name, value)?,
+                None if has_arg => if let Some(value) = args_iter.next() {
+                    check_flag_value(name, &value.to_string_lossy())?;
+                    flags.push(value.clone());
+                },
+                None => (),
+            }
+        } else {
+            filters.push(arg.clone());
+        }
+    }
+    Ok((flags, filters))
+}
+
 /// Extra arguments to add after the stripped command line when running a
 /// single test.
-pub(crate) static RUN_TEST_ARGS: &[&str] = &[
+static RUN_TEST_ARGS: &[&str] = &[
     // --quiet because the test runner output is redundant
     "--quiet",
     // Single threaded because we get parallelism from the parent process
@@ -200,25 +1133,202 @@ pub(crate) static RUN_TEST_ARGS: &[&str] = &[
     // Disable capture since we want the output to be captured by the *parent*
     // process.
     "--nocapture",
+];
+
+/// Environment variables configuring the test harness which `RUN_TEST_ARGS`
+/// makes redundant, or which do not apply when running a single test.
+pub(crate) static RUN_TEST_ENV: &[&str] = &[
+    "RUST_TEST_NOCAPTURE",
+    "RUST_TEST_SHUFFLE",
+    "RUST_TEST_SHUFFLE_SEED",
+    "RUST_TEST_THREADS",
+];
+
+/// Arguments to put directly before the test name when running a single test.
+static FILTER_ARGS: &[&str] = &[
     // Match our test filter exactly so we run exactly one test
     "--exact",
-    // Ensure everything else is interpreted as filters
+    // Ensure the test name is interpreted as a filter whatever it looks like
     "--",
 ];
 
+/// The command line of the test process with everything which does not apply
+/// to a child process removed.
+///
+/// This is what remains after dropping all filters and all flags which
+/// select tests or which rusty-fork overrides for its children (see
+/// `known_flags()`), after expanding `@path` argument files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilteredArgs {
+    args: Vec<OsString>,
+}
+
+impl FilteredArgs {
+    /// Filter the given command line, which includes the executable name as
+    /// its first element.
+    ///
+    /// Unknown flags are handled according to `policy`, unless overridden
+    /// by `RUSTY_FORK_ALLOW_UNKNOWN_FLAGS`.
+    pub fn new<I, S>(argv: I, policy: UnknownFlagPolicy) -> Result<Self>
+    where
+        I : IntoIterator<Item = S>,
+        S : Into<OsString>,
+    {
+        strip_cmdline(argv.into_iter().map(Into::into), policy)
+            .map(|args| FilteredArgs { args })
+    }
+
+    /// Filter the command line of the current process.
+    ///
+    /// If the test executable was launched by a runner which left its own
+    /// arguments in front of the executable's path, those arguments are
+    /// skipped. Use `new()` to supply the command line explicitly when this
+    /// does not suffice.
+    pub fn from_env(policy: UnknownFlagPolicy) -> Result<Self> {
+        Self::new(process_args(), policy)
+    }
+
+    /// Like `from_env()`, but only filter the command line the first time
+    /// this is called with each policy, returning a copy of that result on
+    /// later calls.
+    ///
+    /// This is what `fork()` uses. The cached results are discarded by
+    /// `register_flag()` and `set_harness_kind()`, but not by changes to the
+    /// `RUSTY_FORK_FLAG_*` environment variables, which should be set before
+    /// the first fork.
+    pub fn cached_from_env(policy: UnknownFlagPolicy) -> Result<Self> {
+        cached_filtered_args(policy, process_args)
+    }
+
+    /// Return the arguments which remain after filtering.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Return the full argument list for a child process which runs only the
+    /// test named `test_name`, including any arguments from
+    /// `RUSTY_FORK_EXTRA_ARGS`.
+    pub fn child_args(&self, test_name: &str) -> Result<Vec<OsString>> {
+        self.child_args_with_extra(test_name, &[], &[])
+    }
+
+    /// Like `child_args()`, but additionally pass `extra` to the child,
+    /// after any arguments from `RUSTY_FORK_EXTRA_ARGS`.
+    ///
+    /// The flags among `selection` are added like `extra`, and the other
+    /// arguments are additional filters following `test_name`; see
+    /// `split_selection_args()`.
+    pub(crate) fn child_args_with_extra(&self, test_name: &str,
+                                        extra: &[OsString],
+                                        selection: &[OsString])
+                                        -> Result<Vec<OsString>> {
+        let (selection_flags, filters) = split_selection_args(selection)?;
+        let mut all_extra = extra_args_from_env()?.into_iter()
+            .map(OsString::from).collect::<Vec<_>>();
+        all_extra.extend(extra.iter().cloned());
+        all_extra.extend(selection_flags);
+        let mut args = child_args(self.args.clone(), all_extra, test_name);
+        args.extend(filters);
+        Ok(args)
+    }
+
+    /// Make the child produce colored output even though it is not writing
+    /// to a terminal, by replacing any `--color` with `--color=always`.
+    ///
+    /// Nothing is changed if the test process was passed `--color=never`.
+    /// Return whether the child now uses colors.
+    pub(crate) fn force_color(&mut self) -> bool {
+        const COLOR: &str = "--color=";
+
+        let existing = self.args.iter().position(
+            |arg| arg.to_string_lossy().starts_with(COLOR));
+        if let Some(ix) = existing {
+            if self.args[ix].to_string_lossy()[COLOR.len()..] == *"never" {
+                return false;
+            }
+            self.args.remove(ix);
+        }
+        self.args.push("--color=always".into());
+        true
+    }
+}
+
+/// The environment variable which tells a process which fork points it is a
+/// child of.
+pub const OCCURS_ENV: &str = "RUSTY_FORK_OCCURS";
+const OCCURS_TERM_LENGTH: usize = 17; /* ':' plus 16 hexits */
+
+/// Convert a fork id into the form used in `OCCURS_ENV`.
+pub(crate) fn id_str<ID : Hash>(id: ID) -> String {
+    let mut hasher = fnv::FnvHasher::default();
+    id.hash(&mut hasher);
+
+    format!(":{:016X}", hasher.finish())
+}
+
+pub(crate) fn is_child_of_id(id: &str) -> bool {
+    env::var(OCCURS_ENV).map(|occurs| occurs.contains(id)).unwrap_or(false)
+}
+
+pub(crate) fn occurs_for_child_of_id(id: &str) -> String {
+    let mut occurs = env::var(OCCURS_ENV).unwrap_or_else(|_| String::new());
+    // Prevent misconfiguration creating a fork bomb
+    if occurs.len() > 16 * OCCURS_TERM_LENGTH {
+        panic!("rusty-fork: Not forking due to >=16 levels of recursion");
+    }
+    occurs.push_str(id);
+    occurs
+}
+
+/// Return whether the current process is a child spawned from the fork point
+/// identified by `fork_id`, directly or indirectly.
+///
+/// `fork_id` has the same meaning as for [`fork`](../fn.fork.html).
+pub fn is_child_of<ID : Hash>(fork_id: ID) -> bool {
+    is_child_of_id(&id_str(fork_id))
+}
+
+/// Return the value of `OCCURS_ENV` to give to a child spawned from the fork
+/// point identified by `fork_id`.
+///
+/// ## Panics
+///
+/// Panics if the environment indicates that there are already at least 16
+/// levels of fork nesting.
+pub fn occurs_for_child<ID : Hash>(fork_id: ID) -> String {
+    occurs_for_child_of_id(&id_str(fork_id))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn strip_with(cmdline: &str, policy: UnknownFlagPolicy)
+                  -> Result<String> {
+        strip_cmdline(cmdline.split_whitespace().map(OsString::from), policy)
+            .map(|args| to_strings(args).join(" "))
+    }
+
+    fn to_strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter().map(|arg| arg.into_string().unwrap()).collect()
+    }
+
     fn strip(cmdline: &str) -> Result<String> {
-        strip_cmdline(cmdline.split_whitespace().map(|s| s.to_owned()))
-            .map(|strs| strs.join(" "))
+        strip_with(cmdline, UnknownFlagPolicy::Strict)
+    }
+
+    fn strip_argv(args: &[&str]) -> Result<Vec<String>> {
+        strip_cmdline(args.iter().map(OsString::from),
+                      UnknownFlagPolicy::Strict)
+            .map(to_strings)
     }
 
     #[test]
     fn test_strip() {
         assert_eq!("", &strip("test").unwrap());
         assert_eq!("--ignored", &strip("test --ignored").unwrap());
+        assert_eq!("--include-ignored",
+                   &strip("test --include-ignored").unwrap());
         assert_eq!("", &strip("test --quiet").unwrap());
         assert_eq!("", &strip("test -q").unwrap());
         assert_eq!("", &strip("test -qq").unwrap());
@@ -229,13 +1339,28 @@ mod test {
                    &strip("test -Zunstable-options").unwrap());
         assert_eq!("-Zunstable-options",
                    &strip("test -qZunstable-options").unwrap());
-        assert_eq!("--color auto", &strip("test --color auto").unwrap());
+        assert_eq!("--color=auto", &strip("test --color auto").unwrap());
         assert_eq!("--color=auto", &strip("test --color=auto").unwrap());
         assert_eq!("", &strip("test filter filter2").unwrap());
         assert_eq!("", &strip("test -- --color=auto").unwrap());
+        assert_eq!("--report-time --ignored",
+                   &strip("test --report-time --ignored").unwrap());
+        assert_eq!("--report-time=colored --ignored",
+                   &strip("test --report-time=colored --ignored").unwrap());
+        assert_eq!("--report-time --ignored",
+                   &strip("test --report-time colored --ignored").unwrap());
+        assert_eq!("--ensure-time --ignored",
+                   &strip("test --ensure-time --ignored").unwrap());
+        assert_eq!("", &strip("test --shuffle").unwrap());
+        assert_eq!("", &strip("test --shuffle-seed 1").unwrap());
+        assert_eq!("", &strip("test --shuffle-seed=1").unwrap());
+        assert_eq!("--ignored",
+                   &strip("test --shuffle --shuffle-seed 1 --ignored \
+                           --shuffle-seed=1 filter").unwrap());
 
         match strip("test --plugh").unwrap_err() {
-            Error::UnknownFlag(ref flag) => assert_eq!("--plugh", flag),
+            Error::UnknownFlag { ref flag, .. } =>
+                assert_eq!("--plugh", flag),
             e => panic!("Unexpected error: {}", e),
         }
         match strip("test --help").unwrap_err() {
@@ -244,9 +1369,712 @@ mod test {
         }
     }
 
+    #[test]
+    fn known_flags_handled_per_table() {
+        for &(flag, typ) in KNOWN_FLAGS {
+            match typ {
+                FlagType::Pass(false) => assert_eq!(
+                    flag, strip(&format!("test {} --quiet", flag)).unwrap()),
+                FlagType::Pass(true) => {
+                    let value = allowed_values(flag).map_or("value", |v| v[0]);
+                    let expected = if flag.starts_with("--") {
+                        format!("{}={}", flag, value)
+                    } else {
+                        format!("{} {}", flag, value)
+                    };
+                    assert_eq!(
+                        expected,
+                        strip(&format!("test {} {} --quiet", flag, value))
+                            .unwrap());
+                },
+                FlagType::Drop(false) => assert_eq!(
+                    "--ignored",
+                    strip(&format!("test {} --ignored", flag)).unwrap(),
+                    "for flag {}", flag),
+                FlagType::Drop(true) => {
+                    let value = allowed_values(flag)
+                        .map_or("--ignored", |v| v[0]);
+                    assert_eq!(
+                        "--ignored",
+                        strip(&format!("test {} {} --ignored", flag, value))
+                            .unwrap(),
+                        "for flag {}", flag);
+                },
+                FlagType::Error(_) => match strip(
+                    &format!("test {}", flag)).unwrap_err()
+                {
+                    Error::DisallowedFlag(ref f, _) => assert_eq!(flag, f),
+                    e => panic!("Unexpected error for {}: {}", flag, e),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn argfiles_expanded_before_stripping() {
+        let dir = tempfile::tempdir().unwrap();
+        let argfile = dir.path().join("args");
+        fs::write(&argfile, "--test-threads\n4\n--ignored\n\
+                             --color\nauto\n--skip=other\nfilter\n")
+            .unwrap();
+        let argfile = format!("@{}", argfile.display());
+
+        assert_eq!(vec!["--ignored", "--color=auto"],
+                   strip_argv(&["test", &argfile]).unwrap());
+        assert_eq!(vec!["-Zfoo", "--ignored", "--color=auto", "--show-output"],
+                   strip_argv(&["test", "-Zfoo", &argfile, "--show-output"])
+                   .unwrap());
+        // The executable name is never an argfile
+        assert_eq!(Vec::<String>::new(), strip_argv(&[&argfile]).unwrap());
+    }
+
+    #[test]
+    fn argfiles_only_expanded_in_place_of_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let argfile = dir.path().join("args");
+        fs::write(&argfile, "--skip\n").unwrap();
+        let argfile = format!("@{}", argfile.display());
+
+        let expand = |args: &[&str]| to_strings(expand_argfiles(
+            args.iter().map(OsString::from)).unwrap());
+        assert_eq!(vec!["test", "--skip", "@foo", "--", "@bar"],
+                   expand(&["test", "--skip", "@foo", "--", "@bar"]));
+        assert_eq!(vec!["test", "-Z", "@foo"],
+                   expand(&["test", "-Z", "@foo"]));
+        assert_eq!(vec!["test", "--skip=x", "--skip", "@foo"],
+                   expand(&["test", "--skip=x", &argfile, "@foo"]));
+    }
+
+    #[test]
+    fn missing_argfile_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let argfile = dir.path().join("nonexistent");
+        match strip_argv(&["test", &format!("@{}", argfile.display())])
+            .unwrap_err()
+        {
+            Error::ArgFile(ref path, _) =>
+                assert_eq!(&argfile.display().to_string(), path),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn format_is_not_forwarded() {
+        assert_eq!("-Z unstable-options",
+                   &strip("test --format json -Z unstable-options").unwrap());
+        assert_eq!("-Zunstable-options",
+                   &strip("test --format=json -Zunstable-options").unwrap());
+        for format in &["json", "junit", "pretty", "terse"] {
+            assert_eq!("--ignored", &strip(
+                &format!("test --format {} --ignored", format)).unwrap());
+            assert_eq!("--ignored", &strip(
+                &format!("test -q --format={} --ignored", format)).unwrap());
+            assert_eq!("--ignored", &strip(
+                &format!("test --format {} --quiet --ignored", format))
+                       .unwrap());
+        }
+
+        match strip("test --format xml").unwrap_err() {
+            Error::InvalidFlagValue(ref flag, ref value) => {
+                assert_eq!("--format", flag);
+                assert_eq!("xml", value);
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+        match strip("test --format=xml").unwrap_err() {
+            Error::InvalidFlagValue(ref flag, ref value) => {
+                assert_eq!("--format", flag);
+                assert_eq!("xml", value);
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn color_forwarded_to_child() {
+        for &when in &["auto", "always", "never"] {
+            let expected = format!("--color={}", when);
+            assert_eq!(expected, strip(&format!("test --color {}", when))
+                       .unwrap());
+            assert_eq!(expected, strip(&format!("test --color={}", when))
+                       .unwrap());
+            assert_eq!(format!("{} --ignored", expected), strip(
+                &format!("test --color {} --ignored filter", when)).unwrap());
+
+            let args = to_strings(
+                FilteredArgs::new(vec!["test", "--color", when],
+                                  UnknownFlagPolicy::Strict).unwrap()
+                    .child_args("foo::bar").unwrap());
+            assert_eq!(expected, args[0]);
+        }
+
+        for args in &["test --color sometimes", "test --color=sometimes",
+                      "test --color=", "test --color=Always"] {
+            match strip(args).unwrap_err() {
+                Error::InvalidFlagValue(ref flag, _) =>
+                    assert_eq!("--color", flag),
+                e => panic!("Unexpected error for {}: {}", args, e),
+            }
+        }
+        assert!(Error::InvalidFlagValue(
+            "--color".to_owned(), "sometimes".to_owned()).to_string()
+                .contains("auto, always, never"));
+    }
+
+    #[test]
+    fn shuffle_is_not_forwarded() {
+        assert_eq!(vec!["--ignored"], strip_argv(
+            &["test", "--shuffle", "--shuffle-seed", "42", "--ignored"])
+                   .unwrap());
+    }
+
+    #[test]
+    fn logfile_is_not_forwarded() {
+        assert_eq!(vec!["--ignored"], strip_argv(
+            &["test", "--logfile", "my test output.txt", "--ignored"])
+                   .unwrap());
+        assert_eq!(vec!["--ignored"], strip_argv(
+            &["test", "--logfile=my test output.txt", "--ignored"])
+                   .unwrap());
+        assert_eq!(Vec::<String>::new(), strip_argv(
+            &["test", "filter", "--logfile", "--ignored"]).unwrap());
+    }
+
+    #[test]
+    fn unknown_flags_rejected_when_strict() {
+        for cmdline in &["test --frob-widgets --ignored",
+                         "test --frob-widgets all --ignored",
+                         "test --frob-widgets=all --frob-widgets all"] {
+            match strip(cmdline).unwrap_err() {
+                Error::UnknownFlag { ref flag, .. } =>
+                    assert_eq!("--frob-widgets", flag),
+                e => panic!("Unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_joined_flags_dropped_when_strict() {
+        assert_eq!("--ignored",
+                   &strip("test --frob-widgets=all --ignored").unwrap());
+        assert_eq!("--color=auto --ignored",
+                   &strip("test filter --frob-widgets=all --color auto \
+                           --frob=a=b other --ignored").unwrap());
+        // The following argument is still interpreted on its own
+        assert_eq!("", &strip("test --frob-widgets= filter").unwrap());
+        assert_eq!("--ignored",
+                   &strip("test --logfile out --frob-widgets=x \
+                           --ignored -- --also-a-filter").unwrap());
+    }
+
+    #[test]
+    fn unknown_flag_error_has_context() {
+        let err = strip("test --nocaptur --ignored").unwrap_err();
+        match err {
+            Error::UnknownFlag { ref flag, ref full_args, ref suggestion } => {
+                assert_eq!("--nocaptur", flag);
+                assert_eq!(vec!["test", "--nocaptur", "--ignored"],
+                           *full_args);
+                assert_eq!(Some("--nocapture".to_owned()), *suggestion);
+            },
+            ref e => panic!("Unexpected error: {}", e),
+        }
+
+        let message = err.to_string();
+        assert!(message.contains("Did you mean '--nocapture'?"),
+                "Unexpected message: {}", message);
+        assert!(message.contains("The full command line was: \
+                                  [\"test\", \"--nocaptur\", \"--ignored\"]"),
+                "Unexpected message: {}", message);
+        assert!(message.contains("RUSTY_FORK_FLAG_NOCAPTUR"),
+                "Unexpected message: {}", message);
+        assert!(message.contains("RUSTY_FORK_ALLOW_UNKNOWN_FLAGS=1"),
+                "Unexpected message: {}", message);
+
+        let message = strip("test --fail-fast").unwrap_err().to_string();
+        assert!(!message.contains("Did you mean"),
+                "Unexpected message: {}", message);
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(0, edit_distance("--list", "--list"));
+        assert_eq!(1, edit_distance("--lists", "--list"));
+        assert_eq!(1, edit_distance("--lisp", "--list"));
+        assert_eq!(2, edit_distance("--ilst", "--list"));
+        assert_eq!(6, edit_distance("", "--list"));
+    }
+
+    #[test]
+    fn unknown_flags_dropped_when_lenient() {
+        let lenient = UnknownFlagPolicy::StripAndWarn;
+        assert_eq!("--ignored", &strip_with(
+            "test --frob-widgets --ignored", lenient).unwrap());
+        assert_eq!("--ignored", &strip_with(
+            "test --frob-widgets=all --ignored", lenient).unwrap());
+        assert_eq!("--color=auto", &strip_with(
+            "test --frob-widgets --color auto filter", lenient).unwrap());
+        // Known flags are still validated
+        match strip_with("test --frob-widgets --help", lenient).unwrap_err() {
+            Error::DisallowedFlag(ref flag, _) => assert_eq!("--help", flag),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid flag name")]
+    fn register_flag_rejects_bad_names() {
+        register_flag("-xy", FlagKind::Switch);
+    }
+
+    #[test]
+    fn shell_words_split() {
+        fn split(value: &str) -> Option<Vec<String>> {
+            split_shell_words(value)
+        }
+
+        assert_eq!(Some(vec![]), split(""));
+        assert_eq!(Some(vec![]), split("  \t "));
+        assert_eq!(Some(vec!["--nocapture".to_owned(), "-Zx".to_owned()]),
+                   split(" --nocapture  -Zx "));
+        assert_eq!(Some(vec!["--logfile=my file.txt".to_owned()]),
+                   split("--logfile='my file.txt'"));
+        assert_eq!(Some(vec!["a b".to_owned(), "c\"d".to_owned()]),
+                   split("\"a b\" \"c\\\"d\""));
+        assert_eq!(Some(vec!["a b".to_owned(), "".to_owned()]),
+                   split("a\\ b ''"));
+        assert_eq!(Some(vec!["it's".to_owned()]), split("it\\'s"));
+        assert_eq!(None, split("'unterminated"));
+        assert_eq!(None, split("\"unterminated"));
+        assert_eq!(None, split("trailing\\"));
+    }
+
+    #[test]
+    fn child_args_puts_extra_args_before_filter() {
+        let args = to_strings(child_args(
+            vec!["--ignored".into()],
+            vec!["--color=never".into(), "--nocapture".into(),
+                 "--list".into(), "my arg".into(), "--test-threads".into(),
+                 "4".into(), "--test-threads=2".into(), "--".into(),
+                 "-Zx".into()],
+            "foo::bar"));
+        let mut expected = vec!["--ignored", "--color=never", "my arg", "-Zx"];
+        expected.extend(RUN_TEST_ARGS);
+        expected.extend(FILTER_ARGS);
+        expected.push("foo::bar");
+        assert_eq!(expected, args);
+    }
+
+    #[test]
+    fn quiet_and_show_output_reach_child() {
+        for quiet in &["--quiet", "-q"] {
+            let args = FilteredArgs::new(
+                vec!["test", quiet, "--show-output"],
+                UnknownFlagPolicy::Strict)
+                .unwrap_or_else(|e| panic!("{} rejected: {}", quiet, e))
+                .child_args("foo").unwrap();
+            let args = to_strings(args);
+            // The child always runs quietly, so it only needs the flag once
+            assert_eq!(1, args.iter().filter(|&a| a == "--quiet").count(),
+                       "{:?}", args);
+            assert!(!args.contains(&"-q".to_owned()), "{:?}", args);
+            assert!(args.contains(&"--show-output".to_owned()), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn bench_reaches_child() {
+        let args = FilteredArgs::new(vec!["bench", "--bench", "benches::"],
+                                     UnknownFlagPolicy::Strict)
+            .unwrap_or_else(|e| panic!("--bench rejected: {}", e))
+            .child_args("benches::forked").unwrap();
+        let args = to_strings(args);
+        assert!(args.contains(&"--bench".to_owned()), "{:?}", args);
+        assert!(!args.contains(&"benches::".to_owned()), "{:?}", args);
+        assert!(args.ends_with(&["--exact".to_owned(), "--".to_owned(),
+                                 "benches::forked".to_owned()]),
+                "{:?}", args);
+    }
+
+    #[test]
+    fn force_run_in_process_reaches_child() {
+        let args = FilteredArgs::new(
+            vec!["test", "-Zunstable-options", "--force-run-in-process",
+                 "filter"],
+            UnknownFlagPolicy::Strict)
+            .unwrap_or_else(|e| panic!("--force-run-in-process rejected: {}",
+                                       e))
+            .child_args("foo::bar").unwrap();
+        let args = to_strings(args);
+        assert_eq!(&["-Zunstable-options", "--force-run-in-process"][..],
+                   &args[..2]);
+        assert!(!args.contains(&"filter".to_owned()), "{:?}", args);
+    }
+
+    #[test]
+    fn selection_args_split_around_filter() {
+        let args = FilteredArgs::new(vec!["test", "--show-output"],
+                                     UnknownFlagPolicy::Strict).unwrap()
+            .child_args_with_extra(
+                "generated::case 1", &[],
+                &["--ignored".into(), "generated::case 2".into(),
+                  "--skip".into(), "slow".into(), "--color=never".into(),
+                  "--".into(), "--not-a-flag".into()])
+            .unwrap();
+        let mut expected = vec!["--show-output", "--ignored", "--skip",
+                                "slow", "--color=never"];
+        expected.extend(RUN_TEST_ARGS);
+        expected.extend(FILTER_ARGS);
+        expected.extend(&["generated::case 1", "generated::case 2",
+                          "--not-a-flag"]);
+        assert_eq!(expected, to_strings(args));
+    }
+
+    #[test]
+    fn selection_args_validated() {
+        let args = FilteredArgs::new(vec!["test"], UnknownFlagPolicy::Strict)
+            .unwrap();
+        let select = |selection: &[&str]| args.child_args_with_extra(
+            "foo", &[],
+            &selection.iter().map(OsString::from).collect::<Vec<_>>());
+
+        match select(&["--ignord"]).unwrap_err() {
+            Error::UnknownFlag { ref flag, ref suggestion, .. } => {
+                assert_eq!("--ignord", flag);
+                assert_eq!(Some("--ignored"),
+                           suggestion.as_ref().map(String::as_str));
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+        match select(&["--list"]).unwrap_err() {
+            Error::DisallowedFlag(ref flag, _) => assert_eq!("--list", flag),
+            e => panic!("Unexpected error: {}", e),
+        }
+        match select(&["--color", "sometimes"]).unwrap_err() {
+            Error::InvalidFlagValue(ref flag, ref value) => {
+                assert_eq!("--color", flag);
+                assert_eq!("sometimes", value);
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn child_args_keep_odd_test_name_whole() {
+        for name in &["my_test::case_1_\"hello world\"", "generic::<u8>",
+                      "with # hash", "ünïcödé::tëst", "-x", "a+b",
+                      "case.*", "array[0]"] {
+            let args = to_strings(child_args(vec![], vec![], name));
+            assert_eq!(&["--exact", "--", name][..], &args[args.len() - 3..]);
+            assert_eq!(1, args.iter().filter(|&arg| arg == name).count());
+        }
+    }
+
+    #[test]
+    fn timing_flags_reach_child() {
+        for &(parent, forwarded) in &[
+            ("test -Z unstable-options --report-time filter",
+             "-Z unstable-options --report-time"),
+            ("test -Zunstable-options --report-time=colored --ensure-time",
+             "-Zunstable-options --report-time=colored --ensure-time"),
+            ("test --ensure-time -Zunstable-options --ignored",
+             "--ensure-time -Zunstable-options --ignored"),
+        ] {
+            let args = to_strings(
+                FilteredArgs::new(parent.split(' '),
+                                  UnknownFlagPolicy::Strict).unwrap()
+                    .child_args("foo::bar").unwrap());
+            let mut expected = forwarded.split(' ').collect::<Vec<_>>();
+            expected.extend(RUN_TEST_ARGS);
+            expected.extend(FILTER_ARGS);
+            expected.push("foo::bar");
+            assert_eq!(expected, args, "for parent args {:?}", parent);
+        }
+
+        // Without the gate in the parent's arguments, the child still gets it
+        let args = to_strings(
+            FilteredArgs::new(vec!["test", "--report-time"],
+                              UnknownFlagPolicy::Strict).unwrap()
+                .child_args("foo::bar").unwrap());
+        assert_eq!(&["--report-time", UNSTABLE_GATE][..], &args[..2]);
+    }
+
+    #[test]
+    fn unstable_gate_added_once_when_needed() {
+        let gates = |stripped: &[&str], extra: &[&str]| {
+            let args = to_strings(child_args(
+                stripped.iter().map(OsString::from).collect(),
+                extra.iter().map(OsString::from).collect(),
+                "foo::bar"));
+            let joined = args.iter().filter(|&arg| UNSTABLE_GATE == arg)
+                .count();
+            let split = args.windows(2)
+                .filter(|pair| "-Z" == pair[0] && "unstable-options" == pair[1])
+                .count();
+            joined + split
+        };
+
+        // Stable flags, or none at all
+        assert_eq!(0, gates(&[], &[]));
+        assert_eq!(0, gates(&["--ignored", "--color=always"], &[]));
+        assert_eq!(0, gates(&["--include-ignored"], &["--show-output"]));
+        assert_eq!(0, gates(&["-Zother"], &["--exact", "--quiet"]));
+        // Unstable flags, forwarded or extra
+        assert_eq!(1, gates(&[], &["--report-time"]));
+        assert_eq!(1, gates(&[], &["--report-time=colored", "--shuffle"]));
+        assert_eq!(1, gates(&["--ignored"], &["--shuffle-seed=4"]));
+        assert_eq!(1, gates(&["--exclude-should-panic"], &[]));
+        // Already enabled by the parent's or the extra arguments
+        assert_eq!(1, gates(&["-Zunstable-options"], &["--ensure-time"]));
+        assert_eq!(1, gates(&["-Z", "unstable-options"], &["--report-time"]));
+        assert_eq!(1, gates(&[], &["-Zunstable-options", "--report-time"]));
+        assert_eq!(1, gates(&["-Zunstable-options"],
+                            &["-Zunstable-options", "--report-time"]));
+        // Kept when given explicitly, even if nothing needs it
+        assert_eq!(1, gates(&["-Zunstable-options"], &[]));
+    }
+
+    #[test]
+    fn child_runs_single_threaded() {
+        for parent in &[&["test", "--test-threads", "8"][..],
+                        &["test", "--test-threads=8"][..]] {
+            let stripped = strip_argv(parent).unwrap()
+                .into_iter().map(OsString::from).collect();
+            let args = to_strings(child_args(stripped, vec![], "foo::bar"));
+            let threads = args.iter().enumerate()
+                .filter(|&(_, arg)| arg.starts_with("--test-threads"))
+                .map(|(ix, _)| &*args[ix + 1])
+                .collect::<Vec<_>>();
+            assert_eq!(vec!["1"], threads, "for parent args {:?}", parent);
+        }
+    }
+
+    #[test]
+    fn filtered_args_public_api() {
+        let filtered = FilteredArgs::new(
+            vec!["test", "--color", "auto", "--quiet", "filter"],
+            UnknownFlagPolicy::Strict).unwrap();
+        assert_eq!(&[OsString::from("--color=auto")][..], filtered.args());
+
+        let mut expected = vec!["--color=auto"];
+        expected.extend(RUN_TEST_ARGS);
+        expected.extend(FILTER_ARGS);
+        expected.push("foo::bar");
+        assert_eq!(expected,
+                   to_strings(filtered.child_args("foo::bar").unwrap()));
+    }
+
+    #[test]
+    fn child_filters_to_exactly_one_test() {
+        for parent in &[&["test", "--skip", "foo", "--exact", "bar"][..],
+                        &["test", "--skip=foo", "--exact", "bar"][..],
+                        &["test", "--exact", "--skip", "foo", "--skip", "baz",
+                          "--ignored"][..]] {
+            let args = to_strings(
+                FilteredArgs::new(parent.iter().cloned(),
+                                  UnknownFlagPolicy::Strict).unwrap()
+                    .child_args("foo::bar").unwrap());
+            assert_eq!(1, args.iter().filter(|&arg| "--exact" == arg).count(),
+                       "for parent args {:?}", parent);
+            assert!(!args.iter().any(|arg| arg.starts_with("--skip")),
+                    "for parent args {:?}", parent);
+            assert_eq!(vec!["--", "foo::bar"], args[args.len() - 2..].to_vec(),
+                       "for parent args {:?}", parent);
+        }
+    }
+
+    #[test]
+    fn child_never_inherits_filters_or_skips() {
+        let mut expected = RUN_TEST_ARGS.to_vec();
+        expected.extend(FILTER_ARGS);
+        expected.push("foo::bar");
+
+        for parent in &["test integration",
+                        "test integration --skip slow",
+                        "test foo bar --skip slow --skip=flaky --skip foo::bar",
+                        "test --skip slow --exact integration",
+                        "test --exact --skip=slow foo::bar --skip slow",
+                        "test --skip slow --skip foo -- integration --skip",
+                        "test --skip --exact foo::bar"] {
+            let args = to_strings(
+                FilteredArgs::new(parent.split(' '),
+                                  UnknownFlagPolicy::Strict).unwrap()
+                    .child_args("foo::bar").unwrap());
+            assert_eq!(expected, args, "for parent args {:?}", parent);
+        }
+    }
+
+    #[test]
+    fn force_color_unless_never() {
+        let force = |cmdline: &str| {
+            let mut filtered = FilteredArgs::new(
+                cmdline.split(' '), UnknownFlagPolicy::Strict).unwrap();
+            let forced = filtered.force_color();
+            (forced, to_strings(filtered.args().to_vec()).join(" "))
+        };
+        assert_eq!((true, "--ignored --color=always".to_owned()),
+                   force("test --ignored"));
+        assert_eq!((true, "--ignored --color=always".to_owned()),
+                   force("test --color auto --ignored"));
+        assert_eq!((true, "--color=always".to_owned()),
+                   force("test --color=always"));
+        assert_eq!((false, "--color=never".to_owned()),
+                   force("test --color never"));
+    }
+
+    #[test]
+    fn flags_detected_in_process_args() {
+        let request = |args: &str| args_have_flag(
+            &args.split(' ').map(OsString::from).collect::<Vec<_>>(),
+            &["--nocapture", "--show-output"]);
+        assert!(request("test --nocapture"));
+        assert!(request("test --ignored --show-output filter"));
+        assert!(!request("test --ignored filter"));
+        assert!(!request("--nocapture"));
+        assert!(!request("test -- --nocapture"));
+        assert!(!request("test --skip --nocapture"));
+        assert!(!request("test --color --show-output"));
+        assert!(request("test --skip=--x --show-output"));
+
+        assert!(args_have_flag(&[OsString::from("test"), "--list".into()],
+                               &["--list"]));
+
+        // --nocapture is a switch and must not swallow the following flag
+        assert_eq!("--ignored", &strip("test --nocapture --ignored").unwrap());
+    }
+
+    #[test]
+    fn runner_args_before_exe_skipped() {
+        let skip = |args: &str| skip_to_exe(
+            args.split(' ').map(OsString::from).collect(),
+            OsStr::new("tests-1234"))
+            .into_iter().map(|arg| arg.into_string().unwrap())
+            .collect::<Vec<_>>().join(" ");
+
+        assert_eq!("/target/debug/tests-1234 --ignored filter",
+                   skip("qemu-aarch64 -L /usr/aarch64 \
+                         /target/debug/tests-1234 --ignored filter"));
+        assert_eq!("tests-1234.exe --ignored",
+                   skip("runner.exe tests-1234.exe --ignored"));
+        // Only the first mention counts, and nothing changes when the
+        // executable comes first or is not found at all
+        assert_eq!("tests-1234 tests-1234",
+                   skip("runner tests-1234 tests-1234"));
+        assert_eq!("/bin/tests-1234 filter tests-1234",
+                   skip("/bin/tests-1234 filter tests-1234"));
+        assert_eq!("runner --ignored filter",
+                   skip("runner --ignored filter"));
+
+        let args = FilteredArgs::new(
+            skip_to_exe(vec!["runner".into(), "--runner-flag".into(),
+                             "tests-1234".into(), "--ignored".into(),
+                             "filter".into()],
+                        OsStr::new("tests-1234")),
+            UnknownFlagPolicy::Strict).unwrap();
+        assert_eq!(&[OsString::from("--ignored")], args.args());
+    }
+
     // Subprocess so we can change the environment without affecting other
     // tests
     rusty_fork_test! {
+        #[test]
+        fn occurs_env_identifies_children() {
+            env::remove_var(OCCURS_ENV);
+            assert!(!is_child_of("a"));
+
+            let occurs = occurs_for_child("a");
+            env::set_var(OCCURS_ENV, &occurs);
+            assert!(is_child_of("a"));
+            assert!(!is_child_of("b"));
+
+            let nested = occurs_for_child("b");
+            assert!(nested.starts_with(&occurs));
+            env::set_var(OCCURS_ENV, &nested);
+            assert!(is_child_of("a"));
+            assert!(is_child_of("b"));
+        }
+
+        #[test]
+        fn extra_args_via_env() {
+            assert_eq!(Vec::<String>::new(), extra_args_from_env().unwrap());
+
+            env::set_var("RUSTY_FORK_EXTRA_ARGS",
+                         "--show-output '--our-flag=a b' \"c d\"");
+            assert_eq!(vec!["--show-output", "--our-flag=a b", "c d"],
+                       extra_args_from_env().unwrap());
+
+            env::set_var("RUSTY_FORK_EXTRA_ARGS", "'oops");
+            match extra_args_from_env().unwrap_err() {
+                Error::InvalidArgsEnv(ref var, ref value) => {
+                    assert_eq!("RUSTY_FORK_EXTRA_ARGS", var);
+                    assert_eq!("'oops", value);
+                },
+                e => panic!("Unexpected error: {}", e),
+            }
+        }
+
+        #[test]
+        fn wrapper_via_env() {
+            env::remove_var(runner_env(env!("RUSTY_FORK_TARGET")));
+            assert_eq!(None, wrapper_from_env().unwrap());
+            env::set_var("RUSTY_FORK_WRAPPER", " ");
+            assert_eq!(None, wrapper_from_env().unwrap());
+            env::set_var("RUSTY_FORK_WRAPPER",
+                         "valgrind --error-exitcode=1");
+            assert_eq!(Some(vec!["valgrind".to_owned(),
+                                 "--error-exitcode=1".to_owned()]),
+                       wrapper_from_env().unwrap());
+        }
+
+        #[test]
+        fn target_runner_via_env() {
+            assert_eq!("CARGO_TARGET_THUMBV7EM_NONE_EABIHF_RUNNER",
+                       runner_env("thumbv7em-none-eabihf"));
+            assert_eq!("CARGO_TARGET_THUMBV8M_MAIN_NONE_EABI_RUNNER",
+                       runner_env("thumbv8m.main-none-eabi"));
+
+            assert!(runner_from_env("aarch64-unknown-linux-gnu").is_empty());
+            env::set_var("CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER",
+                         " qemu-aarch64  -L /usr/aarch64-linux-gnu ");
+            assert_eq!(vec!["qemu-aarch64", "-L", "/usr/aarch64-linux-gnu"],
+                       runner_from_env("aarch64-unknown-linux-gnu"));
+        }
+
+        #[test]
+        fn wrapper_overrides_target_runner() {
+            env::set_var(runner_env(env!("RUSTY_FORK_TARGET")),
+                         "fake-runner --flag");
+            assert_eq!(Some(vec!["fake-runner".to_owned(),
+                                 "--flag".to_owned()]),
+                       wrapper_from_env().unwrap());
+            env::set_var("RUSTY_FORK_WRAPPER", "valgrind");
+            assert_eq!(Some(vec!["valgrind".to_owned()]),
+                       wrapper_from_env().unwrap());
+            // Set but empty disables the runner too
+            env::set_var("RUSTY_FORK_WRAPPER", "");
+            assert_eq!(None, wrapper_from_env().unwrap());
+        }
+
+        #[test]
+        fn runner_via_env_overrides_target_runner() {
+            env::set_var(runner_env(env!("RUSTY_FORK_TARGET")),
+                         "fake-runner --flag");
+            env::set_var("RUSTY_FORK_RUNNER", "'other runner' -v");
+            assert_eq!(Some(vec!["other runner".to_owned(), "-v".to_owned()]),
+                       wrapper_from_env().unwrap());
+            env::set_var("RUSTY_FORK_WRAPPER", "valgrind");
+            assert_eq!(Some(vec!["valgrind".to_owned()]),
+                       wrapper_from_env().unwrap());
+        }
+
+        #[test]
+        fn allow_unknown_flags_via_env() {
+            env::set_var("RUSTY_FORK_ALLOW_UNKNOWN_FLAGS", "1");
+            assert_eq!("--ignored",
+                       &strip("test --frob-widgets=all --ignored").unwrap());
+        }
+
+
         #[test]
         fn define_args_via_env() {
             env::set_var("RUSTY_FORK_FLAG_X", "pass");
@@ -255,9 +2083,198 @@ mod test {
             env::set_var("RUSTY_FORK_FLAG_BAZ", "drop-arg");
 
             assert_eq!("-X", &strip("test -X foo").unwrap());
-            assert_eq!("--foo bar", &strip("test --foo bar").unwrap());
+            assert_eq!("--foo=bar", &strip("test --foo bar").unwrap());
             assert_eq!("", &strip("test --bar").unwrap());
             assert_eq!("", &strip("test --baz --notaflag").unwrap());
+
+            let list = ["--list"];
+            assert!(!args_have_flag(&[OsString::from("test"), "--baz".into(),
+                                      "--list".into()], &list));
+            assert!(args_have_flag(&[OsString::from("test"), "--bar".into(),
+                                     "--list".into()], &list));
+        }
+
+        #[test]
+        fn filtered_args_cached_per_policy() {
+            let argv = || vec!["test".into(), "--frob".into(),
+                               "--ignored".into()];
+            let uncalled = || -> Vec<OsString> {
+                panic!("Command line filtered again")
+            };
+
+            register_flag("--frob", FlagKind::PassThrough);
+            assert_eq!(&["--frob", "--ignored"][..], &*to_strings(
+                cached_filtered_args(UnknownFlagPolicy::Strict, argv)
+                    .unwrap().args().to_vec()));
+            assert_eq!(&["--frob", "--ignored"][..], &*to_strings(
+                cached_filtered_args(UnknownFlagPolicy::Strict, uncalled)
+                    .unwrap().args().to_vec()));
+
+            // Each policy is cached separately
+            assert!(cached_filtered_args(
+                UnknownFlagPolicy::StripAndWarn,
+                || vec!["test".into(), "--bar".into()])
+                    .unwrap().args().is_empty());
+
+            // Changing the flag tables requires filtering again
+            register_flag("--frob", FlagKind::Switch);
+            assert_eq!(&["--ignored"][..], &*to_strings(
+                cached_filtered_args(UnknownFlagPolicy::Strict, argv)
+                    .unwrap().args().to_vec()));
+            set_harness_kind(HarnessKind::LibtestMimic);
+            assert_eq!(&["--ignored"][..], &*to_strings(
+                cached_filtered_args(UnknownFlagPolicy::Strict, argv)
+                    .unwrap().args().to_vec()));
+        }
+
+        #[test]
+        fn process_flags_cached() {
+            let cached = || PROCESS_FLAGS_CACHE.read().unwrap().is_some();
+            assert!(!listing_requested());
+            assert!(cached());
+
+            register_flag("--frob", FlagKind::Switch);
+            assert!(!cached());
+        }
+
+        #[test]
+        fn filter_errors_not_cached() {
+            let argv = || vec!["test".into(), "--frob".into()];
+            assert!(cached_filtered_args(UnknownFlagPolicy::Strict, argv)
+                    .is_err());
+            register_flag("--frob", FlagKind::Switch);
+            assert!(cached_filtered_args(UnknownFlagPolicy::Strict, argv)
+                    .unwrap().args().is_empty());
+        }
+
+        #[test]
+        fn mimic_flags_handled_per_table() {
+            set_harness_kind(HarnessKind::LibtestMimic);
+            assert_eq!(MIMIC_KNOWN_FLAGS.len(), known_flags().len());
+            assert_eq!("--show-output --color=never --ignored",
+                       &strip("test --show-output --color never -q \
+                               --format terse --ignored --exact \
+                               --skip foo filter").unwrap());
+
+            for name in &["--report-time", "--shuffle",
+                          "--exclude-should-panic"] {
+                match strip(&format!("test {}", name)).unwrap_err() {
+                    Error::UnknownFlag { ref flag, .. } =>
+                        assert_eq!(name, flag),
+                    e => panic!("Unexpected error for {}: {}", name, e),
+                }
+            }
+        }
+
+        #[test]
+        fn mimic_child_args_not_gated() {
+            set_harness_kind(HarnessKind::LibtestMimic);
+            register_flag("--shuffle", FlagKind::PassThrough);
+            assert_eq!(
+                vec!["--shuffle", "--quiet", "--test-threads", "1",
+                     "--nocapture", "--exact", "--", "foo"],
+                to_strings(child_args(vec!["--shuffle".into()], vec![],
+                                      "foo")));
+        }
+
+        #[test]
+        fn registered_flags_handled_per_kind() {
+            register_flag("--test-cmdline-switch", FlagKind::Switch);
+            register_flag("--test-cmdline-seed", FlagKind::TakesArg);
+            register_flag("--test-cmdline-pass", FlagKind::PassThrough);
+            register_flag("-W", FlagKind::TakesArg);
+
+            assert_eq!("--ignored", &strip(
+                "test --test-cmdline-switch --ignored").unwrap());
+            assert_eq!("--ignored", &strip(
+                "test --test-cmdline-seed 42 --ignored").unwrap());
+            assert_eq!("--ignored", &strip(
+                "test --test-cmdline-seed=42 --ignored").unwrap());
+            assert_eq!("--test-cmdline-pass --ignored", &strip(
+                "test --test-cmdline-pass --ignored").unwrap());
+            assert_eq!("--test-cmdline-pass=x", &strip(
+                "test --test-cmdline-pass=x filter").unwrap());
+            assert_eq!("--ignored", &strip("test -W 42 --ignored").unwrap());
+            assert_eq!("-Zfoo", &strip("test -qW42 -Zfoo").unwrap());
+        }
+
+        #[test]
+        fn arg_flags_accept_both_spellings() {
+            for &(flag, typ) in KNOWN_FLAGS {
+                match typ {
+                    FlagType::Pass(true) | FlagType::Drop(true)
+                        if flag.starts_with("--") => { },
+                    _ => continue,
+                }
+
+                let value = allowed_values(flag).map_or("a=b.txt", |v| v[0]);
+                let separate = strip_argv(&["test", flag, value, "--ignored"])
+                    .unwrap();
+                let joined = strip_argv(
+                    &["test", &format!("{}={}", flag, value), "--ignored"])
+                    .unwrap();
+                assert_eq!(separate, joined, "for flag {}", flag);
+            }
+
+            assert_eq!(vec!["--ignored"], strip_argv(
+                &["test", "--test-threads=4", "--skip=foo", "--ignored"])
+                       .unwrap());
+            assert_eq!(vec!["--ignored"], strip_argv(
+                &["test", "--logfile=a=b.txt", "--ignored"]).unwrap());
+            register_flag("--test-cmdline-joined", FlagKind::PassThroughArg);
+            assert_eq!(vec!["--test-cmdline-joined=a=b"], strip_argv(
+                &["test", "--test-cmdline-joined", "a=b", "filter"]).unwrap());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn non_unicode_args_round_trip() {
+            use std::os::unix::ffi::OsStringExt;
+
+            fn os(bytes: &[u8]) -> OsString {
+                OsString::from_vec(bytes.to_vec())
+            }
+
+            register_flag("--test-cmdline-bytes", FlagKind::PassThroughArg);
+            let stripped = strip_cmdline(vec![
+                os(b"test"),
+                os(b"--test-cmdline-bytes=\xff"),
+                os(b"--test-cmdline-bytes"), os(b"a\xfeb"),
+                os(b"-Z\xff"),
+                os(b"--logfile"), os(b"\xff.txt"),
+                os(b"--skip=\xff"),
+                os(b"filter\xff"),
+                os(b"--ignored"),
+            ].into_iter(), UnknownFlagPolicy::Strict).unwrap();
+            assert_eq!(vec![os(b"--test-cmdline-bytes=\xff"),
+                            os(b"--test-cmdline-bytes=a\xfeb"),
+                            os(b"-Z\xff"), os(b"--ignored")],
+                       stripped);
+
+            let args = child_args(stripped, vec![], "foo::bar");
+            assert_eq!(os(b"--test-cmdline-bytes=\xff"), args[0]);
+        }
+
+        #[test]
+        fn flag_kinds_match_table() {
+            let known = known_flags();
+            assert_eq!(KNOWN_FLAGS.len(), known.len());
+            assert!(known.contains(&("--color", FlagKind::PassThroughArg)));
+            assert!(known.contains(&("--quiet", FlagKind::Switch)));
+
+            assert_eq!(Some(FlagKind::TakesArg), flag_kind("--logfile"));
+            assert_eq!(Some(FlagKind::PassThrough), flag_kind("--ignored"));
+            match flag_kind("--list") {
+                Some(FlagKind::Disallowed(_)) => (),
+                kind => panic!("Unexpected kind: {:?}", kind),
+            }
+            assert_eq!(None, flag_kind("--no-such-flag"));
+
+            register_flag("--test-cmdline-kind", FlagKind::PassThroughArg);
+            assert_eq!(Some(FlagKind::PassThroughArg),
+                       flag_kind("--test-cmdline-kind"));
+            assert_eq!("--test-cmdline-kind=x",
+                       &strip("test --test-cmdline-kind x").unwrap());
         }
     }
 }
@@ -8,21 +8,37 @@
 // except according to those terms.
 
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use crate::child_wrapper::ExitStatusWrapper;
 use crate::cmdline;
+use crate::fork_opts::Priority;
+
+/// The maximum number of lines of child output shown when displaying
+/// `Error::ChildFailed`.
+const DISPLAYED_OUTPUT_LINES: usize = 10;
 
 quick_error! {
     /// Enum for errors produced by the rusty-fork crate.
     #[derive(Debug)]
+    // quick_error doesn't support doc comments on the fields of struct
+    // variants, so they are instead documented on the variants themselves.
+    #[allow(missing_docs)]
     pub enum Error {
         /// An unknown flag was encountered when examining the current
         /// process's argument list.
         ///
-        /// The string is the flag that was encountered.
-        UnknownFlag(flag: String) {
+        /// `flag` is the flag that was encountered, and `full_args` the
+        /// complete argument list it was found in, before any `@path`
+        /// argument files were expanded. `suggestion` is a known flag whose
+        /// name is close to `flag`, if there is one.
+        UnknownFlag { flag: String, full_args: Vec<String>,
+                      suggestion: Option<String> } {
             display("The flag '{:?}' was passed to the Rust test \
                      process, but rusty-fork does not know how to \
-                     handle it.\n\
+                     handle it.{}\n\
+                     The full command line was: {:?}\n\
                      If you are using the standard Rust \
                      test harness and have the latest version of the \
                      rusty-fork crate, please report a bug to\n\
@@ -35,8 +51,11 @@ quick_error! {
                      to the child process.\n\
                      \tdrop - Don't pass the flag to the child process.\n\
                      \tdrop-arg - Don't pass the flag or its following \
-                     argument to the child process.",
-                    flag, cmdline::env_var_for_flag(&flag))
+                     argument to the child process.\n\
+                     Alternatively, set `RUSTY_FORK_ALLOW_UNKNOWN_FLAGS=1` \
+                     to drop all unknown flags with a warning.",
+                    flag, did_you_mean(suggestion), full_args,
+                    cmdline::env_var_for_flag(flag))
         }
         /// A flag was encountered when examining the current process's
         /// argument list which is known but cannot be handled in any sensible
@@ -49,6 +68,165 @@ quick_error! {
                      process, but rusty-fork cannot handle it; \
                      reason: {}", flag, message)
         }
+        /// A flag was encountered when examining the current process's
+        /// argument list with a value that is not one the test harness
+        /// accepts.
+        ///
+        /// The strings are the flag and the value encountered.
+        InvalidFlagValue(flag: String, value: String) {
+            display("The flag '{:?}' was passed to the Rust test \
+                     process with the value '{:?}', but the only \
+                     accepted values are {}",
+                    flag, value,
+                    cmdline::allowed_values(flag).unwrap_or(&[]).join(", "))
+        }
+        /// An environment variable holding a list of arguments, such as
+        /// `RUSTY_FORK_EXTRA_ARGS`, could not be split into arguments.
+        ///
+        /// The strings are the name and the value of the variable.
+        InvalidArgsEnv(var: String, value: String) {
+            display("The environment variable {} has an unterminated \
+                     quote or trailing backslash: {:?}", var, value)
+        }
+        /// An `@path` argument file passed to the current process could not be
+        /// read.
+        ///
+        /// The string is the path to the argument file.
+        ArgFile(path: String, err: io::Error) {
+            cause(err)
+            display("Failed to read argument file '{}': {}", path, err)
+        }
+        /// The child process exited unsuccessfully, or was killed by a
+        /// signal.
+        ///
+        /// `status` is the exit status of the child, and `output` is
+        /// everything the child wrote to its captured standard output and
+        /// standard error, lossily converted to UTF-8. Only the last few lines
+        /// of the output are included when the error is displayed, preceded
+        /// by the child's panic message and location if it panicked.
+        ///
+        /// `kept_output` holds the paths of the files with the child's output
+        /// if `RUSTY_FORK_KEEP_OUTPUT=1` kept them from being deleted, and is
+        /// empty otherwise.
+        ChildFailed { status: ExitStatusWrapper, output: String,
+                      kept_output: Vec<PathBuf> } {
+            display("Child process {}{}{}{}",
+                    describe_exit(status), panic_summary(output),
+                    output_tail(output),
+                    kept_output_note(kept_output))
+        }
+        /// The wrapper command of the child process exited with the code set
+        /// with `ForkOpts::wrapper_error_code()`, reporting an error it
+        /// detected itself.
+        ///
+        /// `code` is that exit code, and `output` and `kept_output` are as
+        /// for `ChildFailed`.
+        WrapperFailed { code: i32, output: String,
+                        kept_output: Vec<PathBuf> } {
+            display("The wrapper of the child process reported an error by \
+                     exiting with code {}{}{}",
+                    code, output_tail(output), kept_output_note(kept_output))
+        }
+        /// The child process failed after an allocation exceeded the limit
+        /// on its address space set with `ForkOpts::memory_limit()` or
+        /// `ForkOpts::rlimit()`.
+        ///
+        /// `limit` is that limit in bytes, and `status`, `output` and
+        /// `kept_output` are as for `ChildFailed`.
+        MemoryLimitExceeded { limit: u64, status: ExitStatusWrapper,
+                              output: String, kept_output: Vec<PathBuf> } {
+            display("Child process exceeded its memory limit of {} bytes \
+                     and {}{}{}",
+                    limit, describe_exit(status), output_tail(output),
+                    kept_output_note(kept_output))
+        }
+        /// The child process was killed because it exceeded the timeout set
+        /// with `ForkOpts::timeout()`.
+        ///
+        /// `elapsed` is how long the child had been running when the timeout
+        /// was noticed, and `test_name` the name of the test it was running.
+        /// `kept_output` is as for `ChildFailed`.
+        Timeout { elapsed: Duration, test_name: String,
+                  kept_output: Vec<PathBuf> } {
+            display("Child process running '{}' timed out after {}.{:03}s{}",
+                    test_name, elapsed.as_secs(), elapsed.subsec_millis(),
+                    kept_output_note(kept_output))
+        }
+        /// The child process exited successfully, but never reached the fork
+        /// point, most likely because its test harness did not know of a test
+        /// with the name passed to `fork()`.
+        ///
+        /// The string is the name of the test.
+        TestNotRun(test_name: String) {
+            display("The child process did not run the test '{}'; it \
+                     probably does not match the name of the test as known \
+                     to the test harness", test_name)
+        }
+        /// `fork()` was called while the test harness was listing tests
+        /// (i.e., was passed `--list`) rather than running them.
+        ///
+        /// No child process is spawned in this case, so that test discovery
+        /// has no side effects.
+        Listing {
+            display("Cannot fork while the test harness is listing tests")
+        }
+        /// Cases run with `ForkPool::run_case()` failed.
+        ///
+        /// Each element is the id of a failed case and a description of the
+        /// failure, such as the message it panicked with.
+        CasesFailed(failures: Vec<(String, String)>) {
+            display("{} case(s) failed:{}", failures.len(),
+                    failures.iter().map(|(id, message)|
+                                        format!("\n\t{}: {}", id, message))
+                    .collect::<String>())
+        }
+        /// The child process could not be spawned.
+        ///
+        /// `program` is the path to the program that failed to start: the
+        /// test executable, or the wrapper command if there is one.
+        SpawnFailed(program: PathBuf, err: io::Error) {
+            cause(err)
+            display("Failed to spawn child process '{}': {}",
+                    program.display(), err)
+        }
+        /// The resource limit on `resource` (e.g. `RLIMIT_AS`) set with
+        /// `ForkOpts::rlimit()` could not be applied to the child process,
+        /// which was therefore not spawned.
+        ResourceLimit(resource: &'static str, err: io::Error) {
+            cause(err)
+            display("Failed to set {} for child process: {}", resource, err)
+        }
+        /// The child process could not be made to run with the credentials
+        /// set with `ForkOpts::uid()`, `gid()` or `groups()`, and was
+        /// therefore not spawned.
+        ///
+        /// `credential` describes the one which could not be set, e.g.
+        /// `user id 1000`.
+        CredentialsFailed(credential: String, err: io::Error) {
+            cause(err)
+            display("Failed to set {} of child process: {}", credential, err)
+        }
+        /// The priority set with `ForkOpts::priority()` could not be applied
+        /// to the child process, which was therefore not spawned. This is
+        /// only returned with `ForkOpts::strict_priority()`.
+        PriorityFailed(priority: Priority, err: io::Error) {
+            cause(err)
+            display("Failed to set priority {:?} of child process: {}",
+                    priority, err)
+        }
+        /// The child process of `fork_returning()` running the named test
+        /// exited successfully without returning a value.
+        NoValueReturned(test_name: String) {
+            display("Child process running '{}' exited without returning \
+                     a value", test_name)
+        }
+        /// The value returned by the child process of `fork_returning()`
+        /// running the named test could not be deserialised.
+        BadValueReturned(test_name: String, err: io::Error) {
+            cause(err)
+            display("Child process running '{}' returned a value which \
+                     could not be read: {}", test_name, err)
+        }
         /// Spawning a subprocess failed.
         SpawnError(err: io::Error) {
             from()
@@ -58,5 +236,133 @@ quick_error! {
     }
 }
 
+/// Describe how a child with the unsuccessful exit status `status` ended,
+/// naming the signal which killed it if there was one.
+fn describe_exit(status: &ExitStatusWrapper) -> String {
+    match (status.unix_signal(), status.unix_signal_name()) {
+        (Some(signal), Some(name)) =>
+            format!("was killed by signal {} ({})", signal, name),
+        (Some(signal), None) => format!("was killed by signal {}", signal),
+        (None, _) => format!("exited unsuccessfully with {}", status),
+    }
+}
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    suggestion.as_ref().map(|flag| format!(" Did you mean '{}'?", flag))
+        .unwrap_or_default()
+}
+
+/// Describe the first panic reported in `output`, if any.
+///
+/// The panic hook writes either `thread '..' panicked at LOCATION:` followed
+/// by the message on the lines after it, or (before Rust 1.73) `thread '..'
+/// panicked at 'MESSAGE', LOCATION` on a single line.
+fn panic_summary(output: &str) -> String {
+    let (first, lines) = match find_panic(output) {
+        Some(panic) => panic,
+        None => return String::new(),
+    };
+    let mut summary = format!("; it panicked at {}", first);
+    if first.ends_with(':') {
+        for line in lines.take(DISPLAYED_OUTPUT_LINES) {
+            summary.push_str("\n\t");
+            summary.push_str(line);
+        }
+    }
+    summary
+}
+
+/// Return the message of the first panic reported in `output`, or `None` if
+/// the child did not panic.
+pub(crate) fn panic_message(output: &str) -> Option<String> {
+    let (first, lines) = find_panic(output)?;
+    if first.ends_with(':') {
+        Some(lines.collect::<Vec<_>>().join("\n"))
+    } else {
+        // 'MESSAGE', LOCATION
+        let end = first.rfind("', ").unwrap_or(first.len());
+        Some(first.get(1..end).unwrap_or("").to_owned())
+    }
+}
+
+/// Find the first panic reported in `output`, returning the rest of the line
+/// after `panicked at ` and the lines following it up to the note about
+/// backtraces.
+fn find_panic(output: &str)
+              -> Option<(&str, impl Iterator<Item = &str>)> {
+    const MARKER: &str = " panicked at ";
+
+    let mut lines = output.lines()
+        .skip_while(|line| !(line.starts_with("thread '") &&
+                             line.contains(MARKER)));
+    let first = lines.next()?;
+    let start = first.find(MARKER).expect("panic line without marker") +
+        MARKER.len();
+    let rest = lines.take_while(|line| !line.starts_with("note: ") &&
+                                !line.starts_with("stack backtrace:"));
+    Some((&first[start..], rest))
+}
+
+fn kept_output_note(kept_output: &[PathBuf]) -> String {
+    if kept_output.is_empty() {
+        return String::new();
+    }
+
+    let mut note = "\nThe output of the child was kept in:".to_owned();
+    for path in kept_output {
+        note.push_str("\n\t");
+        note.push_str(&path.display().to_string());
+    }
+    note
+}
+
+fn output_tail(output: &str) -> String {
+    let lines = output.lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    let start = lines.len().saturating_sub(DISPLAYED_OUTPUT_LINES);
+    let mut tail = "; last lines of output:".to_owned();
+    for line in &lines[start..] {
+        tail.push_str("\n\t");
+        tail.push_str(line);
+    }
+    tail
+}
+
 /// General `Result` type for rusty-fork.
 pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn panic_summary_finds_message_and_location() {
+        assert_eq!("", panic_summary(""));
+        assert_eq!("", panic_summary("running 1 test\nall went well\n"));
+        assert_eq!("; it panicked at src/lib.rs:3:5:\n\tfirst\n\tsecond",
+                   panic_summary("running 1 test\n\
+                                  thread 'foo' panicked at src/lib.rs:3:5:\n\
+                                  first\nsecond\n\
+                                  note: run with `RUST_BACKTRACE=1`\n\
+                                  thread 'bar' panicked at src/x.rs:1:1:\n\
+                                  later\n"));
+        assert_eq!("; it panicked at 'boom', src/lib.rs:3:5",
+                   panic_summary("thread 'main' panicked at 'boom', \
+                                  src/lib.rs:3:5\nstack backtrace:\n"));
+    }
+
+    #[test]
+    fn panic_message_found_in_either_format() {
+        assert_eq!(None, panic_message("running 1 test\nall went well\n"));
+        assert_eq!(Some("first\nsecond".to_owned()),
+                   panic_message("thread 'foo' panicked at src/lib.rs:3:5:\n\
+                                  first\nsecond\n\
+                                  note: run with `RUST_BACKTRACE=1`\n"));
+        assert_eq!(Some("it's, broken".to_owned()),
+                   panic_message("thread 'main' panicked at 'it's, broken', \
+                                  src/lib.rs:3:5\n"));
+    }
+}
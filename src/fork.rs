@@ -9,20 +9,28 @@
 
 use std::fs;
 use std::env;
-use std::hash::{Hash, Hasher};
-use std::io::{self, BufRead, Seek};
+use std::ffi::OsString;
+use std::hash::Hash;
+use std::io::{self, BufRead, Read, Seek, Write};
 use std::panic;
+use std::path::{Path, PathBuf};
 use std::process;
-
-use fnv;
-use tempfile;
+use std::sync::{Arc, Mutex};
+use std::thread;
+#[cfg(feature = "timeout")]
+use std::time::Instant;
 
 use crate::cmdline;
+use crate::sys;
 use crate::error::*;
-use crate::child_wrapper::ChildWrapper;
-
-const OCCURS_ENV: &str = "RUSTY_FORK_OCCURS";
-const OCCURS_TERM_LENGTH: usize = 17; /* ':' plus 16 hexits */
+use crate::captured_output::{CapturedOutput, PipeBuffer, RAN_LINE};
+use crate::child_wrapper::{ChildWrapper, ExitStatusWrapper};
+use crate::child_output::ChildOutput;
+use crate::fork_builder::ForkBuilder;
+use crate::fork_outcome::ForkOutcome;
+use crate::fork_opts::{CaptureMode, ForkOpts, OutputStream, StdinSource};
+#[cfg(unix)]
+use crate::fork_opts::Resource;
 
 /// Simulate a process fork.
 ///
@@ -44,7 +52,13 @@ const OCCURS_TERM_LENGTH: usize = 17; /* ':' plus 16 hexits */
 /// (passing through the redirect mechanism Rust test uses).
 ///
 /// `test_name` must exactly match the full path of the test function being
-/// run.
+/// run. It is passed to the child's harness as an exact filter, so characters
+/// such as `+`, `.` or `[` in it have no special meaning. If the child exits
+/// successfully without reaching this call, e.g. because its test harness
+/// knows the test by another name, `Error::TestNotRun` is returned.
+///
+/// If the test process was passed `--list`, no child is spawned and
+/// `Error::Listing` is returned.
 ///
 /// `fork_id` is a unique identifier identifying this particular fork location.
 /// This *must* be stable across processes of the same executable; pointers are
@@ -55,9 +69,12 @@ const OCCURS_TERM_LENGTH: usize = 17; /* ':' plus 16 hexits */
 /// If this is the parent process, `in_parent` is invoked, and the return value
 /// becomes the return value from this function. The callback is passed a
 /// handle to the file which receives the child's output. If is the callee's
-/// responsibility to wait for the child to exit. If this is the child process,
-/// `in_child` is invoked, and when the callback returns, the child process
-/// exits.
+/// responsibility to wait for the child to exit. If `in_parent` has reaped the
+/// child and the child exited unsuccessfully, `Error::ChildFailed` is returned
+/// instead, carrying the exit status and the child's captured output. If the
+/// child was instead killed for exceeding a timeout, `Error::Timeout` is
+/// returned. If this is the child process, `in_child` is invoked, and when
+/// the callback returns, the child process exits.
 ///
 /// If `in_parent` returns or panics before the child process has terminated,
 /// the child process is killed.
@@ -70,15 +87,35 @@ const OCCURS_TERM_LENGTH: usize = 17; /* ':' plus 16 hexits */
 /// parameters if desired, but should not do anything that would modify or
 /// remove any environment variables beginning with `RUSTY_FORK_`.
 ///
+/// If the `RUSTY_FORK_EXTRA_ARGS` environment variable is set, it is split
+/// into words like a shell would (quotes and backslashes are honoured, but
+/// nothing is expanded), and the resulting arguments are given to the child
+/// test harness as flags, e.g. `RUSTY_FORK_EXTRA_ARGS="--show-output"`.
+/// They are added before `process_modifier` is invoked.
+///
+/// Similarly, `RUSTY_FORK_WRAPPER` can be set to a command through which to
+/// launch the child, e.g. `RUSTY_FORK_WRAPPER="valgrind --error-exitcode=1"`.
+/// Otherwise, the child is launched through the runner given by
+/// `RUSTY_FORK_RUNNER` or `CARGO_TARGET_<triple>_RUNNER`, if any. See
+/// [`ForkOpts::wrapper`](struct.ForkOpts.html#method.wrapper).
+///
+/// `RUSTY_FORK_TEST_EXE` can be set to the path of the test executable for
+/// the child to run, if it is not the current executable. See
+/// [`ForkOpts::child_executable`](
+/// struct.ForkOpts.html#method.child_executable).
+///
+/// Setting `RUSTY_FORK_DEBUG=1` prints the test executable and the complete
+/// command line of each child to standard error right before it is spawned,
+/// which helps to find out why a child did not run the expected test.
+///
 /// ## Panics
 ///
 /// Panics if the environment indicates that there are already at least 16
 /// levels of fork nesting.
 ///
 /// Panics if `std::env::current_exe()` fails determine the path to the current
-/// executable.
-///
-/// Panics if any argument to the current process is not valid UTF-8.
+/// executable, unless another executable was given with
+/// `ForkOpts::child_executable()` or `RUSTY_FORK_TEST_EXE`.
 pub fn fork<ID, MODIFIER, PARENT, CHILD, R>(
     test_name: &str,
     fork_id: ID,
@@ -91,119 +128,1087 @@ where
     PARENT : FnOnce (&mut ChildWrapper, &mut fs::File) -> R,
     CHILD : FnOnce ()
 {
-    let fork_id = id_str(fork_id);
+    ForkBuilder::new(test_name, fork_id)
+        .modify_command(process_modifier)
+        .run(in_parent, in_child)
+}
 
-    // Erase the generics so we don't instantiate the actual implementation for
-    // every single test
-    let mut return_value = None;
-    let mut process_modifier = Some(process_modifier);
+/// Simulate a process fork, with additional configuration.
+///
+/// This behaves exactly like [`fork`](fn.fork.html), except that instead of
+/// taking a `process_modifier`, the child process is configured by `opts`. See
+/// [`ForkOpts`](struct.ForkOpts.html) for the available options and whether
+/// they apply to the parent or the child.
+///
+/// ```
+/// use rusty_fork::{fork_with_opts, rusty_fork_id, ForkOpts};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let opts = ForkOpts::new().env("MY_VAR", "1");
+///     let status = fork_with_opts(
+///         "my_test", rusty_fork_id!(), opts,
+///         |child, _| child.wait().unwrap(),
+///         || assert_eq!(Ok("1".to_owned()), std::env::var("MY_VAR")))
+///         .unwrap();
+///     assert!(status.success());
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+pub fn fork_with_opts<ID, PARENT, CHILD, R>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_parent: PARENT,
+    in_child: CHILD) -> Result<R>
+where
+    ID : Hash,
+    PARENT : FnOnce (&mut ChildWrapper, &mut fs::File) -> R,
+    CHILD : FnOnce ()
+{
+    ForkBuilder::new(test_name, fork_id).opts(opts).run(in_parent, in_child)
+}
+
+/// Simulate a process fork, and return the child's output once it exits.
+///
+/// This is a simpler alternative to
+/// [`fork_with_opts`](fn.fork_with_opts.html) for the common case of running
+/// some code in a child process and then examining its exit status and
+/// output. The parent waits for the child to exit (subject to any timeout in
+/// `opts`), and the exit status and captured output are returned. Unlike the
+/// other fork functions, a child which exits unsuccessfully does *not* produce
+/// `Error::ChildFailed`; check
+/// [`ChildOutput::status`](struct.ChildOutput.html#method.status) instead.
+///
+/// With the default `CaptureMode::Merged`, standard output and standard error
+/// both end up in `ChildOutput::stdout()`. Use `CaptureMode::Separate` to
+/// examine them separately:
+///
+/// ```
+/// use rusty_fork::{fork_output, rusty_fork_id, CaptureMode, ForkOpts};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let output = fork_output(
+///         "my_test", rusty_fork_id!(),
+///         ForkOpts::new().capture_mode(CaptureMode::Separate),
+///         || eprintln!("something went wrong")).unwrap();
+///     assert!(output.status().success());
+///     assert_eq!("", output.stdout());
+///     assert!(output.stderr().contains("something went wrong"));
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// The same panics as for `fork` apply.
+pub fn fork_output<ID, CHILD>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_child: CHILD) -> Result<ChildOutput>
+where
+    ID : Hash,
+    CHILD : FnOnce ()
+{
+    let fork_id = cmdline::id_str(fork_id);
+    let mut in_child = Some(in_child);
+    let mut wait_result = Ok(());
+
+    let output = fork_impl(
+        test_name, fork_id, &opts, CollectOutput::Always,
+        &mut |_| (),
+        &mut |child, _, _| wait_result = child.wait().map(|_| ()),
+        &mut || in_child.take().unwrap()())?;
+    wait_result?;
+    Ok(output.expect("child not reaped after waiting for it"))
+}
+
+/// Like [`fork_with_opts`](fn.fork_with_opts.html), but also return what
+/// the child wrote to its standard output when it succeeds.
+///
+/// `in_parent` need not wait for the child; once it returns, the child is
+/// waited for if it has not been reaped yet. The value returned by
+/// `in_parent` is returned together with the child's standard output, lossily
+/// converted to UTF-8, without the lines the child's test harness prints
+/// before running the test. The output is always captured with
+/// `CaptureMode::Separate`, whatever `opts` says; what the child writes to
+/// standard error is only reported if it fails. If the child exits
+/// unsuccessfully, `Error::ChildFailed` is returned as for `fork`, with both
+/// streams in the error.
+///
+/// This makes it possible to check what a passing child printed:
+///
+/// ```
+/// use rusty_fork::{fork_capture, rusty_fork_id, ForkOpts};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let ((), output) = fork_capture(
+///         "my_test", rusty_fork_id!(), ForkOpts::new(), |_| (),
+///         || println!("hello from child")).unwrap();
+///     assert_eq!("hello from child\n", output);
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// The same panics as for `fork` apply.
+pub fn fork_capture<ID, PARENT, CHILD, R>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_parent: PARENT,
+    in_child: CHILD) -> Result<(R, String)>
+where
+    ID : Hash,
+    PARENT : FnOnce (&mut ChildWrapper) -> R,
+    CHILD : FnOnce ()
+{
+    let opts = opts.capture_mode(CaptureMode::Separate);
     let mut in_parent = Some(in_parent);
     let mut in_child = Some(in_child);
+    let mut return_value = None;
+    let mut wait_result = Ok(());
+
+    let output = fork_impl(
+        test_name, cmdline::id_str(fork_id), &opts,
+        CollectOutput::Successful,
+        &mut |_| (),
+        &mut |child, _, _| {
+            return_value = Some(in_parent.take().unwrap()(child));
+            wait_result = child.wait().map(|_| ());
+        },
+        &mut || in_child.take().unwrap()())?;
+    wait_result?;
+    let output = output.expect("child not reaped after waiting for it");
+    Ok((return_value.unwrap(),
+        strip_harness_preamble(output.stdout()).to_owned()))
+}
+
+/// Return `stdout`, the standard output of a child, without what the test
+/// harness printed before running the test: a `running 1 test` line and the
+/// blank lines before it.
+fn strip_harness_preamble(stdout: &str) -> &str {
+    let rest = stdout.trim_start_matches('\n');
+    match rest.find('\n') {
+        Some(end) if rest[..end].starts_with("running ") => &rest[end + 1..],
+        _ => stdout,
+    }
+}
+
+/// Like [`fork_output`](fn.fork_output.html), but pass the value returned
+/// by `in_child` in the child process back to the parent, instead of the
+/// child's output.
+///
+/// The value is serialised as JSON and handed over through a pipe which the
+/// child inherits, distinct from its standard output, so it can be of any
+/// size and cannot be mixed up with anything the child prints. Since the
+/// child inherits the pipe rather than opening anything, this works just the
+/// same for a child running as another user with `ForkOpts::uid()`.
+///
+/// ```
+/// use rusty_fork::{fork_returning, rusty_fork_id, ForkOpts};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let value: (u32, String) = fork_returning(
+///         "my_test", rusty_fork_id!(), ForkOpts::new(),
+///         || (6 * 7, "answer".to_owned())).unwrap();
+///     assert_eq!((42, "answer".to_owned()), value);
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// If the child exits unsuccessfully, e.g. because `in_child` panicked
+/// before returning a value, `Error::ChildFailed` is returned as for `fork`.
+/// If it exits successfully without returning, e.g. by calling
+/// `std::process::exit(0)`, `Error::NoValueReturned` is returned.
+///
+/// This is only present on Unix and if the "serde" feature is enabled.
+///
+/// The same panics as for `fork` apply.
+#[cfg(all(unix, feature = "serde"))]
+pub fn fork_returning<ID, CHILD, T>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_child: CHILD) -> Result<T>
+where
+    ID : Hash,
+    CHILD : FnOnce () -> T,
+    T : serde::Serialize + serde::de::DeserializeOwned
+{
+    use std::os::unix::io::AsRawFd;
+
+    let (reader, writer) = sys::pipe()?;
+    let fd = writer.as_raw_fd();
+    let mut reader = Some(reader);
+    let mut writer = Some(writer);
+    let mut in_child = Some(in_child);
+    let mut wait_result = Ok(());
+    let mut read_result = None;
+
+    fork_impl(
+        test_name, cmdline::id_str(fork_id), &opts, CollectOutput::Never,
+        &mut |cmd| {
+            sys::inherit_fd(cmd, fd);
+            cmd.env(RETURN_ENV, fd.to_string());
+        },
+        &mut |child, _, _| {
+            // Otherwise, the end of the value would never be seen
+            writer = None;
+            // The value need not fit into the pipe, so it has to be read
+            // while the child is running
+            let mut reader = reader.take().unwrap();
+            let reading = thread::spawn(move || {
+                let mut value = Vec::new();
+                reader.read_to_end(&mut value).map(|_| value)
+            });
+            wait_result = child.wait().map(|_| ());
+            read_result = Some(reading.join().unwrap_or_else(
+                |panic| panic::resume_unwind(panic)));
+        },
+        &mut || {
+            // Taken before running `in_child`, so that nothing it spawns
+            // keeps the pipe open
+            let pipe = env::var(RETURN_ENV).ok()
+                .and_then(|fd| fd.parse().ok())
+                .and_then(sys::take_inherited_fd)
+                .expect("no pipe to return the value to the parent through");
+            let value = in_child.take().unwrap()();
+            let mut pipe = io::BufWriter::new(pipe);
+            serde_json::to_writer(&mut pipe, &value).map_err(io::Error::from)
+                .and_then(|_| pipe.flush())
+                .expect("failed to return the value to the parent");
+        })?;
+    wait_result?;
+    let value = read_result.expect("child not reaped after waiting for it")?;
+    if value.is_empty() {
+        return Err(Error::NoValueReturned(test_name.to_owned()));
+    }
+    serde_json::from_slice(&value).map_err(
+        |err| Error::BadValueReturned(test_name.to_owned(), err.into()))
+}
+
+/// The environment variable giving the number of the file descriptor
+/// through which the child of `fork_returning()` returns its value.
+#[cfg(all(unix, feature = "serde"))]
+const RETURN_ENV: &str = "RUSTY_FORK_RETURN_FD";
+
+/// Simulate a process fork, and report how the child ended as a
+/// `ForkOutcome` rather than as an error.
+///
+/// This behaves like [`fork_with_opts`](fn.fork_with_opts.html), except that
+/// the child is always reaped: if `in_parent` returns without having waited
+/// for the child, it is waited for then (subject to any timeout in `opts`).
+/// A child which exits unsuccessfully, is killed by a signal, or times out
+/// results in the corresponding `ForkOutcome` instead of
+/// `Error::ChildFailed` or `Error::Timeout`, so that custom harnesses can
+/// decide for themselves what each means. Errors are still returned for
+/// everything else that can go wrong, such as the child not running the test
+/// at all (`Error::TestNotRun`) or exceeding its memory limit
+/// (`Error::MemoryLimitExceeded`).
+///
+/// ```
+/// use rusty_fork::{try_fork, rusty_fork_id, ForkOpts, ForkOutcome};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     match try_fork("my_test", rusty_fork_id!(), ForkOpts::new(),
+///                    |_, _| (), || std::process::abort()).unwrap() {
+///         ForkOutcome::Signaled { signal, .. } =>
+///             println!("child killed by signal {}", signal),
+///         outcome => panic!("unexpected outcome: {:?}", outcome),
+///     }
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// The same panics as for `fork` apply.
+pub fn try_fork<ID, PARENT, CHILD, R>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_parent: PARENT,
+    in_child: CHILD) -> Result<ForkOutcome<R>>
+where
+    ID : Hash,
+    PARENT : FnOnce (&mut ChildWrapper, &mut fs::File) -> R,
+    CHILD : FnOnce ()
+{
+    let mut wait_result = Ok(());
+    let result = ForkBuilder::new(test_name, fork_id).opts(opts).run(
+        |child, file| {
+            let ret = in_parent(child, file);
+            wait_result = child.wait().map(|_| ());
+            ret
+        },
+        in_child);
+    match result {
+        Ok(ret) => {
+            wait_result?;
+            Ok(ForkOutcome::Passed(ret))
+        },
+        Err(err) => ForkOutcome::from_error(err),
+    }
+}
+
+/// The exit code with which [`skip`](fn.skip.html) ends the child process,
+/// following the convention of Automake for skipped tests.
+pub const SKIP_EXIT_CODE: i32 = 77;
+
+/// Skip the test running in the child process, e.g. because something it
+/// needs turns out not to be available.
+///
+/// This flushes standard output and exits the child with `SKIP_EXIT_CODE`,
+/// which `try_fork` reports as `ForkOutcome::Skipped`. The other fork
+/// functions cannot tell a skipped child from a failed one, and so report it
+/// as `Error::ChildFailed`.
+///
+/// ```
+/// use rusty_fork::{try_fork, rusty_fork_id, skip, ForkOpts, ForkOutcome};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     match try_fork("my_test", rusty_fork_id!(), ForkOpts::new(),
+///                    |_, _| (), || skip()).unwrap() {
+///         ForkOutcome::Skipped { .. } => println!("skipped"),
+///         outcome => panic!("unexpected outcome: {:?}", outcome),
+///     }
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// ## Panics
+///
+/// Panics if not called in a child process started by rusty-fork, since
+/// exiting would end the whole test process.
+pub fn skip() -> ! {
+    if env::var_os(cmdline::OCCURS_ENV).is_none() {
+        panic!("rusty_fork::skip() called outside of a child process");
+    }
+
+    let _ = io::stdout().flush();
+    process::exit(SKIP_EXIT_CODE)
+}
+
+/// Run each of `cases` in a child process of its own, with up to
+/// `max_parallel` children running at once, and return how each ended.
+///
+/// Each case is the fork id of the child, which must be different for every
+/// case, and the function to run in it. Every child is run as by
+/// [`try_fork`](fn.try_fork.html) with `opts`, and the results are in the
+/// same order as `cases`, regardless of the order in which the children
+/// finish. A `max_parallel` of 0 is treated as 1.
+///
+/// In a child process, only the function of the case the child was spawned
+/// for is run, after which the process exits.
+///
+/// ```
+/// use rusty_fork::{fork_many, rusty_fork_id, ForkOpts, ForkOutcome};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let cases = (0..4).map(|n| ((rusty_fork_id!(), n), move || {
+///         assert!(n < 4);
+///     }));
+///     for result in fork_many("my_test", &ForkOpts::new(), cases, 2) {
+///         match result.unwrap() {
+///             ForkOutcome::Passed(()) => (),
+///             outcome => panic!("unexpected outcome: {:?}", outcome),
+///         }
+///     }
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// The same panics as for `fork` apply.
+pub fn fork_many<I, ID, CHILD>(
+    test_name: &str,
+    opts: &ForkOpts,
+    cases: I,
+    max_parallel: usize) -> Vec<Result<ForkOutcome<()>>>
+where
+    I : IntoIterator<Item = (ID, CHILD)>,
+    ID : Hash + Send,
+    CHILD : FnOnce () + Send,
+{
+    let cases = cases.into_iter().collect::<Vec<_>>();
+    // Forking for any other case would spawn grandchildren
+    if let Some(case) = cases.iter()
+        .position(|(id, _)| cmdline::is_child_of(id))
+    {
+        let (id, in_child) = cases.into_iter().nth(case)
+            .expect("case vanished");
+        return vec![try_fork(test_name, id, opts.clone(),
+                             |_, _| (), in_child)];
+    }
+
+    let count = cases.len();
+    let queue = Mutex::new(cases.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..max_parallel.max(1).min(count) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap_or_else(|e| e.into_inner())
+                    .next();
+                let (ix, (id, in_child)) = match next {
+                    Some(case) => case,
+                    None => break,
+                };
+                let result = try_fork(test_name, id, opts.clone(),
+                                      |_, _| (), in_child);
+                results.lock().unwrap_or_else(|e| e.into_inner())[ix] =
+                    Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|e| e.into_inner()).into_iter()
+        .map(|result| result.expect("case not run"))
+        .collect()
+}
+
+/// Copy the child's output in `file` to our own. If `collect` is true, the
+/// output is also returned.
+pub(crate) fn echo_output<F : Read + Seek>(file: &mut F, collect: bool)
+                                           -> Vec<u8> {
+    // Awkwardly, `print!()` and `println!()` are our only gateway to putting
+    // things in the captured output. Generally test output really is text, so
+    // work on that assumption and read line-by-line, converting lossily into
+    // UTF-8 so we can println!() it.
+    let _ = file.seek(io::SeekFrom::Start(0));
+
+    let mut collected = Vec::new();
+    let mut buf = Vec::new();
+    let mut br = io::BufReader::new(file);
+    loop {
+        // We can't use read_line() or lines() since they break if there's any
+        // non-UTF-8 output at all. \n occurs at the end of the line endings on
+        // all major platforms, so we can just use that as a delimiter.
+        if br.read_until(b'\n', &mut buf).is_err() {
+            break;
+        }
+        if buf.is_empty() {
+            break;
+        }
+
+        // not println!() because we already have a line ending from above.
+        print!("{}", String::from_utf8_lossy(&buf));
+        if collect {
+            collected.extend_from_slice(&buf);
+        }
+        buf.clear();
+    }
 
-    fork_impl(test_name, fork_id,
-              &mut |cmd| process_modifier.take().unwrap()(cmd),
-              &mut |child, file| return_value = Some(
-                  in_parent.take().unwrap()(child, file)),
-              &mut || in_child.take().unwrap()())
-        .map(|_| return_value.unwrap())
+    collected
 }
 
-fn fork_impl(test_name: &str, fork_id: String,
-             process_modifier: &mut dyn FnMut (&mut process::Command),
-             in_parent: &mut dyn FnMut (&mut ChildWrapper, &mut fs::File),
-             in_child: &mut dyn FnMut ()) -> Result<()> {
-    let mut occurs = env::var(OCCURS_ENV).unwrap_or_else(|_| String::new());
-    if occurs.contains(&fork_id) {
-        match panic::catch_unwind(panic::AssertUnwindSafe(in_child)) {
-            Ok(_) => process::exit(0),
-            // Assume that the default panic handler already printed something
-            //
-            // We don't use process::abort() since it produces core dumps on
-            // some systems and isn't something more special than a normal
-            // panic.
-            Err(_) => process::exit(70 /* EX_SOFTWARE */),
+/// Like `echo_output()`, but if `label` is true and the child wrote anything
+/// to `output`, precede it with a header naming `stream`.
+fn echo_labeled_output(output: &mut CapturedOutput, collect: bool,
+                       label: bool, stream: &str) -> Vec<u8> {
+    output.wait_drained();
+    let omitted = output.truncate();
+    if label && (!output.is_empty() || omitted > 0) {
+        println!("---- child {} ----", stream);
+    }
+
+    let mut collected = Vec::new();
+    if omitted > 0 {
+        let note = format!(
+            "---- {} earlier bytes of output omitted ----\n", omitted);
+        print!("{}", note);
+        if collect {
+            collected.extend_from_slice(note.as_bytes());
         }
+    }
+    collected.extend(echo_output(output, collect));
+    collected
+}
+
+/// Which children `fork_impl()` returns the output of, provided `in_parent`
+/// reaps them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CollectOutput {
+    /// Never return the output.
+    Never,
+    /// Return the output of a child which exited successfully. A child which
+    /// failed is still reported as an error.
+    Successful,
+    /// Return the output whether or not the child exited successfully.
+    Always,
+}
+
+/// The shared implementation of all the fork functions.
+pub(crate) fn fork_impl(
+    test_name: &str, fork_id: String, opts: &ForkOpts,
+    collect_output: CollectOutput,
+    process_modifier: &mut dyn FnMut (&mut process::Command),
+    in_parent: &mut dyn FnMut (&mut ChildWrapper, &mut CapturedOutput,
+                               Option<&mut CapturedOutput>),
+    in_child: &mut dyn FnMut ()) -> Result<Option<ChildOutput>>
+{
+    if cmdline::is_child_of_id(&fork_id) {
+        run_in_child(in_child)
     } else {
-        // Prevent misconfiguration creating a fork bomb
-        if occurs.len() > 16 * OCCURS_TERM_LENGTH {
-            panic!("rusty-fork: Not forking due to >=16 levels of recursion");
+        if cmdline::listing_requested() {
+            return Err(Error::Listing);
+        }
+
+        let occurs = cmdline::occurs_for_child_of_id(&fork_id);
+
+        // With both streams inherited, there is nothing to capture in memory,
+        // but the child still needs a file to mark that it ran
+        let memory_limit = match opts.memory_capture_limit {
+            Some(_) if opts.inherit_stdout && opts.inherit_stderr => None,
+            limit => limit,
+        };
+
+        #[cfg(feature = "sweep-temp-files")]
+        if memory_limit.is_none() {
+            crate::sweep::sweep_once();
+        }
+
+        // The files are named after the test so that, should they outlive us
+        // (e.g., because we were killed), it is clear where they came from.
+        let temp_file = || tempfile::Builder::new()
+            .prefix(&temp_file_prefix(test_name))
+            .tempfile();
+        let mut command = child_command(test_name, opts)?;
+        let mut internal_env: Vec<(&str, OsString)> =
+            vec![(cmdline::OCCURS_ENV, occurs.into())];
+        let mut stdout_temp = None;
+        let mut stderr_temp = None;
+        let mut ran_marker = None;
+        let mut pipe_buffers = None;
+        if let Some(limit) = memory_limit {
+            let max = opts.max_output_bytes;
+            let stdout = PipeBuffer::new(limit, max);
+            let stderr = match opts.capture_mode {
+                CaptureMode::Merged => Arc::clone(&stdout),
+                CaptureMode::Separate => PipeBuffer::new(limit, max),
+            };
+            internal_env.push((
+                RAN_STREAM_ENV,
+                if opts.inherit_stderr { "stdout" } else { "stderr" }.into()));
+            command
+                .stdout(process::Stdio::piped())
+                .stderr(process::Stdio::piped());
+            pipe_buffers = Some((stdout, stderr));
+        } else {
+            let stdout = temp_file()?;
+            let stderr = match opts.capture_mode {
+                CaptureMode::Merged => None,
+                CaptureMode::Separate => Some(temp_file()?),
+            };
+            // Mark the files as in use so that a concurrent sweep leaves them
+            // be
+            #[cfg(feature = "sweep-temp-files")]
+            for temp in Some(&stdout).into_iter().chain(stderr.as_ref()) {
+                let _ = sys::lock_shared(temp.as_file());
+            }
+            // If the child's harness does not know the test by `test_name`,
+            // the child exits successfully without ever getting here, so it
+            // needs to tell us explicitly that it did
+            let marker = temp_file()?;
+            // The child opens this one itself, possibly as another user
+            #[cfg(unix)]
+            {
+                if opts.uid.is_some() || opts.gid.is_some() {
+                    sys::fchown(marker.as_file(), opts.uid, opts.gid)?;
+                }
+            }
+            internal_env.push((RAN_ENV, marker.path().into()));
+            if opts.on_output_line.is_some() ||
+                opts.max_output_bytes.is_some()
+            {
+                // The output has to pass through us on its way to the files
+                // to be seen by the hook or limited in size
+                let max = opts.max_output_bytes;
+                let stdout_feed = PipeBuffer::feeding_file(
+                    stdout.as_file().try_clone()?, max);
+                let stderr_feed = match stderr {
+                    Some(ref temp) => PipeBuffer::feeding_file(
+                        temp.as_file().try_clone()?, max),
+                    None => Arc::clone(&stdout_feed),
+                };
+                command
+                    .stdout(process::Stdio::piped())
+                    .stderr(process::Stdio::piped());
+                pipe_buffers = Some((stdout_feed, stderr_feed));
+            } else {
+                let file = stdout.as_file().try_clone()?;
+                command
+                    .stdout(file.try_clone()?)
+                    .stderr(match stderr {
+                        Some(ref temp) => temp.as_file().try_clone()?,
+                        None => file,
+                    });
+            }
+            stdout_temp = Some(stdout);
+            stderr_temp = stderr;
+            ran_marker = Some(marker);
+        }
+
+        struct KillOnDrop {
+            child: ChildWrapper,
+            stdout: CapturedOutput,
+            stderr: Option<CapturedOutput>,
+            label_output: bool,
+            finished: bool,
         }
+        impl KillOnDrop {
+            /// Kill the child if it hasn't exited yet and copy its output to
+            /// our own. If `collect` is true, the output is also returned,
+            /// stdout first.
+            fn finish(&mut self, collect: bool) -> (Vec<u8>, Vec<u8>) {
+                self.finished = true;
+
+                let _ = self.child.kill();
+                // Whatever the child left running could otherwise keep going
+                // indefinitely
+                self.child.kill_tree();
+
+                let label = self.label_output && self.stderr.is_some();
+                let stdout = echo_labeled_output(
+                    &mut self.stdout, collect, label, "stdout");
+                let stderr = match self.stderr {
+                    Some(ref mut stderr) => echo_labeled_output(
+                        stderr, collect, label, "stderr"),
+                    None => Vec::new(),
+                };
+                (stdout, stderr)
+            }
 
-        let file = tempfile::tempfile()?;
+            /// Return whether the child reached the fork point, going by
+            /// `ran_marker` if output is captured in files.
+            fn ran(&self, ran_marker: Option<&tempfile::NamedTempFile>)
+                   -> Result<bool> {
+                if let Some(marker) = ran_marker {
+                    return Ok(marker.as_file().metadata()?.len() > 0);
+                }
+
+                self.stdout.wait_drained();
+                let stderr_ran = match self.stderr {
+                    Some(ref stderr) => {
+                        stderr.wait_drained();
+                        stderr.saw_ran_line()
+                    },
+                    None => false,
+                };
+                Ok(self.stdout.saw_ran_line() || stderr_ran)
+            }
+
+            /// Return the temporary files holding the output of the child,
+            /// writing it to new ones if it is captured in memory.
+            fn output_files(&mut self, test_name: &str,
+                            stdout_temp: Option<tempfile::NamedTempFile>,
+                            stderr_temp: Option<tempfile::NamedTempFile>)
+                            -> Vec<tempfile::NamedTempFile> {
+                if stdout_temp.is_some() {
+                    return stdout_temp.into_iter().chain(stderr_temp)
+                        .collect();
+                }
 
-        struct KillOnDrop(ChildWrapper, fs::File);
+                let mut files = Vec::new();
+                for output in Some(&mut self.stdout).into_iter()
+                    .chain(self.stderr.as_mut())
+                {
+                    let file = tempfile::Builder::new()
+                        .prefix(&temp_file_prefix(test_name))
+                        .tempfile()
+                        .and_then(|mut temp| {
+                            output.seek(io::SeekFrom::Start(0))?;
+                            io::copy(output, &mut temp)?;
+                            Ok(temp)
+                        });
+                    if let Ok(file) = file {
+                        files.push(file);
+                    }
+                }
+                files
+            }
+        }
         impl Drop for KillOnDrop {
             fn drop(&mut self) {
-                // Kill the child if it hasn't exited yet
-                let _ = self.0.kill();
-
-                // Copy the child's output to our own
-                // Awkwardly, `print!()` and `println!()` are our only gateway
-                // to putting things in the captured output. Generally test
-                // output really is text, so work on that assumption and read
-                // line-by-line, converting lossily into UTF-8 so we can
-                // println!() it.
-                let _ = self.1.seek(io::SeekFrom::Start(0));
-
-                let mut buf = Vec::new();
-                let mut br = io::BufReader::new(&mut self.1);
-                loop {
-                    // We can't use read_line() or lines() since they break if
-                    // there's any non-UTF-8 output at all. \n occurs at the
-                    // end of the line endings on all major platforms, so we
-                    // can just use that as a delimiter.
-                    if br.read_until(b'\n', &mut buf).is_err() {
-                        break;
-                    }
-                    if buf.is_empty() {
-                        break;
-                    }
-
-                    // not println!() because we already have a line ending
-                    // from above.
-                    print!("{}", String::from_utf8_lossy(&buf));
-                    buf.clear();
+                if !self.finished {
+                    self.finish(false);
                 }
             }
         }
 
-        occurs.push_str(&fork_id);
-        let mut command =
-            process::Command::new(
-                env::current_exe()
-                    .expect("current_exe() failed, cannot fork"));
-        command
-            .args(cmdline::strip_cmdline(env::args())?)
-            .args(cmdline::RUN_TEST_ARGS)
-            .arg(test_name)
-            .env(OCCURS_ENV, &occurs)
-            .stdin(process::Stdio::null())
-            .stdout(file.try_clone()?)
-            .stderr(file.try_clone()?);
+        command.stdin(opts.stdin.stdio()?);
+        opts.configure_command(&mut command);
+        // After the options so that they can't get in the way
+        command.envs(internal_env);
+        if opts.kill_process_tree {
+            sys::ProcessTree::prepare(&mut command);
+        }
         process_modifier(&mut command);
 
-        let mut child = command.spawn().map(ChildWrapper::new)
-            .map(|p| KillOnDrop(p, file))?;
+        let (stdout, stderr) = match stdout_temp {
+            Some(ref stdout_temp) => (
+                CapturedOutput::file(
+                    stdout_temp.as_file().try_clone()?,
+                    pipe_buffers.as_ref().map(|feeds| Arc::clone(&feeds.0))),
+                match stderr_temp {
+                    Some(ref temp) => Some(CapturedOutput::file(
+                        temp.as_file().try_clone()?,
+                        pipe_buffers.as_ref()
+                            .map(|feeds| Arc::clone(&feeds.1)))),
+                    None => None,
+                }),
+            None => {
+                let (ref stdout, ref stderr) = *pipe_buffers.as_ref()
+                    .expect("neither files nor pipes for output");
+                (CapturedOutput::memory(Arc::clone(stdout)),
+                 match opts.capture_mode {
+                     CaptureMode::Merged => None,
+                     CaptureMode::Separate =>
+                         Some(CapturedOutput::memory(Arc::clone(stderr))),
+                 })
+            },
+        };
+        let mut child = spawn(&mut command, opts).map(ChildWrapper::new)
+            .map(|child| KillOnDrop {
+                child, stdout, stderr,
+                label_output: opts.label_output, finished: false,
+            })?;
+        if let Some((stdout, stderr)) = pipe_buffers {
+            let hook = &opts.on_output_line;
+            let inner = child.child.inner_mut();
+            if let Some(pipe) = inner.stdout.take() {
+                PipeBuffer::drain(&stdout, pipe, OutputStream::Stdout,
+                                  hook.clone());
+            }
+            if let Some(pipe) = inner.stderr.take() {
+                PipeBuffer::drain(&stderr, pipe, OutputStream::Stderr,
+                                  hook.clone());
+            }
+            child.child.set_output_feeds(vec![stdout, stderr]);
+        }
+        if let StdinSource::Bytes(_) = *opts.stdin {
+            let source = Arc::clone(&opts.stdin);
+            let stdin = child.child.take_stdin();
+            thread::spawn(move || {
+                if let (StdinSource::Bytes(ref bytes), Some(mut stdin)) =
+                    (&*source, stdin)
+                {
+                    // The child is free not to read everything
+                    let _ = stdin.write_all(bytes);
+                }
+            });
+        }
+        if opts.kill_process_tree {
+            let tree = sys::ProcessTree::attach(child.child.inner())?;
+            child.child.set_process_tree(tree);
+        }
+        #[cfg(feature = "timeout")]
+        {
+            if let Some(timeout) = opts.timeout {
+                child.child.set_deadline(Instant::now() + timeout,
+                                         opts.grace_period,
+                                         opts.on_timeout.clone());
+            }
+        }
+
+        in_parent(&mut child.child, &mut child.stdout, child.stderr.as_mut());
+
+        if let Some(elapsed) = child.child.timed_out_after() {
+            child.finish(false);
+            return Err(Error::Timeout {
+                elapsed,
+                test_name: test_name.to_owned(),
+                kept_output: keep_output(|| child.output_files(
+                    test_name, stdout_temp, stderr_temp)),
+            });
+        }
+
+        match child.child.exit_status() {
+            Some(status) if status.success() &&
+                !child.ran(ran_marker.as_ref())? =>
+            {
+                child.finish(false);
+                Err(Error::TestNotRun(test_name.to_owned()))
+            },
+            Some(status) if CollectOutput::Always == collect_output ||
+                (status.success() &&
+                 CollectOutput::Successful == collect_output) =>
+            {
+                let (stdout, stderr) = child.finish(true);
+                Ok(Some(ChildOutput::new(status, &stdout, &stderr)))
+            },
+            Some(status) if !status.success() => {
+                let (mut output, stderr) = child.finish(true);
+                output.extend_from_slice(&stderr);
+                let output = String::from_utf8_lossy(&output).into_owned();
+                let kept_output = keep_output(|| child.output_files(
+                    test_name, stdout_temp, stderr_temp));
+                Err(child_failure(opts, status, output, kept_output))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Run `in_child` in the child process of a fork, and exit once it returns.
+pub(crate) fn run_in_child(in_child: &mut dyn FnMut ()) -> ! {
+    // Tell the parent that the test was found. If this fails, the parent
+    // reports the test as not run, which is better than the opposite.
+    if let Some(path) = env::var_os(RAN_ENV) {
+        let _ = fs::OpenOptions::new().append(true).open(path)
+            .and_then(|mut file| file.write_all(b"x"));
+    }
+    match env::var_os(RAN_STREAM_ENV) {
+        Some(ref stream) if stream == "stdout" => {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(RAN_LINE)
+                .and_then(|_| stdout.flush());
+        },
+        Some(ref stream) if stream == "stderr" => {
+            let _ = io::stderr().write_all(RAN_LINE);
+        },
+        _ => (),
+    }
+
+    match panic::catch_unwind(panic::AssertUnwindSafe(in_child)) {
+        Ok(_) => process::exit(0),
+        // Assume that the default panic handler already printed something
+        //
+        // We don't use process::abort() since it produces core dumps on
+        // some systems and isn't something more special than a normal
+        // panic.
+        Err(_) => process::exit(70 /* EX_SOFTWARE */),
+    }
+}
+
+/// Return the error describing how a child which exited unsuccessfully with
+/// `status`, having written `output`, failed.
+pub(crate) fn child_failure(opts: &ForkOpts, status: ExitStatusWrapper,
+                            output: String, kept_output: Vec<PathBuf>)
+                            -> Error {
+    match (status.code(), opts.wrapper_error_code,
+           exceeded_memory_limit(opts, &output)) {
+        (Some(code), Some(error_code), _) if code == error_code =>
+            Error::WrapperFailed { code, output, kept_output },
+        (_, _, Some(limit)) => Error::MemoryLimitExceeded {
+            limit, status, output, kept_output,
+        },
+        _ => Error::ChildFailed { status, output, kept_output },
+    }
+}
+
+/// Return the limit on the address space of the child if `output` shows that
+/// it failed because an allocation exceeded that limit.
+///
+/// The standard library reports being refused memory as `memory allocation
+/// of N bytes failed` before aborting.
+#[cfg(unix)]
+fn exceeded_memory_limit(opts: &ForkOpts, output: &str) -> Option<u64> {
+    let limit = opts.rlimits.iter()
+        .find(|&&(resource, _, _)| Resource::AddressSpace == resource)
+        .map(|&(_, soft, _)| soft)
+        .filter(|&soft| u64::MAX != soft)?;
+    output.lines()
+        .any(|line| line.starts_with("memory allocation of ") &&
+             line.ends_with(" bytes failed"))
+        .then_some(limit)
+}
+
+#[cfg(not(unix))]
+fn exceeded_memory_limit(_: &ForkOpts, _: &str) -> Option<u64> {
+    None
+}
+
+/// Build the command which runs the test `test_name` in a child process,
+/// without any of the fork-specific environment or redirections.
+pub(crate) fn child_command(test_name: &str, opts: &ForkOpts)
+                            -> Result<process::Command> {
+    let exe = child_executable(opts);
+    let wrapper = match opts.wrapper {
+        Some(ref wrapper) => wrapper.clone(),
+        None => cmdline::wrapper_from_env()?
+            .map(|wrapper| wrapper.into_iter().map(Into::into).collect())
+            .unwrap_or_default(),
+    };
+    let mut command = match wrapper.split_first() {
+        Some((program, args)) => {
+            let mut command = process::Command::new(program);
+            command.args(args).arg(exe);
+            command
+        },
+        None => process::Command::new(exe),
+    };
+    opts.clear_command_env(&mut command);
+    if let Some(ref raw_args) = opts.raw_args {
+        command.args(raw_args);
+    } else {
+        let mut args = match opts.parent_args {
+            Some(ref parent_args) => cmdline::FilteredArgs::new(
+                parent_args.clone(), opts.unknown_flag_policy)?,
+            None => cmdline::FilteredArgs::cached_from_env(
+                opts.unknown_flag_policy)?,
+        };
+        // The child does not write to our terminal, so it would otherwise
+        // turn colors off
+        if opts.preserve_color && env::var_os("NO_COLOR").is_none() &&
+            sys::stdout_is_terminal() && args.force_color()
+        {
+            command.env("CLICOLOR_FORCE", "1");
+        }
+        command.args(args.child_args_with_extra(
+            test_name, &opts.extra_args, &opts.selection_args)?);
+        if opts.normalize_test_env {
+            for var in cmdline::RUN_TEST_ENV {
+                command.env_remove(var);
+            }
+        }
+    }
+    Ok(command)
+}
+
+/// Return the test executable the child is to run.
+fn child_executable(opts: &ForkOpts) -> PathBuf {
+    match opts.child_executable {
+        Some(ref exe) => exe.clone(),
+        None => cmdline::test_exe_from_env().unwrap_or_else(
+            || cmdline::current_exe()
+                .expect("current_exe() failed, cannot fork").to_owned()),
+    }
+}
+
+/// Spawn `command`, built by `child_command()` with `opts`, identifying the
+/// program which could not be started if that fails.
+pub(crate) fn spawn(command: &mut process::Command, opts: &ForkOpts)
+                    -> Result<process::Child> {
+    prepare_spawn(command, opts)?;
+    command.spawn().map_err(|err| Error::SpawnFailed(
+        command.get_program().into(), err))
+}
+
+/// Check that the child can be spawned with `opts` and apply the last of
+/// them to `command`, right before it is spawned.
+pub(crate) fn prepare_spawn(command: &mut process::Command, opts: &ForkOpts)
+                            -> Result<()> {
+    match env::var_os(DEBUG_ENV) {
+        Some(ref value) if value == "1" =>
+            eprint!("{}", describe_command(command, &child_executable(opts))),
+        _ => (),
+    }
+    #[cfg(unix)]
+    {
+        for &(resource, soft, hard) in &opts.rlimits {
+            sys::check_rlimit(resource, soft, hard).map_err(
+                |err| Error::ResourceLimit(resource.name(), err))?;
+        }
+        sys::check_credentials(opts.uid, opts.gid, opts.groups.as_deref())
+            .map_err(|(credential, err)|
+                     Error::CredentialsFailed(credential, err))?;
+    }
+    if let Some(priority) = opts.priority {
+        match sys::check_priority(priority) {
+            Ok(()) => sys::set_priority(
+                command, priority, opts.strict_priority),
+            Err(err) if opts.strict_priority =>
+                return Err(Error::PriorityFailed(priority, err)),
+            Err(err) => eprintln!(
+                "rusty-fork: warning: not setting priority {:?} of child \
+                 process: {}", priority, err),
+        }
+    }
+    Ok(())
+}
+
+/// The environment variable which, when set to `1`, makes `spawn()` print the
+/// command line of each child before spawning it.
+const DEBUG_ENV: &str = "RUSTY_FORK_DEBUG";
+
+/// Describe the test executable `exe` and the full command line `command`
+/// runs it with, for `RUSTY_FORK_DEBUG`.
+fn describe_command(command: &process::Command, exe: &Path) -> String {
+    let mut description = format!(
+        "rusty-fork: test executable: {}\nrusty-fork: child command: {:?}",
+        exe.display(), command.get_program());
+    for arg in command.get_args() {
+        description.push_str(&format!(" {:?}", arg));
+    }
+    description.push('\n');
+    description
+}
 
-        let ret = in_parent(&mut child.0, &mut child.1);
+/// The environment variable which, when set to `1`, keeps the files holding
+/// the output of a failed child from being deleted.
+const KEEP_OUTPUT_ENV: &str = "RUSTY_FORK_KEEP_OUTPUT";
 
-        Ok(ret)
+/// If `RUSTY_FORK_KEEP_OUTPUT=1`, keep the temporary files returned by
+/// `files` holding the output of a child instead of deleting them, and return
+/// their paths.
+///
+/// This is best-effort; a file which cannot be kept is deleted as usual.
+pub(crate) fn keep_output<F>(files: F) -> Vec<PathBuf>
+where F : FnOnce () -> Vec<tempfile::NamedTempFile> {
+    match env::var_os(KEEP_OUTPUT_ENV) {
+        Some(ref value) if value == "1" => (),
+        _ => return Vec::new(),
     }
+
+    files().into_iter()
+        .filter_map(|temp| temp.keep().ok())
+        .map(|(_, path)| path)
+        .collect()
 }
 
-fn id_str<ID : Hash>(id: ID) -> String {
-    let mut hasher = fnv::FnvHasher::default();
-    id.hash(&mut hasher);
+/// The environment variable naming the file a child appends to once it has
+/// reached the fork point.
+pub(crate) const RAN_ENV: &str = "RUSTY_FORK_RAN_MARKER";
+
+/// The environment variable naming the stream, `stdout` or `stderr`, to which
+/// a child whose output is captured in memory writes `RAN_LINE` once it has
+/// reached the fork point.
+const RAN_STREAM_ENV: &str = "RUSTY_FORK_RAN_STREAM";
 
-    return format!(":{:016X}", hasher.finish());
+/// The prefix shared by the names of all temporary files holding the output
+/// of a child.
+pub(crate) const TEMP_FILE_PREFIX: &str = "rusty-fork-";
+
+/// Return the prefix for the name of the temporary file holding the output of
+/// a child running `test_name`.
+pub(crate) fn temp_file_prefix(test_name: &str) -> String {
+    let mut prefix = TEMP_FILE_PREFIX.to_owned();
+    prefix.extend(
+        test_name.chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() || '_' == ch {
+                ch
+            } else {
+                '-'
+            })
+            .take(64));
+    prefix.push('-');
+    prefix
 }
 
+
 #[cfg(test)]
 mod test {
+    use std::ffi::OsStr;
     use std::io::Read;
     use std::thread;
 
     use super::*;
+    use crate::fork_opts::Priority;
 
     fn sleep(ms: u64) {
         thread::sleep(::std::time::Duration::from_millis(ms));
@@ -235,6 +1240,49 @@ mod test {
         assert!(child.wait().unwrap().success());
     }
 
+    #[test]
+    fn temp_file_prefix_sanitises_test_name() {
+        assert_eq!("rusty-fork-fork--test--foo_bar-",
+                   temp_file_prefix("fork::test::foo_bar"));
+        assert_eq!("rusty-fork-a-b-", temp_file_prefix("a/b"));
+    }
+
+    #[test]
+    fn temp_file_named_after_test() {
+        fn temp_files_for_test() -> usize {
+            let prefix = temp_file_prefix(
+                "fork::test::temp_file_named_after_test");
+            fs::read_dir(env::temp_dir()).unwrap()
+                .filter(|entry| entry.as_ref().unwrap().file_name()
+                        .to_string_lossy().starts_with(&prefix))
+                .count()
+        }
+
+        fork("fork::test::temp_file_named_after_test", rusty_fork_id!(),
+             |_| (),
+             |child, _| {
+                 // The output and the marker that the test ran
+                 assert_eq!(2, temp_files_for_test());
+                 assert!(child.wait().unwrap().success());
+             },
+             || ()).unwrap();
+        assert_eq!(0, temp_files_for_test());
+    }
+
+    #[test]
+    fn misnamed_test_reported_as_not_run() {
+        let err = fork("fork::test::no_such_test", rusty_fork_id!(),
+                       |_| (),
+                       |child, _| { child.wait().unwrap(); },
+                       || ()).unwrap_err();
+        match err {
+            Error::TestNotRun(ref name) =>
+                assert_eq!("fork::test::no_such_test", name),
+            e => panic!("Unexpected error: {}", e),
+        }
+        assert!(err.to_string().contains("fork::test::no_such_test"));
+    }
+
     #[test]
     fn fork_basically_works() {
         let status =
@@ -245,6 +1293,19 @@ mod test {
         assert!(status.success());
     }
 
+    #[test]
+    fn fork_with_seeded_id() {
+        for case in &["a", "b"] {
+            let output = fork_output(
+                "fork::test::fork_with_seeded_id",
+                crate::RustyForkId::from_seed(case), ForkOpts::new(),
+                || println!("running case {}", case)).unwrap();
+            assert!(output.status().success());
+            assert!(output.stdout().contains(&format!("running case {}", case)),
+                    "Unexpected output:\n{}", output.stdout());
+        }
+    }
+
     #[test]
     fn child_output_captured_and_repeated() {
         let output = fork(
@@ -306,12 +1367,1641 @@ mod test {
 
     #[test]
     fn child_aborted_if_panics() {
-        let status = fork(
+        let err = fork(
             "fork::test::child_aborted_if_panics",
             rusty_fork_id!(),
             |_| (),
             |child, _| child.wait().unwrap(),
-            || panic!("testing a panic, nothing to see here")).unwrap();
-        assert_eq!(70, status.code().unwrap());
+            || panic!("testing a panic, nothing to see here")).unwrap_err();
+        match err {
+            Error::ChildFailed { status, .. } =>
+                assert_eq!(70, status.code().unwrap()),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_killed_by_sigsegv_reports_signal_name() {
+        let err = fork(
+            "fork::test::child_killed_by_sigsegv_reports_signal_name",
+            rusty_fork_id!(),
+            |_| (),
+            |child, _| { child.wait().unwrap(); },
+            || sys::die_by_signal(libc::SIGSEGV)).unwrap_err();
+        match err {
+            Error::ChildFailed { ref status, .. } => {
+                assert_eq!(Some(libc::SIGSEGV), status.unix_signal());
+                assert_eq!(Some("SIGSEGV"), status.unix_signal_name());
+            },
+            ref e => panic!("Unexpected error: {}", e),
+        }
+        let message = err.to_string();
+        assert!(message.contains(&format!(
+            "killed by signal {} (SIGSEGV)", libc::SIGSEGV)),
+                "Unexpected message: {}", message);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_aborting_reports_sigabrt() {
+        let err = fork(
+            "fork::test::child_aborting_reports_sigabrt", rusty_fork_id!(),
+            |_| (),
+            |child, _| { child.wait().unwrap(); },
+            || process::abort()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(&format!(
+            "killed by signal {} (SIGABRT)", libc::SIGABRT)),
+                "Unexpected message: {}", message);
+    }
+
+    #[test]
+    fn child_failure_reports_status_and_output() {
+        let err = fork(
+            "fork::test::child_failure_reports_status_and_output",
+            rusty_fork_id!(),
+            |_| (),
+            |child, _| { child.wait().unwrap(); },
+            || {
+                println!("about to exit");
+                process::exit(3);
+            }).unwrap_err();
+        match err {
+            Error::ChildFailed { ref status, ref output, .. } => {
+                assert_eq!(Some(3), status.code());
+                assert!(output.contains("about to exit\n"),
+                        "Had unexpected output:\n{}", output);
+            },
+            ref e => panic!("Unexpected error: {}", e),
+        }
+        assert!(err.to_string().contains("about to exit"));
+    }
+
+    #[test]
+    fn child_failure_reports_panic_message() {
+        let err = fork(
+            "fork::test::child_failure_reports_panic_message",
+            rusty_fork_id!(),
+            // The backtrace pushes the message out of the tail of the output
+            |cmd| { cmd.env("RUST_BACKTRACE", "1"); },
+            |child, _| { child.wait().unwrap(); },
+            || panic!("unique panic message 0x5eed")).unwrap_err();
+        let message = err.to_string();
+        // The location follows the message in panics before Rust 1.73
+        assert!(message.contains("it panicked at "),
+                "Unexpected error: {}", message);
+        assert!(message.contains("src/fork.rs:"),
+                "Unexpected error: {}", message);
+        assert!(message.contains("unique panic message 0x5eed"),
+                "Unexpected error: {}", message);
+    }
+
+    #[test]
+    fn fork_with_opts_sets_env() {
+        let status = fork_with_opts(
+            "fork::test::fork_with_opts_sets_env",
+            rusty_fork_id!(),
+            ForkOpts::new()
+                .env("FORK_OPTS_TEST_VAR", "overwritten")
+                .env("FORK_OPTS_TEST_VAR", "a=b"),
+            |child, _| child.wait().unwrap(),
+            || assert_eq!(Ok("a=b".to_owned()),
+                          env::var("FORK_OPTS_TEST_VAR"))).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn fork_with_opts_inherit_stdout() {
+        let output = fork(
+            "fork::test::fork_with_opts_inherit_stdout",
+            rusty_fork_id!(),
+            capturing_output, wait_for_child_output,
+            || {
+                let captured = fork_with_opts(
+                    "fork::test::fork_with_opts_inherit_stdout",
+                    rusty_fork_id!(),
+                    ForkOpts::new().inherit_stdout(true),
+                    |child, file| {
+                        assert!(child.wait().unwrap().success());
+                        let mut captured = String::new();
+                        file.seek(io::SeekFrom::Start(0)).unwrap();
+                        file.read_to_string(&mut captured).unwrap();
+                        captured
+                    },
+                    || println!("hello from child")).unwrap();
+                assert!(!captured.contains("hello from child"));
+            }).unwrap();
+        assert!(output.contains("hello from child"));
+    }
+
+    #[test]
+    fn fork_with_opts_raw_args() {
+        let test_name = "fork::test::fork_with_opts_raw_args";
+        let output = fork_with_opts(
+            test_name, rusty_fork_id!(),
+            ForkOpts::new().raw_args(vec![
+                "--test-threads", "1", "--nocapture", "--exact", test_name]),
+            |child, file| {
+                assert!(child.wait().unwrap().success());
+                let mut output = String::new();
+                file.seek(io::SeekFrom::Start(0)).unwrap();
+                file.read_to_string(&mut output).unwrap();
+                output
+            },
+            || println!("hello from child")).unwrap();
+        // Without `--quiet`, the harness in the child prints the test name
+        assert!(output.contains(&format!("test {} ...", test_name)),
+                "Had unexpected output:\n{}", output);
+        assert!(output.contains("hello from child"));
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn fork_returning_passes_value_to_parent() {
+        let value: (u32, String) = fork_returning(
+            "fork::test::fork_returning_passes_value_to_parent",
+            rusty_fork_id!(), ForkOpts::new(),
+            || {
+                println!("not part of the value");
+                (42, "from child".to_owned())
+            }).unwrap();
+        assert_eq!((42, "from child".to_owned()), value);
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn fork_returning_passes_large_binary_value() {
+        // Larger than any pipe buffer, and not valid UTF-8
+        let expected = (0..1 << 20).map(|ix| (ix % 251) as u8)
+            .collect::<Vec<u8>>();
+        let value: Vec<u8> = fork_returning(
+            "fork::test::fork_returning_passes_large_binary_value",
+            rusty_fork_id!(), ForkOpts::new(),
+            || (0..1 << 20).map(|ix| (ix % 251) as u8).collect::<Vec<u8>>())
+            .unwrap();
+        assert!(expected == value, "value of {} bytes differs", value.len());
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn fork_returning_child_panics() {
+        match fork_returning(
+            "fork::test::fork_returning_child_panics", rusty_fork_id!(),
+            ForkOpts::new(),
+            || -> u32 { panic!("no value today") })
+        {
+            Err(Error::ChildFailed { ref output, .. }) =>
+                assert!(output.contains("no value today"), "{}", output),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[test]
+    fn fork_returning_child_exits_early() {
+        match fork_returning(
+            "fork::test::fork_returning_child_exits_early", rusty_fork_id!(),
+            ForkOpts::new(),
+            || -> u32 { process::exit(0) })
+        {
+            Err(Error::NoValueReturned(ref test_name)) => assert_eq!(
+                "fork::test::fork_returning_child_exits_early", test_name),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(all(unix, feature = "serde"))]
+    #[allow(unsafe_code)]
+    #[test]
+    fn fork_returning_with_credentials() {
+        // Safety: these functions have no preconditions.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let value: u32 = fork_returning(
+            "fork::test::fork_returning_with_credentials", rusty_fork_id!(),
+            ForkOpts::new().uid(uid).gid(gid),
+            // Safety: as above.
+            || unsafe { libc::getuid() }).unwrap();
+        assert_eq!(uid, value);
+    }
+
+    #[test]
+    fn try_fork_passed() {
+        let outcome = try_fork(
+            "fork::test::try_fork_passed", rusty_fork_id!(), ForkOpts::new(),
+            |_, _| 42,
+            || process::exit(0)).unwrap();
+        match outcome {
+            ForkOutcome::Passed(42) => (),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn try_fork_failed() {
+        let outcome = try_fork(
+            "fork::test::try_fork_failed", rusty_fork_id!(), ForkOpts::new(),
+            |_, _| (),
+            || {
+                println!("about to fail");
+                process::exit(3);
+            }).unwrap();
+        match outcome {
+            ForkOutcome::Failed { ref status, ref output } => {
+                assert_eq!(Some(3), status.code());
+                assert!(output.contains("about to fail\n"),
+                        "Had unexpected output:\n{}", output);
+            },
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn try_fork_skipped() {
+        let outcome = try_fork(
+            "fork::test::try_fork_skipped", rusty_fork_id!(), ForkOpts::new(),
+            |_, _| (),
+            || {
+                println!("nothing to test");
+                skip()
+            }).unwrap();
+        match outcome {
+            ForkOutcome::Skipped { ref output } =>
+                assert!(output.contains("nothing to test\n"),
+                        "Had unexpected output:\n{}", output),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn skipped_child_fails_fork() {
+        match fork("fork::test::skipped_child_fails_fork", rusty_fork_id!(),
+                   |_| (), |child, _| { child.wait().unwrap(); }, || skip())
+        {
+            Err(Error::ChildFailed { status, .. }) =>
+                assert_eq!(Some(SKIP_EXIT_CODE), status.code()),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of a child process")]
+    fn skip_outside_child_panics() {
+        skip();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn try_fork_signaled() {
+        let outcome = try_fork(
+            "fork::test::try_fork_signaled", rusty_fork_id!(), ForkOpts::new(),
+            |_, _| (),
+            || process::abort()).unwrap();
+        match outcome {
+            ForkOutcome::Signaled { signal, .. } =>
+                assert_eq!(libc::SIGABRT, signal),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn try_fork_timed_out() {
+        let outcome = try_fork(
+            "fork::test::try_fork_timed_out", rusty_fork_id!(),
+            ForkOpts::new().timeout(::std::time::Duration::from_millis(100)),
+            |_, _| (),
+            || sleep(10_000)).unwrap();
+        match outcome {
+            ForkOutcome::TimedOut { elapsed } =>
+                assert!(elapsed.as_secs() < 5, "elapsed = {:?}", elapsed),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn try_fork_still_reports_errors() {
+        match try_fork(
+            "fork::test::no_such_test", rusty_fork_id!(), ForkOpts::new(),
+            |_, _| (),
+            || ())
+        {
+            Err(Error::TestNotRun(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn fork_many_results_in_order_of_cases() {
+        // The later cases finish first
+        let cases = (0..4).map(|n| ((rusty_fork_id!(), n), move || {
+            sleep(300 - 100 * n);
+            process::exit(n as i32 + 1);
+        }));
+        let codes = fork_many(
+            "fork::test::fork_many_results_in_order_of_cases",
+            &ForkOpts::new(), cases, 4)
+            .into_iter().map(|outcome| match outcome.unwrap() {
+                ForkOutcome::Failed { status, .. } => status.code(),
+                outcome => panic!("Unexpected outcome: {:?}", outcome),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Some(1), Some(2), Some(3), Some(4)], codes);
+    }
+
+    #[test]
+    fn fork_many_bounds_parallelism() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        fn now_ms() -> u128 {
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let cases = (0..6).map(|n| ((rusty_fork_id!(), n), move || {
+            let start = now_ms();
+            sleep(200);
+            let log = Path::new(&env::var_os("FORK_MANY_LOG").unwrap())
+                .join(n.to_string());
+            fs::write(log, format!("{} {}", start, now_ms())).unwrap();
+        }));
+        for outcome in fork_many(
+            "fork::test::fork_many_bounds_parallelism",
+            &ForkOpts::new().env("FORK_MANY_LOG", dir.path()), cases, 2)
+        {
+            match outcome.unwrap() {
+                ForkOutcome::Passed(()) => (),
+                outcome => panic!("Unexpected outcome: {:?}", outcome),
+            }
+        }
+
+        let runs = (0..6).map(|n| {
+            let run = fs::read_to_string(dir.path().join(n.to_string()))
+                .unwrap();
+            let mut times = run.split(' ').map(|t| t.parse::<u128>().unwrap());
+            (times.next().unwrap(), times.next().unwrap())
+        }).collect::<Vec<_>>();
+        let most_concurrent = runs.iter().map(|&(start, _)| {
+            runs.iter().filter(|&&(s, e)| s <= start && start < e).count()
+        }).max().unwrap();
+        // Whether two runs overlap at all depends on how busy the machine is
+        assert!(most_concurrent <= 2, "Unexpected runs: {:?}", runs);
+    }
+
+    #[test]
+    fn fork_with_opts_extra_args() {
+        let test_name = "fork::test::fork_with_opts_extra_args";
+        let output = fork_output(
+            test_name, rusty_fork_id!(),
+            ForkOpts::new()
+                .extra_args(vec!["--show-output", "--test-threads", "1"])
+                .extra_args(vec!["--include-ignored"]),
+            || println!("child args: {}",
+                        env::args().skip(1).collect::<Vec<_>>().join(" ")))
+            .unwrap();
+        assert!(output.stdout().contains(&format!(
+            "child args: --show-output --include-ignored --quiet \
+             --test-threads 1 --nocapture --exact -- {}\n", test_name)),
+                "Unexpected output:\n{}", output.stdout());
+    }
+
+    /// Fork into the ignored test `ignored_fork_point`, which the child
+    /// only runs if told to with `--ignored`.
+    fn fork_into_ignored_test(opts: ForkOpts) -> Result<ExitStatusWrapper> {
+        fork_with_opts(
+            "fork::test::ignored_fork_point", rusty_fork_id!(), opts,
+            |child, _| child.wait().unwrap(),
+            || ())
+    }
+
+    #[test]
+    #[ignore]
+    fn ignored_fork_point() {
+        fork_into_ignored_test(ForkOpts::new()).unwrap();
+    }
+
+    #[test]
+    fn selection_flags_reach_child() {
+        match fork_into_ignored_test(ForkOpts::new()) {
+            Err(Error::TestNotRun(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let status = fork_into_ignored_test(
+            ForkOpts::new().selection_args(vec!["--ignored"])).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn bench_reaches_child() {
+        // In bench mode, the harness skips the plain test the child would
+        // otherwise run
+        match fork_with_opts(
+            "fork::test::fork_basically_works", rusty_fork_id!(),
+            ForkOpts::new().parent_args(vec!["bench", "--bench"]),
+            |child, _| child.wait().unwrap(), || ())
+        {
+            Err(Error::TestNotRun(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn special_characters_in_name_match_literally() {
+        // Each would select `fork_basically_works` if taken as a pattern or a
+        // substring rather than the exact name
+        for name in &["fork::test::fork_basically_works+",
+                      "fork::test::fork_basically_works.*",
+                      "fork::test::fork_basically_work[s]",
+                      "fork::test::fork_basically_"] {
+            match fork(name, rusty_fork_id!(),
+                       |_| (), |child, _| child.wait().unwrap(), || ())
+            {
+                Err(Error::TestNotRun(_)) => (),
+                result => panic!("Unexpected result for {}: {:?}",
+                                 name, result),
+            }
+        }
+    }
+
+    #[test]
+    fn selection_filters_select_child() {
+        // As for a harness which generates its tests at runtime, where the
+        // name passed to `fork()` is not the one the harness knows the test
+        // by
+        let output = fork_output(
+            "fork::test::<generated> case #1", rusty_fork_id!(),
+            ForkOpts::new()
+                .selection_args(
+                    vec!["fork::test::selection_filters_select_child"]),
+            || println!("child ran")).unwrap();
+        assert!(output.stdout().contains("child ran\n"),
+                "Unexpected output:\n{}", output.stdout());
+    }
+
+    #[test]
+    fn unknown_selection_flag_rejected() {
+        match fork_into_ignored_test(
+            ForkOpts::new().selection_args(vec!["--ignored", "--frob"]))
+        {
+            Err(Error::UnknownFlag { ref flag, .. }) =>
+                assert_eq!("--frob", flag),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    fn child_test_env(test_name: &str, opts: ForkOpts) -> String {
+        fork_output(test_name, rusty_fork_id!(), opts, || {
+            for var in cmdline::RUN_TEST_ENV {
+                println!("{}={:?}", var, env::var(var).ok());
+            }
+        }).unwrap().stdout().to_owned()
+    }
+
+    // Subprocesses so we can change the environment without affecting other
+    // tests. The variables are set from outside, since the child of each
+    // test runs its body again before getting to its own fork.
+    rusty_fork_test! {
+        #![rusty_fork(env(RUST_TEST_THREADS = "4", RUST_TEST_NOCAPTURE = "1"))]
+
+        #[test]
+        fn test_env_not_passed_to_child() {
+            let output = child_test_env(
+                "fork::test::test_env_not_passed_to_child",
+                ForkOpts::new().env("RUST_TEST_SHUFFLE", "1"));
+            assert!(output.contains("RUST_TEST_THREADS=None\n"), "{}", output);
+            assert!(output.contains("RUST_TEST_NOCAPTURE=None\n"),
+                    "{}", output);
+            // Explicitly requested for the child
+            assert!(output.contains("RUST_TEST_SHUFFLE=Some(\"1\")\n"),
+                    "{}", output);
+        }
+
+        #[test]
+        fn test_env_passed_to_child_on_request() {
+            let output = child_test_env(
+                "fork::test::test_env_passed_to_child_on_request",
+                ForkOpts::new().normalize_test_env(false));
+            assert!(output.contains("RUST_TEST_THREADS=Some(\"4\")\n"),
+                    "{}", output);
+            assert!(output.contains("RUST_TEST_NOCAPTURE=Some(\"1\")\n"),
+                    "{}", output);
+        }
+    }
+
+    #[test]
+    fn debug_env_shows_child_command() {
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args(vec!["--nocapture", "--exact",
+                       "fork::test::fork_basically_works"])
+            .env(DEBUG_ENV, "1")
+            .output().unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let exe = cmdline::current_exe().unwrap();
+        assert!(stderr.contains(&format!(
+            "rusty-fork: test executable: {}\n", exe.display())),
+                "Unexpected output:\n{}", stderr);
+        assert!(stderr.contains(&format!(
+            "rusty-fork: child command: {:?}", exe)),
+                "Unexpected output:\n{}", stderr);
+        assert!(stderr.contains(
+            " \"--exact\" \"--\" \"fork::test::fork_basically_works\"\n"),
+                "Unexpected output:\n{}", stderr);
+    }
+
+    #[test]
+    fn child_runs_in_current_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        for &(cwd, expected) in &[
+            (dir.path(), dir.path()),
+            (Path::new("src"), Path::new(env!("CARGO_MANIFEST_DIR"))),
+        ] {
+            let expected = expected.canonicalize().unwrap();
+            let expected = if cwd.is_relative() {
+                expected.join(cwd)
+            } else {
+                expected
+            };
+            let output = ForkBuilder::new(
+                "fork::test::child_runs_in_current_dir", rusty_fork_id!())
+                .current_dir(cwd)
+                .env("FORK_EXPECTED_CWD", &expected)
+                .run(|child, file| {
+                    wait_for_child(child, file);
+                    let mut output = String::new();
+                    file.seek(io::SeekFrom::Start(0)).unwrap();
+                    file.read_to_string(&mut output).unwrap();
+                    output
+                }, || {
+                    assert_eq!(env::var_os("FORK_EXPECTED_CWD").unwrap(),
+                               env::current_dir().unwrap().canonicalize()
+                               .unwrap().into_os_string());
+                    println!("ran in the right place");
+                }).unwrap();
+            assert!(output.contains("ran in the right place"),
+                    "Unexpected output:\n{}", output);
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(env(FORK_ENV_INHERITED = "1"))]
+
+        #[test]
+        fn env_set_and_removed_in_child() {
+            ForkBuilder::new("fork::test::env_set_and_removed_in_child",
+                             rusty_fork_id!())
+                .env("FORK_ENV_SET", "a")
+                .envs(vec![("FORK_ENV_SET", "b"), ("FORK_ENV_OTHER", "c")])
+                .env_remove("FORK_ENV_INHERITED")
+                .run(wait_for_child, || {
+                    assert_eq!("b", env::var("FORK_ENV_SET").unwrap());
+                    assert_eq!("c", env::var("FORK_ENV_OTHER").unwrap());
+                    assert!(env::var_os("FORK_ENV_INHERITED").is_none());
+                }).unwrap();
+        }
+
+        #[test]
+        fn env_cleared_in_child() {
+            env::set_var("FORK_ENV_KEPT", "k");
+            ForkBuilder::new("fork::test::env_cleared_in_child",
+                             rusty_fork_id!())
+                .env_clear()
+                .env_inherit(vec!["FORK_ENV_KEPT", "FORK_ENV_NOT_SET"])
+                .env("FORK_ENV_SET", "s")
+                .run(wait_for_child, || {
+                    assert!(env::var_os("FORK_ENV_INHERITED").is_none());
+                    assert!(env::var_os("FORK_ENV_NOT_SET").is_none());
+                    assert_eq!("k", env::var("FORK_ENV_KEPT").unwrap());
+                    assert_eq!("s", env::var("FORK_ENV_SET").unwrap());
+                }).unwrap();
+        }
+
+        #[test]
+        fn env_isolated_in_child() {
+            env::set_var("FORK_ENV_ALLOWED", "a");
+            for &memory in &[false, true] {
+                let mut builder = ForkBuilder::new(
+                    "fork::test::env_isolated_in_child", rusty_fork_id!())
+                    .isolate_env(vec!["FORK_ENV_ALLOWED"]);
+                if memory {
+                    builder = builder.capture_in_memory(1024);
+                }
+                builder.run_captured(
+                    |child, _| assert!(child.wait().unwrap().success()),
+                    || {
+                        assert!(env::var_os("FORK_ENV_INHERITED").is_none());
+                        assert_eq!("a", env::var("FORK_ENV_ALLOWED").unwrap());
+                    }).unwrap();
+            }
+        }
+
+        #[test]
+        fn env_cannot_clobber_internal_vars() {
+            for &memory in &[false, true] {
+                let mut builder = ForkBuilder::new(
+                    "fork::test::env_cannot_clobber_internal_vars",
+                    rusty_fork_id!())
+                    .env(cmdline::OCCURS_ENV, "bogus")
+                    .env_remove(RAN_ENV)
+                    .env_remove(RAN_STREAM_ENV);
+                if memory {
+                    builder = builder.capture_in_memory(1024);
+                }
+                builder.run_captured(
+                    |child, _| assert!(child.wait().unwrap().success()),
+                    || ()).unwrap();
+            }
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(env(RUSTY_FORK_KEEP_OUTPUT = "1"))]
+
+        #[test]
+        fn failed_child_output_kept_from_memory() {
+            let err = ForkBuilder::new(
+                "fork::test::failed_child_output_kept_from_memory",
+                rusty_fork_id!())
+                .capture_in_memory(1024)
+                .run_captured(|child, _| { child.wait().unwrap(); }, || {
+                    println!("output worth keeping");
+                    process::exit(3);
+                }).unwrap_err();
+            let kept = match err {
+                Error::ChildFailed { ref kept_output, .. } =>
+                    kept_output.clone(),
+                ref e => panic!("Unexpected error: {}", e),
+            };
+            assert_eq!(1, kept.len());
+            let output = fs::read_to_string(&kept[0]).unwrap();
+            fs::remove_file(&kept[0]).unwrap();
+            assert!(output.contains("output worth keeping\n"),
+                    "Had unexpected output:\n{}", output);
+        }
+
+        #[test]
+        fn failed_child_output_kept_on_request() {
+            let err = fork(
+                "fork::test::failed_child_output_kept_on_request",
+                rusty_fork_id!(),
+                |_| (),
+                |child, _| { child.wait().unwrap(); },
+                || {
+                    println!("output worth keeping");
+                    process::exit(3);
+                }).unwrap_err();
+            let kept = match err {
+                Error::ChildFailed { ref kept_output, .. } =>
+                    kept_output.clone(),
+                ref e => panic!("Unexpected error: {}", e),
+            };
+            assert_eq!(1, kept.len());
+            assert!(err.to_string().contains(&*kept[0].to_string_lossy()),
+                    "Path missing from message: {}", err);
+
+            let output = fs::read_to_string(&kept[0]).unwrap();
+            fs::remove_file(&kept[0]).unwrap();
+            assert!(output.contains("output worth keeping\n"),
+                    "Had unexpected output:\n{}", output);
+        }
+    }
+
+    #[test]
+    fn failed_child_output_not_kept_by_default() {
+        let err = fork(
+            "fork::test::failed_child_output_not_kept_by_default",
+            rusty_fork_id!(),
+            |_| (),
+            |child, _| { child.wait().unwrap(); },
+            || process::exit(3)).unwrap_err();
+        match err {
+            Error::ChildFailed { ref kept_output, .. } =>
+                assert!(kept_output.is_empty()),
+            ref e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fork_with_opts_parent_args() {
+        let test_name = "fork::test::fork_with_opts_parent_args";
+        // As seen by a test run through a runner which appends its own
+        // arguments to the harness's
+        let output = fork_output(
+            test_name, rusty_fork_id!(),
+            ForkOpts::new().parent_args(vec![
+                "tests", "--include-ignored", "runner-arg"]),
+            || println!("child args: {}",
+                        env::args().skip(1).collect::<Vec<_>>().join(" ")))
+            .unwrap();
+        assert!(output.stdout().contains(&format!(
+            "child args: --include-ignored --quiet \
+             --test-threads 1 --nocapture --exact -- {}\n", test_name)),
+                "Unexpected output:\n{}", output.stdout());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fork_with_opts_wrapper() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("wrapper-args");
+        let test_name = "fork::test::fork_with_opts_wrapper";
+
+        let status = fork_with_opts(
+            test_name, rusty_fork_id!(),
+            ForkOpts::new().wrapper(PathBuf::from("sh"), vec![
+                OsString::from("-c"),
+                OsString::from("echo \"$@\" > \"$0\"; exec \"$@\""),
+                log.clone().into_os_string()]),
+            |child, _| child.wait().unwrap(),
+            || ()).unwrap();
+        assert!(status.success());
+
+        let logged = fs::read_to_string(&log).unwrap();
+        let exe = env::current_exe().unwrap();
+        assert!(logged.starts_with(&exe.display().to_string()),
+                "Unexpected wrapper arguments: {}", logged);
+        assert!(logged.trim_end().ends_with(&format!("-- {}", test_name)),
+                "Unexpected wrapper arguments: {}", logged);
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn child_launched_through_target_runner() {
+            env::set_var(cmdline::runner_env(env!("RUSTY_FORK_TARGET")),
+                         "fake-runner --flag");
+            let command = child_command(
+                "fork::test::child_launched_through_target_runner",
+                &ForkOpts::new()).unwrap();
+            assert_eq!("fake-runner", command.get_program());
+            let args = command.get_args().take(2).collect::<Vec<_>>();
+            assert_eq!(vec![OsStr::new("--flag"),
+                            env::current_exe().unwrap().as_os_str()],
+                       args);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn child_runs_under_fake_runner() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = tempfile::tempdir().unwrap();
+            let log = dir.path().join("runner-args");
+            let runner = dir.path().join("runner");
+            fs::write(&runner, format!(
+                "#!/bin/sh\necho \"$@\" > '{}'\nexec \"$@\"\n",
+                log.display())).unwrap();
+            fs::set_permissions(&runner, fs::Permissions::from_mode(0o755))
+                .unwrap();
+            env::set_var(cmdline::runner_env(env!("RUSTY_FORK_TARGET")),
+                         &runner);
+            let test_name = "fork::test::child_runs_under_fake_runner";
+
+            ForkBuilder::new(test_name, rusty_fork_id!())
+                .run(wait_for_child, || ()).unwrap();
+            let logged = fs::read_to_string(&log).unwrap();
+            assert!(logged.trim_end().ends_with(&format!("-- {}", test_name)),
+                    "Unexpected runner arguments: {}", logged);
+
+            // An explicit wrapper wins, as does disabling the wrapper
+            fs::remove_file(&log).unwrap();
+            fork_with_opts(test_name, rusty_fork_id!(),
+                           ForkOpts::new().wrapper("env", Vec::<&str>::new()),
+                           wait_for_child, || ()).unwrap();
+            assert!(!log.exists());
+            fork_with_opts(test_name, rusty_fork_id!(),
+                           ForkOpts::new().no_wrapper(),
+                           wait_for_child, || ()).unwrap();
+            assert!(!log.exists());
+        }
+    }
+
+    rusty_fork_test! {
+        // Forked so that no other thread can be spawning a process, and thus
+        // hold the copy open for writing, when it is executed
+        #[test]
+        fn child_runs_other_executable() {
+            let dir = tempfile::tempdir().unwrap();
+            let copy = dir.path().join("copy-of-test-exe");
+            fs::copy(env::current_exe().unwrap(), &copy).unwrap();
+            let test_name = "fork::test::child_runs_other_executable";
+
+            let in_child = || assert_eq!(
+                env::var_os("FORK_EXPECTED_EXE").unwrap(),
+                env::current_exe().unwrap().into_os_string());
+            ForkBuilder::new(test_name, rusty_fork_id!())
+                .child_executable(&copy)
+                .env("FORK_EXPECTED_EXE", &copy)
+                .run(wait_for_child, in_child).unwrap();
+
+            env::set_var("RUSTY_FORK_TEST_EXE", &copy);
+            ForkBuilder::new(test_name, rusty_fork_id!())
+                .env("FORK_EXPECTED_EXE", &copy)
+                .run(wait_for_child, in_child).unwrap();
+        }
+
+        #[test]
+        fn spawn_failure_names_program() {
+            let missing = Path::new("/nonexistent/rusty-fork-test-exe");
+            let err = ForkBuilder::new(
+                "fork::test::spawn_failure_names_program", rusty_fork_id!())
+                .child_executable(missing)
+                .run(wait_for_child, || ()).unwrap_err();
+            match err {
+                Error::SpawnFailed(ref program, _) =>
+                    assert_eq!(missing, program),
+                ref e => panic!("Unexpected error: {}", e),
+            }
+            assert!(err.to_string().contains(
+                "/nonexistent/rusty-fork-test-exe"), "{}", err);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn address_space_limited_in_child() {
+        use crate::fork_opts::Resource;
+
+        const LIMIT: u64 = 1 << 30;
+        let output = fork_output(
+            "fork::test::address_space_limited_in_child", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate)
+                .rlimit(Resource::AddressSpace, LIMIT, LIMIT),
+            || {
+                let buffer = Vec::<u8>::with_capacity(2 * LIMIT as usize);
+                println!("allocated {}", buffer.capacity());
+            }).unwrap();
+        assert!(!output.status().success());
+        assert!(output.stderr().contains("memory allocation of"),
+                "Unexpected output:\n{}", output.stderr());
+        assert!(!output.stdout().contains("allocated"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memory_limit_exceeded_in_child() {
+        const LIMIT: u64 = 1 << 30;
+        let err = fork_with_opts(
+            "fork::test::memory_limit_exceeded_in_child", rusty_fork_id!(),
+            ForkOpts::new().memory_limit(LIMIT),
+            |child, _| { child.wait().unwrap(); }, || {
+                let buffer = Vec::<u8>::with_capacity(2 * LIMIT as usize);
+                println!("allocated {}", buffer.capacity());
+            }).unwrap_err();
+        match err {
+            Error::MemoryLimitExceeded { limit, ref status, ref output, .. }
+            => {
+                assert_eq!(LIMIT, limit);
+                assert!(!status.success());
+                assert!(output.contains("memory allocation of"), "{}", output);
+                assert!(!output.contains("allocated"), "{}", output);
+            },
+            ref e => panic!("Unexpected error: {}", e),
+        }
+        assert!(err.to_string().starts_with(
+            "Child process exceeded its memory limit of 1073741824 bytes"),
+                "{}", err);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn other_failure_under_memory_limit_not_attributed_to_it() {
+        match fork_with_opts(
+            "fork::test::other_failure_under_memory_limit_not_attributed_to_it",
+            rusty_fork_id!(), ForkOpts::new().memory_limit(1 << 30),
+            |child, _| { child.wait().unwrap(); },
+            || panic!("not a memory problem")).unwrap_err()
+        {
+            Error::ChildFailed { ref output, .. } =>
+                assert!(output.contains("not a memory problem"), "{}", output),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_rlimit_names_resource() {
+        use crate::fork_opts::Resource;
+
+        let err = fork_with_opts(
+            "fork::test::invalid_rlimit_names_resource", rusty_fork_id!(),
+            ForkOpts::new().rlimit(Resource::OpenFiles, 64, 32),
+            wait_for_child, || ()).unwrap_err();
+        match err {
+            Error::ResourceLimit(resource, _) =>
+                assert_eq!("RLIMIT_NOFILE", resource),
+            ref e => panic!("Unexpected error: {}", e),
+        }
+        assert!(err.to_string().contains("RLIMIT_NOFILE"), "{}", err);
+    }
+
+    /// Return the nice value of the calling process.
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    fn nice() -> i32 {
+        // Safety: `getpriority()` has no preconditions.
+        unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn child_runs_at_priority() {
+        // The lowest priority, which anyone may lower their priority to
+        fork_with_opts(
+            "fork::test::child_runs_at_priority", rusty_fork_id!(),
+            ForkOpts::new().priority(Priority::Nice(19))
+                .strict_priority(true),
+            wait_for_child, || assert_eq!(19, nice())).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_priority_fails_if_strict() {
+        let err = fork_with_opts(
+            "fork::test::invalid_priority_fails_if_strict", rusty_fork_id!(),
+            ForkOpts::new().priority(Priority::Nice(100))
+                .strict_priority(true),
+            wait_for_child, || ()).unwrap_err();
+        match err {
+            Error::PriorityFailed(Priority::Nice(100), _) => (),
+            ref e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_priority_only_warns() {
+        fork_with_opts(
+            "fork::test::invalid_priority_only_warns", rusty_fork_id!(),
+            ForkOpts::new().priority(Priority::Nice(100))
+                .env("FORK_TEST_NICE", nice().to_string()),
+            wait_for_child,
+            || assert_eq!(env::var("FORK_TEST_NICE").unwrap(),
+                          nice().to_string())).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    #[test]
+    fn child_runs_with_credentials() {
+        // Safety: these functions have no preconditions.
+        let (uid, gid, root) = unsafe {
+            (libc::getuid(), libc::getgid(), 0 == libc::geteuid())
+        };
+        let mut opts = ForkOpts::new().uid(uid).gid(gid)
+            .env("FORK_TEST_IDS", format!("{} {}", uid, gid));
+        if root {
+            opts = opts.groups(&[gid]);
+        }
+        fork_with_opts(
+            "fork::test::child_runs_with_credentials", rusty_fork_id!(),
+            opts, wait_for_child,
+            move || {
+                // Safety: as above, and `getgroups()` writes nothing when
+                // asked for the number of groups.
+                let (uid, gid, groups) = unsafe {
+                    (libc::getuid(), libc::getgid(),
+                     libc::getgroups(0, ::std::ptr::null_mut()))
+                };
+                assert_eq!(env::var("FORK_TEST_IDS").unwrap(),
+                           format!("{} {}", uid, gid));
+                if root {
+                    assert_eq!(1, groups);
+                }
+            }).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    #[test]
+    fn unpermitted_credentials_rejected() {
+        // Safety: these functions have no preconditions.
+        let (uid, root) = unsafe { (libc::getuid(), 0 == libc::geteuid()) };
+        if root {
+            // Root may switch to any user
+            return;
+        }
+
+        let err = fork_with_opts(
+            "fork::test::unpermitted_credentials_rejected", rusty_fork_id!(),
+            ForkOpts::new().uid(uid + 1), wait_for_child, || ())
+            .unwrap_err();
+        match err {
+            Error::CredentialsFailed(ref credential, _) =>
+                assert_eq!(format!("user id {}", uid + 1), *credential),
+            ref e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrapper_error_told_apart_from_child_failure() {
+        let test_name =
+            "fork::test::wrapper_error_told_apart_from_child_failure";
+        // Like valgrind, fails after a successful child
+        let opts = || ForkOpts::new()
+            .wrapper("sh", vec!["-c", "\"$@\" && exit 99", "sh"])
+            .wrapper_error_code(99);
+
+        let err = fork_with_opts(
+            test_name, rusty_fork_id!(), opts(),
+            |child, _| { child.wait().unwrap(); },
+            || println!("leaked memory")).unwrap_err();
+        match err {
+            Error::WrapperFailed { code, ref output, .. } => {
+                assert_eq!(99, code);
+                assert!(output.contains("leaked memory"),
+                        "Unexpected output:\n{}", output);
+            },
+            ref e => panic!("Unexpected error: {}", e),
+        }
+
+        let err = fork_with_opts(
+            test_name, rusty_fork_id!(), opts(),
+            |child, _| { child.wait().unwrap(); },
+            || panic!("test failed")).unwrap_err();
+        match err {
+            Error::ChildFailed { .. } => (),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrapper_exit_status_is_child_status() {
+        let err = fork_with_opts(
+            "fork::test::wrapper_exit_status_is_child_status",
+            rusty_fork_id!(),
+            ForkOpts::new().wrapper("sh", vec!["-c", "exec \"$@\"", "sh"]),
+            |child, _| { child.wait().unwrap(); },
+            || process::exit(3)).unwrap_err();
+        match err {
+            Error::ChildFailed { status, .. } =>
+                assert_eq!(Some(3), status.code()),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fork_output_separates_streams() {
+        let output = fork_output(
+            "fork::test::fork_output_separates_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate),
+            || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            }).unwrap();
+        assert!(output.status().success());
+        assert!(output.stdout().contains("to stdout\n"));
+        assert!(!output.stdout().contains("to stderr"));
+        assert_eq!("to stderr\n", output.stderr());
+    }
+
+    #[test]
+    fn fork_output_empty_streams() {
+        let output = fork_output(
+            "fork::test::fork_output_empty_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate),
+            || ()).unwrap();
+        assert!(output.status().success());
+        assert_eq!("", output.stderr());
+
+        let output = fork_output(
+            "fork::test::fork_output_empty_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate),
+            || println!("only stdout")).unwrap();
+        assert_eq!("", output.stderr());
+
+        let output = fork_output(
+            "fork::test::fork_output_empty_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate)
+                .inherit_stdout(true),
+            || eprintln!("only stderr")).unwrap();
+        assert_eq!("", output.stdout());
+        assert_eq!("only stderr\n", output.stderr());
+    }
+
+    #[test]
+    fn fork_output_merged_by_default() {
+        let output = fork_output(
+            "fork::test::fork_output_merged_by_default", rusty_fork_id!(),
+            ForkOpts::new(),
+            || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            }).unwrap();
+        assert!(output.stdout().contains("to stdout\nto stderr\n"));
+        assert_eq!("", output.stderr());
+    }
+
+    #[test]
+    fn fork_capture_returns_output_of_passing_child() {
+        let (ran_parent, output) = fork_capture(
+            "fork::test::fork_capture_returns_output_of_passing_child",
+            rusty_fork_id!(),
+            ForkOpts::new().env("FORK_CAPTURE_GREETING", "hello"),
+            |_| true,
+            || {
+                println!("{} from child",
+                         env::var("FORK_CAPTURE_GREETING").unwrap());
+                eprintln!("to stderr");
+            }).unwrap();
+        assert!(ran_parent);
+        assert_eq!("hello from child\n", output);
+    }
+
+    #[test]
+    fn fork_capture_ignores_capture_mode() {
+        let ((), output) = fork_capture(
+            "fork::test::fork_capture_ignores_capture_mode", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Merged)
+                .capture_in_memory(1024),
+            |_| (),
+            || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            }).unwrap();
+        assert_eq!("to stdout\n", output);
+    }
+
+    #[test]
+    fn harness_preamble_stripped() {
+        assert_eq!("out\n", strip_harness_preamble("\nrunning 1 test\nout\n"));
+        assert_eq!("", strip_harness_preamble("running 1 test\n"));
+        assert_eq!("out\n", strip_harness_preamble("out\n"));
+        assert_eq!("\nout\nrunning 1 test\n",
+                   strip_harness_preamble("\nout\nrunning 1 test\n"));
+    }
+
+    #[test]
+    fn fork_capture_waits_for_child() {
+        let ((), output) = fork_capture(
+            "fork::test::fork_capture_waits_for_child", rusty_fork_id!(),
+            ForkOpts::new(), |_| (),
+            || {
+                sleep(100);
+                println!("finished");
+            }).unwrap();
+        assert_eq!("finished\n", output);
+    }
+
+    #[test]
+    fn fork_capture_of_failed_child() {
+        match fork_capture(
+            "fork::test::fork_capture_of_failed_child", rusty_fork_id!(),
+            ForkOpts::new(), |_| (),
+            || {
+                println!("failing");
+                process::exit(3);
+            })
+        {
+            Err(Error::ChildFailed { ref status, ref output, .. }) => {
+                assert_eq!(Some(3), status.code());
+                assert!(output.contains("failing"), "{}", output);
+            },
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn fork_output_of_failed_child() {
+        let output = fork_output(
+            "fork::test::fork_output_of_failed_child", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate),
+            || {
+                eprintln!("failing");
+                process::exit(3);
+            }).unwrap();
+        assert_eq!(Some(3), output.status().code());
+        assert_eq!("failing\n", output.stderr());
+    }
+
+    #[test]
+    fn memory_capture_separates_streams() {
+        let output = fork_output(
+            "fork::test::memory_capture_separates_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_mode(CaptureMode::Separate)
+                .capture_in_memory(1024),
+            || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            }).unwrap();
+        assert!(output.status().success());
+        assert!(output.stdout().contains("to stdout\n"));
+        assert!(!output.stdout().contains("to stderr"));
+        assert_eq!("to stderr\n", output.stderr());
+
+        let output = fork_output(
+            "fork::test::memory_capture_separates_streams", rusty_fork_id!(),
+            ForkOpts::new().capture_in_memory(1024),
+            || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            }).unwrap();
+        assert!(output.stdout().contains("to stdout\n"));
+        assert!(output.stdout().contains("to stderr\n"));
+        assert!(!output.stdout().contains('\x1e'), "{:?}", output.stdout());
+    }
+
+    #[test]
+    fn memory_capture_creates_no_temp_files() {
+        let prefix = temp_file_prefix(
+            "fork::test::memory_capture_creates_no_temp_files");
+        let temp_files = || fs::read_dir(env::temp_dir()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name()
+                    .to_string_lossy().starts_with(&prefix))
+            .count();
+
+        let output = ForkBuilder::new(
+            "fork::test::memory_capture_creates_no_temp_files",
+            rusty_fork_id!())
+            .capture_in_memory(1024)
+            .run_captured(|child, output| {
+                assert!(child.wait().unwrap().success());
+                assert_eq!(0, temp_files());
+                assert!(output.is_in_memory());
+                output.wait_drained();
+                let mut text = String::new();
+                output.read_to_string(&mut text).unwrap();
+                text
+            }, || println!("hello from child")).unwrap();
+        assert!(output.contains("hello from child\n"), "{}", output);
+    }
+
+    #[test]
+    fn memory_capture_detects_test_not_run() {
+        let err = ForkBuilder::new("fork::test::no_such_test", rusty_fork_id!())
+            .capture_in_memory(1024)
+            .run_captured(|child, _| { child.wait().unwrap(); }, || ())
+            .unwrap_err();
+        match err {
+            Error::TestNotRun(_) => (),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn memory_capture_of_failed_child() {
+        let err = ForkBuilder::new(
+            "fork::test::memory_capture_of_failed_child", rusty_fork_id!())
+            .capture_in_memory(1024)
+            .run_captured(|child, _| { child.wait().unwrap(); }, || {
+                eprintln!("failing");
+                process::exit(3);
+            }).unwrap_err();
+        match err {
+            Error::ChildFailed { ref status, ref output, .. } => {
+                assert_eq!(Some(3), status.code());
+                assert!(output.contains("failing\n"), "{:?}", output);
+                assert!(!output.contains('\x1e'), "{:?}", output);
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    fn read_stdin() -> Vec<u8> {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input).unwrap();
+        input
+    }
+
+    #[test]
+    fn child_reads_bytes_from_stdin() {
+        // More than fits into a pipe, so that it has to be fed as the child
+        // reads
+        let input = b"0123456789abcdef".repeat(64 * 1024);
+        let expected = input.clone();
+        ForkBuilder::new("fork::test::child_reads_bytes_from_stdin",
+                         rusty_fork_id!())
+            .stdin(StdinSource::Bytes(input))
+            .run(wait_for_child, || assert!(read_stdin() == expected))
+            .unwrap();
+    }
+
+    #[test]
+    fn child_reads_file_from_stdin() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"from a file\n").unwrap();
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+        ForkBuilder::new("fork::test::child_reads_file_from_stdin",
+                         rusty_fork_id!())
+            .stdin(StdinSource::File(file))
+            .run(wait_for_child,
+                 || assert_eq!(b"from a file\n".to_vec(), read_stdin()))
+            .unwrap();
+    }
+
+    #[test]
+    fn child_stdin_empty_by_default() {
+        fork("fork::test::child_stdin_empty_by_default", rusty_fork_id!(),
+             |_| (), wait_for_child,
+             || assert_eq!(Vec::<u8>::new(), read_stdin())).unwrap();
+    }
+
+    #[test]
+    fn child_reads_stdin_from_parent() {
+        ForkBuilder::new("fork::test::child_reads_stdin_from_parent",
+                         rusty_fork_id!())
+            .stdin(StdinSource::Piped)
+            .run(|child, _| {
+                let mut stdin = child.take_stdin().unwrap();
+                stdin.write_all(b"interactive\n").unwrap();
+                drop(stdin);
+                assert!(child.take_stdin().is_none());
+                assert!(child.wait().unwrap().success());
+            }, || assert_eq!(b"interactive\n".to_vec(), read_stdin()))
+            .unwrap();
+    }
+
+    #[test]
+    fn output_truncated_to_max() {
+        let print_lines = || {
+            for i in 0..10000 {
+                println!("line {}", i);
+            }
+            process::exit(3);
+        };
+
+        let err = fork_with_opts(
+            "fork::test::output_truncated_to_max", rusty_fork_id!(),
+            ForkOpts::new().max_output_bytes(1000),
+            |child, _| { child.wait().unwrap(); }, print_lines).unwrap_err();
+        let output = match err {
+            Error::ChildFailed { output, .. } => output,
+            e => panic!("Unexpected error: {}", e),
+        };
+        let (note, rest) = output.split_at(output.find('\n').unwrap() + 1);
+        assert!(note.starts_with("---- ") &&
+                note.ends_with(" earlier bytes of output omitted ----\n"),
+                "{:?}", note);
+        assert_eq!(1000, rest.len());
+        assert!(rest.ends_with("line 9999\n"), "{:?}", rest);
+
+        let output = fork_output(
+            "fork::test::output_truncated_to_max", rusty_fork_id!(),
+            ForkOpts::new().max_output_bytes(1000).capture_in_memory(100),
+            print_lines).unwrap();
+        assert!(output.stdout().contains(" earlier bytes of output omitted"));
+        assert!(output.stdout().ends_with("line 9999\n"));
+        assert!(output.stdout().len() < 1100, "{:?}", output.stdout());
+    }
+
+    #[test]
+    fn output_lines_streamed_while_running() {
+        use std::path::Path;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let dir = tempfile::tempdir().unwrap();
+        let go = dir.path().join("go");
+        let (sender, lines) = mpsc::channel();
+        let opts = ForkOpts::new()
+            .env("OUTPUT_LINE_GO", &go)
+            .on_output_line(move |_, line| {
+                let _ = sender.send(line.to_vec());
+            });
+        fork_with_opts(
+            "fork::test::output_lines_streamed_while_running",
+            rusty_fork_id!(), opts,
+            |child, _| {
+                // The child only goes on once we have seen the first line
+                while lines.recv_timeout(Duration::from_secs(30)).unwrap() !=
+                    b"first\n"
+                { }
+                fs::write(&go, "").unwrap();
+                assert!(child.wait().unwrap().success());
+                let rest = lines.try_iter().collect::<Vec<_>>();
+                assert!(rest.contains(&b"second\n".to_vec()), "{:?}", rest);
+            },
+            || {
+                println!("first");
+                let go = env::var_os("OUTPUT_LINE_GO").unwrap();
+                while !Path::new(&go).exists() {
+                    sleep(10);
+                }
+                println!("second");
+            }).unwrap();
+    }
+
+    #[test]
+    fn output_lines_keep_partial_lines_and_bytes() {
+        use std::sync::{Arc, Mutex};
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&lines);
+        let output = fork_output(
+            "fork::test::output_lines_keep_partial_lines_and_bytes",
+            rusty_fork_id!(),
+            ForkOpts::new()
+                .capture_mode(CaptureMode::Separate)
+                .capture_in_memory(1024)
+                .on_output_line(move |stream, line| {
+                    seen.lock().unwrap().push((stream, line.to_vec()));
+                }),
+            || {
+                eprintln!("to stderr");
+                let mut stdout = io::stdout();
+                stdout.write_all(b"\xffpartial").unwrap();
+                stdout.flush().unwrap();
+            }).unwrap();
+        assert!(output.stdout().ends_with("\u{fffd}partial"),
+                "{:?}", output.stdout());
+        assert_eq!("to stderr\n", output.stderr());
+
+        let lines = lines.lock().unwrap();
+        assert!(lines.contains(
+            &(OutputStream::Stdout, b"\xffpartial".to_vec())), "{:?}", lines);
+        assert!(lines.contains(
+            &(OutputStream::Stderr, b"to stderr\n".to_vec())), "{:?}", lines);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn memory_capture_drains_chatty_child() {
+        use std::time::Duration;
+
+        let line = "x".repeat(1023);
+        let (len, in_memory) = ForkBuilder::new(
+            "fork::test::memory_capture_drains_chatty_child", rusty_fork_id!())
+            .capture_in_memory(64 * 1024)
+            .timeout(Duration::from_secs(60))
+            .run_captured(|child, output| {
+                assert!(child.wait().unwrap().success());
+                output.wait_drained();
+                let mut text = Vec::new();
+                output.read_to_end(&mut text).unwrap();
+                (text.iter().filter(|&&b| b'x' == b).count(),
+                 output.is_in_memory())
+            }, || for _ in 0..4096 {
+                println!("{}", line);
+            }).unwrap();
+        assert_eq!(4096 * 1023, len);
+        assert!(!in_memory);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn fork_with_opts_timeout_kills_child() {
+        use std::time::Duration;
+
+        let err = fork_with_opts(
+            "fork::test::fork_with_opts_timeout_kills_child",
+            rusty_fork_id!(),
+            ForkOpts::new().timeout(Duration::from_millis(500)),
+            |child, _| {
+                assert!(child.deadline().is_some());
+                assert!(!child.wait().unwrap().success());
+                assert!(child.timed_out());
+            },
+            || sleep(10_000)).unwrap_err();
+        match err {
+            Error::Timeout { elapsed, ref test_name, .. } => {
+                assert!(elapsed >= Duration::from_millis(500) &&
+                        elapsed < Duration::from_millis(10_000),
+                        "Unexpected elapsed time: {:?}", elapsed);
+                assert_eq!("fork::test::fork_with_opts_timeout_kills_child",
+                           test_name);
+            },
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn on_timeout_sees_child_pid() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let observed = Arc::new(Mutex::new(None));
+        let observed_in_hook = observed.clone();
+        let mut pid = 0;
+        let err = fork_with_opts(
+            "fork::test::on_timeout_sees_child_pid",
+            rusty_fork_id!(),
+            ForkOpts::new().timeout(Duration::from_millis(500))
+                .on_timeout(move |pid| {
+                    *observed_in_hook.lock().unwrap() = Some(pid);
+                }),
+            |child, _| {
+                pid = child.id();
+                child.wait().unwrap();
+            },
+            || sleep(10_000)).unwrap_err();
+        match err {
+            Error::Timeout { .. } => (),
+            e => panic!("Unexpected error: {}", e),
+        }
+        assert_eq!(Some(pid), *observed.lock().unwrap());
+    }
+
+    #[cfg(all(feature = "timeout", unix))]
+    #[allow(unsafe_code)]
+    #[test]
+    fn timed_out_child_gets_grace_period() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        static TERMINATED: AtomicBool = AtomicBool::new(false);
+        extern "C" fn on_sigterm(_: libc::c_int) {
+            TERMINATED.store(true, Ordering::SeqCst);
+        }
+
+        let mut output = String::new();
+        let err = fork_with_opts(
+            "fork::test::timed_out_child_gets_grace_period",
+            rusty_fork_id!(),
+            ForkOpts::new()
+                .timeout(Duration::from_millis(500))
+                .grace_period(Duration::from_secs(10)),
+            |child, file| {
+                let status = child.wait().unwrap();
+                assert!(child.timed_out());
+                assert!(status.success());
+
+                file.seek(io::SeekFrom::Start(0)).unwrap();
+                file.read_to_string(&mut output).unwrap();
+            },
+            || {
+                unsafe {
+                    libc::signal(libc::SIGTERM,
+                                 on_sigterm as extern "C" fn(libc::c_int)
+                                 as libc::sighandler_t);
+                }
+                while !TERMINATED.load(Ordering::SeqCst) {
+                    sleep(10);
+                }
+                println!("cleaned up after SIGTERM");
+            }).unwrap_err();
+        match err {
+            Error::Timeout { .. } => (),
+            e => panic!("Unexpected error: {}", e),
+        }
+        assert!(output.contains("cleaned up after SIGTERM"),
+                "Had unexpected output:\n{}", output);
+    }
+
+    #[cfg(all(feature = "timeout", unix))]
+    #[allow(unsafe_code)]
+    #[test]
+    fn child_terminated_gracefully() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::Duration;
+
+        static TERMINATED: AtomicBool = AtomicBool::new(false);
+        extern "C" fn on_sigterm(_: libc::c_int) {
+            TERMINATED.store(true, Ordering::SeqCst);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let ready = dir.path().join("ready");
+        let mut output = String::new();
+        fork_with_opts(
+            "fork::test::child_terminated_gracefully", rusty_fork_id!(),
+            ForkOpts::new().env("FORK_READY_FILE", &ready),
+            |child, file| {
+                while !ready.exists() {
+                    sleep(10);
+                }
+                assert!(child.deadline().is_none());
+                let status = child.terminate_gracefully(
+                    Duration::from_secs(10)).unwrap();
+                assert!(status.success());
+                assert!(!child.timed_out());
+                // Already reaped
+                assert!(child.terminate_gracefully(Duration::from_secs(0))
+                        .unwrap().success());
+
+                file.seek(io::SeekFrom::Start(0)).unwrap();
+                file.read_to_string(&mut output).unwrap();
+            },
+            || {
+                unsafe {
+                    libc::signal(libc::SIGTERM,
+                                 on_sigterm as extern "C" fn(libc::c_int)
+                                 as libc::sighandler_t);
+                }
+                fs::write(env::var_os("FORK_READY_FILE").unwrap(), "")
+                    .unwrap();
+                while !TERMINATED.load(Ordering::SeqCst) {
+                    sleep(10);
+                }
+                println!("final line after SIGTERM");
+            }).unwrap();
+        assert!(output.contains("final line after SIGTERM"),
+                "Had unexpected output:\n{}", output);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn fork_with_opts_timeout_passes() {
+        use std::time::Duration;
+
+        let (status, timed_out) = fork_with_opts(
+            "fork::test::fork_with_opts_timeout_passes",
+            rusty_fork_id!(),
+            ForkOpts::new().timeout(Duration::from_millis(10_000)),
+            |child, _| (child.wait().unwrap(), child.timed_out()),
+            || ()).unwrap();
+        assert!(status.success());
+        assert!(!timed_out);
     }
 }
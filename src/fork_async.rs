@@ -0,0 +1,568 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, ChildStdin};
+use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::child_wrapper::ExitStatusWrapper;
+use crate::cmdline;
+use crate::error::*;
+use crate::fork::{self, RAN_ENV};
+use crate::fork_opts::{CaptureMode, ForkOpts, StdinSource, TimeoutHook};
+use crate::sys;
+
+/// Like [`fork_with_opts`](fn.fork_with_opts.html), but return a future
+/// which supervises the child without ever blocking the thread polling it.
+///
+/// This is for async tests, where the blocking waits of `ChildWrapper` would
+/// tie up a thread of the runtime for as long as the child runs. The child
+/// is spawned through `tokio::process`, and `in_parent` is an async closure
+/// which is passed an [`AsyncChildWrapper`](struct.AsyncChildWrapper.html)
+/// for the child, and whose output the future completes with. The future
+/// must be polled within a tokio runtime with the I/O and time drivers
+/// enabled, as `#[tokio::test]` provides.
+///
+/// ```
+/// use rusty_fork::{fork_async, rusty_fork_id, ForkOpts};
+///
+/// # /*
+/// #[tokio::test]
+/// # */
+/// async fn my_test() {
+///     let status = fork_async(
+///         "my_test", rusty_fork_id!(), ForkOpts::new(),
+///         |mut child| async move { child.wait().await.unwrap() },
+///         || println!("hello from child"))
+///         .await.unwrap();
+///     assert!(status.success());
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// In the child process, `in_child` is run right away, before this function
+/// returns, and the child exits once it does.
+///
+/// If `in_parent` completes without having waited for the child, the child is
+/// killed and reaped. Its exit status then says nothing about the test, so
+/// the future completes with the output of `in_parent` as long as the child
+/// got as far as running the test, and with `Error::TestNotRun` otherwise.
+/// Should the future be dropped before it completes, the child is killed,
+/// along with the processes it started if `ForkOpts::kill_process_tree()`
+/// was given.
+///
+/// The output of the child is always captured in temporary files, which are
+/// read asynchronously once the child has exited. `ForkOpts::on_output_line()`,
+/// `max_output_bytes()` and `capture_in_memory()` are not honoured.
+///
+/// This is only present if the "tokio" feature is enabled.
+///
+/// The same panics as for `fork` apply.
+pub fn fork_async<ID, PARENT, FUT, CHILD, R>(
+    test_name: &str,
+    fork_id: ID,
+    opts: ForkOpts,
+    in_parent: PARENT,
+    in_child: CHILD) -> impl Future<Output = Result<R>>
+where
+    ID : Hash,
+    PARENT : FnOnce (AsyncChildWrapper) -> FUT,
+    FUT : Future<Output = R>,
+    CHILD : FnOnce ()
+{
+    let fork_id = cmdline::id_str(fork_id);
+    if cmdline::is_child_of_id(&fork_id) {
+        let mut in_child = Some(in_child);
+        fork::run_in_child(&mut || in_child.take().unwrap()());
+    }
+
+    let test_name = test_name.to_owned();
+    async move { fork_parent(&test_name, fork_id, &opts, in_parent).await }
+}
+
+/// The parent side of `fork_async()`.
+async fn fork_parent<PARENT, FUT, R>(
+    test_name: &str, fork_id: String, opts: &ForkOpts, in_parent: PARENT)
+    -> Result<R>
+where
+    PARENT : FnOnce (AsyncChildWrapper) -> FUT,
+    FUT : Future<Output = R>,
+{
+    if cmdline::listing_requested() {
+        return Err(Error::Listing);
+    }
+
+    let occurs = cmdline::occurs_for_child_of_id(&fork_id);
+
+    #[cfg(feature = "sweep-temp-files")]
+    crate::sweep::sweep_once();
+
+    let temp_file = || tempfile::Builder::new()
+        .prefix(&fork::temp_file_prefix(test_name))
+        .tempfile();
+    let stdout_temp = temp_file()?;
+    let stderr_temp = match opts.capture_mode {
+        CaptureMode::Merged => None,
+        CaptureMode::Separate => Some(temp_file()?),
+    };
+    // Mark the files as in use so that a concurrent sweep leaves them be
+    #[cfg(feature = "sweep-temp-files")]
+    for temp in Some(&stdout_temp).into_iter().chain(stderr_temp.as_ref()) {
+        let _ = sys::lock_shared(temp.as_file());
+    }
+    let ran_marker = temp_file()?;
+    // The child opens this one itself, possibly as another user
+    #[cfg(unix)]
+    {
+        if opts.uid.is_some() || opts.gid.is_some() {
+            sys::fchown(ran_marker.as_file(), opts.uid, opts.gid)?;
+        }
+    }
+
+    let mut command = fork::child_command(test_name, opts)?;
+    let file = stdout_temp.as_file().try_clone()?;
+    command
+        .stdout(file.try_clone()?)
+        .stderr(match stderr_temp {
+            Some(ref temp) => temp.as_file().try_clone()?,
+            None => file,
+        })
+        .stdin(opts.stdin.stdio()?);
+    opts.configure_command(&mut command);
+    // After the options so that they can't get in the way
+    command
+        .env(cmdline::OCCURS_ENV, occurs)
+        .env(RAN_ENV, ran_marker.path());
+    if opts.kill_process_tree {
+        sys::ProcessTree::prepare(&mut command);
+    }
+    fork::prepare_spawn(&mut command, opts)?;
+
+    let program = command.get_program().to_owned();
+    // Should the future be dropped, `State` kills the child as it goes
+    let mut child = tokio::process::Command::from(command).spawn()
+        .map_err(|err| Error::SpawnFailed(program.into(), err))?;
+    let id = child.id().expect("child reaped before it was waited for");
+    if let StdinSource::Bytes(_) = *opts.stdin {
+        if let Some(mut stdin) = child.stdin.take() {
+            let source = Arc::clone(&opts.stdin);
+            tokio::spawn(async move {
+                if let StdinSource::Bytes(ref bytes) = *source {
+                    // The child is free not to read everything
+                    let _ = stdin.write_all(bytes).await;
+                }
+            });
+        }
+    }
+    let tree = if opts.kill_process_tree {
+        Some(sys::ProcessTree::attach_async(&child)?)
+    } else {
+        None
+    };
+
+    let spawned = Instant::now();
+    let state = Arc::new(Mutex::new(State {
+        child, id,
+        exit_status: None,
+        deadline: opts.timeout.map(|timeout| spawned + timeout),
+        grace_period: opts.grace_period,
+        spawned,
+        on_timeout: opts.on_timeout.clone(),
+        tree,
+        timed_out_after: None,
+    }));
+    let return_value = in_parent(AsyncChildWrapper {
+        id, state: Arc::clone(&state),
+    }).await;
+
+    let mut state = state.lock().await;
+    let waited = state.exit_status.is_some();
+    if !waited {
+        let _ = state.kill();
+        let status = state.child.wait().await?;
+        state.reaped(status);
+    }
+    // Whatever the child left running could otherwise keep going
+    // indefinitely
+    state.kill_tree();
+
+    // The child was killed if `in_parent` did not wait for it, so its status
+    // says nothing about the test
+    let status = if waited { state.exit_status } else { None };
+    let ran = 0 < tokio::fs::metadata(ran_marker.path()).await?.len();
+    let failed = state.timed_out_after.is_none() &&
+        status.map_or(false, |status| !status.success());
+    let (mut output, stderr) = echo_output(
+        &stdout_temp, stderr_temp.as_ref(), failed,
+        opts.label_output && stderr_temp.is_some()).await;
+    let files = || Some(stdout_temp).into_iter().chain(stderr_temp)
+        .collect();
+
+    if let Some(elapsed) = state.timed_out_after {
+        return Err(Error::Timeout {
+            elapsed,
+            test_name: test_name.to_owned(),
+            kept_output: fork::keep_output(files),
+        });
+    }
+
+    match status {
+        Some(status) if status.success() && !ran =>
+            Err(Error::TestNotRun(test_name.to_owned())),
+        None if !ran => Err(Error::TestNotRun(test_name.to_owned())),
+        Some(status) if !status.success() => {
+            output.extend_from_slice(&stderr);
+            let output = String::from_utf8_lossy(&output).into_owned();
+            Err(fork::child_failure(
+                opts, status, output, fork::keep_output(files)))
+        },
+        _ => Ok(return_value),
+    }
+}
+
+/// Copy the output of the child from `stdout` and `stderr`, if captured
+/// separately, to our own. If `collect` is true, the output is also
+/// returned, stdout first.
+async fn echo_output(stdout: &NamedTempFile, stderr: Option<&NamedTempFile>,
+                     collect: bool, label: bool) -> (Vec<u8>, Vec<u8>) {
+    let stdout = echo_file(stdout, collect, label, "stdout").await;
+    let stderr = match stderr {
+        Some(stderr) => echo_file(stderr, collect, label, "stderr").await,
+        None => Vec::new(),
+    };
+    (stdout, stderr)
+}
+
+/// Like `fork::echo_output()` for `file`, but if `label` is true and the
+/// child wrote anything to it, precede it with a header naming `stream`.
+async fn echo_file(file: &NamedTempFile, collect: bool, label: bool,
+                   stream: &str) -> Vec<u8> {
+    // As with the synchronous forks, output which cannot be read is lost
+    let output = tokio::fs::read(file.path()).await.unwrap_or_default();
+    if label && !output.is_empty() {
+        println!("---- child {} ----", stream);
+    }
+    fork::echo_output(&mut io::Cursor::new(output), collect)
+}
+
+/// The child process of [`fork_async`](fn.fork_async.html).
+///
+/// This is the counterpart of [`ChildWrapper`](struct.ChildWrapper.html) for
+/// async tests: the same timeout applies to its wait methods, but they are
+/// awaited instead of blocking the thread.
+///
+/// This is only present if the "tokio" feature is enabled.
+#[derive(Debug)]
+pub struct AsyncChildWrapper {
+    id: u32,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    child: Child,
+    id: u32,
+    exit_status: Option<ExitStatusWrapper>,
+    deadline: Option<Instant>,
+    grace_period: Duration,
+    spawned: Instant,
+    on_timeout: Option<TimeoutHook>,
+    /// The tree of processes rooted at the child, if it is tracked.
+    tree: Option<sys::ProcessTree>,
+    /// How long the child had been running when it was found to have
+    /// exceeded its deadline.
+    timed_out_after: Option<Duration>,
+}
+
+impl AsyncChildWrapper {
+    /// Returns the OS-assigned process identifier associated with this child.
+    ///
+    /// This succeeds even if the child has already been reaped. In this case,
+    /// the process id may reference no process at all or even an unrelated
+    /// process.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Take the writing end of the pipe to the child's standard input.
+    ///
+    /// This returns `None` unless the fork was configured with
+    /// `StdinSource::Piped`, or if the pipe has already been taken.
+    pub async fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.state.lock().await.child.stdin.take()
+    }
+
+    /// Waits for the child to exit completely, returning the status that it
+    /// exited with.
+    ///
+    /// This behaves like [`ChildWrapper::wait`][wait], including the timeout,
+    /// except that the deadline is enforced with `tokio::time::timeout`
+    /// rather than by blocking.
+    ///
+    /// [wait]: struct.ChildWrapper.html#method.wait
+    pub async fn wait(&mut self) -> io::Result<ExitStatusWrapper> {
+        self.state.lock().await.wait().await
+    }
+
+    /// Forces the child to exit. This is equivalent to sending a SIGKILL on
+    /// unix platforms.
+    ///
+    /// If the fork was configured with `ForkOpts::kill_process_tree()`, all
+    /// processes started by the child are killed as well.
+    ///
+    /// If the process has already been reaped by this handle, returns a
+    /// `NotFound` error.
+    pub async fn kill(&mut self) -> io::Result<()> {
+        self.state.lock().await.kill()
+    }
+
+    /// Asks the child to exit, forces it to exit if it is still running after
+    /// `grace`, and reaps it, returning the status it exited with.
+    ///
+    /// See [`ChildWrapper::terminate_gracefully`][terminate_gracefully].
+    ///
+    /// [terminate_gracefully]:
+    /// struct.ChildWrapper.html#method.terminate_gracefully
+    pub async fn terminate_gracefully(&mut self, grace: Duration)
+                                      -> io::Result<ExitStatusWrapper> {
+        self.state.lock().await.terminate_gracefully(grace).await
+    }
+
+    /// Returns whether the child was killed because it exceeded the timeout
+    /// configured for the fork.
+    ///
+    /// This only becomes `true` as a result of one of the wait methods on
+    /// this handle noticing the timeout has elapsed.
+    pub async fn timed_out(&self) -> bool {
+        self.state.lock().await.timed_out_after.is_some()
+    }
+}
+
+impl State {
+    /// Record that the child exited with `status`.
+    fn reaped(&mut self, status: process::ExitStatus) -> ExitStatusWrapper {
+        let status = ExitStatusWrapper::std(status);
+        self.exit_status = Some(status);
+        status
+    }
+
+    /// Kill whatever remains of the tree of processes rooted at the child,
+    /// if it is tracked.
+    fn kill_tree(&self) {
+        if let Some(ref tree) = self.tree {
+            let _ = tree.kill();
+        }
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        if self.exit_status.is_none() {
+            self.kill_tree();
+            self.child.start_kill()
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound,
+                               "Process already reaped"))
+        }
+    }
+
+    async fn wait(&mut self) -> io::Result<ExitStatusWrapper> {
+        if let Some(status) = self.exit_status {
+            return Ok(status);
+        }
+
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let status = time::timeout(remaining, self.child.wait()).await;
+            return match status {
+                Ok(status) => Ok(self.reaped(status?)),
+                Err(_) => self.expire().await,
+            };
+        }
+
+        let status = self.child.wait().await?;
+        Ok(self.reaped(status))
+    }
+
+    /// Terminate the child because it ran past its deadline and reap it.
+    async fn expire(&mut self) -> io::Result<ExitStatusWrapper> {
+        // The timer may fire marginally before the deadline; don't report the
+        // child as having run for less time than it was allowed.
+        let now = Instant::now();
+        let expired_at = match self.deadline {
+            Some(deadline) if deadline > now => deadline,
+            _ => now,
+        };
+        self.timed_out_after = Some(expired_at - self.spawned);
+
+        if let Some(ref on_timeout) = self.on_timeout {
+            on_timeout.call(self.id);
+        }
+
+        self.terminate_gracefully(self.grace_period).await
+    }
+
+    async fn terminate_gracefully(&mut self, grace: Duration)
+                                  -> io::Result<ExitStatusWrapper> {
+        if let Some(status) = self.exit_status {
+            return Ok(status);
+        }
+
+        // The child could exit before the signals below are sent, in which
+        // case sending them fails but the waits still succeed.
+        if grace > Duration::from_secs(0) {
+            let _ = match self.tree {
+                Some(ref tree) => tree.terminate(),
+                None => sys::terminate_async(&mut self.child),
+            };
+            if let Ok(status) = time::timeout(grace, self.child.wait()).await {
+                return Ok(self.reaped(status?));
+            }
+        }
+
+        self.kill_tree();
+        let _ = self.child.start_kill();
+        let status = self.child.wait().await?;
+        Ok(self.reaped(status))
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        // Only reached without the child having been reaped if the future of
+        // `fork_async()` is dropped; tokio reaps it in the background
+        let _ = self.kill();
+        self.kill_tree();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn fork_async_awaits_child() {
+        let status = fork_async(
+            "fork_async::test::fork_async_awaits_child",
+            rusty_fork_id!(), ForkOpts::new(),
+            |mut child| async move { child.wait().await.unwrap() },
+            || println!("hello from child")).await.unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn fork_async_reports_child_failure() {
+        let result = fork_async(
+            "fork_async::test::fork_async_reports_child_failure",
+            rusty_fork_id!(), ForkOpts::new(),
+            |mut child| async move { child.wait().await.unwrap(); },
+            || {
+                println!("about to fail");
+                process::exit(3);
+            }).await;
+        match result {
+            Err(Error::ChildFailed { status, output, .. }) => {
+                assert_eq!(Some(3), status.code());
+                assert!(output.contains("about to fail\n"),
+                        "Unexpected output:\n{}", output);
+            },
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_async_enforces_timeout() {
+        let result = fork_async(
+            "fork_async::test::fork_async_enforces_timeout",
+            rusty_fork_id!(),
+            ForkOpts::new().timeout(Duration::from_millis(100)),
+            |mut child| async move { child.wait().await.unwrap(); },
+            || thread::sleep(Duration::from_secs(30))).await;
+        match result {
+            Err(Error::Timeout { elapsed, .. }) =>
+                assert!(elapsed < Duration::from_secs(30),
+                        "Timed out after {:?}", elapsed),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_async_kills_child_not_waited_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let started = dir.path().join("started");
+        let start = Instant::now();
+        fork_async(
+            "fork_async::test::fork_async_kills_child_not_waited_for",
+            rusty_fork_id!(),
+            ForkOpts::new().env("FORK_ASYNC_STARTED", &started),
+            |_| async {
+                while !started.exists() {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            },
+            || {
+                std::fs::write(
+                    std::env::var_os("FORK_ASYNC_STARTED").unwrap(), "")
+                    .unwrap();
+                thread::sleep(Duration::from_secs(30));
+            }).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn fork_async_not_waited_for_reports_test_not_run() {
+        let result = fork_async(
+            "fork_async::test::no_such_test", rusty_fork_id!(),
+            ForkOpts::new(),
+            |_| time::sleep(Duration::from_millis(100)),
+            || ()).await;
+        match result {
+            Err(Error::TestNotRun(ref name)) =>
+                assert_eq!("fork_async::test::no_such_test", name),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_async_does_not_block_runtime() {
+        let test_name = "fork_async::test::fork_async_does_not_block_runtime";
+        let id = rusty_fork_id!();
+        let exited = &Mutex::new(Vec::new());
+        // `#[tokio::test]` polls both on the same thread, so the quick child
+        // can only be reaped first if waiting for the slow one does not block
+        let slow = fork_async(
+            test_name, (&id, "slow"), ForkOpts::new(),
+            |mut child| async move {
+                child.wait().await.unwrap();
+                exited.lock().unwrap().push("slow");
+            },
+            || thread::sleep(Duration::from_secs(1)));
+        let quick = fork_async(
+            test_name, (&id, "quick"), ForkOpts::new(),
+            |mut child| async move {
+                child.wait().await.unwrap();
+                exited.lock().unwrap().push("quick");
+            },
+            || ());
+        let (slow, quick) = tokio::join!(slow, quick);
+        slow.unwrap();
+        quick.unwrap();
+        assert_eq!(vec!["quick", "slow"], *exited.lock().unwrap());
+    }
+}
@@ -0,0 +1,430 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::Hash;
+use std::path::Path;
+use std::process;
+#[cfg(feature = "timeout")]
+use std::time::Duration;
+
+use crate::captured_output::CapturedOutput;
+use crate::child_wrapper::ChildWrapper;
+use crate::cmdline;
+use crate::error::Result;
+use crate::fork::{fork_impl, CollectOutput};
+use crate::fork_opts::{CaptureMode, ForkOpts, OutputStream, StdinSource};
+
+/// Configures and runs a simulated process fork.
+///
+/// This is the same as calling [`fork`](fn.fork.html) or
+/// [`fork_with_opts`](fn.fork_with_opts.html), but with each piece of
+/// configuration given by a method of its own rather than by position. With
+/// nothing but `test_name` and `fork_id` set, `run()` behaves exactly like
+/// `fork()` with a `process_modifier` which does nothing.
+///
+/// ```
+/// use std::time::Duration;
+/// use rusty_fork::{rusty_fork_id, ForkBuilder};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn my_test() {
+///     let builder = ForkBuilder::new("my_test", rusty_fork_id!())
+///         .env("MY_VAR", "1");
+///     # #[cfg(feature = "timeout")]
+///     let builder = builder.timeout(Duration::from_secs(10));
+///     let status = builder
+///         .modify_command(|cmd| { cmd.env("OTHER_VAR", "2"); })
+///         .run(|child, _| child.wait().unwrap(),
+///              || assert_eq!(Ok("1".to_owned()), std::env::var("MY_VAR")))
+///         .unwrap();
+///     assert!(status.success());
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+pub struct ForkBuilder<'a> {
+    test_name: String,
+    fork_id: String,
+    opts: ForkOpts,
+    modifiers: Vec<CommandModifier<'a>>,
+}
+
+type CommandModifier<'a> = Box<dyn FnMut (&mut process::Command) + 'a>;
+
+impl<'a> ForkBuilder<'a> {
+    /// Start configuring a fork of the test `test_name` at the fork point
+    /// identified by `fork_id`.
+    ///
+    /// See [`fork`](fn.fork.html) for what these need to be.
+    pub fn new<ID : Hash>(test_name: &str, fork_id: ID) -> Self {
+        ForkBuilder {
+            test_name: test_name.to_owned(),
+            fork_id: cmdline::id_str(fork_id),
+            opts: ForkOpts::new(),
+            modifiers: Vec::new(),
+        }
+    }
+
+    /// Replace all options set so far with `opts`.
+    ///
+    /// This is the way to set options of `ForkOpts` which have no method of
+    /// their own here. Command modifiers are kept.
+    pub fn opts(mut self, opts: ForkOpts) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Invoke `modifier` on the `std::process::Command` immediately before
+    /// spawning the child process.
+    ///
+    /// This is the `process_modifier` of [`fork`](fn.fork.html). Modifiers
+    /// are invoked in the order they were added, after all other options
+    /// have been applied.
+    pub fn modify_command<F>(mut self, modifier: F) -> Self
+    where F : FnOnce (&mut process::Command) + 'a {
+        let mut modifier = Some(modifier);
+        self.modifiers.push(Box::new(
+            move |cmd| modifier.take().expect("modifier invoked twice")(cmd)));
+        self
+    }
+
+    /// Like [`ForkOpts::timeout`](struct.ForkOpts.html#method.timeout).
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts = self.opts.timeout(timeout);
+        self
+    }
+
+    /// Like [`ForkOpts::env`](struct.ForkOpts.html#method.env).
+    pub fn env<K : AsRef<OsStr>, V : AsRef<OsStr>>(mut self, key: K, value: V)
+                                                  -> Self {
+        self.opts = self.opts.env(key, value);
+        self
+    }
+
+    /// Like [`ForkOpts::envs`](struct.ForkOpts.html#method.envs).
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I : IntoIterator<Item = (K, V)>,
+        K : AsRef<OsStr>,
+        V : AsRef<OsStr>,
+    {
+        self.opts = self.opts.envs(vars);
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::env_remove`](struct.ForkOpts.html#method.env_remove).
+    pub fn env_remove<K : AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.opts = self.opts.env_remove(key);
+        self
+    }
+
+    /// Like [`ForkOpts::env_clear`](struct.ForkOpts.html#method.env_clear).
+    pub fn env_clear(mut self) -> Self {
+        self.opts = self.opts.env_clear();
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::env_inherit`](struct.ForkOpts.html#method.env_inherit).
+    pub fn env_inherit<I, K>(mut self, keys: I) -> Self
+    where
+        I : IntoIterator<Item = K>,
+        K : AsRef<OsStr>,
+    {
+        self.opts = self.opts.env_inherit(keys);
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::isolate_env`](struct.ForkOpts.html#method.isolate_env).
+    pub fn isolate_env<I, K>(mut self, allowlist: I) -> Self
+    where
+        I : IntoIterator<Item = K>,
+        K : AsRef<OsStr>,
+    {
+        self.opts = self.opts.isolate_env(allowlist);
+        self
+    }
+
+    /// Like [`ForkOpts::child_executable`](
+    /// struct.ForkOpts.html#method.child_executable).
+    pub fn child_executable<P : AsRef<Path>>(mut self, path: P) -> Self {
+        self.opts = self.opts.child_executable(path);
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::current_dir`](struct.ForkOpts.html#method.current_dir).
+    pub fn current_dir<P : AsRef<Path>>(mut self, dir: P) -> Self {
+        self.opts = self.opts.current_dir(dir);
+        self
+    }
+
+    /// Like [`ForkOpts::stdin`](struct.ForkOpts.html#method.stdin).
+    pub fn stdin(mut self, source: StdinSource) -> Self {
+        self.opts = self.opts.stdin(source);
+        self
+    }
+
+    /// Like [`ForkOpts::inherit_stdout`][inherit_stdout].
+    ///
+    /// [inherit_stdout]: struct.ForkOpts.html#method.inherit_stdout
+    pub fn inherit_stdout(mut self, inherit: bool) -> Self {
+        self.opts = self.opts.inherit_stdout(inherit);
+        self
+    }
+
+    /// Like [`ForkOpts::inherit_stderr`][inherit_stderr].
+    ///
+    /// [inherit_stderr]: struct.ForkOpts.html#method.inherit_stderr
+    pub fn inherit_stderr(mut self, inherit: bool) -> Self {
+        self.opts = self.opts.inherit_stderr(inherit);
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::capture_mode`](struct.ForkOpts.html#method.capture_mode).
+    pub fn capture_mode(mut self, mode: CaptureMode) -> Self {
+        self.opts = self.opts.capture_mode(mode);
+        self
+    }
+
+    /// Like [`ForkOpts::on_output_line`][on_output_line].
+    ///
+    /// [on_output_line]: struct.ForkOpts.html#method.on_output_line
+    pub fn on_output_line<F>(mut self, f: F) -> Self
+    where F : FnMut (OutputStream, &[u8]) + Send + 'static {
+        self.opts = self.opts.on_output_line(f);
+        self
+    }
+
+    /// Like
+    /// [`ForkOpts::capture_in_memory`][capture_in_memory].
+    ///
+    /// This only takes effect with `run_captured()`.
+    ///
+    /// [capture_in_memory]: struct.ForkOpts.html#method.capture_in_memory
+    pub fn capture_in_memory(mut self, limit: usize) -> Self {
+        self.opts = self.opts.capture_in_memory(limit);
+        self
+    }
+
+    /// Perform the fork, invoking `in_parent` in the parent process and
+    /// `in_child` in the child process.
+    ///
+    /// This returns what [`fork`](fn.fork.html) would given the same
+    /// callbacks.
+    pub fn run<PARENT, CHILD, R>(mut self, in_parent: PARENT, in_child: CHILD)
+                                 -> Result<R>
+    where
+        PARENT : FnOnce (&mut ChildWrapper, &mut fs::File) -> R,
+        CHILD : FnOnce ()
+    {
+        // `in_parent` needs an actual file
+        self.opts.memory_capture_limit = None;
+        self.run_captured(
+            |child, output| in_parent(
+                child, output.as_file_mut().expect("output not in a file")),
+            in_child)
+    }
+
+    /// Like `run()`, but pass `in_parent` a `CapturedOutput` instead of a
+    /// file.
+    ///
+    /// This and [`fork_output`](fn.fork_output.html) are the only ways to
+    /// fork which honour [`ForkOpts::capture_in_memory`][capture_in_memory],
+    /// which avoids temporary files as long as the child does not write much.
+    ///
+    /// ```
+    /// use std::io::Read;
+    /// use rusty_fork::{rusty_fork_id, ForkBuilder};
+    ///
+    /// # /*
+    /// #[test]
+    /// # */
+    /// fn my_test() {
+    ///     let output = ForkBuilder::new("my_test", rusty_fork_id!())
+    ///         .capture_in_memory(64 * 1024)
+    ///         .run_captured(|child, output| {
+    ///             child.wait().unwrap();
+    ///             let mut text = String::new();
+    ///             output.read_to_string(&mut text).unwrap();
+    ///             text
+    ///         }, || println!("hello from child"))
+    ///         .unwrap();
+    ///     assert!(output.contains("hello from child"));
+    /// }
+    /// # fn main() { } // Can't actually run this without a test harness
+    /// ```
+    ///
+    /// [capture_in_memory]: struct.ForkOpts.html#method.capture_in_memory
+    pub fn run_captured<PARENT, CHILD, R>(
+        mut self, in_parent: PARENT, in_child: CHILD) -> Result<R>
+    where
+        PARENT : FnOnce (&mut ChildWrapper, &mut CapturedOutput) -> R,
+        CHILD : FnOnce ()
+    {
+        // Erase the generics so we don't instantiate the actual
+        // implementation for every single test
+        let mut return_value = None;
+        let mut in_parent = Some(in_parent);
+        let mut in_child = Some(in_child);
+        let modifiers = &mut self.modifiers;
+
+        fork_impl(&self.test_name, self.fork_id, &self.opts,
+                  CollectOutput::Never,
+                  &mut |cmd| for modifier in modifiers.iter_mut() {
+                      modifier(cmd);
+                  },
+                  &mut |child, output, _| return_value = Some(
+                      in_parent.take().unwrap()(child, output)),
+                  &mut || in_child.take().unwrap()())
+            .map(|_| return_value.unwrap())
+    }
+
+    /// Like `run()`, but capture the child's standard error separately from
+    /// its standard output, and pass `in_parent` the files receiving each,
+    /// standard output first.
+    ///
+    /// This implies `CaptureMode::Separate`, whatever the options say.
+    pub fn run_with_stderr<PARENT, CHILD, R>(
+        mut self, in_parent: PARENT, in_child: CHILD) -> Result<R>
+    where
+        PARENT : FnOnce (&mut ChildWrapper, &mut fs::File, &mut fs::File) -> R,
+        CHILD : FnOnce ()
+    {
+        let mut return_value = None;
+        let mut in_parent = Some(in_parent);
+        let mut in_child = Some(in_child);
+        let modifiers = &mut self.modifiers;
+        let mut opts = self.opts.capture_mode(CaptureMode::Separate);
+        opts.memory_capture_limit = None;
+
+        fork_impl(&self.test_name, self.fork_id, &opts, CollectOutput::Never,
+                  &mut |cmd| for modifier in modifiers.iter_mut() {
+                      modifier(cmd);
+                  },
+                  &mut |child, stdout, stderr| return_value = Some(
+                      in_parent.take().unwrap()(
+                          child,
+                          stdout.as_file_mut().expect("output not in a file"),
+                          stderr.and_then(CapturedOutput::as_file_mut)
+                              .expect("stderr not captured separately"))),
+                  &mut || in_child.take().unwrap()())
+            .map(|_| return_value.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::{self, Read, Seek};
+
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn defaults_match_fork() {
+        let status = ForkBuilder::new(
+            "fork_builder::test::defaults_match_fork", rusty_fork_id!())
+            .run(|child, _| child.wait().unwrap(),
+                 || println!("hello from child"))
+            .unwrap();
+        assert!(status.success());
+
+        match ForkBuilder::new(
+            "fork_builder::test::", rusty_fork_id!())
+            .run(|child, _| { child.wait().unwrap(); }, || ())
+        {
+            Err(Error::TestNotRun(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn modifiers_applied_in_order_after_options() {
+        let mut output = String::new();
+        ForkBuilder::new(
+            "fork_builder::test::modifiers_applied_in_order_after_options",
+            rusty_fork_id!())
+            .env("FORK_BUILDER_VAR", "from-env")
+            .modify_command(|cmd| { cmd.env("FORK_BUILDER_VAR", "first"); })
+            .modify_command(|cmd| {
+                cmd.env("FORK_BUILDER_VAR",
+                        "second").stdout(process::Stdio::piped());
+            })
+            .run(|child, _| {
+                child.inner_mut().stdout.as_mut().unwrap()
+                    .read_to_string(&mut output).unwrap();
+                assert!(child.wait().unwrap().success());
+            }, || print!("{}", env::var("FORK_BUILDER_VAR").unwrap()))
+            .unwrap();
+        assert!(output.ends_with("\nsecond"), "Unexpected output: {}", output);
+    }
+
+    #[test]
+    fn opts_replace_earlier_options() {
+        let status = ForkBuilder::new(
+            "fork_builder::test::opts_replace_earlier_options",
+            rusty_fork_id!())
+            .env("FORK_BUILDER_VAR", "dropped")
+            .opts(ForkOpts::new().env("FORK_BUILDER_OTHER", "kept"))
+            .run(|child, _| child.wait().unwrap(), || {
+                assert!(env::var_os("FORK_BUILDER_VAR").is_none());
+                assert_eq!("kept", env::var("FORK_BUILDER_OTHER").unwrap());
+            })
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn run_with_stderr_separates_streams() {
+        let (stdout, stderr) = ForkBuilder::new(
+            "fork_builder::test::run_with_stderr_separates_streams",
+            rusty_fork_id!())
+            .run_with_stderr(|child, stdout, stderr| {
+                assert!(child.wait().unwrap().success());
+                let mut out = (String::new(), String::new());
+                stdout.seek(io::SeekFrom::Start(0)).unwrap();
+                stderr.seek(io::SeekFrom::Start(0)).unwrap();
+                stdout.read_to_string(&mut out.0).unwrap();
+                stderr.read_to_string(&mut out.1).unwrap();
+                out
+            }, || {
+                println!("to stdout");
+                eprintln!("to stderr");
+            })
+            .unwrap();
+        assert!(stdout.contains("to stdout\n"), "stdout: {}", stdout);
+        assert!(!stdout.contains("to stderr"), "stdout: {}", stdout);
+        assert_eq!("to stderr\n", stderr);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn timeout_applies() {
+        let err = ForkBuilder::new(
+            "fork_builder::test::timeout_applies", rusty_fork_id!())
+            .timeout(Duration::from_millis(100))
+            .run(|child, _| { child.wait().unwrap(); },
+                 || ::std::thread::sleep(Duration::from_secs(10)))
+            .unwrap_err();
+        match err {
+            Error::Timeout { .. } => (),
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,1019 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "timeout")]
+use std::time::Duration;
+
+use crate::sys;
+
+/// How flags passed to the test process which rusty-fork does not recognise
+/// are handled when building the child's command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownFlagPolicy {
+    /// Fail with `Error::UnknownFlag`. This is the default.
+    ///
+    /// Unknown flags written in the `--flag=value` form are still dropped
+    /// with a warning, since it is clear that the following argument does
+    /// not belong to them.
+    Strict,
+    /// Drop the flag, print a warning to standard error, and carry on.
+    ///
+    /// The unknown flag is assumed not to take a separate argument. If the
+    /// flag did in fact take one, the argument will usually be interpreted
+    /// as a test filter, and therefore also be dropped.
+    StripAndWarn,
+}
+
+/// How the standard output and standard error of the child process are
+/// captured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Both streams are captured together, interleaved in the order they were
+    /// written. This is the default.
+    Merged,
+    /// Each stream is captured on its own.
+    ///
+    /// The `file` passed to `in_parent` then only receives standard output.
+    /// Both streams are available separately through
+    /// [`fork_output`](fn.fork_output.html) and
+    /// [`ForkBuilder::run_with_stderr`][run_with_stderr].
+    ///
+    /// [run_with_stderr]: struct.ForkBuilder.html#method.run_with_stderr
+    Separate,
+}
+
+/// A resource whose use by the child process can be limited with
+/// [`ForkOpts::rlimit`](struct.ForkOpts.html#method.rlimit).
+///
+/// This is only present on Unix.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// The size of the child's virtual address space, in bytes
+    /// (`RLIMIT_AS`). Allocations which would grow it beyond the limit fail.
+    AddressSpace,
+    /// The CPU time the child may use, in seconds (`RLIMIT_CPU`). The child
+    /// is sent `SIGXCPU` once it reaches the soft limit, and killed once it
+    /// reaches the hard limit.
+    CpuTime,
+    /// One more than the highest file descriptor the child may open
+    /// (`RLIMIT_NOFILE`).
+    OpenFiles,
+    /// The size of the largest core dump the child may produce, in bytes
+    /// (`RLIMIT_CORE`). A limit of 0 disables core dumps.
+    CoreFileSize,
+}
+
+#[cfg(unix)]
+impl Resource {
+    /// The name of the corresponding C constant, e.g. `RLIMIT_AS`.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Resource::AddressSpace => "RLIMIT_AS",
+            Resource::CpuTime => "RLIMIT_CPU",
+            Resource::OpenFiles => "RLIMIT_NOFILE",
+            Resource::CoreFileSize => "RLIMIT_CORE",
+        }
+    }
+}
+
+/// The scheduling priority of the child process, set with
+/// [`ForkOpts::priority`](struct.ForkOpts.html#method.priority).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Lower than normal: a nice value of 10 on Unix, and
+    /// `BELOW_NORMAL_PRIORITY_CLASS` on Windows.
+    BelowNormal,
+    /// Normal: a nice value of 0 on Unix, and `NORMAL_PRIORITY_CLASS` on
+    /// Windows.
+    Normal,
+    /// Higher than normal: a nice value of -10 on Unix, which usually takes
+    /// privileges to set, and `ABOVE_NORMAL_PRIORITY_CLASS` on Windows.
+    AboveNormal,
+    /// The given nice value on Unix, from -20 (the highest priority) to 19
+    /// (the lowest). On Windows, a negative value means `AboveNormal`, a
+    /// positive one `BelowNormal`, and 0 `Normal`.
+    Nice(i32),
+}
+
+impl Priority {
+    /// Return the nice value this priority corresponds to on Unix.
+    pub(crate) fn nice(self) -> i32 {
+        match self {
+            Priority::BelowNormal => 10,
+            Priority::Normal => 0,
+            Priority::AboveNormal => -10,
+            Priority::Nice(nice) => nice,
+        }
+    }
+}
+
+/// Where the standard input of the child process comes from.
+#[derive(Debug)]
+pub enum StdinSource {
+    /// The child's standard input is empty. This is the default.
+    Null,
+    /// The child reads the given bytes, followed by end-of-file.
+    ///
+    /// The bytes are written by a background thread in the parent, so there
+    /// may be more of them than fit into the pipe at once. Whatever the child
+    /// does not read is discarded.
+    Bytes(Vec<u8>),
+    /// The child reads from the given file, starting at its current
+    /// position.
+    ///
+    /// All children forked with the same options read from the same open
+    /// file, and so share its position.
+    File(fs::File),
+    /// The child reads from a pipe, the writing end of which `in_parent` can
+    /// take with [`ChildWrapper::take_stdin`][take_stdin].
+    ///
+    /// The child sees end-of-file once the writing end is dropped. If it is
+    /// never taken, the wait methods of `ChildWrapper` drop it before waiting.
+    ///
+    /// [take_stdin]: struct.ChildWrapper.html#method.take_stdin
+    Piped,
+}
+
+impl StdinSource {
+    pub(crate) fn stdio(&self) -> io::Result<Stdio> {
+        Ok(match *self {
+            StdinSource::Null => Stdio::null(),
+            StdinSource::Bytes(_) | StdinSource::Piped => Stdio::piped(),
+            StdinSource::File(ref file) => file.try_clone()?.into(),
+        })
+    }
+}
+
+/// One of the output streams of the child process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+type LineCallback = dyn FnMut (OutputStream, &[u8]) + Send;
+
+/// A callback invoked with each line the child writes.
+#[derive(Clone)]
+pub(crate) struct OutputHook(Arc<Mutex<LineCallback>>);
+
+impl OutputHook {
+    pub(crate) fn call(&self, stream: OutputStream, line: &[u8]) {
+        (self.0.lock().unwrap_or_else(|e| e.into_inner()))(stream, line)
+    }
+}
+
+impl fmt::Debug for OutputHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OutputHook")
+    }
+}
+
+/// A callback invoked with the process id of a child which exceeded its
+/// timeout, before the child is terminated.
+#[cfg(feature = "timeout")]
+#[derive(Clone)]
+pub(crate) struct TimeoutHook(Arc<dyn Fn (u32) + Send + Sync>);
+
+#[cfg(feature = "timeout")]
+impl TimeoutHook {
+    pub(crate) fn call(&self, pid: u32) {
+        (self.0)(pid)
+    }
+}
+
+#[cfg(feature = "timeout")]
+impl fmt::Debug for TimeoutHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TimeoutHook")
+    }
+}
+
+/// Options controlling how [`fork_with_opts`](fn.fork_with_opts.html) spawns
+/// and supervises the child process.
+///
+/// A default `ForkOpts` reproduces the behaviour of plain
+/// [`fork`](fn.fork.html). Options are set with chained builder calls:
+///
+/// ```
+/// # #[cfg(feature = "timeout")] {
+/// use std::time::Duration;
+/// use rusty_fork::ForkOpts;
+///
+/// let opts = ForkOpts::new()
+///     .timeout(Duration::from_secs(10))
+///     .env("RUST_BACKTRACE", "1")
+///     .inherit_stdout(true);
+/// # let _ = opts; }
+/// ```
+///
+/// Each option applies to one side of the fork:
+///
+/// - Options which configure the child process itself (`env`,
+///   `inherit_stdout`, `inherit_stderr`) are applied to the
+///   `std::process::Command` before it is spawned, and so take effect in the
+///   child.
+///
+/// - Options which govern supervision (`timeout`, `grace_period`,
+///   `on_timeout`) take effect
+///   in the parent, through the `ChildWrapper` passed to the `in_parent`
+///   callback.
+#[derive(Clone, Debug)]
+pub struct ForkOpts {
+    #[cfg(feature = "timeout")]
+    pub(crate) timeout: Option<Duration>,
+    #[cfg(feature = "timeout")]
+    pub(crate) grace_period: Duration,
+    #[cfg(feature = "timeout")]
+    pub(crate) on_timeout: Option<TimeoutHook>,
+    /// Variables to set, or with no value, to remove, in order.
+    env: Vec<(OsString, Option<OsString>)>,
+    clear_env: bool,
+    inherit_env: Vec<OsString>,
+    current_dir: Option<PathBuf>,
+    pub(crate) inherit_stdout: bool,
+    pub(crate) inherit_stderr: bool,
+    pub(crate) unknown_flag_policy: UnknownFlagPolicy,
+    pub(crate) raw_args: Option<Vec<OsString>>,
+    pub(crate) parent_args: Option<Vec<OsString>>,
+    pub(crate) extra_args: Vec<OsString>,
+    pub(crate) selection_args: Vec<OsString>,
+    pub(crate) wrapper: Option<Vec<OsString>>,
+    pub(crate) child_executable: Option<PathBuf>,
+    pub(crate) wrapper_error_code: Option<i32>,
+    pub(crate) capture_mode: CaptureMode,
+    pub(crate) memory_capture_limit: Option<usize>,
+    pub(crate) max_output_bytes: Option<usize>,
+    pub(crate) on_output_line: Option<OutputHook>,
+    pub(crate) stdin: Arc<StdinSource>,
+    pub(crate) label_output: bool,
+    pub(crate) preserve_color: bool,
+    pub(crate) kill_process_tree: bool,
+    #[cfg(unix)]
+    pub(crate) rlimits: Vec<(Resource, u64, u64)>,
+    pub(crate) priority: Option<Priority>,
+    pub(crate) strict_priority: bool,
+    #[cfg(unix)]
+    pub(crate) uid: Option<u32>,
+    #[cfg(unix)]
+    pub(crate) gid: Option<u32>,
+    #[cfg(unix)]
+    pub(crate) groups: Option<Vec<u32>>,
+    pub(crate) normalize_test_env: bool,
+}
+
+impl Default for ForkOpts {
+    fn default() -> Self {
+        ForkOpts {
+            #[cfg(feature = "timeout")]
+            timeout: None,
+            #[cfg(feature = "timeout")]
+            grace_period: Duration::from_secs(2),
+            #[cfg(feature = "timeout")]
+            on_timeout: None,
+            env: Vec::new(),
+            clear_env: false,
+            inherit_env: Vec::new(),
+            current_dir: None,
+            inherit_stdout: false,
+            inherit_stderr: false,
+            unknown_flag_policy: UnknownFlagPolicy::Strict,
+            raw_args: None,
+            parent_args: None,
+            extra_args: Vec::new(),
+            selection_args: Vec::new(),
+            wrapper: None,
+            child_executable: None,
+            wrapper_error_code: None,
+            capture_mode: CaptureMode::Merged,
+            memory_capture_limit: None,
+            max_output_bytes: None,
+            on_output_line: None,
+            stdin: Arc::new(StdinSource::Null),
+            label_output: false,
+            preserve_color: true,
+            kill_process_tree: false,
+            #[cfg(unix)]
+            rlimits: Vec::new(),
+            priority: None,
+            strict_priority: false,
+            #[cfg(unix)]
+            uid: None,
+            #[cfg(unix)]
+            gid: None,
+            #[cfg(unix)]
+            groups: None,
+            normalize_test_env: true,
+        }
+    }
+}
+
+impl ForkOpts {
+    /// Create a new `ForkOpts` with all options at their defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum time the child process may run (parent side).
+    ///
+    /// Once the timeout has elapsed, the wait methods on the `ChildWrapper`
+    /// terminate the child instead of continuing to wait for it, and
+    /// `ChildWrapper::timed_out()` starts returning `true`. The terminated
+    /// child's exit status is returned as usual.
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long a child which exceeded the timeout is given to exit on its
+    /// own before it is killed forcibly (parent side).
+    ///
+    /// When the timeout elapses, the child is first sent `SIGTERM` so that it
+    /// has an opportunity to flush output, write coverage data, and so forth.
+    /// If it is still running after the grace period, it is sent `SIGKILL`.
+    /// The default is 2 seconds. A grace period of zero kills the child
+    /// immediately.
+    ///
+    /// On Windows, there is no way to politely ask a single process to exit,
+    /// so the child is always terminated immediately.
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Set a callback to be invoked when the child exceeds the timeout
+    /// (parent side).
+    ///
+    /// The callback is passed the process id of the child, and is called
+    /// while the child is still running, right before it is terminated. This
+    /// makes it possible to e.g. capture a core dump or stack trace of a hung
+    /// test with an external tool. The grace period only starts once the
+    /// callback returns. By default, there is no callback.
+    ///
+    /// This is only present if the "timeout" feature is enabled.
+    #[cfg(feature = "timeout")]
+    pub fn on_timeout<F : Fn (u32) + Send + Sync + 'static>(mut self, f: F)
+                                                         -> Self {
+        self.on_timeout = Some(TimeoutHook(Arc::new(f)));
+        self
+    }
+
+    /// Set an environment variable in the child process (child side).
+    ///
+    /// If the same variable is set or removed more than once, the last call
+    /// wins. Variables which rusty-fork uses internally to coordinate with
+    /// the child, such as `RUSTY_FORK_OCCURS`, are set regardless.
+    pub fn env<K : AsRef<OsStr>, V : AsRef<OsStr>>(mut self, key: K, value: V)
+                                                  -> Self {
+        self.env.push((key.as_ref().to_owned(),
+                       Some(value.as_ref().to_owned())));
+        self
+    }
+
+    /// Set each of the environment variables in `vars` in the child process
+    /// (child side).
+    ///
+    /// This is the same as calling `env()` for each of them in turn.
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I : IntoIterator<Item = (K, V)>,
+        K : AsRef<OsStr>,
+        V : AsRef<OsStr>,
+    {
+        for (key, value) in vars {
+            self = self.env(key, value);
+        }
+        self
+    }
+
+    /// Remove an environment variable from the child process (child side).
+    ///
+    /// The child otherwise inherits all environment variables of the parent,
+    /// apart from those removed by `normalize_test_env`. Like `env()`, this
+    /// does not affect the variables rusty-fork uses internally.
+    pub fn env_remove<K : AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Start the child process from an empty environment instead of a copy of
+    /// the parent's (child side).
+    ///
+    /// Only variables passed to `env_inherit()` are then taken over from the
+    /// parent, along with the variables rusty-fork needs itself. Those are
+    /// the ones it uses to coordinate with the child (`RUSTY_FORK_OCCURS`,
+    /// which tells the child which test it is to run, `RUSTY_FORK_RAN_MARKER`
+    /// and `RUSTY_FORK_RAN_STREAM`, `RUSTY_FORK_RETURN_FD` for
+    /// `fork_returning()`, and `CLICOLOR_FORCE` when colored output is
+    /// preserved), and the few the platform needs for the child to start
+    /// at all: `LD_LIBRARY_PATH` on
+    /// most Unix systems, `DYLD_LIBRARY_PATH` and
+    /// `DYLD_FALLBACK_LIBRARY_PATH` on macOS, and `SystemRoot` and `PATH` on
+    /// Windows, where many programs fail in obscure ways without them.
+    /// Variables set with `env()` are set on top of the empty environment.
+    ///
+    /// A `wrapper` program runs with the same environment as the child, and
+    /// so must not depend on any other variables either. Neither do the
+    /// `RUSTY_FORK_*` variables configuring rusty-fork reach the child, so a
+    /// child which forks again needs them passed to `env_inherit()`.
+    pub fn env_clear(mut self) -> Self {
+        self.clear_env = true;
+        self
+    }
+
+    /// Take over the variables named by `keys` from the environment of the
+    /// parent when `env_clear()` is in effect (child side).
+    ///
+    /// Variables not set in the parent are left unset. This has no effect
+    /// without `env_clear()`, since the child then inherits every variable
+    /// anyway.
+    pub fn env_inherit<I, K>(mut self, keys: I) -> Self
+    where
+        I : IntoIterator<Item = K>,
+        K : AsRef<OsStr>,
+    {
+        self.inherit_env.extend(
+            keys.into_iter().map(|key| key.as_ref().to_owned()));
+        self
+    }
+
+    /// Start the child process with only the variables named by `allowlist`
+    /// taken over from the environment of the parent (child side).
+    ///
+    /// This is a shorthand for `env_clear().env_inherit(allowlist)`; see
+    /// `env_clear()` for the variables which are kept regardless.
+    pub fn isolate_env<I, K>(self, allowlist: I) -> Self
+    where
+        I : IntoIterator<Item = K>,
+        K : AsRef<OsStr>,
+    {
+        self.env_clear().env_inherit(allowlist)
+    }
+
+    /// Run the child process in the directory `dir` (child side).
+    ///
+    /// A relative `dir` is relative to the current directory of the parent at
+    /// the time the child is spawned. By default, the child inherits the
+    /// current directory of the parent. The files capturing the output of the
+    /// child are not affected by `dir`.
+    pub fn current_dir<P : AsRef<Path>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Set where the child's standard input comes from (child side).
+    ///
+    /// See [`StdinSource`](enum.StdinSource.html). By default, the child's
+    /// standard input is empty. This has no effect on `ForkPool`, whose
+    /// workers receive their cases through standard input.
+    pub fn stdin(mut self, source: StdinSource) -> Self {
+        self.stdin = Arc::new(source);
+        self
+    }
+
+    /// Control whether the child's standard output is inherited from the
+    /// parent process rather than captured (child side).
+    ///
+    /// By default, the child's output is captured into a temporary file and
+    /// later copied to the parent's standard output, which integrates it with
+    /// the test harness's output capturing. With this option set, whatever the
+    /// child writes to standard output goes directly to wherever the parent's
+    /// standard output goes, as it is written. Standard error is still
+    /// captured.
+    pub fn inherit_stdout(mut self, inherit: bool) -> Self {
+        self.inherit_stdout = inherit;
+        self
+    }
+
+    /// Control whether the child's standard error is inherited from the
+    /// parent process rather than captured (child side).
+    ///
+    /// This is the standard error counterpart of
+    /// [`inherit_stdout`](#method.inherit_stdout).
+    pub fn inherit_stderr(mut self, inherit: bool) -> Self {
+        self.inherit_stderr = inherit;
+        self
+    }
+
+    /// Control what happens when the parent process was passed a flag which
+    /// rusty-fork does not know how to handle (parent side).
+    ///
+    /// The default, `UnknownFlagPolicy::Strict`, fails the fork with
+    /// `Error::UnknownFlag`. Setting the environment variable
+    /// `RUSTY_FORK_ALLOW_UNKNOWN_FLAGS` to `1` selects
+    /// `UnknownFlagPolicy::StripAndWarn` regardless of this option.
+    pub fn unknown_flag_policy(mut self, policy: UnknownFlagPolicy) -> Self {
+        self.unknown_flag_policy = policy;
+        self
+    }
+
+    /// Give the child process exactly the arguments in `args` (child side).
+    ///
+    /// Normally, the child's arguments are derived from those of the current
+    /// process, which are assumed to be for the standard test harness: flags
+    /// which do not make sense for the child are removed, and filters are
+    /// replaced so that only the test named by `test_name` runs. This is not
+    /// appropriate for programs which do their own argument parsing, such as
+    /// `harness = false` test targets, which can instead use this option to
+    /// take full control of the child's command line. The arguments of the
+    /// current process are then not examined at all, and
+    /// `RUSTY_FORK_EXTRA_ARGS` is not consulted.
+    ///
+    /// The child process must still arrive at the same `fork()` call, with
+    /// the same `fork_id`, for `in_child` to be run.
+    pub fn raw_args<I, S>(mut self, args: I) -> Self
+    where
+        I : IntoIterator<Item = S>,
+        S : AsRef<OsStr>,
+    {
+        self.raw_args = Some(
+            args.into_iter().map(|arg| arg.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Derive the child's command line from `args` instead of the command
+    /// line of the current process (parent side).
+    ///
+    /// `args` is a full command line of the test harness, starting with the
+    /// path to the executable. This is for runners which launch the test
+    /// executable in a way that leaves unrelated arguments in its command
+    /// line, which rusty-fork would otherwise take for test filters. (A
+    /// runner whose arguments come before the path to the executable is
+    /// detected automatically.) `args` is filtered like the real command
+    /// line, so `RUSTY_FORK_EXTRA_ARGS` and `extra_args` still apply.
+    ///
+    /// This has no effect together with `raw_args`.
+    pub fn parent_args<I, S>(mut self, args: I) -> Self
+    where
+        I : IntoIterator<Item = S>,
+        S : AsRef<OsStr>,
+    {
+        self.parent_args = Some(
+            args.into_iter().map(|arg| arg.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Pass the additional arguments in `args` to the child process (child
+    /// side).
+    ///
+    /// The arguments are added to the child's command line in addition to
+    /// those derived from the current process and `RUSTY_FORK_EXTRA_ARGS`,
+    /// after the latter. They are placed before the `--` which precedes the
+    /// name of the test to run, so that the test harness treats flags among
+    /// them as flags. Flags which rusty-fork always passes to the child
+    /// (`--quiet`, `--test-threads`, `--nocapture`, `--exact`) are left out,
+    /// along with their values, since the harness does not accept them
+    /// twice.
+    ///
+    /// Calling this more than once adds to the arguments. This has no effect
+    /// together with `raw_args`.
+    pub fn extra_args<I, S>(mut self, args: I) -> Self
+    where
+        I : IntoIterator<Item = S>,
+        S : AsRef<OsStr>,
+    {
+        self.extra_args.extend(
+            args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Select tests for the child process with `args` in addition to the
+    /// test name passed to `fork()` (child side).
+    ///
+    /// This is for harnesses whose test names are only known at runtime,
+    /// which may need additional filters or flags such as `--ignored` for
+    /// the child to run the right test. Flags among `args` are placed before
+    /// the `--` preceding the test name, like those from `extra_args`, and
+    /// must be flags the test harness knows about (see
+    /// `cmdline::known_flags()`); an unknown flag makes the fork fail with
+    /// `Error::UnknownFlag` regardless of `unknown_flag_policy`. The other
+    /// arguments, and everything after a `--` among `args`, are added as
+    /// filters after the test name, which the child matches exactly like the
+    /// test name.
+    ///
+    /// Calling this more than once adds to the arguments. This has no effect
+    /// together with `raw_args`.
+    pub fn selection_args<I, S>(mut self, args: I) -> Self
+    where
+        I : IntoIterator<Item = S>,
+        S : AsRef<OsStr>,
+    {
+        self.selection_args.extend(
+            args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Launch the child process through a wrapper command, such as
+    /// `valgrind` or `qemu-aarch64` (child side).
+    ///
+    /// The child is then started as `program`, followed by `args`, the path
+    /// to the test executable and the child's arguments. The
+    /// wrapper must exit with the status of the child, or at least
+    /// unsuccessfully if the child failed (e.g. with valgrind's
+    /// `--error-exitcode`), since that is how the child's result is
+    /// determined.
+    ///
+    /// If this is not set, the wrapper is taken from the
+    /// `RUSTY_FORK_WRAPPER` environment variable, which is split into words
+    /// like a shell would. If that is not set either, the runner Cargo uses
+    /// for the target the tests were built for is used, as given by the
+    /// `CARGO_TARGET_<triple>_RUNNER` environment variable, so that children
+    /// of cross-compiled tests can run too. A runner configured only in
+    /// `.cargo/config.toml` is not visible to the tests, and can be passed on
+    /// in `RUSTY_FORK_RUNNER` instead, which is also split like a shell
+    /// would and takes precedence over the one from Cargo. An empty
+    /// `RUSTY_FORK_WRAPPER` disables the wrapper entirely, as does
+    /// `no_wrapper()`.
+    pub fn wrapper<P, I, S>(mut self, program: P, args: I) -> Self
+    where
+        P : AsRef<OsStr>,
+        I : IntoIterator<Item = S>,
+        S : AsRef<OsStr>,
+    {
+        let mut wrapper = vec![program.as_ref().to_owned()];
+        wrapper.extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self.wrapper = Some(wrapper);
+        self
+    }
+
+    /// Launch the child process without any wrapper command, even if one is
+    /// set in `RUSTY_FORK_WRAPPER` or as the runner of the target (child
+    /// side).
+    ///
+    /// This undoes any earlier call to `wrapper()`.
+    pub fn no_wrapper(mut self) -> Self {
+        self.wrapper = Some(Vec::new());
+        self
+    }
+
+    /// Set the exit code with which the wrapper command reports an error it
+    /// detected itself, such as the one given to valgrind's
+    /// `--error-exitcode` (parent side).
+    ///
+    /// A child which exits with `code` is then reported as
+    /// `Error::WrapperFailed` rather than `Error::ChildFailed`, so that e.g. a
+    /// memory error is told apart from a failed test. `code` should be one
+    /// the test itself never exits with; a test which panics normally exits
+    /// with 101. By default, every unsuccessful exit is reported as
+    /// `Error::ChildFailed`.
+    pub fn wrapper_error_code(mut self, code: i32) -> Self {
+        self.wrapper_error_code = Some(code);
+        self
+    }
+
+    /// Run `path` as the test executable in the child process, instead of
+    /// the current executable (child side).
+    ///
+    /// This is for setups where the running binary is not the real test
+    /// executable, or where the path it was started from may disappear.
+    /// `path` must be a test executable containing the same tests. If this is
+    /// not set, the `RUSTY_FORK_TEST_EXE` environment variable is used if it
+    /// is set, and the current executable otherwise.
+    pub fn child_executable<P : AsRef<Path>>(mut self, path: P) -> Self {
+        self.child_executable = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Control whether the child's standard output and standard error are
+    /// captured together or separately (child side).
+    ///
+    /// See [`CaptureMode`](enum.CaptureMode.html).
+    pub fn capture_mode(mut self, mode: CaptureMode) -> Self {
+        self.capture_mode = mode;
+        self
+    }
+
+    /// Capture the child's output in memory rather than in temporary files
+    /// (parent side).
+    ///
+    /// The child's standard output and standard error are then connected to
+    /// pipes, which threads in the parent drain as the child writes to them,
+    /// so no temporary file is created for a child which writes little. Once
+    /// more than `limit` bytes have been captured from a stream (from both
+    /// streams together with `CaptureMode::Merged`), what was captured so far
+    /// moves to a temporary file which receives the rest, as it would
+    /// without this option.
+    ///
+    /// This only applies where `in_parent` is not passed a `std::fs::File`:
+    /// to [`fork_output`](fn.fork_output.html) and
+    /// [`ForkBuilder::run_captured`][run_captured]. Everything else always
+    /// captures the output in temporary files. With `CaptureMode::Merged`,
+    /// lines written to the two streams are interleaved in the order the
+    /// parent reads them, which is not necessarily the order the child wrote
+    /// them in. A process modifier must not change where the child's output
+    /// goes.
+    ///
+    /// [run_captured]: struct.ForkBuilder.html#method.run_captured
+    pub fn capture_in_memory(mut self, limit: usize) -> Self {
+        self.memory_capture_limit = Some(limit);
+        self
+    }
+
+    /// Limit how much of the child's output is kept (parent side).
+    ///
+    /// Once the child has written more than `max` bytes to a stream (to both
+    /// streams together with `CaptureMode::Merged`), the earliest output is
+    /// thrown away, keeping the end, which usually says the most about what
+    /// went wrong. The captured output then begins with a line saying how
+    /// many bytes were left out when it is copied to the parent's standard
+    /// output, and so in `Error::ChildFailed` and `ChildOutput`. This keeps a
+    /// child which writes endlessly from filling up the disk or memory.
+    ///
+    /// The output passes through the parent on its way to be captured to
+    /// enforce the limit. While the child is running, up to about twice
+    /// `max` bytes may be kept, and the file passed to `in_parent` may
+    /// shrink. By default, there is no limit.
+    pub fn max_output_bytes(mut self, max: usize) -> Self {
+        self.max_output_bytes = Some(max);
+        self
+    }
+
+    /// Set a callback to be invoked with each line the child writes, while
+    /// the child is running (parent side).
+    ///
+    /// The callback is passed the stream the line was written to and the
+    /// line itself, including its line feed, exactly as the child wrote it,
+    /// so it may not be valid UTF-8. A final line without a line feed is
+    /// passed on once the child closes the stream. Lines written to an
+    /// inherited stream are not seen. This is in addition to the normal
+    /// capture of the output, which is unaffected.
+    ///
+    /// The callback is invoked on a background thread of the parent, which
+    /// reads the output from a pipe; with `CaptureMode::Merged`, it may be
+    /// invoked for each stream in turn in an order other than the one the
+    /// child wrote the lines in. Everything the child wrote has been passed
+    /// to the callback by the time one of the wait methods on the
+    /// `ChildWrapper` reports that the child has exited. By default, there
+    /// is no callback.
+    ///
+    /// ```
+    /// use rusty_fork::{fork_with_opts, rusty_fork_id, ForkOpts};
+    ///
+    /// # /*
+    /// #[test]
+    /// # */
+    /// fn my_test() {
+    ///     let opts = ForkOpts::new().on_output_line(|stream, line| {
+    ///         eprint!("{:?}: {}", stream, String::from_utf8_lossy(line));
+    ///     });
+    ///     fork_with_opts("my_test", rusty_fork_id!(), opts,
+    ///                    |child, _| { child.wait().unwrap(); },
+    ///                    || println!("progress")).unwrap();
+    /// }
+    /// # fn main() { } // Can't actually run this without a test harness
+    /// ```
+    pub fn on_output_line<F>(mut self, f: F) -> Self
+    where F : FnMut (OutputStream, &[u8]) + Send + 'static {
+        self.on_output_line = Some(OutputHook(Arc::new(Mutex::new(f))));
+        self
+    }
+
+    /// Control whether each of the child's output streams is preceded by a
+    /// header naming it when they are copied to the parent's standard output
+    /// (parent side).
+    ///
+    /// This only applies with `CaptureMode::Separate`, where standard output
+    /// and standard error would otherwise just be printed one after the
+    /// other. A stream the child wrote nothing to gets no header. This is
+    /// off by default; `rusty_fork_test!` turns it on.
+    pub fn label_output(mut self, label: bool) -> Self {
+        self.label_output = label;
+        self
+    }
+
+    /// Control whether the child is made to produce colored output when the
+    /// parent's standard output is a terminal (child side).
+    ///
+    /// Since the child's output is captured into a file, it would normally
+    /// turn colors off, even though the output is eventually copied to a
+    /// terminal. This option, which is on by default, instead passes
+    /// `--color=always` and sets `CLICOLOR_FORCE=1` in the child, unless the
+    /// parent was passed `--color=never` or `NO_COLOR` is set. It has no
+    /// effect together with `raw_args`, or on platforms other than Unix.
+    ///
+    /// Turn this off if the output is consumed by something which cannot
+    /// handle escape sequences.
+    pub fn preserve_color(mut self, preserve: bool) -> Self {
+        self.preserve_color = preserve;
+        self
+    }
+
+    /// Control whether processes started by the child are killed together
+    /// with it (both sides).
+    ///
+    /// Normally, only the child itself is killed when it exceeds its timeout
+    /// or the fork is abandoned, and anything it started keeps running. With
+    /// this option set, the child is put in a process group of its own on
+    /// Unix, or a job object on Windows, and once the fork is over, whatever
+    /// is left in the group is killed, whether or not the child finished on
+    /// its own.
+    ///
+    /// On Unix, the child is then no longer in the foreground process group
+    /// of the terminal, so e.g. pressing Ctrl-C only interrupts the parent. On
+    /// Windows, processes the child starts in the moment between it being
+    /// spawned and it being assigned to the job object escape the job.
+    pub fn kill_process_tree(mut self, kill: bool) -> Self {
+        self.kill_process_tree = kill;
+        self
+    }
+
+    /// Limit the use of `resource` by the child to `soft`, which the child
+    /// may raise itself as far as `hard` (child side).
+    ///
+    /// The limit is set with `setrlimit()` right before the test executable
+    /// is started, so it already applies while the test harness starts up.
+    /// A limit of `u64::MAX` means no limit (`RLIM_INFINITY`). If the same
+    /// resource is limited more than once, the last limit wins.
+    ///
+    /// If the limit cannot be set, e.g. because `soft` exceeds `hard` or
+    /// `hard` exceeds the hard limit of the parent, spawning the child fails
+    /// with `Error::ResourceLimit`.
+    ///
+    /// Note that a limit on the address space also limits the harness and any
+    /// wrapper the child is run under, and that the threads of the harness
+    /// reserve a good deal of address space without using it.
+    ///
+    /// This is only present on Unix.
+    #[cfg(unix)]
+    pub fn rlimit(mut self, resource: Resource, soft: u64, hard: u64)
+                  -> Self {
+        self.rlimits.retain(|&(limited, _, _)| limited != resource);
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Limit the address space of the child to `bytes` (child side), so that
+    /// trying to allocate more memory than that fails.
+    ///
+    /// This is the same as `rlimit(Resource::AddressSpace, bytes, bytes)`,
+    /// and the notes there apply. If the child fails after an allocation was
+    /// refused, the fork fails with `Error::MemoryLimitExceeded` rather than
+    /// `Error::ChildFailed`, whichever way the limit was set.
+    ///
+    /// This is only present on Linux, since not all Unix systems enforce
+    /// this limit.
+    #[cfg(target_os = "linux")]
+    pub fn memory_limit(self, bytes: u64) -> Self {
+        self.rlimit(Resource::AddressSpace, bytes, bytes)
+    }
+
+    /// Run the child at the scheduling priority `priority` (child side).
+    ///
+    /// On Unix, the nice value of the child is set with `setpriority()`
+    /// right before the test executable is started. On Windows, the child is
+    /// created with the corresponding priority class, which replaces any
+    /// other creation flags set through the `process_modifier` of `fork()`.
+    ///
+    /// If the priority is invalid, or the parent is not permitted to set it
+    /// (e.g. raising it without privileges on Unix), a warning is printed to
+    /// standard error and the child runs at the priority it would have had
+    /// otherwise, unless `strict_priority()` is set.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Control whether a priority set with `priority()` which cannot be
+    /// applied makes spawning the child fail with `Error::PriorityFailed`,
+    /// rather than only produce a warning (child side).
+    pub fn strict_priority(mut self, strict: bool) -> Self {
+        self.strict_priority = strict;
+        self
+    }
+
+    /// Run the child as the user `uid` (child side).
+    ///
+    /// Together with `gid()` and `groups()`, this makes it possible to run a
+    /// test as an unprivileged user from a test process running as root. The
+    /// supplementary groups are set first, then the group id, and the user id
+    /// last, right before the test executable is started. Unlike
+    /// `std::os::unix::process::CommandExt::uid()`, this leaves the
+    /// supplementary groups alone unless `groups()` is set too.
+    ///
+    /// If the parent is not permitted to switch to `uid`, spawning the child
+    /// fails with `Error::CredentialsFailed`. The files capturing the child's
+    /// output are opened before the switch, so the child can write to them
+    /// whatever user it runs as. The test executable, on the other hand, must
+    /// be accessible to `uid`.
+    ///
+    /// This is only present on Unix.
+    #[cfg(unix)]
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Run the child with the group id `gid` (child side).
+    ///
+    /// See `uid()`. This is only present on Unix.
+    #[cfg(unix)]
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Run the child with exactly the supplementary groups `groups` (child
+    /// side).
+    ///
+    /// See `uid()`. Setting the supplementary groups requires privileges,
+    /// even if they do not change. This is only present on Unix.
+    #[cfg(unix)]
+    pub fn groups(mut self, groups: &[u32]) -> Self {
+        self.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Control whether the environment variables by which the test harness
+    /// can be configured instead of with flags are removed from the child's
+    /// environment (child side).
+    ///
+    /// These are `RUST_TEST_THREADS`, `RUST_TEST_NOCAPTURE`,
+    /// `RUST_TEST_SHUFFLE` and `RUST_TEST_SHUFFLE_SEED`. The child runs a
+    /// single test with flags which take care of the first two, and the
+    /// others do not apply to it, so by default none of them are passed on.
+    /// (The parent still takes `RUST_TEST_NOCAPTURE` into account: tests
+    /// defined with `rusty_fork_test!` stream the child's output when it is
+    /// set.) Variables set with `env()` or by the `process_modifier` of
+    /// `fork()` are kept either way. This has no effect together with
+    /// `raw_args`.
+    pub fn normalize_test_env(mut self, normalize: bool) -> Self {
+        self.normalize_test_env = normalize;
+        self
+    }
+
+    /// Clear the environment of the freshly created `command` if
+    /// `env_clear()` is in effect, keeping only the inherited variables.
+    pub(crate) fn clear_command_env(&self, command: &mut Command) {
+        if !self.clear_env {
+            return;
+        }
+
+        command.env_clear();
+        let keys = sys::STARTUP_ENV.iter().map(OsStr::new)
+            .chain(self.inherit_env.iter().map(OsString::as_os_str));
+        for key in keys {
+            if let Some(value) = env::var_os(key) {
+                command.env(key, value);
+            }
+        }
+    }
+
+    pub(crate) fn configure_command(&self, command: &mut Command) {
+        for (key, value) in &self.env {
+            match *value {
+                Some(ref value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(ref dir) = self.current_dir {
+            // Resolved here since it is platform-specific whether a relative
+            // program path would otherwise be taken relative to `dir`
+            let dir = env::current_dir().map(|cwd| cwd.join(dir))
+                .unwrap_or_else(|_| dir.clone());
+            command.current_dir(dir);
+        }
+
+        #[cfg(unix)]
+        {
+            if !self.rlimits.is_empty() {
+                sys::set_rlimits(command, &self.rlimits);
+            }
+            if self.uid.is_some() || self.gid.is_some() ||
+                self.groups.is_some()
+            {
+                sys::set_credentials(command, self.uid, self.gid,
+                                     self.groups.clone());
+            }
+        }
+
+        if self.inherit_stdout {
+            command.stdout(Stdio::inherit());
+        }
+        if self.inherit_stderr {
+            command.stderr(Stdio::inherit());
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+use crate::child_wrapper::ExitStatusWrapper;
+use crate::error::Error;
+use crate::fork::SKIP_EXIT_CODE;
+
+/// How a child process run by [`try_fork`](fn.try_fork.html) ended.
+///
+/// Unlike the errors returned by the other fork functions, all of these are
+/// normal outcomes of running a test, which the caller can report however it
+/// sees fit.
+#[derive(Debug)]
+pub enum ForkOutcome<T> {
+    /// The child exited successfully. The value is what `in_parent`
+    /// returned.
+    Passed(T),
+    /// The child exited unsuccessfully with the exit code in `status`.
+    ///
+    /// `output` is everything the child wrote to its captured standard
+    /// output and standard error, lossily converted to UTF-8.
+    #[allow(missing_docs)]
+    Failed { status: ExitStatusWrapper, output: String },
+    /// The child was killed because it exceeded the timeout set with
+    /// `ForkOpts::timeout()`, after running for `elapsed`.
+    #[allow(missing_docs)]
+    TimedOut { elapsed: Duration },
+    /// The child was terminated by the Unix signal `signal` (e.g. `SIGABRT`
+    /// if it aborted), other than for exceeding its timeout.
+    ///
+    /// `output` is as for `Failed`. This never happens on other platforms.
+    #[allow(missing_docs)]
+    Signaled { signal: i32, output: String },
+    /// The child skipped the test by calling [`skip`](fn.skip.html), or
+    /// otherwise exiting with `SKIP_EXIT_CODE`.
+    ///
+    /// `output` is as for `Failed`.
+    #[allow(missing_docs)]
+    Skipped { output: String },
+}
+
+impl<T> ForkOutcome<T> {
+    /// Return the outcome corresponding to `err`, or `err` itself if it
+    /// does not describe how a child ended.
+    pub(crate) fn from_error(err: Error) -> Result<Self, Error> {
+        match err {
+            Error::ChildFailed { status, output, .. } =>
+                Ok(match (status.unix_signal(), status.code()) {
+                    (Some(signal), _) =>
+                        ForkOutcome::Signaled { signal, output },
+                    (None, Some(SKIP_EXIT_CODE)) =>
+                        ForkOutcome::Skipped { output },
+                    (None, _) => ForkOutcome::Failed { status, output },
+                }),
+            Error::Timeout { elapsed, .. } =>
+                Ok(ForkOutcome::TimedOut { elapsed }),
+            err => Err(err),
+        }
+    }
+}
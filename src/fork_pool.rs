@@ -0,0 +1,606 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::any::Any;
+use std::fs;
+use std::hash::Hash;
+use std::io::{self, BufRead, Write};
+use std::panic;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::cmdline;
+use crate::error::*;
+use crate::fork::{child_command, echo_output, spawn};
+use crate::fork_opts::ForkOpts;
+
+/// Start of the lines by which a worker reports to the parent.
+///
+/// It begins with an ASCII record separator so that it does not turn up in
+/// the output of cases by accident.
+const MARKER: &str = "\u{1e}rusty-fork-pool:";
+
+/// Runs many cases of a data-driven test in a small pool of long-lived child
+/// processes, rather than forking a fresh process for each case.
+///
+/// Like [`fork`](fn.fork.html), this works by running the test named
+/// `test_name` again in each child, which must arrive at the same
+/// `ForkPool::new()` call and then make the same sequence of `run_case()`
+/// calls. The parent hands the id of each case to an idle worker over its
+/// standard input; the worker skips the cases it was not given and runs the
+/// one it was, reporting whether it passed back to the parent. Failed cases
+/// are collected and returned by `finish()` once every case has run.
+///
+/// ## Isolation
+///
+/// Since one worker runs many cases, the cases are only isolated from the
+/// parent and from cases running in other workers, not from one another.
+/// Anything a case leaves behind in global state (statics, thread-locals,
+/// the environment, the current directory, leaked threads) is seen by the
+/// cases later run by the same worker, and which cases those are depends on
+/// timing. Cases relying on a pristine process need `fork()` instead.
+///
+/// A case which panics fails without affecting the worker. A case which kills
+/// its worker (e.g. by aborting or calling `process::exit()`) fails with the
+/// worker's exit status, and a new worker is started for later cases.
+///
+/// The sequence of calls to `run_case()` must be the same in every process,
+/// and case ids must be unique within it and must not contain line breaks.
+/// The output of cases is forwarded to the parent's standard output as it
+/// arrives, so the output of concurrent cases may be interleaved.
+///
+/// The timeout options of `ForkOpts` and `ForkOpts::kill_process_tree()` are
+/// not applied to pooled workers.
+///
+/// ```
+/// use rusty_fork::{rusty_fork_id, rusty_fork_test_name, ForkPool};
+///
+/// # /*
+/// #[test]
+/// # */
+/// fn squares_are_non_negative() {
+///     let mut pool = ForkPool::new(
+///         rusty_fork_test_name!(squares_are_non_negative),
+///         rusty_fork_id!(), 4).unwrap();
+///     for n in -100i32..100 {
+///         pool.run_case(&n.to_string(), || assert!(n * n >= 0)).unwrap();
+///     }
+///     pool.finish().unwrap();
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+#[derive(Debug)]
+pub struct ForkPool {
+    role: Role,
+}
+
+#[derive(Debug)]
+enum Role {
+    Parent(Box<Parent>),
+    Worker(Worker),
+}
+
+impl ForkPool {
+    /// Create a pool of at most `workers` child processes for the test
+    /// named `test_name`.
+    ///
+    /// `test_name` and `fork_id` have the same meaning as for
+    /// [`fork`](fn.fork.html). Workers are started as cases are handed out,
+    /// so no more processes are started than there are cases.
+    ///
+    /// In a worker, this does not return until the parent has handed it a
+    /// case, and exits the process if it never does.
+    ///
+    /// If the test process was passed `--list`, `Error::Listing` is returned.
+    pub fn new<ID : Hash>(test_name: &str, fork_id: ID, workers: usize)
+                          -> Result<Self> {
+        Self::with_opts(test_name, fork_id, workers, ForkOpts::new())
+    }
+
+    /// Like `new()`, but configure the workers with `opts`.
+    pub fn with_opts<ID : Hash>(test_name: &str, fork_id: ID, workers: usize,
+                                opts: ForkOpts) -> Result<Self> {
+        let fork_id = cmdline::id_str(fork_id);
+        let role = if cmdline::is_child_of_id(&fork_id) {
+            Role::Worker(Worker::start())
+        } else if cmdline::listing_requested() {
+            return Err(Error::Listing);
+        } else {
+            Role::Parent(Box::new(Parent::new(
+                test_name, &fork_id, opts,
+                if 0 == workers { 1 } else { workers })))
+        };
+        Ok(ForkPool { role })
+    }
+
+    /// Run the case identified by `id`, which runs `case`, in one of the
+    /// workers.
+    ///
+    /// In the parent, this returns once an idle worker has been given the
+    /// case, without waiting for it to finish; whether it passed is only
+    /// known after `finish()`. It fails if a worker cannot be started, or
+    /// with `Error::TestNotRun` if a worker exited without reaching the
+    /// pool.
+    ///
+    /// In a worker, `case` is run if it is the case the parent handed to it,
+    /// and otherwise skipped. When the parent has no further cases for the
+    /// worker, the process exits.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `id` contains a line break.
+    pub fn run_case<F : FnOnce ()>(&mut self, id: &str, case: F)
+                                   -> Result<()> {
+        assert!(!id.contains(&['\n', '\r'][..]),
+                "rusty-fork: case id {:?} contains a line break", id);
+
+        match self.role {
+            Role::Parent(ref mut parent) => parent.run_case(id),
+            Role::Worker(ref mut worker) => {
+                worker.run_case(id, case);
+                Ok(())
+            },
+        }
+    }
+
+    /// Wait for all cases to finish and shut the workers down.
+    ///
+    /// Returns `Error::CasesFailed` listing the failed cases, if any.
+    ///
+    /// A worker does not return from this; the process exits once the parent
+    /// has no more cases for it.
+    pub fn finish(mut self) -> Result<()> {
+        match self.role {
+            Role::Parent(ref mut parent) => parent.finish(),
+            Role::Worker(ref mut worker) => worker.finish(),
+        }
+    }
+}
+
+/// A message from a worker, as read from its standard output.
+#[derive(Debug)]
+enum Report {
+    /// Output of the case being run, to be passed on.
+    Output(Vec<u8>),
+    /// The worker has reached the pool.
+    Ready,
+    /// The case with the given id passed.
+    Passed(String),
+    /// The case with the given id failed with the given message.
+    Failed(String, String),
+    /// The worker closed its standard output, most likely by exiting.
+    Exited,
+}
+
+#[derive(Debug)]
+struct PooledChild {
+    child: process::Child,
+    stdin: Option<process::ChildStdin>,
+    stderr: fs::File,
+    ready: bool,
+    /// The id of the case the worker is running, if any.
+    case: Option<String>,
+}
+
+#[derive(Debug)]
+struct Parent {
+    test_name: String,
+    occurs: String,
+    opts: ForkOpts,
+    max_workers: usize,
+    /// Indexed by worker number; `None` once the worker has exited.
+    workers: Vec<Option<PooledChild>>,
+    sender: mpsc::Sender<(usize, Report)>,
+    reports: mpsc::Receiver<(usize, Report)>,
+    failures: Vec<(String, String)>,
+}
+
+impl Parent {
+    fn new(test_name: &str, fork_id: &str, opts: ForkOpts,
+           max_workers: usize) -> Self {
+        let (sender, reports) = mpsc::channel();
+        Parent {
+            test_name: test_name.to_owned(),
+            occurs: cmdline::occurs_for_child_of_id(fork_id),
+            opts,
+            max_workers,
+            workers: Vec::new(),
+            sender,
+            reports,
+            failures: Vec::new(),
+        }
+    }
+
+    fn run_case(&mut self, id: &str) -> Result<()> {
+        let ix = self.idle_worker()?;
+        let worker = self.workers[ix].as_mut().expect("idle worker gone");
+        worker.case = Some(id.to_owned());
+        // If the worker is gone, we find out from its reports
+        if let Some(ref mut stdin) = worker.stdin {
+            let _ = writeln!(stdin, "{}", id).and_then(|_| stdin.flush());
+        }
+        Ok(())
+    }
+
+    /// Return the index of a worker not running any case, starting one or
+    /// waiting for one to finish its case as needed.
+    fn idle_worker(&mut self) -> Result<usize> {
+        loop {
+            let idle = self.workers.iter().position(|worker| match *worker {
+                Some(ref worker) => worker.case.is_none(),
+                None => false,
+            });
+            if let Some(ix) = idle {
+                return Ok(ix);
+            }
+
+            if self.live_workers() < self.max_workers {
+                self.spawn()?;
+            } else {
+                self.process_report()?;
+            }
+        }
+    }
+
+    fn live_workers(&self) -> usize {
+        self.workers.iter().filter(|worker| worker.is_some()).count()
+    }
+
+    fn spawn(&mut self) -> Result<()> {
+        let stderr = tempfile::tempfile()?;
+        let mut command = child_command(&self.test_name, &self.opts)?;
+        command.stderr(stderr.try_clone()?);
+        self.opts.configure_command(&mut command);
+        command
+            .env(cmdline::OCCURS_ENV, &self.occurs)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped());
+
+        let mut child = spawn(&mut command, &self.opts)?;
+        let stdout = child.stdout.take().expect("stdout not piped");
+        let stdin = child.stdin.take();
+        let ix = self.workers.len();
+        let sender = self.sender.clone();
+        thread::spawn(move || read_reports(ix, stdout, sender));
+
+        self.workers.push(Some(PooledChild {
+            child, stdin, stderr, ready: false, case: None,
+        }));
+        Ok(())
+    }
+
+    /// Wait for the next report from any worker and act on it.
+    fn process_report(&mut self) -> Result<()> {
+        // We hold a sender ourselves, so this cannot fail
+        let (ix, report) = self.reports.recv().expect("report channel closed");
+        match report {
+            Report::Output(output) =>
+                print!("{}", String::from_utf8_lossy(&output)),
+            Report::Ready => self.worker(ix).ready = true,
+            Report::Passed(id) => {
+                let worker = self.worker(ix);
+                debug_assert_eq!(Some(&id), worker.case.as_ref());
+                worker.case = None;
+            },
+            Report::Failed(id, message) => {
+                self.worker(ix).case = None;
+                self.failures.push((id, message));
+            },
+            Report::Exited => {
+                let mut worker = self.workers[ix].take()
+                    .expect("worker exited twice");
+                drop(worker.stdin.take());
+                let status = worker.child.wait()?;
+                echo_output(&mut worker.stderr, false);
+
+                if !worker.ready {
+                    return Err(Error::TestNotRun(self.test_name.clone()));
+                }
+                if let Some(id) = worker.case.take() {
+                    self.failures.push((id, format!(
+                        "worker exited during the case with {}", status)));
+                } else if !status.success() {
+                    self.failures.push(("(between cases)".to_owned(), format!(
+                        "worker exited with {}", status)));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn worker(&mut self, ix: usize) -> &mut PooledChild {
+        self.workers[ix].as_mut().expect("report from exited worker")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // Without further cases, the workers exit
+        for worker in self.workers.iter_mut().filter_map(Option::as_mut) {
+            drop(worker.stdin.take());
+        }
+        while self.live_workers() > 0 {
+            self.process_report()?;
+        }
+
+        if self.failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::CasesFailed(self.failures.split_off(0)))
+        }
+    }
+}
+
+impl Drop for Parent {
+    fn drop(&mut self) {
+        for worker in self.workers.iter_mut().filter_map(Option::as_mut) {
+            let _ = worker.child.kill();
+            let _ = worker.child.wait();
+        }
+    }
+}
+
+/// Read the reports of worker `ix` from its standard output until it is
+/// closed, passing them to `sender`.
+fn read_reports(ix: usize, stdout: process::ChildStdout,
+                sender: mpsc::Sender<(usize, Report)>) {
+    let mut reader = io::BufReader::new(stdout);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        let marker = buf.windows(MARKER.len())
+            .position(|window| window == MARKER.as_bytes());
+        let reports = match marker {
+            Some(start) => vec![
+                Report::Output(buf[..start].to_vec()),
+                parse_report(&String::from_utf8_lossy(
+                    &buf[start + MARKER.len()..])),
+            ],
+            None => vec![Report::Output(buf.clone())],
+        };
+        for report in reports {
+            match report {
+                Report::Output(ref output) if output.is_empty() => (),
+                report => if sender.send((ix, report)).is_err() {
+                    return;
+                },
+            }
+        }
+    }
+    let _ = sender.send((ix, Report::Exited));
+}
+
+fn parse_report(line: &str) -> Report {
+    let mut fields = line.trim_end_matches('\n').split('\t');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("ready"), None, None) => Report::Ready,
+        (Some("passed"), Some(id), None) => Report::Passed(unescape(id)),
+        (Some("failed"), Some(id), Some(message)) =>
+            Report::Failed(unescape(id), unescape(message)),
+        _ => Report::Output(line.as_bytes().to_vec()),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if '\\' == ch {
+            match chars.next() {
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some(ch) => unescaped.push(ch),
+                None => unescaped.push('\\'),
+            }
+        } else {
+            unescaped.push(ch);
+        }
+    }
+    unescaped
+}
+
+#[derive(Debug)]
+struct Worker {
+    /// The id of the case the parent handed to us.
+    next: String,
+}
+
+impl Worker {
+    fn start() -> Self {
+        report(&["ready"]);
+        Worker { next: next_request() }
+    }
+
+    fn run_case<F : FnOnce ()>(&mut self, id: &str, case: F) {
+        if id != self.next {
+            return;
+        }
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(case)) {
+            Ok(()) => report(&["passed", id]),
+            Err(payload) => report(&["failed", id, &panic_message(&*payload)]),
+        }
+        self.next = next_request();
+    }
+
+    fn finish(&mut self) -> ! {
+        // Any case still handed to us must come earlier in the sequence than
+        // the one we ran last, or not at all
+        loop {
+            report(&["failed", &self.next,
+                     "case not reached by the worker; cases must be run in \
+                      the same order in every process"]);
+            self.next = next_request();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Don't swallow a panic outside of any case; it fails the test in the
+        // worker, which the parent reports
+        if !thread::panicking() {
+            self.finish();
+        }
+    }
+}
+
+/// Send a report consisting of `fields` to the parent.
+fn report(fields: &[&str]) {
+    let fields = fields.iter().map(|field| escape(field))
+        .collect::<Vec<_>>();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{}{}", MARKER, fields.join("\t"))
+        .and_then(|_| stdout.flush());
+}
+
+/// Return the id of the next case the parent hands to us, exiting if there
+/// are no more.
+fn next_request() -> String {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => process::exit(0),
+        Ok(_) => line.trim_end_matches('\n').to_owned(),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const RECORD_ENV: &str = "FORK_POOL_TEST_RECORD";
+
+    /// Run `cases` in a pool of two workers, each case recording its id and
+    /// the worker's pid. Return the result of `finish()` and the records.
+    fn run_pool(test_name: &str, cases: &[(&str, fn ())])
+                -> (Result<()>, Vec<(String, u32)>) {
+        let dir = tempfile::tempdir().unwrap();
+        let record = env::var_os(RECORD_ENV)
+            .unwrap_or_else(|| dir.path().join("record").into_os_string());
+
+        let mut pool = ForkPool::with_opts(
+            test_name, rusty_fork_id!(), 2,
+            ForkOpts::new().env(RECORD_ENV, &record)).unwrap();
+        for &(id, case) in cases {
+            pool.run_case(id, || {
+                let mut file = fs::OpenOptions::new()
+                    .create(true).append(true)
+                    .open(env::var_os(RECORD_ENV).unwrap()).unwrap();
+                writeln!(file, "{} {}", id, process::id()).unwrap();
+                case();
+            }).unwrap();
+        }
+        let result = pool.finish();
+
+        let records = fs::read_to_string(&record).unwrap_or_default()
+            .lines()
+            .map(|line| {
+                let mut parts = line.rsplitn(2, ' ');
+                let pid = parts.next().unwrap().parse().unwrap();
+                (parts.next().unwrap().to_owned(), pid)
+            })
+            .collect();
+        (result, records)
+    }
+
+    #[test]
+    fn cases_routed_to_pooled_workers() {
+        let cases = [
+            ("a", (|| ()) as fn ()), ("b", || ()), ("c d", || ()),
+            ("e\tf", || ()), ("g\\h", || ()), ("i", || ()),
+        ];
+        let (result, records) = run_pool(
+            "fork_pool::test::cases_routed_to_pooled_workers", &cases);
+        result.unwrap();
+
+        let mut ids = records.iter().map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec!["a", "b", "c d", "e\tf", "g\\h", "i"], ids);
+
+        let pids = records.iter().map(|&(_, pid)| pid)
+            .collect::<HashSet<_>>();
+        assert!(pids.len() <= 2, "Used {} workers", pids.len());
+        assert!(!pids.contains(&process::id()));
+    }
+
+    #[test]
+    fn failed_cases_reported() {
+        let cases = [
+            ("ok", (|| ()) as fn ()),
+            ("panics", || panic!("case\tpanicked\nhere")),
+            ("exits", || process::exit(3)),
+            ("after", || ()),
+        ];
+        let (result, records) =
+            run_pool("fork_pool::test::failed_cases_reported", &cases);
+        let mut failures = match result {
+            Err(Error::CasesFailed(failures)) => failures,
+            result => panic!("Unexpected result: {:?}", result),
+        };
+        failures.sort();
+        assert_eq!(2, failures.len(), "Failures: {:?}", failures);
+        assert_eq!(("exits".to_owned(),
+                    "worker exited during the case with exit status: 3"
+                    .to_owned()),
+                   failures[0]);
+        assert_eq!(("panics".to_owned(), "case\tpanicked\nhere".to_owned()),
+                   failures[1]);
+
+        // Every case ran despite the failures
+        assert_eq!(4, records.len());
+    }
+
+    #[test]
+    fn wrong_test_name_not_run() {
+        let mut pool = ForkPool::new(
+            "fork_pool::test::no_such_test", rusty_fork_id!(), 1).unwrap();
+        match pool.run_case("case", || ()).and_then(|_| pool.finish()) {
+            Err(Error::TestNotRun(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reports_round_trip() {
+        for &text in &["plain", "tab\there", "line\nbreak", "back\\slash\\t",
+                       ""] {
+            assert_eq!(text, unescape(&escape(text)));
+        }
+        match parse_report("failed\tid\\tx\tmessage\\n\n") {
+            Report::Failed(ref id, ref message) => {
+                assert_eq!("id\tx", id);
+                assert_eq!("message\n", message);
+            },
+            report => panic!("Unexpected report: {:?}", report),
+        }
+    }
+}
@@ -12,9 +12,21 @@
 //! Some functionality in this module is useful to other implementors and
 //! unlikely to change. This subset is documented and considered stable.
 
-use std::process::Command;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::thread;
+#[cfg(feature = "timeout")]
+use std::time::Duration;
 
 use crate::child_wrapper::ChildWrapper;
+use crate::cmdline;
+use crate::error::{panic_message, Error};
+use crate::fork_builder::ForkBuilder;
+use crate::fork_opts::{CaptureMode, ForkOpts};
+use crate::sugar::RustyForkId;
 
 /// Run Rust tests in subprocesses.
 ///
@@ -41,14 +53,14 @@ use crate::child_wrapper::ChildWrapper;
 /// Each test will be run in its own process. If the subprocess exits
 /// unsuccessfully for any reason, including due to signals, the test fails.
 ///
-/// It is also possible to specify a timeout which is applied to all tests in
+/// It is also possible to specify options which are applied to all tests in
 /// the block, like so:
 ///
 /// ```
 /// use rusty_fork::rusty_fork_test;
 ///
 /// rusty_fork_test! {
-///     #![rusty_fork(timeout_ms = 1000)]
+///     #![rusty_fork(timeout_ms = 1000, env(RUST_BACKTRACE = "1"))]
 /// # /*
 ///     #[test]
 /// # */
@@ -62,49 +74,252 @@ use crate::child_wrapper::ChildWrapper;
 /// # fn main() { my_test(); }
 /// ```
 ///
-/// If any individual test takes more than the given timeout, the child is
-/// terminated and the test panics.
+/// The available options are:
+///
+/// - `timeout_ms = N` — If any individual test takes more than `N`
+///   milliseconds, the child is terminated and the test panics. `0` means no
+///   timeout. `N` can be any expression of type `u64`, such as a constant or
+///   arithmetic on one. Using the timeout feature requires the `timeout`
+///   feature for this crate to be enabled (which it is by default).
+///
+///   Non-zero timeouts can be adjusted at runtime, e.g. for slow CI machines,
+///   with two environment variables. `RUSTY_FORK_TIMEOUT_MULTIPLIER` (a
+///   floating-point number) is first multiplied into `N`, and then
+///   `RUSTY_FORK_TIMEOUT_MS` sets a floor, so that the effective timeout is
+///   the greater of the two.
+///
+/// - `timeout_s = N` — Like `timeout_ms`, but `N` is in seconds.
+///
+/// - `timeout = "duration"` — Like `timeout_ms`, but the timeout is given as
+///   a string such as `"1m30s"` or `"2h"`: one or more numbers, each followed
+///   by a unit of `h`, `m`, `s` or `ms`, optionally separated by spaces. The
+///   string must be a literal or constant; one which cannot be parsed results
+///   in a compile error.
+///
+///   ```compile_fail
+///   rusty_fork::rusty_fork_test! {
+///       #![rusty_fork(timeout = "90 seconds")]
+///
+///       fn my_test() { }
+///   }
+///   # fn main() { }
+///   ```
+///
+/// - `env(NAME = "value", ...)` — Set the given environment variables in the
+///   child process. If the same variable is given more than once, the last
+///   value wins.
+///
+/// - `no_capture` — Let the child write directly to the parent's standard
+///   output and standard error instead of capturing its output, so that it
+///   can be seen as it is produced, like with `cargo test -- --nocapture`.
+///   Timeouts still apply.
+///
+///   This is implied for all tests when the test process itself is passed
+///   `--nocapture` or `--show-output`, or `RUST_TEST_NOCAPTURE` is set.
+///
+/// - `merge_output` — Capture the child's standard output and standard error
+///   together, in the order they were written, rather than separately. By
+///   default, the captured output of each stream is shown on its own, under
+///   a header naming it, so that e.g. a panic message on standard error does
+///   not end up in the middle of lines printed to standard output.
+///
+/// - `cwd = "path"` — Run the child in the directory `path`. A relative path
+///   is relative to the root of the crate defining the tests (as given by
+///   `CARGO_MANIFEST_DIR` when it is compiled), wherever the tests are run
+///   from.
+///
+/// - `kill_process_tree` — Kill the processes the test starts together with
+///   the child, rather than only the child itself, when the test exceeds its
+///   timeout or finishes. On Unix, this runs the child in a process group of
+///   its own, which is then no longer the foreground process group of the
+///   terminal. See `ForkOpts::kill_process_tree()`.
+///
+/// - `grace_period_ms = N` — When a test exceeds its timeout, the child is
+///   first asked to terminate (with `SIGTERM` on Unix), and is only killed
+///   forcibly if it is still running `N` milliseconds later. The default is
+///   2000.
+///
+/// - `retry = N` — If a test fails, run it again in a new child, up to `N`
+///   more times. The test only fails if every attempt fails. This is meant as
+///   a stopgap for tests which are flaky for reasons outside of their
+///   control. Tests marked `#[should_panic]` are never retried, since their
+///   first failure is already the expected outcome; `should_panic` must be
+///   written directly on the test (not e.g. via `cfg_attr`) to be noticed.
+///
+/// An option not in this list results in a compile error.
+///
+/// Other attributes on the tests, such as `#[ignore]` or
+/// `#[ignore = "reason"]`, are kept on the generated test functions. An
+/// ignored test is thus skipped as usual, and forked like any other test when
+/// it is run with `--ignored` or `--include-ignored`.
+///
+/// A test marked `#[should_panic(expected = "message")]` only passes if the
+/// child panicked with a message containing `message`. It fails if the child
+/// panicked with any other message, or failed without panicking, even if
+/// `message` appears elsewhere in its output.
+///
+/// `rusty_fork_test!` never adds `#[test]` itself; each function is
+/// registered with the harness only by the attributes written on it. Tests
+/// for a custom harness can therefore use its own attribute (e.g.
+/// `#[my_harness::test]`) instead of `#[test]`, as long as the harness knows
+/// the test by the name `rusty_fork_test_name!` gives it.
+///
+/// All attributes are kept exactly as written, in the same order, including
+/// repeated ones. Tests can thus also be parameterised with attributes such
+/// as `#[test_case(...)]` from the `test-case` crate, in which case the test
+/// function takes arguments and each case is forked on its own:
+///
+/// ```
+/// use rusty_fork::rusty_fork_test;
+/// use test_case::test_case;
+///
+/// rusty_fork_test! {
+///     #[test_case(2, 4)]
+///     #[test_case(3, 9)]
+///     fn square(n: u32, expected: u32) {
+///         assert_eq!(expected, n * n);
+///     }
+/// }
+/// # fn main() { } // Can't actually run this without a test harness
+/// ```
+///
+/// Arguments must be given as `name: Type`. Such an attribute is expected to
+/// generate one test per case in a module named after the function, and the
+/// case being run is identified by the name of the thread the standard test
+/// harness runs it on. Where that does not tell (with other harnesses, or
+/// with older versions of the standard harness when they run only one test
+/// at a time, e.g. with `--test-threads=1` or on a single CPU), a warning is
+/// printed and the case is run directly in the test process, as under Miri
+/// below.
+///
+/// When the tests are run by [cargo-nextest](https://nexte.st/), which
+/// already runs each test in its own process, the tests are not forked, and
+/// instead run directly in the process nextest started. Environment variables
+/// from `env(...)` are still set, but `timeout_ms` is not enforced (nextest
+/// has its own timeouts). Set `RUSTY_FORK_FORCE_FORK=1` to fork regardless,
+/// e.g. for tests which abort the process they run in. Conversely, setting
+/// `RUSTY_FORK_PASSTHROUGH=1` runs the tests directly as under nextest, for
+/// other runners which isolate each test in a process of its own.
 ///
-/// Using the timeout feature requires the `timeout` feature for this crate to
-/// be enabled (which it is by default).
+/// Tests are likewise run directly when running under
+/// [Miri](https://github.com/rust-lang/miri), which cannot spawn processes.
+/// Timeouts are not enforced, and environment variables from `env(...)` are
+/// set in the one process shared by all tests. In either case, the current
+/// directory is changed for `cwd`.
 #[macro_export]
 macro_rules! rusty_fork_test {
-    (#![rusty_fork(timeout_ms = $timeout:expr)]
+    (@option $options:ident) => { };
+    (@option $options:ident , $($rest:tt)*) => {
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident env($($key:ident = $value:expr),* $(,)?)
+     $($rest:tt)*) => {
+        $($options.env(stringify!($key), $value);)*
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident cwd = $value:expr , $($rest:tt)*) => {
+        $options.cwd(env!("CARGO_MANIFEST_DIR"), $value);
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident cwd = $value:expr) => {
+        $options.cwd(env!("CARGO_MANIFEST_DIR"), $value);
+    };
+    (@option $options:ident timeout = $value:expr , $($rest:tt)*) => {
+        $crate::rusty_fork_test!(@option $options timeout = $value);
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident timeout = $value:expr) => {
+        // Parsed in a constant so that a malformed duration fails the build
+        $options.timeout_ms({
+            const TIMEOUT_MS: u64 = $crate::fork_test::duration_ms($value);
+            TIMEOUT_MS
+        });
+    };
+    (@option $options:ident $name:ident = $value:expr , $($rest:tt)*) => {
+        $options.$name($value);
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident $name:ident = $value:expr) => {
+        $options.$name($value);
+    };
+    (@option $options:ident $name:ident , $($rest:tt)*) => {
+        $options.$name();
+        $crate::rusty_fork_test!(@option $options $($rest)*);
+    };
+    (@option $options:ident $name:ident) => {
+        $options.$name();
+    };
+
+    (#![rusty_fork($($option:tt)*)]
+     $(
+         $(#[$($attr:tt)*])*
+         fn $test_name:ident($($arg:ident : $arg_ty:ty),* $(,)?) $body:block
+    )*) => {
+        $crate::rusty_fork_test! {
+            @tests ($($option)*)
+            $($(#[$($attr)*])* fn $test_name($($arg: $arg_ty),*) $body)*
+        }
+    };
+
+    (@tests $options:tt
      $(
-         $(#[$meta:meta])*
-         fn $test_name:ident() $body:block
+         $(#[$($attr:tt)*])*
+         fn $test_name:ident($($arg:ident : $arg_ty:ty),* $(,)?) $body:block
     )*) => { $(
-        $(#[$meta])*
-        fn $test_name() {
-            // Eagerly convert everything to function pointers so that all
-            // tests use the same instantiation of `fork`.
-            fn body_fn() $body
-            let body: fn () = body_fn;
-
-            fn supervise_fn(child: &mut $crate::ChildWrapper,
-                            _file: &mut ::std::fs::File) {
-                $crate::fork_test::supervise_child(child, $timeout)
-            }
-            let supervise:
-                fn (&mut $crate::ChildWrapper, &mut ::std::fs::File) =
-                supervise_fn;
+        $(#[$($attr)*])*
+        fn $test_name($($arg: $arg_ty),*) {
+            // Eagerly box everything so that all tests use the same
+            // instantiation of `fork`.
+            fn body_fn($($arg: $arg_ty),*) $body
+            let body: Box<dyn FnOnce ()> =
+                Box::new(move || body_fn($($arg),*));
+
+            #[allow(unused_mut)]
+            let mut options = $crate::fork_test::TestOptions::new();
+            $crate::rusty_fork_test!(@options options $options);
+            $crate::rusty_fork_test!(@should_panic options $(#[$($attr)*])*);
 
-            $crate::fork(
-                $crate::rusty_fork_test_name!($test_name),
-                $crate::rusty_fork_id!(),
-                $crate::fork_test::no_configure_child,
-                supervise, body).expect("forking test failed")
+            $crate::rusty_fork_test!(
+                @run $test_name ($($arg)*) options body)
         }
     )* };
 
+    (@run $test_name:ident () $options:ident $body:ident) => {
+        $crate::fork_test::run_test(
+            $crate::rusty_fork_test_name!($test_name),
+            $crate::rusty_fork_named_id!(
+                $crate::rusty_fork_test_name!($test_name)),
+            $options, $body)
+    };
+    (@run $test_name:ident ($($_arg:ident)+) $options:ident $body:ident) => {
+        $crate::fork_test::run_parameterised_test(
+            $crate::rusty_fork_test_name!($test_name), $options, $body)
+    };
+
+    (@should_panic $options:ident) => { };
+    (@should_panic $options:ident
+     #[should_panic(expected = $expected:expr)] $($_rest:tt)*) => {
+        $options.should_panic_expected($expected);
+    };
+    (@should_panic $options:ident #[should_panic $($_args:tt)*]
+     $($_rest:tt)*) => {
+        $options.should_panic();
+    };
+    (@should_panic $options:ident #[$($_attr:tt)*] $($rest:tt)*) => {
+        $crate::rusty_fork_test!(@should_panic $options $($rest)*);
+    };
+
+    (@options $options:ident ($($option:tt)*)) => {
+        $crate::rusty_fork_test!(@option $options $($option)*);
+    };
+
     ($(
-         $(#[$meta:meta])*
-         fn $test_name:ident() $body:block
+         $(#[$($attr:tt)*])*
+         fn $test_name:ident($($arg:ident : $arg_ty:ty),* $(,)?) $body:block
     )*) => {
-        rusty_fork_test! {
-            #![rusty_fork(timeout_ms = 0)]
-
-            $($(#[$meta])* fn $test_name() $body)*
+        $crate::rusty_fork_test! {
+            @tests ()
+            $($(#[$($attr)*])* fn $test_name($($arg: $arg_ty),*) $body)*
         }
     };
 }
@@ -125,21 +340,371 @@ macro_rules! rusty_fork_test_name {
     }
 }
 
+/// Options set through the `#![rusty_fork(...)]` header of
+/// `rusty_fork_test!`.
+///
+/// Each option in the header is lowered to a call to the method of the same
+/// name.
 #[allow(missing_docs)]
 #[doc(hidden)]
-pub fn supervise_child(child: &mut ChildWrapper, timeout_ms: u64) {
-    if timeout_ms > 0 {
-        wait_timeout(child, timeout_ms)
-    } else {
-        let status = child.wait().expect("failed to wait for child");
-        assert!(status.success(),
-                "child exited unsuccessfully with {}", status);
+#[derive(Debug, Default)]
+pub struct TestOptions {
+    timeout_ms: u64,
+    grace_period_ms: Option<u64>,
+    env: Vec<(String, OsString)>,
+    cwd: Option<PathBuf>,
+    no_capture: bool,
+    merge_output: bool,
+    kill_process_tree: bool,
+    retry: u32,
+    should_panic: bool,
+    expected_panic: Option<String>,
+}
+
+#[allow(missing_docs)]
+#[doc(hidden)]
+impl TestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn timeout_ms(&mut self, timeout_ms: u64) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    pub fn timeout_s(&mut self, timeout_s: u64) {
+        self.timeout_ms = timeout_s.saturating_mul(1000);
+    }
+
+    pub fn grace_period_ms(&mut self, grace_period_ms: u64) {
+        self.grace_period_ms = Some(grace_period_ms);
+    }
+
+    pub fn env<V : AsRef<OsStr>>(&mut self, key: &str, value: V) {
+        self.env.push((key.to_owned(), value.as_ref().to_owned()));
+    }
+
+    /// `dir` relative to `manifest_dir`, the root of the crate defining the
+    /// test.
+    pub fn cwd(&mut self, manifest_dir: &str, dir: &str) {
+        self.cwd = Some(Path::new(manifest_dir).join(dir));
+    }
+
+    pub fn no_capture(&mut self) {
+        self.no_capture = true;
+    }
+
+    pub fn merge_output(&mut self) {
+        self.merge_output = true;
+    }
+
+    pub fn kill_process_tree(&mut self) {
+        self.kill_process_tree = true;
+    }
+
+    pub fn retry(&mut self, retry: u32) {
+        self.retry = retry;
+    }
+
+    /// Called for tests marked `#[should_panic]`.
+    pub fn should_panic(&mut self) {
+        self.should_panic = true;
+    }
+
+    /// Called for tests marked `#[should_panic(expected = "...")]`.
+    pub fn should_panic_expected(&mut self, expected: &str) {
+        self.should_panic = true;
+        self.expected_panic = Some(expected.to_owned());
+    }
+
+    /// Return the timeout after applying the adjustments from the
+    /// environment, or 0 for no timeout.
+    fn effective_timeout_ms(&self) -> u64 {
+        if 0 == self.timeout_ms {
+            return 0;
+        }
+
+        let mut timeout_ms = self.timeout_ms;
+        if let Some(multiplier) = env_var_parsed::<f64>(
+            "RUSTY_FORK_TIMEOUT_MULTIPLIER")
+        {
+            timeout_ms = (timeout_ms as f64 * multiplier).ceil() as u64;
+        }
+        if let Some(floor) = env_var_parsed::<u64>("RUSTY_FORK_TIMEOUT_MS") {
+            timeout_ms = timeout_ms.max(floor);
+        }
+        // A multiplier of 0 must not disable the timeout
+        timeout_ms.max(1)
+    }
+
+    fn fork_opts(&self) -> ForkOpts {
+        let no_capture = self.no_capture || cmdline::nocapture_requested();
+        let mut opts = self.env.iter().fold(
+            ForkOpts::new(), |opts, (key, value)| opts.env(key, value))
+            .inherit_stdout(no_capture)
+            .inherit_stderr(no_capture)
+            .kill_process_tree(self.kill_process_tree);
+        if !self.merge_output {
+            opts = opts.capture_mode(CaptureMode::Separate)
+                .label_output(true);
+        }
+        if let Some(ref cwd) = self.cwd {
+            opts = opts.current_dir(cwd);
+        }
+
+        #[cfg(feature = "timeout")]
+        let opts = {
+            let mut opts = opts;
+            let timeout_ms = self.effective_timeout_ms();
+            if timeout_ms > 0 {
+                opts = opts.timeout(Duration::from_millis(timeout_ms));
+            }
+            if let Some(grace_period_ms) = self.grace_period_ms {
+                opts = opts.grace_period(
+                    Duration::from_millis(grace_period_ms));
+            }
+            opts
+        };
+
+        opts
+    }
+}
+
+/// Read and parse the environment variable `name`, if it is set.
+///
+/// ## Panics
+///
+/// Panics if the variable is set but cannot be parsed.
+fn env_var_parsed<T : str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().map(|value| value.parse().unwrap_or_else(
+        |_| panic!("rusty-fork: invalid value {:?} for {}", value, name)))
+}
+
+/// Return whether tests should be run in a child process.
+///
+/// This is not the case under cargo-nextest when it runs each test in its own
+/// process anyway, or with `RUSTY_FORK_PASSTHROUGH=1`, unless
+/// `RUSTY_FORK_FORCE_FORK=1` is set.
+fn should_fork() -> bool {
+    match env::var("RUSTY_FORK_FORCE_FORK") {
+        Ok(ref value) if value == "1" => return true,
+        _ => (),
+    }
+    match env::var("RUSTY_FORK_PASSTHROUGH") {
+        Ok(ref value) if value == "1" => return false,
+        _ => (),
+    }
+
+    let in_nextest = env::var_os("NEXTEST").is_some();
+    let process_per_test = env::var("NEXTEST_EXECUTION_MODE")
+        .map(|mode| "process-per-test" == mode)
+        // Versions of nextest which don't set this only have this mode
+        .unwrap_or(true);
+    !(in_nextest && process_per_test)
+}
+
+/// Return the name under which the test harness is running a case of the
+/// parameterised test function at `function_path`, if it can be told.
+///
+/// Attributes such as `#[test_case(...)]` generate one test per case, in a
+/// module named after the function, which calls the function with the
+/// arguments of the case. The case itself does not know its name, but the
+/// standard harness runs each test on a thread named after it, except for
+/// older versions which run a single test at a time on the main thread. A
+/// child process started for a case finds the name on its own command line
+/// instead, whatever harness it runs under.
+fn parameterised_case_name(function_path: &str) -> Option<String> {
+    let prefix = format!("{}::", function_path);
+    let thread = thread::current();
+    thread.name().map(str::to_owned)
+        .filter(|name| name.starts_with(&prefix))
+        .or_else(|| cmdline::child_test_name()
+                 .filter(|name| name.starts_with(&prefix)))
+}
+
+#[allow(missing_docs)]
+#[doc(hidden)]
+pub fn run_parameterised_test(function_path: &str, options: TestOptions,
+                              body: Box<dyn FnOnce () + '_>) {
+    match parameterised_case_name(function_path) {
+        // Each case gets an id of its own, even though all of them share the
+        // one call site
+        Some(case) =>
+            run_test(&case, RustyForkId::from_seed(&case), options, body),
+        None => {
+            if cmdline::listing_requested() {
+                return;
+            }
+            eprintln!("rusty-fork: warning: cannot tell which case of the \
+                       parameterised test {} is running; running it without \
+                       forking", function_path);
+            run_inline(options, body);
+        },
+    }
+}
+
+/// Run a test body in the current process instead of forking.
+fn run_inline(options: TestOptions, body: Box<dyn FnOnce () + '_>) {
+    // Under nextest, we are the only test in this process, so there's nobody
+    // else to be affected by the environment
+    for (key, value) in &options.env {
+        env::set_var(key, value);
+    }
+    if let Some(ref cwd) = options.cwd {
+        env::set_current_dir(cwd).unwrap_or_else(|err| panic!(
+            "rusty-fork: cannot change to {}: {}", cwd.display(), err));
+    }
+    body();
+}
+
+#[allow(missing_docs)]
+#[doc(hidden)]
+pub fn run_test(test_name: &str, fork_id: RustyForkId,
+                options: TestOptions, body: Box<dyn FnOnce () + '_>) {
+    // The standard harness never runs tests while listing them, but a custom
+    // one might; either way, there is nothing to run, and forking would fail
+    // with `Error::Listing`
+    if cmdline::listing_requested() {
+        return;
+    }
+    // Miri cannot spawn processes at all; `cfg!` rather than `#[cfg]` so that
+    // this path is always compiled.
+    if cfg!(miri) || !should_fork() {
+        run_inline(options, body);
+        return;
+    }
+
+    let timeout_ms = options.effective_timeout_ms();
+    // Retrying a test which is expected to panic would just run it again for
+    // nothing
+    let attempts = if options.should_panic { 1 } else { options.retry + 1 };
+    // Only ever called in the child, which stops after the first attempt
+    let mut body = Some(body);
+    for attempt in 1..=attempts {
+        let result = ForkBuilder::new(test_name, fork_id.clone())
+            .opts(options.fork_opts())
+            .run(|child: &mut ChildWrapper, _: &mut fs::File|
+                 supervise_child(child, timeout_ms),
+                 || body.take().expect("test body already run")());
+        let err = match result {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+
+        if attempt < attempts {
+            println!("rusty-fork: attempt {} of {} failed, retrying: {}",
+                     attempt, attempts, err);
+        } else if let Error::Timeout { .. } = err {
+            panic!("{} (timeout was {} ms)", err, timeout_ms);
+        } else if let Some(ref expected) = options.expected_panic {
+            panic_expecting(&err, expected);
+        } else {
+            panic!("forking test failed: {}", err);
+        }
+    }
+}
+
+/// Fail a test marked `#[should_panic(expected = "...")]` whose child failed
+/// with `err`.
+///
+/// The test harness checks our own panic message for `expected`, so the
+/// child's output is only included in it if the child panicked with a
+/// message containing `expected`. Otherwise, the harness would find
+/// `expected` in whatever the child wrote before failing.
+fn panic_expecting(err: &Error, expected: &str) -> ! {
+    let message = match *err {
+        Error::ChildFailed { ref output, .. } => panic_message(output),
+        _ => None,
+    };
+    match message {
+        Some(ref message) if message.contains(expected) =>
+            panic!("forking test failed: {}", err),
+        Some(message) =>
+            panic!("forking test panicked with an unexpected message: {:?}",
+                   message),
+        None => panic!("forking test failed without panicking"),
     }
 }
 
 #[allow(missing_docs)]
 #[doc(hidden)]
-pub fn no_configure_child(_child: &mut Command) { }
+pub fn supervise_child(child: &mut ChildWrapper, timeout_ms: u64) {
+    if timeout_ms > 0 && !cfg!(feature = "timeout") {
+        panic!("Using the timeout feature of rusty_fork_test! requires \
+                enabling the `timeout` feature on the rusty-fork crate.");
+    }
+
+    // Any timeout is enforced by `wait()` itself. Both timeouts and
+    // unsuccessful exits are then reported by `fork_with_opts()`.
+    child.wait().expect("failed to wait for child");
+}
+
+/// Parse a duration such as `"1m30s"` for the `timeout` option of
+/// `rusty_fork_test!`, returning the number of milliseconds.
+///
+/// This panics if `s` is malformed, which is a compile error when it is
+/// evaluated in a constant.
+#[doc(hidden)]
+pub const fn duration_ms(s: &str) -> u64 {
+    let s = s.as_bytes();
+    let mut total: u64 = 0;
+    let mut parts = 0;
+    let mut ix = 0;
+    while ix < s.len() {
+        if s[ix] == b' ' {
+            ix += 1;
+            continue;
+        }
+
+        let mut n: u64 = 0;
+        let start = ix;
+        while ix < s.len() && s[ix].is_ascii_digit() {
+            n = match n.checked_mul(10) {
+                Some(n) => n + (s[ix] - b'0') as u64,
+                None => panic!("rusty_fork: timeout duration is too large"),
+            };
+            ix += 1;
+        }
+        if ix == start {
+            panic!("rusty_fork: expected a number in timeout duration");
+        }
+
+        let unit_ms = if ix + 1 < s.len() && s[ix] == b'm' && s[ix+1] == b's' {
+            ix += 2;
+            1
+        } else if ix < s.len() && s[ix] == b'h' {
+            ix += 1;
+            3_600_000
+        } else if ix < s.len() && s[ix] == b'm' {
+            ix += 1;
+            60_000
+        } else if ix < s.len() && s[ix] == b's' {
+            ix += 1;
+            1000
+        } else {
+            panic!("rusty_fork: expected a unit of `h`, `m`, `s` or `ms` \
+                    after a number in timeout duration");
+        };
+        if ix < s.len() && s[ix].is_ascii_alphabetic() {
+            panic!("rusty_fork: expected a unit of `h`, `m`, `s` or `ms` \
+                    after a number in timeout duration");
+        }
+
+        total = match n.checked_mul(unit_ms) {
+            Some(ms) => match total.checked_add(ms) {
+                Some(total) => total,
+                None => panic!("rusty_fork: timeout duration is too large"),
+            },
+            None => panic!("rusty_fork: timeout duration is too large"),
+        };
+        parts += 1;
+    }
+
+    if parts == 0 {
+        panic!("rusty_fork: timeout duration is empty");
+    }
+    total
+}
 
 /// Transform a string representing a qualified path as generated via
 /// `module_path!()` into a qualified path as expected by the standard Rust
@@ -148,28 +713,230 @@ pub fn fix_module_path(path: &str) -> &str {
     path.find("::").map(|ix| &path[ix+2..]).unwrap_or(path)
 }
 
-#[cfg(feature = "timeout")]
-fn wait_timeout(child: &mut ChildWrapper, timeout_ms: u64) {
-    use std::time::Duration;
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process::{self, Command};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-    let timeout = Duration::from_millis(timeout_ms);
-    let status = child.wait_timeout(timeout).expect("failed to wait for child");
-    if let Some(status) = status {
-        assert!(status.success(),
-                "child exited unsuccessfully with {}", status);
-    } else {
-        panic!("child process exceeded {} ms timeout", timeout_ms);
+    /// Run the named test in a fresh instance of the test executable with the
+    /// given extra arguments, returning whether the test ran its body and
+    /// whether the process exited successfully.
+    fn run_in_harness(test_name: &str, args: &[&str]) -> (bool, bool) {
+        let (runs, success) = runs_in_harness(test_name, args);
+        (runs > 0, success)
     }
-}
 
-#[cfg(not(feature = "timeout"))]
-fn wait_timeout(_: &mut ChildWrapper, _: u64) {
-    panic!("Using the timeout feature of rusty_fork_test! requires \
-            enabling the `timeout` feature on the rusty-fork crate.");
-}
+    /// Like `run_in_harness()`, but return how many times the test body ran.
+    fn runs_in_harness(test_name: &str, args: &[&str]) -> (usize, bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let sentinel = dir.path().join("ran");
+        let status = Command::new(env::current_exe().unwrap())
+            .args(args)
+            .arg("--exact").arg(test_name)
+            .env(SENTINEL_ENV, &sentinel)
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status().unwrap();
+        let runs = fs::read(&sentinel).map(|runs| runs.len()).unwrap_or(0);
+        (runs, status.success())
+    }
+
+    /// Run the named test in a fresh instance of the test executable, returning
+    /// everything the test harness wrote to standard output.
+    fn harness_output(test_name: &str, args: &[&str]) -> String {
+        let output = Command::new(env::current_exe().unwrap())
+            .args(args)
+            .arg("--exact").arg(test_name)
+            .stderr(process::Stdio::null())
+            .output().unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    const SENTINEL_ENV: &str = "FORK_TEST_SENTINEL";
+
+    /// Record that the test body ran, returning how many times it had run
+    /// before.
+    fn touch_sentinel() -> usize {
+        use std::io::Write;
+
+        if let Some(path) = env::var_os(SENTINEL_ENV) {
+            let mut file = fs::OpenOptions::new()
+                .create(true).append(true).open(Path::new(&path)).unwrap();
+            let previous = file.metadata().unwrap().len() as usize;
+            file.write_all(b"x").unwrap();
+            previous
+        } else {
+            0
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        #[ignore]
+        fn ignored_child() {
+            touch_sentinel();
+        }
+
+        #[test]
+        #[ignore = "only run by other tests"]
+        fn ignored_with_reason_child() {
+            touch_sentinel();
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(retry = 2)]
+
+        #[test]
+        #[ignore]
+        fn flaky_child() {
+            assert!(touch_sentinel() > 0, "failing the first attempt");
+        }
+
+        #[test]
+        #[ignore]
+        fn always_failing_child() {
+            touch_sentinel();
+            panic!("failing every attempt");
+        }
+
+        /// Doc comments and other attributes don't hide `should_panic`
+        #[test]
+        #[ignore]
+        #[should_panic]
+        fn should_panic_child() {
+            touch_sentinel();
+            panic!("expected panic");
+        }
+
+        #[test]
+        #[ignore]
+        #[should_panic(expected = "boom")]
+        fn wrong_panic_message_child() {
+            touch_sentinel();
+            println!("boom");
+            panic!("bang");
+        }
+    }
+
+    #[test]
+    fn retry_until_success() {
+        assert_eq!((2, true), runs_in_harness(
+            "fork_test::test::flaky_child", &["--ignored"]));
+    }
+
+    #[test]
+    fn retry_gives_up_after_limit() {
+        assert_eq!((3, false), runs_in_harness(
+            "fork_test::test::always_failing_child", &["--ignored"]));
+    }
+
+    #[test]
+    fn should_panic_not_retried() {
+        assert_eq!((1, true), runs_in_harness(
+            "fork_test::test::should_panic_child", &["--ignored"]));
+    }
+
+    #[test]
+    fn wrong_panic_message_fails() {
+        assert_eq!((1, false), runs_in_harness(
+            "fork_test::test::wrong_panic_message_child", &["--ignored"]));
+    }
+
+    #[test]
+    fn ignored_test_runs_under_ignored() {
+        assert_eq!((true, true), run_in_harness(
+            "fork_test::test::ignored_child", &["--ignored"]));
+    }
+
+    #[test]
+    fn ignored_test_runs_under_include_ignored() {
+        assert_eq!((true, true), run_in_harness(
+            "fork_test::test::ignored_child", &["--include-ignored"]));
+    }
+
+    #[test]
+    fn ignored_test_runs_once_under_skip() {
+        assert_eq!((1, true), runs_in_harness(
+            "fork_test::test::ignored_child",
+            &["--ignored", "--skip", "no_such_test"]));
+    }
+
+    #[test]
+    fn parent_filters_and_skips_not_forwarded() {
+        // Were any of these forwarded, the child would match no test and
+        // "pass" without running it
+        assert_eq!((1, true), runs_in_harness(
+            "fork_test::test::ignored_child",
+            &["--ignored", "fork_test", "--skip", "slow", "--skip",
+              "ignored", "--skip=fork_test"]));
+    }
+
+    #[test]
+    fn extra_args_reach_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let logfile = dir.path().join("log file.txt");
+        let status = Command::new(env::current_exe().unwrap())
+            .arg("--ignored")
+            .arg("--exact").arg("fork_test::test::ignored_child")
+            .env("RUSTY_FORK_EXTRA_ARGS",
+                 format!("--logfile '{}'", logfile.display()))
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status().unwrap();
+        assert!(status.success());
+        assert!(logfile.exists());
+    }
+
+    rusty_fork_test! {
+        #[test]
+        #[ignore]
+        fn print_args_child() {
+            println!("child args: {:?}", env::args().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn logfile_not_passed_to_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let logfile = dir.path().join("test.log");
+        let joined = format!("--logfile={}", logfile.display());
+        for logfile_args in &[&["--logfile", &*logfile.to_string_lossy()][..],
+                              &[&*joined][..]] {
+            let mut args = vec!["--ignored", "--nocapture"];
+            args.extend(*logfile_args);
+            let output = harness_output(
+                "fork_test::test::print_args_child", &args);
+            assert!(output.contains("child args: "),
+                    "Unexpected output:\n{}", output);
+            assert!(!output.contains("logfile"),
+                    "Unexpected output:\n{}", output);
+            // The parent still writes its own logfile
+            assert!(fs::read_to_string(&logfile).unwrap()
+                    .contains("fork_test::test::print_args_child"));
+        }
+    }
+
+    #[test]
+    fn ignored_test_not_run_by_default() {
+        assert_eq!((false, true), run_in_harness(
+            "fork_test::test::ignored_child", &[]));
+        assert_eq!((false, true), run_in_harness(
+            "fork_test::test::ignored_with_reason_child", &[]));
+    }
+
+    #[test]
+    fn ignored_with_reason_runs_under_ignored() {
+        for flag in &["--ignored", "--include-ignored"] {
+            assert_eq!((true, true), run_in_harness(
+                "fork_test::test::ignored_with_reason_child", &[flag]),
+                       "with {}", flag);
+        }
+    }
 
-#[cfg(test)]
-mod test {
     rusty_fork_test! {
         #[test]
         fn trivial() { }
@@ -180,6 +947,12 @@ mod test {
             panic!("just testing a panic, nothing to see here");
         }
 
+        #[test]
+        #[should_panic(expected = "boom")]
+        fn expected_panic_message_child() {
+            panic!("it went boom");
+        }
+
         #[test]
         #[should_panic]
         fn aborting_child() {
@@ -204,4 +977,476 @@ mod test {
             println!("goodbye from child");
         }
     }
+
+    rusty_fork_test! {
+        #![rusty_fork(no_capture)]
+
+        #[test]
+        fn no_capture_child() {
+            println!("hello from no_capture child");
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn captured_child() {
+            println!("hello from captured child");
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        #[ignore]
+        fn both_streams_child() {
+            println!("hello on stdout");
+            eprintln!("hello on stderr");
+            println!("goodbye on stdout");
+            // So that the harness shows the output
+            panic!("failing on purpose");
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(merge_output)]
+
+        #[test]
+        #[ignore]
+        fn merged_streams_child() {
+            println!("hello on stdout");
+            eprintln!("hello on stderr");
+            println!("goodbye on stdout");
+            // So that the harness shows the output
+            panic!("failing on purpose");
+        }
+    }
+
+    rusty_fork_test! {
+        #[allow(dead_code)]
+        fn not_registered_without_test_attr() { }
+
+        // Stands for the attribute of some other harness
+        #[cfg_attr(all(), test)]
+        fn registered_by_other_attr() { }
+    }
+
+    #[test]
+    fn test_attr_not_added() {
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("--list").output().unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(!output.contains("not_registered_without_test_attr"),
+                "Unexpected output:\n{}", output);
+        assert_eq!(1, output.lines().filter(
+            |line| line.starts_with(
+                "fork_test::test::registered_by_other_attr:")).count(),
+                   "Unexpected output:\n{}", output);
+    }
+
+    #[test]
+    fn listing_spawns_no_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let sentinel = dir.path().join("ran");
+        let output = Command::new(env::current_exe().unwrap())
+            .args(vec!["--list", "--include-ignored"])
+            .env(SENTINEL_ENV, &sentinel)
+            .output().unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(output.lines().any(
+            |line| line == "fork_test::test::should_panic_child: test"),
+                "Unexpected output:\n{}", output);
+        assert!(!sentinel.exists());
+    }
+
+    #[test]
+    fn streams_shown_under_headers() {
+        let output = harness_output(
+            "fork_test::test::both_streams_child",
+            &["--ignored"]);
+        assert!(output.contains("---- child stdout ----\n"),
+                "Unexpected output:\n{}", output);
+        assert!(output.contains(
+            "hello on stdout\ngoodbye on stdout\n\
+             ---- child stderr ----\nhello on stderr\n"),
+                "Unexpected output:\n{}", output);
+    }
+
+    #[test]
+    fn streams_interleaved_with_merge_output() {
+        let output = harness_output(
+            "fork_test::test::merged_streams_child",
+            &["--ignored"]);
+        assert!(output.contains(
+            "hello on stdout\nhello on stderr\ngoodbye on stdout\n"),
+                "Unexpected output:\n{}", output);
+        assert!(!output.contains("---- child"),
+                "Unexpected output:\n{}", output);
+    }
+
+    #[test]
+    fn no_capture_output_bypasses_harness_capture() {
+        assert!(harness_output("fork_test::test::no_capture_child", &[])
+                .contains("hello from no_capture child"));
+        assert!(!harness_output("fork_test::test::captured_child", &[])
+                .contains("hello from captured child"));
+    }
+
+    rusty_fork_test! {
+        #[test]
+        #[ignore]
+        fn stderr_child() {
+            eprintln!("hello on stderr from child");
+        }
+    }
+
+    /// Run `stderr_child` with the given arguments and environment, returning
+    /// whether its output reached the harness's standard error directly.
+    fn stderr_child_is_live(args: &[&str], env: &[(&str, &str)]) -> bool {
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("--ignored")
+            .args(args)
+            .arg("--exact").arg("fork_test::test::stderr_child")
+            .env_remove("RUST_TEST_NOCAPTURE")
+            .envs(env.iter().cloned())
+            .output().unwrap();
+        assert!(output.status.success());
+        // Captured output is replayed on standard output instead
+        String::from_utf8_lossy(&output.stderr)
+            .contains("hello on stderr from child")
+    }
+
+    #[test]
+    fn parent_nocapture_makes_child_output_live() {
+        assert!(!stderr_child_is_live(&[], &[]));
+        assert!(stderr_child_is_live(&["--nocapture"], &[]));
+        assert!(stderr_child_is_live(&["--show-output"], &[]));
+        assert!(stderr_child_is_live(&[], &[("RUST_TEST_NOCAPTURE", "1")]));
+        assert!(!stderr_child_is_live(&[], &[("RUST_TEST_NOCAPTURE", "0")]));
+    }
+
+    #[test]
+    #[cfg(feature = "timeout")]
+    fn parent_nocapture_still_times_out() {
+        assert_eq!((false, false), run_in_harness(
+            "fork_test::test::sleeping_child",
+            &["--ignored", "--nocapture"]));
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(timeout_ms = 500)]
+
+        #[test]
+        #[ignore]
+        #[cfg(feature = "timeout")]
+        fn sleeping_child() {
+            ::std::thread::sleep(
+                ::std::time::Duration::from_millis(10000));
+            touch_sentinel();
+        }
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "timeout"))]
+    fn process_tree_killed_on_timeout() {
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("sleeper.pid");
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--ignored", "--exact",
+                    "fork_test::test::process_tree_child"])
+            .env(SENTINEL_ENV, &pid_file)
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status().unwrap();
+        assert!(!status.success());
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            // Once killed, the sleeper may linger as a zombie until reaped
+            let stat = Command::new("ps")
+                .args(["-o", "stat=", "-p", &pid])
+                .output().unwrap().stdout;
+            let stat = String::from_utf8_lossy(&stat);
+            if stat.trim().is_empty() || stat.trim().starts_with('Z') {
+                break;
+            }
+            assert!(Instant::now() < deadline,
+                    "sleeper {} still running", pid);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(kill_process_tree, timeout_ms = 500)]
+
+        #[test]
+        #[ignore]
+        #[cfg(all(unix, feature = "timeout"))]
+        fn process_tree_child() {
+            use std::{thread, time::Duration};
+
+            let output = Command::new("sh").arg("-c")
+                .arg("sleep 30 >/dev/null 2>&1 & echo $!")
+                .output().unwrap();
+            fs::write(env::var_os(SENTINEL_ENV).unwrap(),
+                      String::from_utf8_lossy(&output.stdout).trim())
+                .unwrap();
+            thread::sleep(Duration::from_secs(10));
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(no_capture, timeout_ms = 1000)]
+
+        #[test]
+        #[should_panic]
+        #[cfg(feature = "timeout")]
+        fn no_capture_timeout_fails() {
+            ::std::thread::sleep(
+                ::std::time::Duration::from_millis(10000));
+        }
+    }
+
+    #[cfg(feature = "timeout")]
+    const TIMEOUT_MS: u64 = 5000;
+
+    rusty_fork_test! {
+        #![rusty_fork(timeout_ms = 2 * TIMEOUT_MS / 5)]
+
+        #[test]
+        #[cfg(feature = "timeout")]
+        fn timeout_from_expression() {
+            assert!(env::var_os(crate::cmdline::OCCURS_ENV).is_some());
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(
+            env(FORK_TEST_ENV_A = "1",
+                FORK_TEST_ENV_B = format!("x={}", 1 + 1),
+                FORK_TEST_ENV_A = "2"),
+        )]
+
+        #[test]
+        fn env_set_in_child() {
+            assert_eq!(Ok("2".to_owned()),
+                       ::std::env::var("FORK_TEST_ENV_A"));
+            assert_eq!(Ok("x=2".to_owned()),
+                       ::std::env::var("FORK_TEST_ENV_B"));
+        }
+    }
+    rusty_fork_test! {
+        #![rusty_fork(cwd = "src")]
+
+        #[test]
+        fn cwd_relative_to_crate_root() {
+            assert_eq!(
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("src")
+                    .canonicalize().unwrap(),
+                env::current_dir().unwrap().canonicalize().unwrap());
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(cwd = env!("CARGO_MANIFEST_DIR"), retry = 0)]
+
+        #[test]
+        fn cwd_absolute() {
+            assert_eq!(
+                Path::new(env!("CARGO_MANIFEST_DIR")).canonicalize().unwrap(),
+                env::current_dir().unwrap().canonicalize().unwrap());
+        }
+    }
+
+    #[test]
+    fn duration_ms_parses_units() {
+        assert_eq!(90_000, super::duration_ms("1m30s"));
+        assert_eq!(7_200_000, super::duration_ms("2h"));
+        assert_eq!(1500, super::duration_ms("1s 500ms"));
+        assert_eq!(250, super::duration_ms("250ms"));
+        assert_eq!(0, super::duration_ms("0s"));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a unit")]
+    fn duration_ms_rejects_unknown_unit() {
+        super::duration_ms("90 seconds");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a number")]
+    fn duration_ms_rejects_missing_number() {
+        super::duration_ms("ms");
+    }
+
+    #[test]
+    #[should_panic(expected = "empty")]
+    fn duration_ms_rejects_empty() {
+        super::duration_ms("");
+    }
+
+    #[test]
+    fn timeout_options_lowered_to_ms() {
+        let mut options = super::TestOptions::new();
+        rusty_fork_test!(@option options timeout_ms = 1500);
+        assert_eq!(1500, options.timeout_ms);
+        rusty_fork_test!(@option options timeout_s = 30);
+        assert_eq!(30_000, options.timeout_ms);
+        rusty_fork_test!(@option options timeout = "1m30s", retry = 1);
+        assert_eq!(90_000, options.timeout_ms);
+        assert_eq!(1, options.retry);
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(timeout = "500ms")]
+
+        #[test]
+        #[should_panic]
+        #[cfg(feature = "timeout")]
+        fn timeout_string_enforced() {
+            ::std::thread::sleep(
+                ::std::time::Duration::from_millis(10000));
+        }
+    }
+
+    rusty_fork_test! {
+        #![rusty_fork(timeout_s = 1)]
+
+        #[test]
+        #[should_panic]
+        #[cfg(feature = "timeout")]
+        fn timeout_s_enforced() {
+            ::std::thread::sleep(
+                ::std::time::Duration::from_millis(10000));
+        }
+    }
+
+    // The lints are denied so that dropping either attribute fails the build
+    #[deny(unused_variables, unused_mut)]
+    mod repeated_attributes {
+        rusty_fork_test! {
+            #[test]
+            #[cfg_attr(all(), allow(unused_variables))]
+            #[cfg_attr(all(), allow(unused_mut))]
+            fn kept() {
+                let mut unused = ();
+            }
+        }
+    }
+
+    fn options_with_timeout(timeout_ms: u64) -> super::TestOptions {
+        let mut options = super::TestOptions::new();
+        options.timeout_ms(timeout_ms);
+        options
+    }
+
+    // Subprocesses so we can change the environment without affecting other
+    // tests
+    rusty_fork_test! {
+        #[test]
+        fn timeout_multiplier_from_env() {
+            env::set_var("RUSTY_FORK_TIMEOUT_MULTIPLIER", "2.5");
+            assert_eq!(2500, options_with_timeout(1000).effective_timeout_ms());
+            assert_eq!(0, options_with_timeout(0).effective_timeout_ms());
+        }
+
+        #[test]
+        fn timeout_floor_from_env() {
+            env::set_var("RUSTY_FORK_TIMEOUT_MS", "5000");
+            assert_eq!(5000, options_with_timeout(1000).effective_timeout_ms());
+            assert_eq!(6000, options_with_timeout(6000).effective_timeout_ms());
+            assert_eq!(0, options_with_timeout(0).effective_timeout_ms());
+
+            // The floor applies after the multiplier
+            env::set_var("RUSTY_FORK_TIMEOUT_MULTIPLIER", "3");
+            assert_eq!(5000, options_with_timeout(1000).effective_timeout_ms());
+            assert_eq!(6000, options_with_timeout(2000).effective_timeout_ms());
+        }
+
+        #[test]
+        #[cfg(feature = "timeout")]
+        fn timeout_from_env_changes_deadline() {
+            use std::time::Duration;
+
+            assert_eq!(Some(Duration::from_millis(1000)),
+                       options_with_timeout(1000).fork_opts().timeout);
+            env::set_var("RUSTY_FORK_TIMEOUT_MULTIPLIER", "0.5");
+            assert_eq!(Some(Duration::from_millis(500)),
+                       options_with_timeout(1000).fork_opts().timeout);
+        }
+    }
+    static RAN_INLINE: AtomicBool = AtomicBool::new(false);
+
+    fn record_ran_inline() {
+        RAN_INLINE.store(true, Ordering::SeqCst);
+    }
+
+    static RAN_IN_PID: AtomicUsize = AtomicUsize::new(0);
+
+    fn record_pid() {
+        RAN_IN_PID.store(process::id() as usize, Ordering::SeqCst);
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn not_forked_under_nextest() {
+            env::set_var("NEXTEST", "1");
+            assert!(!super::should_fork());
+            env::set_var("NEXTEST_EXECUTION_MODE", "process-per-test");
+            assert!(!super::should_fork());
+
+            super::run_test(
+                "fork_test::test::not_forked_under_nextest",
+                rusty_fork_id!(), super::TestOptions::new(),
+                Box::new(record_ran_inline));
+            assert!(RAN_INLINE.load(Ordering::SeqCst));
+        }
+
+        #[cfg(miri)]
+        #[test]
+        fn not_forked_under_miri() {
+            super::run_test(
+                "fork_test::test::not_forked_under_miri",
+                rusty_fork_id!(), super::TestOptions::new(),
+                Box::new(record_ran_inline));
+            assert!(RAN_INLINE.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn not_forked_with_passthrough() {
+            env::set_var("RUSTY_FORK_PASSTHROUGH", "1");
+            assert!(!super::should_fork());
+
+            super::run_test(
+                "fork_test::test::not_forked_with_passthrough",
+                rusty_fork_id!(), super::TestOptions::new(),
+                Box::new(record_pid));
+            assert_eq!(process::id() as usize,
+                       RAN_IN_PID.load(Ordering::SeqCst));
+        }
+
+        #[test]
+        fn forked_with_passthrough_if_forced() {
+            env::set_var("RUSTY_FORK_PASSTHROUGH", "1");
+            env::set_var("RUSTY_FORK_FORCE_FORK", "1");
+            assert!(super::should_fork());
+        }
+
+        #[test]
+        fn forked_under_nextest_if_forced() {
+            env::set_var("NEXTEST", "1");
+            env::set_var("RUSTY_FORK_FORCE_FORK", "1");
+            assert!(super::should_fork());
+        }
+
+        #[test]
+        fn forked_under_other_nextest_modes() {
+            env::set_var("NEXTEST", "1");
+            env::set_var("NEXTEST_EXECUTION_MODE", "some-future-mode");
+            assert!(super::should_fork());
+        }
+    }
 }
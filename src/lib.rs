@@ -8,6 +8,8 @@
 // except according to those terms.
 
 #![deny(missing_docs, unsafe_code)]
+// For the many variants of `Error` defined with `quick_error!`
+#![recursion_limit = "256"]
 
 //! Rusty-fork provides a way to "fork" unit tests into separate processes.
 //!
@@ -15,25 +17,25 @@
 //! processes:
 //!
 //! - When tests share a process, if any test causes the process to abort,
-//! segfault, overflow the stack, etc., the entire test runner process dies. If
-//! the test is in a subprocess, only the subprocess dies and the test runner
-//! simply fails the test.
+//!   segfault, overflow the stack, etc., the entire test runner process dies.
+//!   If the test is in a subprocess, only the subprocess dies and the test
+//!   runner simply fails the test.
 //!
 //! - Isolating a test to a subprocess makes it possible to add a timeout to
-//! the test and forcibly terminate it and produce a normal test failure.
+//!   the test and forcibly terminate it and produce a normal test failure.
 //!
 //! - Tests which need to interact with some inherently global property, such
-//! as the current working directory, can do so without interfering with other
-//! tests.
+//!   as the current working directory, can do so without interfering with other
+//!   tests.
 //!
 //! This crate itself provides two things:
 //!
 //! - The [`rusty_fork_test!`](macro.rusty_fork_test.html) macro, which is a
-//! simple way to wrap standard Rust tests to be run in subprocesses with
-//! optional timeouts.
+//!   simple way to wrap standard Rust tests to be run in subprocesses with
+//!   optional timeouts.
 //!
 //! - The [`fork`](fn.fork.html) function which can be used as a building block
-//! to make other types of process isolation strategies.
+//!   to make other types of process isolation strategies.
 //!
 //! ## Quick Start
 //!
@@ -68,7 +70,14 @@
 //! ```
 //!
 //! For more advanced usage, have a look at the [`fork`](fn.fork.html)
-//! function.
+//! function, or at [`ForkBuilder`](struct.ForkBuilder.html) for the same
+//! with named settings. Custom harnesses which want to tell apart the ways a
+//! child can fail can use [`try_fork`](fn.try_fork.html) instead.
+//!
+//! Data-driven tests with many cases can use a
+//! [`ForkPool`](struct.ForkPool.html) to run their cases in a few reused
+//! child processes instead of one process per case, at the cost of cases in
+//! the same process sharing its global state.
 //!
 //! ## How rusty-fork works
 //!
@@ -76,10 +85,10 @@
 //! test environment for a number of reasons.
 //!
 //! - While true process forking can be done on Windows, it's neither fast nor
-//! reliable.
+//!   reliable.
 //!
 //! - The Rust test environment is multi-threaded, so attempting to do anything
-//! non-trivial after a process fork would result in undefined behaviour.
+//!   non-trivial after a process fork would result in undefined behaviour.
 //!
 //! Rusty-fork instead works by _spawning_ a fresh instance of the current
 //! process, after adjusting the command-line to ensure that only the desired
@@ -92,7 +101,8 @@
 //!
 //! Since it needs to spawn new copies of the test runner executable,
 //! rusty-fork does need to know about the meaning of every flag passed by the
-//! user. If any unknown flags are encountered, forking will fail. Please do
+//! user. If any unknown flags are encountered, forking will fail (unless they
+//! are written as `--flag=value`, in which case they are dropped). Please do
 //! not hesitate to file
 //! [issues](https://github.com/AltSysrq/rusty-fork/issues) if rusty-fork fails
 //! to recognise any valid flags passed to the test runner.
@@ -111,6 +121,27 @@
 //! In general, arguments that affect which tests are run should be dropped,
 //! and others should be passed.
 //!
+//! Harnesses which add flags of their own can instead register them at
+//! runtime with `register_flag()`. Harnesses built with `libtest-mimic`,
+//! which accepts fewer flags than the standard harness, should call
+//! `set_harness_kind(HarnessKind::LibtestMimic)` at the start of `main()`
+//! so that flags are checked against its table instead.
+//!
+//! Alternatively, setting `RUSTY_FORK_ALLOW_UNKNOWN_FLAGS=1` makes rusty-fork
+//! drop any flag it does not recognise, printing a warning instead of
+//! failing. The same can be requested for individual forks with
+//! `ForkOpts::unknown_flag_policy()`.
+//!
+//! The output of each child process is captured in a temporary file whose
+//! name starts with `rusty-fork-`. These files are left behind if the test
+//! process is killed. Enabling the `sweep-temp-files` feature makes each test
+//! process remove such files of the current user which have not been modified
+//! for a day and are not in use.
+//!
+//! Setting `RUSTY_FORK_KEEP_OUTPUT=1` keeps the files of children which fail
+//! or time out instead of deleting them, and adds their paths to the error,
+//! for inspecting the full output afterwards.
+//!
 //! <!-- ENDREADME -->
 
 #[macro_use] extern crate quick_error;
@@ -118,11 +149,38 @@
 #[macro_use] mod sugar;
 #[macro_use] pub mod fork_test;
 mod error;
-mod cmdline;
+pub mod cmdline;
 mod fork;
+#[cfg(feature = "tokio")]
+mod fork_async;
+mod fork_builder;
+mod fork_opts;
+mod fork_outcome;
+mod fork_pool;
 mod child_wrapper;
+mod child_output;
+mod captured_output;
+mod sys;
+#[cfg(feature = "sweep-temp-files")]
+mod sweep;
 
 pub use crate::sugar::RustyForkId;
 pub use crate::error::{Error, Result};
-pub use crate::fork::fork;
+pub use crate::cmdline::{register_flag, set_harness_kind, FlagKind,
+                         HarnessKind};
+pub use crate::fork::{fork, fork_capture, fork_many, fork_output,
+                      fork_with_opts, skip, try_fork, SKIP_EXIT_CODE};
+#[cfg(all(unix, feature = "serde"))]
+pub use crate::fork::fork_returning;
+#[cfg(feature = "tokio")]
+pub use crate::fork_async::{fork_async, AsyncChildWrapper};
+pub use crate::fork_builder::ForkBuilder;
+#[cfg(unix)]
+pub use crate::fork_opts::Resource;
+pub use crate::fork_opts::{CaptureMode, ForkOpts, OutputStream, Priority,
+                           StdinSource, UnknownFlagPolicy};
+pub use crate::fork_outcome::ForkOutcome;
+pub use crate::fork_pool::ForkPool;
+pub use crate::child_output::ChildOutput;
+pub use crate::captured_output::CapturedOutput;
 pub use crate::child_wrapper::{ChildWrapper, ExitStatusWrapper};
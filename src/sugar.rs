@@ -1,5 +1,5 @@
 //-
-// Copyright 2018 Jason Lingle
+// Copyright 2018, 2020 Jason Lingle
 //
 // Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
 // http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
@@ -21,15 +21,68 @@ macro_rules! rusty_fork_id { () => { {
     $crate::RustyForkId::of(::std::any::TypeId::of::<_RustyForkId>())
 } } }
 
+/// Like [`rusty_fork_id!`](macro.rusty_fork_id.html), but additionally
+/// attaches a human-readable label to the identifier.
+///
+/// The label, which must be a `&'static str`, shows up in the `Debug`
+/// representation of the id so that it can be attributed to a particular
+/// test, e.g., `rusty_fork_named_id!("my_module::my_test")`. It does not
+/// otherwise affect how the id behaves; every invocation still produces a
+/// distinct id.
+#[macro_export]
+macro_rules! rusty_fork_named_id { ($label:expr) => { {
+    struct _RustyForkId;
+    $crate::RustyForkId::of(::std::any::TypeId::of::<_RustyForkId>())
+        .with_label($label)
+} } }
+
 /// The type of the value produced by
-/// [`rusty_fork_id!`](macro.rusty_fork_id.html).
+/// [`rusty_fork_id!`](macro.rusty_fork_id.html) and
+/// [`rusty_fork_named_id!`](macro.rusty_fork_named_id.html).
+///
+/// An id can also be derived from a string with
+/// [`from_seed`](#method.from_seed).
 #[derive(Clone, Hash, PartialEq, Debug)]
-pub struct RustyForkId(::std::any::TypeId);
+pub struct RustyForkId {
+    id: IdKey,
+    label: Option<&'static str>,
+}
+
+#[derive(Clone, Hash, PartialEq, Debug)]
+enum IdKey {
+    Type(::std::any::TypeId),
+    Seed(String),
+}
+
 impl RustyForkId {
     #[allow(missing_docs)]
     #[doc(hidden)]
     pub fn of(id: ::std::any::TypeId) -> Self {
-        RustyForkId(id)
+        RustyForkId { id: IdKey::Type(id), label: None }
+    }
+
+    /// Produce an identifier from an arbitrary string.
+    ///
+    /// The same seed always produces the same identifier, in every process,
+    /// and different seeds produce different identifiers. This is useful for
+    /// data-driven tests, where each case needs a distinct fork id but there
+    /// is only one call site to use `rusty_fork_id!` in; the case's name or
+    /// index can be used as the seed instead. Identifiers from seeds never
+    /// equal those produced by `rusty_fork_id!`.
+    pub fn from_seed(seed: &str) -> Self {
+        RustyForkId { id: IdKey::Seed(seed.to_owned()), label: None }
+    }
+
+    #[allow(missing_docs)]
+    #[doc(hidden)]
+    pub fn with_label(self, label: &'static str) -> Self {
+        RustyForkId { label: Some(label), ..self }
+    }
+
+    /// Return the label given to
+    /// [`rusty_fork_named_id!`](macro.rusty_fork_named_id.html), if any.
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
     }
 }
 
@@ -39,4 +92,29 @@ mod test {
     fn ids_are_actually_distinct() {
         assert_ne!(rusty_fork_id!(), rusty_fork_id!());
     }
+
+    #[test]
+    fn named_ids_are_distinct_and_labelled() {
+        let a = rusty_fork_named_id!("sugar::test::a");
+        let b = rusty_fork_named_id!("sugar::test::b");
+        assert_ne!(a, b);
+        assert_eq!(a, a.clone());
+        assert_eq!(Some("sugar::test::a"), a.label());
+        assert_eq!(None, rusty_fork_id!().label());
+        assert!(format!("{:?}", a).contains("sugar::test::a"));
+        assert!(format!("{:?}", b).contains("sugar::test::b"));
+    }
+
+    #[test]
+    fn seeded_ids_are_deterministic_and_distinct() {
+        use crate::cmdline::id_str;
+        use crate::RustyForkId;
+
+        let a = RustyForkId::from_seed("case 1");
+        assert_eq!(a, RustyForkId::from_seed("case 1"));
+        assert_eq!(id_str(&a), id_str(RustyForkId::from_seed("case 1")));
+        assert_ne!(a, RustyForkId::from_seed("case 2"));
+        assert_ne!(id_str(&a), id_str(RustyForkId::from_seed("case 2")));
+        assert!(format!("{:?}", a).contains("case 1"));
+    }
 }
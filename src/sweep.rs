@@ -0,0 +1,128 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Removal of output files left behind by earlier runs.
+//!
+//! The temporary files holding the output of child processes are normally
+//! deleted once the child has been reaped, but not if the parent is itself
+//! killed. With the "sweep-temp-files" feature, the first fork of each process
+//! removes such files which have gone unmodified for `MAX_AGE`.
+//!
+//! Files of a run which is still going on are never removed: every fork holds
+//! a shared lock on its files until they are deleted, which the sweep checks
+//! for before removing anything. (On platforms without advisory locks, only
+//! the age of the file protects it.)
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
+
+use crate::fork::TEMP_FILE_PREFIX;
+use crate::sys;
+
+/// How long a file must have gone unmodified before it is considered
+/// abandoned.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Remove abandoned output files from the system temporary directory, unless
+/// this has already been done by this process.
+pub(crate) fn sweep_once() {
+    static SWEEP: Once = Once::new();
+    SWEEP.call_once(|| sweep(&env::temp_dir(), MAX_AGE));
+}
+
+/// Remove the output files in `dir` which belong to the current user, have
+/// not been modified for `max_age`, and are not locked by a live fork.
+///
+/// This is best-effort; any file which cannot be examined is left alone.
+fn sweep(dir: &Path, max_age: Duration) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let now = SystemTime::now();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name();
+        if !name.to_string_lossy().starts_with(TEMP_FILE_PREFIX) {
+            continue;
+        }
+
+        let path = entry.path();
+        let age = fs::symlink_metadata(&path).ok()
+            .filter(|metadata| metadata.is_file() && is_ours(metadata))
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| now.duration_since(modified).ok());
+        match age {
+            Some(age) if age >= max_age => (),
+            _ => continue,
+        }
+
+        // Keep the file open until it is gone so that nobody can lock it in
+        // between
+        if let Ok(file) = fs::File::open(&path) {
+            if let Ok(true) = sys::try_lock_exclusive(&file) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_ours(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.uid() == sys::current_uid()
+}
+
+#[cfg(not(unix))]
+fn is_ours(_metadata: &fs::Metadata) -> bool {
+    // The temporary directory is already private to the user
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn stale_files_removed_and_fresh_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("rusty-fork-stale");
+        let live = dir.path().join("rusty-fork-live");
+        let unrelated = dir.path().join("unrelated");
+        fs::write(&stale, b"").unwrap();
+        fs::write(&live, b"").unwrap();
+        fs::write(&unrelated, b"").unwrap();
+        let live_file = fs::File::open(&live).unwrap();
+        sys::lock_shared(&live_file).unwrap();
+
+        thread::sleep(Duration::from_millis(1500));
+        let fresh = dir.path().join("rusty-fork-fresh");
+        fs::write(&fresh, b"").unwrap();
+
+        sweep(dir.path(), Duration::from_secs(1));
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(unrelated.exists());
+        if cfg!(unix) {
+            assert!(live.exists());
+        }
+
+        // Once the fork holding it is gone, the file can be removed
+        drop(live_file);
+        sweep(dir.path(), Duration::from_secs(1));
+        assert!(!live.exists());
+        assert!(fresh.exists());
+    }
+}
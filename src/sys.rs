@@ -0,0 +1,845 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Internal module containing the platform-specific operations (process
+//! manipulation, file locking, terminal detection) which the standard library
+//! does not provide.
+//!
+//! This is the only module permitted to use `unsafe`, since it needs to call
+//! into the operating system directly.
+
+#![allow(unsafe_code)]
+
+#[cfg(any(unix, feature = "sweep-temp-files"))]
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::os::raw::c_int;
+use std::process::{Child, Command};
+
+use crate::fork_opts::Priority;
+#[cfg(unix)]
+use crate::fork_opts::Resource;
+
+/// Ask the child process to terminate, giving it a chance to clean up.
+///
+/// On Unix, this sends `SIGTERM`. Windows has no equivalent which can be
+/// directed at a single process that was not started in its own process
+/// group, so the process is terminated forcibly.
+///
+/// The caller must ensure the child has not yet been reaped.
+#[cfg(all(unix, feature = "timeout"))]
+pub(crate) fn terminate(child: &mut Child) -> io::Result<()> {
+    // Safety: `kill()` has no memory-safety preconditions. The caller
+    // guarantees the pid still refers to our unreaped child.
+    if 0 == unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Ask the child process to terminate, giving it a chance to clean up.
+///
+/// On Unix, this sends `SIGTERM`. Windows has no equivalent which can be
+/// directed at a single process that was not started in its own process
+/// group, so the process is terminated forcibly.
+///
+/// The caller must ensure the child has not yet been reaped.
+#[cfg(all(not(unix), feature = "timeout"))]
+pub(crate) fn terminate(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// Like `terminate()`, but for a child spawned through tokio.
+///
+/// This does nothing if tokio has already reaped the child.
+#[cfg(all(unix, feature = "tokio"))]
+pub(crate) fn terminate_async(child: &mut tokio::process::Child)
+                              -> io::Result<()> {
+    let pid = match child.id() {
+        Some(pid) => pid,
+        None => return Ok(()),
+    };
+    // Safety: `kill()` has no memory-safety preconditions. Tokio only reports
+    // the pid of a child it has not reaped yet.
+    if 0 == unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Like `terminate()`, but for a child spawned through tokio.
+///
+/// This does nothing if tokio has already reaped the child.
+#[cfg(all(not(unix), feature = "tokio"))]
+pub(crate) fn terminate_async(child: &mut tokio::process::Child)
+                              -> io::Result<()> {
+    child.start_kill()
+}
+
+/// Return whether the standard output of the current process is a terminal.
+#[cfg(unix)]
+pub(crate) fn stdout_is_terminal() -> bool {
+    // Safety: `isatty()` has no preconditions.
+    1 == unsafe { libc::isatty(libc::STDOUT_FILENO) }
+}
+
+/// Return whether the standard output of the current process is a terminal.
+///
+/// This is not detected on this platform, so it is assumed not to be.
+#[cfg(not(unix))]
+pub(crate) fn stdout_is_terminal() -> bool {
+    false
+}
+
+/// The environment variables a process may need just to start up on this
+/// platform, such as the search path for shared libraries.
+#[cfg(windows)]
+pub(crate) const STARTUP_ENV: &[&str] = &["SystemRoot", "PATH"];
+/// The environment variables a process may need just to start up on this
+/// platform, such as the search path for shared libraries.
+#[cfg(target_os = "macos")]
+pub(crate) const STARTUP_ENV: &[&str] = &[
+    "DYLD_LIBRARY_PATH", "DYLD_FALLBACK_LIBRARY_PATH"];
+/// The environment variables a process may need just to start up on this
+/// platform, such as the search path for shared libraries.
+#[cfg(not(any(windows, target_os = "macos")))]
+pub(crate) const STARTUP_ENV: &[&str] = &["LD_LIBRARY_PATH"];
+
+/// Return the conventional name of the Unix signal `signal`, such as
+/// `SIGSEGV`, if it is one of the standard signals.
+#[cfg(unix)]
+pub(crate) fn signal_name(signal: i32) -> Option<&'static str> {
+    static NAMES: &[(c_int, &str)] = &[
+        (libc::SIGABRT, "SIGABRT"),
+        (libc::SIGALRM, "SIGALRM"),
+        (libc::SIGBUS, "SIGBUS"),
+        (libc::SIGFPE, "SIGFPE"),
+        (libc::SIGHUP, "SIGHUP"),
+        (libc::SIGILL, "SIGILL"),
+        (libc::SIGINT, "SIGINT"),
+        (libc::SIGKILL, "SIGKILL"),
+        (libc::SIGPIPE, "SIGPIPE"),
+        (libc::SIGQUIT, "SIGQUIT"),
+        (libc::SIGSEGV, "SIGSEGV"),
+        (libc::SIGSYS, "SIGSYS"),
+        (libc::SIGTERM, "SIGTERM"),
+        (libc::SIGTRAP, "SIGTRAP"),
+        (libc::SIGUSR1, "SIGUSR1"),
+        (libc::SIGUSR2, "SIGUSR2"),
+        (libc::SIGXCPU, "SIGXCPU"),
+        (libc::SIGXFSZ, "SIGXFSZ"),
+    ];
+
+    NAMES.iter().filter(|&&(number, _)| number == signal)
+        .map(|&(_, name)| name).next()
+}
+
+/// Return the conventional name of the Unix signal `signal`.
+///
+/// There are no signals on this platform.
+#[cfg(not(unix))]
+pub(crate) fn signal_name(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+/// Terminate the current process with `signal`, as if it had not been
+/// handled, for tests of how the death of a child is reported.
+#[cfg(all(test, unix))]
+pub(crate) fn die_by_signal(signal: i32) -> ! {
+    // Safety: Resetting the disposition of a signal and raising it have no
+    // memory-safety preconditions. The standard library's handler for
+    // `SIGSEGV` would otherwise return without terminating the process.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    unreachable!("process survived signal {}", signal)
+}
+
+/// Return the effective user id of the current process.
+#[cfg(all(unix, feature = "sweep-temp-files"))]
+pub(crate) fn current_uid() -> u32 {
+    // Safety: `geteuid()` has no preconditions and cannot fail.
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(all(unix, feature = "sweep-temp-files"))]
+fn flock(file: &File, operation: c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: `flock()` has no memory-safety preconditions, and `file` keeps
+    // the descriptor open for the duration of the call.
+    if 0 == unsafe { libc::flock(file.as_raw_fd(), operation) } {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Take a shared advisory lock on `file`, marking it as in use.
+///
+/// The lock belongs to the open file description, so it is held for as long
+/// as any descriptor for it, including those inherited by a child process,
+/// remains open.
+#[cfg(all(unix, feature = "sweep-temp-files"))]
+pub(crate) fn lock_shared(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_SH)
+}
+
+/// Take a shared advisory lock on `file`, marking it as in use.
+///
+/// There is no such lock on this platform, so this does nothing.
+#[cfg(all(not(unix), feature = "sweep-temp-files"))]
+pub(crate) fn lock_shared(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Try to take an exclusive advisory lock on `file` without blocking,
+/// returning whether it succeeded, i.e., whether no one else has the file
+/// locked.
+#[cfg(all(unix, feature = "sweep-temp-files"))]
+pub(crate) fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+    match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(()) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Try to take an exclusive advisory lock on `file` without blocking,
+/// returning whether it succeeded, i.e., whether no one else has the file
+/// locked.
+///
+/// There is no such lock on this platform, so this always succeeds.
+#[cfg(all(not(unix), feature = "sweep-temp-files"))]
+pub(crate) fn try_lock_exclusive(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// The type of the `resource` argument of `setrlimit()`.
+#[cfg(all(any(target_os = "linux", target_os = "hurd"), target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(any(target_os = "linux", target_os = "hurd"),
+                        target_env = "gnu"))))]
+type RlimitResource = c_int;
+
+/// Convert `resource` and its limits to what `setrlimit()` takes.
+#[cfg(unix)]
+fn rlimit(resource: Resource, soft: u64, hard: u64)
+          -> (RlimitResource, libc::rlimit) {
+    let resource = match resource {
+        Resource::AddressSpace => libc::RLIMIT_AS,
+        Resource::CpuTime => libc::RLIMIT_CPU,
+        Resource::OpenFiles => libc::RLIMIT_NOFILE,
+        Resource::CoreFileSize => libc::RLIMIT_CORE,
+    };
+    let value = |limit| if u64::MAX == limit {
+        libc::RLIM_INFINITY
+    } else {
+        limit as libc::rlim_t
+    };
+    (resource, libc::rlimit { rlim_cur: value(soft), rlim_max: value(hard) })
+}
+
+/// Set up `command` so that the process it spawns starts with the given
+/// resource limits.
+#[cfg(unix)]
+pub(crate) fn set_rlimits(command: &mut Command,
+                          limits: &[(Resource, u64, u64)]) {
+    use std::os::unix::process::CommandExt;
+
+    let limits: Vec<_> = limits.iter()
+        .map(|&(resource, soft, hard)| rlimit(resource, soft, hard))
+        .collect();
+    // Safety: `setrlimit()` is async-signal-safe, and the limits are
+    // converted beforehand so that nothing is allocated between `fork()` and
+    // `exec()`.
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in &limits {
+                if 0 != libc::setrlimit(*resource, limit) {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Check whether a child of this process would be able to set the given
+/// limit on `resource`, failing with the error `setrlimit()` would fail with
+/// if not.
+///
+/// Errors from `setrlimit()` in the child only reach the parent as a bare
+/// error code, so this is used to tell which limit is at fault beforehand.
+#[cfg(unix)]
+pub(crate) fn check_rlimit(resource: Resource, soft: u64, hard: u64)
+                           -> io::Result<()> {
+    let (resource, limit) = rlimit(resource, soft, hard);
+    if limit.rlim_cur > limit.rlim_max {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // Safety: `current` is valid for writes.
+    if 0 != unsafe { libc::getrlimit(resource, &mut current) } {
+        return Err(io::Error::last_os_error());
+    }
+    // Only a privileged process may raise its hard limit. Checking for root
+    // is an approximation, but anything it misses still fails to spawn.
+    // Safety: `geteuid()` has no preconditions.
+    if limit.rlim_max > current.rlim_max && 0 != unsafe { libc::geteuid() } {
+        return Err(io::Error::from_raw_os_error(libc::EPERM));
+    }
+    Ok(())
+}
+
+/// Set up `command` so that the process it spawns runs at `priority`.
+///
+/// Setting it is only allowed to fail in the child if not `strict`, since
+/// `check_priority()` can only approximate what is permitted.
+#[cfg(unix)]
+pub(crate) fn set_priority(command: &mut Command, priority: Priority,
+                           strict: bool) {
+    use std::os::unix::process::CommandExt;
+
+    let nice = priority.nice();
+    // Safety: `setpriority()` is async-signal-safe, and nothing else is done
+    // between `fork()` and `exec()`.
+    unsafe {
+        command.pre_exec(move || {
+            if 0 == libc::setpriority(libc::PRIO_PROCESS, 0, nice) || !strict {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        });
+    }
+}
+
+/// Set up `command` so that the process it spawns runs at `priority`.
+#[cfg(windows)]
+pub(crate) fn set_priority(command: &mut Command, priority: Priority,
+                           _strict: bool) {
+    use std::os::windows::process::CommandExt;
+
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x20;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x8000;
+
+    command.creation_flags(match priority.nice() {
+        nice if nice < 0 => ABOVE_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        _ => BELOW_NORMAL_PRIORITY_CLASS,
+    });
+}
+
+/// Set up `command` so that the process it spawns runs at `priority`.
+///
+/// There is no way to do so on this platform, which `check_priority()`
+/// reports.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn set_priority(_command: &mut Command, _priority: Priority,
+                           _strict: bool) { }
+
+/// Check whether a child of this process would be able to run at
+/// `priority`, failing with the error setting it would fail with if not.
+///
+/// Only root is assumed to be able to raise the priority, which is an
+/// approximation.
+#[cfg(unix)]
+pub(crate) fn check_priority(priority: Priority) -> io::Result<()> {
+    let nice = priority.nice();
+    if !(-20..=19).contains(&nice) {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // Safety: these functions have no preconditions. `getpriority()` can
+    // only fail for other processes.
+    let (current, root) = unsafe {
+        (libc::getpriority(libc::PRIO_PROCESS, 0), 0 == libc::geteuid())
+    };
+    if nice < current && !root {
+        return Err(io::Error::from_raw_os_error(libc::EACCES));
+    }
+    Ok(())
+}
+
+/// Check whether a child of this process would be able to run at
+/// `priority`, failing with the error setting it would fail with if not.
+///
+/// On Windows, every priority maps to a class which can always be set.
+#[cfg(windows)]
+pub(crate) fn check_priority(_priority: Priority) -> io::Result<()> {
+    Ok(())
+}
+
+/// Check whether a child of this process would be able to run at
+/// `priority`, failing with the error setting it would fail with if not.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn check_priority(_priority: Priority) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+                       "priorities are not supported on this platform"))
+}
+
+/// Set up `command` so that the process it spawns runs with the given
+/// supplementary groups, group id and user id, set in that order.
+#[cfg(unix)]
+pub(crate) fn set_credentials(command: &mut Command, uid: Option<u32>,
+                              gid: Option<u32>, groups: Option<Vec<u32>>) {
+    use std::os::unix::process::CommandExt;
+
+    let groups: Option<Vec<libc::gid_t>> = groups.map(
+        |groups| groups.into_iter().map(|gid| gid as libc::gid_t).collect());
+    // Safety: `setgroups()`, `setgid()` and `setuid()` are async-signal-safe,
+    // and the groups are converted beforehand so that nothing is allocated
+    // between `fork()` and `exec()`.
+    unsafe {
+        command.pre_exec(move || {
+            let failed = |result: c_int| if 0 == result {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            };
+            if let Some(ref groups) = groups {
+                failed(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+            }
+            if let Some(gid) = gid {
+                failed(libc::setgid(gid as libc::gid_t))?;
+            }
+            if let Some(uid) = uid {
+                failed(libc::setuid(uid as libc::uid_t))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Check whether a child of this process would be able to switch to the
+/// given credentials, returning a description of the first one it could not
+/// switch to and the error it would fail with if not.
+///
+/// As for `check_rlimit()`, this is so that errors can say what went wrong.
+/// Only root is assumed to be able to switch to other users or groups, which
+/// is an approximation, but anything it misses still fails to spawn.
+#[cfg(unix)]
+pub(crate) fn check_credentials(uid: Option<u32>, gid: Option<u32>,
+                                groups: Option<&[u32]>)
+                                -> Result<(), (String, io::Error)> {
+    let denied = || io::Error::from_raw_os_error(libc::EPERM);
+    // Safety: these functions have no preconditions.
+    let (euid, ruid, egid, rgid) = unsafe {
+        (libc::geteuid(), libc::getuid(), libc::getegid(), libc::getgid())
+    };
+    if 0 == euid {
+        return Ok(());
+    }
+
+    if groups.is_some() {
+        return Err(("supplementary groups".to_owned(), denied()));
+    }
+    if let Some(gid) = gid {
+        if gid != egid && gid != rgid {
+            return Err((format!("group id {}", gid), denied()));
+        }
+    }
+    if let Some(uid) = uid {
+        if uid != euid && uid != ruid {
+            return Err((format!("user id {}", uid), denied()));
+        }
+    }
+    Ok(())
+}
+
+/// Change the owner of `file` to `uid` and its group to `gid`, leaving either
+/// unchanged if `None`.
+#[cfg(unix)]
+pub(crate) fn fchown(file: &File, uid: Option<u32>, gid: Option<u32>)
+                     -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // `-1` leaves the id unchanged
+    let uid = uid.map_or(libc::uid_t::MAX, |uid| uid as libc::uid_t);
+    let gid = gid.map_or(libc::gid_t::MAX, |gid| gid as libc::gid_t);
+    // Safety: `fchown()` has no memory-safety preconditions, and `file` keeps
+    // the descriptor open for the duration of the call.
+    if 0 == unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Create a pipe, returning its reading end first.
+///
+/// Both ends are closed on exec, so that no child inherits them unless passed
+/// to `inherit_fd()`.
+#[cfg(all(unix, feature = "serde"))]
+pub(crate) fn pipe() -> io::Result<(File, File)> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0 as c_int; 2];
+    // Safety: `fds` has room for the two descriptors written to it.
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let created = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    // Safety: as above.
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let created = unsafe { libc::pipe(fds.as_mut_ptr()) };
+    if 0 != created {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safety: The descriptors were just created, and nothing else owns them.
+    let (read, write) = unsafe {
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+    // Without `pipe2()`, a process spawned by another thread in the meantime
+    // may still inherit them
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        set_cloexec(fds[0], true)?;
+        set_cloexec(fds[1], true)?;
+    }
+    Ok((read, write))
+}
+
+/// Make the child spawned by `command` inherit the descriptor `fd`, which is
+/// otherwise closed on exec, under the same number.
+///
+/// `fd` must be kept open until the child has been spawned.
+#[cfg(all(unix, feature = "serde"))]
+pub(crate) fn inherit_fd(command: &mut Command, fd: c_int) {
+    use std::os::unix::process::CommandExt;
+
+    // Safety: `fcntl()` is async-signal-safe, and nothing else is done
+    // between `fork()` and `exec()`.
+    unsafe {
+        command.pre_exec(move || set_cloexec(fd, false));
+    }
+}
+
+/// Take ownership of the descriptor `fd` inherited from the parent through
+/// `inherit_fd()`, so that no process spawned from now on inherits it in
+/// turn.
+///
+/// Returns `None` if `fd` is not open.
+#[cfg(all(unix, feature = "serde"))]
+pub(crate) fn take_inherited_fd(fd: c_int) -> Option<File> {
+    use std::os::unix::io::FromRawFd;
+
+    set_cloexec(fd, true).ok()?;
+    // Safety: `fd` is open, and the parent passed it to us alone.
+    Some(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(all(unix, feature = "serde"))]
+fn set_cloexec(fd: c_int, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    // Safety: `fcntl()` has no memory-safety preconditions, and fails
+    // harmlessly if `fd` is not open.
+    if -1 == unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The child process of a fork together with every process it starts, so that
+/// none of them outlive the fork.
+///
+/// On Unix, this is a process group led by the child. On Windows, it is a job
+/// object which the child is assigned to right after it starts; processes the
+/// child starts before that are not part of the job.
+#[derive(Debug)]
+pub(crate) struct ProcessTree {
+    #[cfg(unix)]
+    pgid: libc::pid_t,
+    #[cfg(windows)]
+    job: windows::Handle,
+}
+
+// The job handle is only ever used through thread-safe system calls
+#[cfg(windows)]
+unsafe impl Send for ProcessTree { }
+#[cfg(windows)]
+unsafe impl Sync for ProcessTree { }
+
+impl ProcessTree {
+    /// Set up `command` so that the process it spawns can lead a tree.
+    #[cfg(unix)]
+    pub(crate) fn prepare(command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        // Safety: `setpgid()` is async-signal-safe, and nothing else is done
+        // between `fork()` and `exec()`.
+        unsafe {
+            command.pre_exec(|| if 0 == libc::setpgid(0, 0) {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            });
+        }
+    }
+
+    /// Set up `command` so that the process it spawns can lead a tree.
+    #[cfg(not(unix))]
+    pub(crate) fn prepare(_command: &mut Command) { }
+
+    /// Start tracking the tree of `child`, which was spawned from a command
+    /// passed to `prepare()`.
+    #[cfg(unix)]
+    pub(crate) fn attach(child: &Child) -> io::Result<Self> {
+        Ok(ProcessTree { pgid: child.id() as libc::pid_t })
+    }
+
+    /// Start tracking the tree of `child`, which was spawned from a command
+    /// passed to `prepare()`.
+    #[cfg(windows)]
+    pub(crate) fn attach(child: &Child) -> io::Result<Self> {
+        use std::os::windows::io::AsRawHandle;
+
+        Self::attach_handle(child.as_raw_handle() as windows::Handle)
+    }
+
+    #[cfg(windows)]
+    fn attach_handle(handle: windows::Handle) -> io::Result<Self> {
+        windows::Job::create().and_then(|job| {
+            job.assign(handle)?;
+            Ok(ProcessTree { job: job.into_handle() })
+        })
+    }
+
+    /// Start tracking the tree of `child`, which was spawned from a command
+    /// passed to `prepare()`.
+    #[cfg(not(any(unix, windows)))]
+    pub(crate) fn attach(_child: &Child) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "process trees are not supported on this platform"))
+    }
+
+    /// Like `attach()`, but for a child spawned through tokio.
+    #[cfg(all(unix, feature = "tokio"))]
+    pub(crate) fn attach_async(child: &tokio::process::Child)
+                               -> io::Result<Self> {
+        match child.id() {
+            Some(pid) => Ok(ProcessTree { pgid: pid as libc::pid_t }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                       "Process already reaped")),
+        }
+    }
+
+    /// Like `attach()`, but for a child spawned through tokio.
+    #[cfg(all(windows, feature = "tokio"))]
+    pub(crate) fn attach_async(child: &tokio::process::Child)
+                               -> io::Result<Self> {
+        match child.raw_handle() {
+            Some(handle) => Self::attach_handle(handle as windows::Handle),
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                       "Process already reaped")),
+        }
+    }
+
+    /// Like `attach()`, but for a child spawned through tokio.
+    #[cfg(all(not(any(unix, windows)), feature = "tokio"))]
+    pub(crate) fn attach_async(_child: &tokio::process::Child)
+                               -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "process trees are not supported on this platform"))
+    }
+
+    /// Ask every process in the tree to terminate.
+    ///
+    /// On Windows, this is the same as `kill()`.
+    #[cfg(all(unix, feature = "timeout"))]
+    pub(crate) fn terminate(&self) -> io::Result<()> {
+        self.signal(libc::SIGTERM)
+    }
+
+    /// Ask every process in the tree to terminate.
+    ///
+    /// On Windows, this is the same as `kill()`.
+    #[cfg(all(not(unix), feature = "timeout"))]
+    pub(crate) fn terminate(&self) -> io::Result<()> {
+        self.kill()
+    }
+
+    /// Forcibly kill every process in the tree.
+    ///
+    /// This may be called after the child itself has been reaped, to clean
+    /// up whatever it left behind.
+    #[cfg(unix)]
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+
+    /// Forcibly kill every process in the tree.
+    ///
+    /// This may be called after the child itself has been reaped, to clean
+    /// up whatever it left behind.
+    #[cfg(windows)]
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        windows::terminate_job(self.job)
+    }
+
+    /// Forcibly kill every process in the tree.
+    ///
+    /// This may be called after the child itself has been reaped, to clean
+    /// up whatever it left behind.
+    #[cfg(not(any(unix, windows)))]
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn signal(&self, signal: c_int) -> io::Result<()> {
+        // Safety: `kill()` has no memory-safety preconditions. The process
+        // group id cannot be reused while any member of the group remains,
+        // and if none remains, this fails harmlessly.
+        if 0 == unsafe { libc::kill(-self.pgid, signal) } {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        // The job is set to kill everything in it once closed
+        windows::close(self.job);
+    }
+}
+
+/// The parts of the Windows API needed for job objects, declared here to
+/// avoid depending on a binding crate for a handful of functions.
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+
+    pub(crate) type Handle = *mut u8;
+    type Bool = i32;
+
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: u32 = 9;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        read_operation_count: u64,
+        write_operation_count: u64,
+        other_operation_count: u64,
+        read_transfer_count: u64,
+        write_transfer_count: u64,
+        other_transfer_count: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut u8, name: *const u16) -> Handle;
+        fn SetInformationJobObject(job: Handle, class: u32, info: *mut u8,
+                                   length: u32) -> Bool;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> Bool;
+        fn TerminateJobObject(job: Handle, exit_code: u32) -> Bool;
+        fn CloseHandle(handle: Handle) -> Bool;
+    }
+
+    fn check(result: Bool) -> io::Result<()> {
+        if 0 != result {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Owns a job handle until it is handed over with `into_handle()`.
+    pub(crate) struct Job(Handle);
+
+    impl Job {
+        /// Create an anonymous job which kills its processes once closed.
+        pub(crate) fn create() -> io::Result<Self> {
+            // Safety: Null attributes and name are permitted.
+            let job = Job(unsafe {
+                CreateJobObjectW(ptr::null_mut(), ptr::null())
+            });
+            if job.0.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut info = ExtendedLimitInformation::default();
+            info.basic_limit_information.limit_flags =
+                JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            // Safety: `info` is a valid `JOBOBJECT_EXTENDED_LIMIT_INFORMATION`
+            // of the given size.
+            check(unsafe {
+                SetInformationJobObject(
+                    job.0, JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS,
+                    &mut info as *mut ExtendedLimitInformation as *mut u8,
+                    mem::size_of::<ExtendedLimitInformation>() as u32)
+            })?;
+            Ok(job)
+        }
+
+        pub(crate) fn assign(&self, process: Handle) -> io::Result<()> {
+            // Safety: Both handles are valid for the duration of the call.
+            check(unsafe { AssignProcessToJobObject(self.0, process) })
+        }
+
+        pub(crate) fn into_handle(self) -> Handle {
+            let handle = self.0;
+            mem::forget(self);
+            handle
+        }
+    }
+
+    impl Drop for Job {
+        fn drop(&mut self) {
+            close(self.0);
+        }
+    }
+
+    pub(crate) fn terminate_job(job: Handle) -> io::Result<()> {
+        // Safety: The caller owns `job`.
+        check(unsafe { TerminateJobObject(job, 1) })
+    }
+
+    pub(crate) fn close(handle: Handle) {
+        // Safety: The caller owns `handle` and does not use it afterwards.
+        unsafe { CloseHandle(handle); }
+    }
+}
@@ -0,0 +1,66 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Forking from an integration test target, whose crate is not the one
+//! defining `rusty_fork_test!`.
+
+use std::env;
+
+use rusty_fork::{fork, rusty_fork_id, rusty_fork_test, rusty_fork_test_name};
+use rusty_fork::cmdline::OCCURS_ENV;
+use rusty_fork::Error;
+
+fn in_child() -> bool {
+    env::var_os(OCCURS_ENV).is_some()
+}
+
+rusty_fork_test! {
+    #[test]
+    fn top_level_test_runs_in_child() {
+        assert!(in_child());
+    }
+}
+
+mod nested {
+    pub mod deeper {
+        use super::super::in_child;
+        use rusty_fork::rusty_fork_test;
+
+        rusty_fork_test! {
+            #[test]
+            fn nested_test_runs_in_child() {
+                assert!(in_child());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_name_matches_harness() {
+    assert_eq!("test_name_matches_harness",
+               rusty_fork_test_name!(test_name_matches_harness));
+
+    let status = fork(
+        rusty_fork_test_name!(test_name_matches_harness), rusty_fork_id!(),
+        |_| (),
+        |child, _| child.wait().unwrap(),
+        || assert!(in_child())).unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn wrong_test_name_fails() {
+    match fork("integration_target::wrong_test_name_fails", rusty_fork_id!(),
+               |_| (),
+               |child, _| { child.wait().unwrap(); },
+               || ()) {
+        Err(Error::TestNotRun(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
@@ -0,0 +1,109 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Forking from a harness with the command line of one built with the
+//! `libtest-mimic` crate.
+//!
+//! The harness is reproduced here rather than depending on the crate itself,
+//! and is as strict as the real one: any flag `libtest-mimic` does not have
+//! is rejected, as is more than one filter.
+
+use std::env;
+use std::process;
+
+use rusty_fork::cmdline::OCCURS_ENV;
+use rusty_fork::{fork, fork_with_opts, rusty_fork_id, set_harness_kind};
+use rusty_fork::{Error, ForkOpts, HarnessKind};
+
+static SWITCHES: &[&str] = &[
+    "--bench", "--exact", "--ignored", "--include-ignored", "--nocapture",
+    "--quiet", "--show-output", "--test", "-q",
+];
+static WITH_VALUE: &[&str] = &[
+    "--color", "--format", "--logfile", "--skip", "--test-threads", "-Z",
+];
+
+struct Args {
+    exact: bool,
+    filter: Option<String>,
+}
+
+fn parse_args() -> Args {
+    let mut parsed = Args { exact: false, filter: None };
+    let mut args = env::args().skip(1);
+    let mut positional_only = false;
+    while let Some(arg) = args.next() {
+        let name = arg.split('=').next().unwrap().to_owned();
+        if positional_only || !arg.starts_with('-') {
+            if parsed.filter.is_some() {
+                eprintln!("error: unexpected argument '{}'", arg);
+                process::exit(2);
+            }
+            parsed.filter = Some(arg);
+        } else if "--" == arg {
+            positional_only = true;
+        } else if "--exact" == arg {
+            parsed.exact = true;
+        } else if SWITCHES.contains(&&*arg) {
+        } else if WITH_VALUE.contains(&&*name) {
+            if name == arg {
+                args.next();
+            }
+        } else {
+            eprintln!("error: unexpected argument '{}' found", arg);
+            process::exit(2);
+        }
+    }
+    parsed
+}
+
+fn forked_trial() {
+    let status = fork(
+        "forked_trial", rusty_fork_id!(),
+        |_| (),
+        |child, _| child.wait().unwrap(),
+        || assert!(env::var_os(OCCURS_ENV).is_some())).unwrap();
+    assert!(status.success());
+}
+
+fn libtest_only_flags_rejected() {
+    let opts = ForkOpts::new().parent_args(
+        vec!["libtest_mimic", "--report-time", "--show-output"]);
+    match fork_with_opts(
+        "libtest_only_flags_rejected", rusty_fork_id!(), opts,
+        |child, _| { child.wait().unwrap(); },
+        || ())
+    {
+        Err(Error::UnknownFlag { ref flag, .. }) =>
+            assert_eq!("--report-time", flag),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+static TRIALS: &[(&str, fn ())] = &[
+    ("forked_trial", forked_trial),
+    ("libtest_only_flags_rejected", libtest_only_flags_rejected),
+];
+
+fn main() {
+    set_harness_kind(HarnessKind::LibtestMimic);
+
+    let args = parse_args();
+    for &(name, trial) in TRIALS {
+        let selected = match args.filter {
+            None => true,
+            Some(ref filter) if args.exact => filter == name,
+            Some(ref filter) => name.contains(&**filter),
+        };
+        if selected {
+            trial();
+            println!("test {} ... ok", name);
+        }
+    }
+}
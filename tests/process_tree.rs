@@ -0,0 +1,121 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tests that `ForkOpts::kill_process_tree()` kills the processes started by
+//! the child, with a process group on Unix and a job object on Windows.
+
+#![cfg(any(unix, windows))]
+
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusty_fork::{fork_with_opts, rusty_fork_id, ForkOpts};
+
+const PID_FILE_ENV: &str = "PROCESS_TREE_PID_FILE";
+
+/// Start a long-running process in the background and record its pid where
+/// the parent can find it.
+fn start_sleeper() {
+    #[cfg(unix)]
+    let output = Command::new("sh").arg("-c")
+        .arg("sleep 30 >/dev/null 2>&1 & echo $!")
+        .output().unwrap();
+    #[cfg(windows)]
+    let output = Command::new("powershell")
+        .arg("-NoProfile").arg("-Command")
+        .arg("(Start-Process ping -ArgumentList '-n','30','127.0.0.1' \
+              -WindowStyle Hidden -PassThru).Id")
+        .output().unwrap();
+    fs::write(env::var_os(PID_FILE_ENV).unwrap(),
+              String::from_utf8_lossy(&output.stdout).trim()).unwrap();
+}
+
+/// Return whether the process `pid` is still running, as opposed to having
+/// exited, whether or not it has been reaped yet.
+#[cfg(unix)]
+fn is_running(pid: &str) -> bool {
+    let output = Command::new("ps")
+        .arg("-o").arg("stat=").arg("-p").arg(pid)
+        .output().unwrap();
+    let stat = String::from_utf8_lossy(&output.stdout);
+    !stat.trim().is_empty() && !stat.trim().starts_with('Z')
+}
+
+/// Return whether the process `pid` is still running.
+#[cfg(windows)]
+fn is_running(pid: &str) -> bool {
+    let output = Command::new("tasklist")
+        .arg("/FI").arg(format!("PID eq {}", pid)).arg("/NH")
+        .output().unwrap();
+    String::from_utf8_lossy(&output.stdout).split_whitespace()
+        .any(|word| word == pid)
+}
+
+/// Forcibly kill the process `pid`, if it is still running.
+fn kill(pid: &str) {
+    #[cfg(unix)]
+    let _ = Command::new("kill").arg(pid).status();
+    #[cfg(windows)]
+    let _ = Command::new("taskkill").arg("/F").arg("/PID").arg(pid).status();
+}
+
+/// Wait up to a few seconds for `pid` to exit, returning whether it did.
+fn exits_soon(pid: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if !is_running(pid) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn run(test_name: &str, kill_process_tree: bool, in_child: fn ()) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("sleeper.pid");
+    let opts = ForkOpts::new()
+        .env(PID_FILE_ENV, &pid_file)
+        .kill_process_tree(kill_process_tree);
+    #[cfg(feature = "timeout")]
+    let opts = opts.timeout(Duration::from_millis(500));
+
+    let _ = fork_with_opts(
+        test_name, rusty_fork_id!(), opts,
+        |child, _| { child.wait().unwrap(); },
+        in_child);
+    fs::read_to_string(&pid_file).unwrap()
+}
+
+#[cfg(feature = "timeout")]
+#[test]
+fn tree_killed_on_timeout() {
+    let pid = run("tree_killed_on_timeout", true, || {
+        start_sleeper();
+        thread::sleep(Duration::from_secs(10));
+    });
+    assert!(exits_soon(&pid), "sleeper {} still running", pid);
+}
+
+#[test]
+fn tree_killed_after_child_exits() {
+    let pid = run("tree_killed_after_child_exits", true, start_sleeper);
+    assert!(exits_soon(&pid), "sleeper {} still running", pid);
+}
+
+#[test]
+fn tree_not_killed_by_default() {
+    let pid = run("tree_not_killed_by_default", false, start_sleeper);
+    let survived = is_running(&pid);
+    kill(&pid);
+    assert!(survived, "sleeper {} was killed", pid);
+}
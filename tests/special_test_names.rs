@@ -0,0 +1,89 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Forking tests whose names contain characters which would not survive being
+//! joined into a command string.
+//!
+//! libtest only produces test names made of identifiers, so this target has
+//! its own minimal harness, like those of parametrised test frameworks: a
+//! test runs if there is no filter, or if the filter following `--` equals
+//! its name and `--exact` was given.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process;
+
+use rusty_fork::{fork, rusty_fork_id};
+
+const RECORD_ENV: &str = "SPECIAL_TEST_NAMES_RECORD";
+
+static TEST_NAMES: &[&str] = &[
+    "my_test::case_1_\"hello world\"",
+    "my_test::case_2_'single' quotes",
+    "generic::<Vec<u8>>",
+    "ünïcödé::tëst",
+    "with # hash",
+    "$HOME `backticks` \\ and ; semicolon",
+    "my_test::case",
+];
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let filter = args.iter().position(|arg| "--" == arg)
+        .and_then(|ix| args.get(ix + 1));
+    if filter.is_some() && !args.iter().any(|arg| "--exact" == arg) {
+        panic!("Filter without --exact in {:?}", args);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut failed = false;
+    for &test_name in TEST_NAMES {
+        match filter {
+            Some(filter) if filter != test_name => continue,
+            _ => (),
+        }
+
+        // Only the parent gets here with `RECORD_ENV` unset
+        let record = env::var_os(RECORD_ENV)
+            .unwrap_or_else(|| dir.path().join("record").into_os_string());
+        let _ = fs::remove_file(&record);
+
+        let result = fork(
+            test_name, rusty_fork_id!(),
+            |cmd| { cmd.env(RECORD_ENV, &record); },
+            |child, _| child.wait().unwrap(),
+            || {
+                let mut file = fs::OpenOptions::new()
+                    .create(true).append(true)
+                    .open(env::var_os(RECORD_ENV).unwrap()).unwrap();
+                writeln!(file, "{}", test_name).unwrap();
+            });
+        match result {
+            Ok(ref status) if status.success() => (),
+            result => {
+                println!("{}: unexpected result {:?}", test_name, result);
+                failed = true;
+                continue;
+            },
+        }
+
+        if filter.is_none() {
+            let ran = fs::read_to_string(&record).unwrap_or_default();
+            if format!("{}\n", test_name) != ran {
+                println!("{}: child ran {:?}", test_name, ran);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
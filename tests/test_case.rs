@@ -0,0 +1,76 @@
+//-
+// Copyright 2020 Jason Lingle
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parameterised tests in `rusty_fork_test!`, each case of which is
+//! generated by `#[test_case(...)]` and forked on its own.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+
+use rusty_fork::cmdline::OCCURS_ENV;
+use rusty_fork::rusty_fork_test;
+use test_case::test_case;
+
+const LOG_ENV: &str = "TEST_CASE_LOG_DIR";
+
+rusty_fork_test! {
+    #[test_case(1, "one" ; "first")]
+    #[test_case(2, "two" ; "second")]
+    fn each_case_forked(n: u32, name: &str) {
+        if let Some(dir) = env::var_os(LOG_ENV) {
+            fs::write(Path::new(&dir).join(name),
+                      format!("{} {} {}", n, process::id(),
+                              env::var_os(OCCURS_ENV).is_some()))
+                .unwrap();
+        }
+    }
+}
+
+/// Run both cases of `each_case_forked` in a copy of the harness with
+/// `test_threads`, and return whether each was run in a child process.
+fn run_cases(test_threads: &str) -> Vec<bool> {
+    let dir = tempfile::tempdir().unwrap();
+    let harness = Command::new(env::current_exe().unwrap())
+        .arg("each_case_forked::").arg("--test-threads").arg(test_threads)
+        .env(LOG_ENV, dir.path())
+        .stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    let harness_pid = harness.id().to_string();
+    let output = harness.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Harness failed:\n{}\n{}",
+            stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("2 passed"), "Unexpected output:\n{}", stdout);
+
+    [("1", "one"), ("2", "two")].iter().map(|&(n, name)| {
+        let log = fs::read_to_string(dir.path().join(name)).unwrap();
+        let fields = log.split(' ').collect::<Vec<_>>();
+        assert_eq!(n, fields[0]);
+        // Either run in a child, or directly in the harness
+        let forked = "true" == fields[2];
+        assert_eq!(forked, harness_pid != fields[1], "{}", log);
+        forked
+    }).collect()
+}
+
+#[test]
+fn both_cases_forked_and_run() {
+    // Older harnesses run tests on the main thread if there is just one
+    assert_eq!(vec![true, true], run_cases("2"));
+}
+
+#[test]
+fn both_cases_run_one_at_a_time() {
+    // Whether the cases can be forked depends on the version of the harness,
+    // but they are run either way, and treated alike
+    let forked = run_cases("1");
+    assert_eq!(forked[0], forked[1]);
+}